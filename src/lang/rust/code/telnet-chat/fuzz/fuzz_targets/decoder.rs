@@ -0,0 +1,61 @@
+//! Feeds arbitrary bytes to `TelnetCodec`, once whole and once split into
+//! chunks, and checks that both give the same items and that any error is
+//! `InvalidData`. Both the line mode and the data mode are checked.
+//!
+//!     cd telnet-chat/fuzz && cargo +nightly fuzz run decoder
+//!
+//! The first byte of the input picks the chunk size for the split run.
+
+#![no_main]
+
+use std::io;
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+use telnet_chat::telnet::{Item, TelnetCodec};
+
+fn decode<'a>(
+    mut codec: TelnetCodec,
+    chunks: impl Iterator<Item = &'a [u8]>,
+) -> (Vec<Item>, Option<io::ErrorKind>) {
+    let mut buf = BytesMut::new();
+    let mut items: Vec<Item> = Vec::new();
+
+    for chunk in chunks {
+        buf.extend_from_slice(chunk);
+        loop {
+            match codec.decode(&mut buf) {
+                // In data mode, text is split wherever the reads end, so runs
+                // of it are joined.
+                Ok(Some(Item::Data(more))) => match items.last_mut() {
+                    Some(Item::Data(data)) => data.extend(more),
+                    _ => items.push(Item::Data(more)),
+                },
+                Ok(Some(item)) => items.push(item),
+                Ok(None) => break,
+                Err(err) => return (items, Some(err.kind())),
+            }
+        }
+    }
+
+    (items, None)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (size, input) = match data.split_first() {
+        Some((size, input)) => (*size as usize % 16 + 1, input),
+        None => return,
+    };
+
+    for codec in [TelnetCodec::new, TelnetCodec::data] {
+        let whole = decode(codec(), std::iter::once(input));
+        let split = decode(codec(), input.chunks(size));
+        assert_eq!(whole, split);
+
+        if let Some(kind) = whole.1 {
+            assert_eq!(kind, io::ErrorKind::InvalidData);
+        }
+    }
+});
@@ -1,13 +1,43 @@
+use std::future::Future;
 use std::net::SocketAddr;
 use std::io;
+use std::sync::Arc;
 
 use crate::main_loop::{ServerHandle, ToServer};
-use crate::client::{spawn_client, ClientInfo};
+use crate::client::{spawn_client, ClientInfo, Connection};
+use crate::proxy::{self, Cidr};
 
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 
-pub async fn start_accept(bind: SocketAddr, mut handle: ServerHandle) {
-    let res = accept_loop(bind, handle.clone()).await;
+/// Where the accept loop gets its connections. Besides `TcpListener`, the
+/// simulation tests have one that hands out in-memory connections.
+pub trait Listener: Send + 'static {
+    type Conn: Connection;
+
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Conn, SocketAddr)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Conn = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
+
+/// Accept connections on `bind`. Connections from the `proxies` start with a
+/// PROXY header, which gives the address of the real client.
+pub async fn start_accept(bind: SocketAddr, proxies: Vec<Cidr>, mut handle: ServerHandle) {
+    match TcpListener::bind(bind).await {
+        Ok(listen) => start_accept_on(listen, proxies, handle).await,
+        Err(err) => handle.send(ToServer::FatalError(err)).await,
+    }
+}
+
+/// Like `start_accept`, but with a listener that is already bound. This lets
+/// the caller bind port zero and find out which port it got.
+pub async fn start_accept_on(listen: impl Listener, proxies: Vec<Cidr>, mut handle: ServerHandle) {
+    let res = accept_loop(listen, proxies.into(), handle.clone()).await;
     match res {
         Ok(()) => {},
         Err(err) => {
@@ -17,24 +47,47 @@ pub async fn start_accept(bind: SocketAddr, mut handle: ServerHandle) {
 }
 
 pub async fn accept_loop(
-    bind: SocketAddr,
+    mut listen: impl Listener,
+    proxies: Arc<[Cidr]>,
     handle: ServerHandle
 ) -> Result<(), io::Error> {
 
-    let listen = TcpListener::bind(bind).await?;
-
     loop {
-        let (tcp, ip) = listen.accept().await?;
+        let (mut conn, ip) = listen.accept().await?;
 
         let id = handle.next_id();
 
-        let data = ClientInfo {
-            ip,
-            id,
-            tcp,
-            handle: handle.clone(),
-        };
+        if !proxies.iter().any(|cidr| cidr.contains(ip.ip())) {
+            let data = ClientInfo {
+                ip,
+                id,
+                conn: Box::new(conn),
+                handle: handle.clone(),
+            };
+
+            spawn_client(data);
+            continue;
+        }
+
+        // Waiting for the header must not hold up other connections.
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let ip = match proxy::read_header_timeout(&mut conn).await {
+                Ok(client) => client.unwrap_or(ip),
+                Err(err) => {
+                    eprintln!("{} sent no valid PROXY header: {}.", ip, err);
+                    return;
+                },
+            };
+
+            let data = ClientInfo {
+                ip,
+                id,
+                conn: Box::new(conn),
+                handle,
+            };
 
-        spawn_client(data);
+            spawn_client(data);
+        });
     }
 }
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use argon2::Argon2;
+use argon2::password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+
+const MAX_NICK_LEN: usize = 16;
+
+/// The registered accounts, stored in a local file with one `nick:hash` line
+/// per account, where the hash is an argon2 PHC string.
+///
+/// Hashing is slow on purpose, so `verify` and `set_password` should be called
+/// through `spawn_blocking`.
+#[derive(Debug)]
+pub struct AccountStore {
+    path: PathBuf,
+    accounts: Mutex<HashMap<String, String>>,
+}
+
+impl AccountStore {
+    /// Load the accounts from the given file. A missing file is treated as an
+    /// empty store, and is created on the first registration.
+    pub fn load(path: PathBuf) -> Result<AccountStore, io::Error> {
+        let mut accounts = HashMap::new();
+
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for line in text.lines() {
+                    if let Some((nick, hash)) = line.split_once(':') {
+                        accounts.insert(nick_key(nick), hash.to_string());
+                    }
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+
+        Ok(AccountStore {
+            path,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(&nick_key(nick))
+    }
+
+    /// Check the password of a registered nick. Returns false if the nick is
+    /// not registered.
+    pub fn verify(&self, nick: &str, password: &str) -> bool {
+        let hash = match self.accounts.lock().unwrap().get(&nick_key(nick)) {
+            Some(hash) => hash.clone(),
+            None => return false,
+        };
+
+        match PasswordHash::new(&hash) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Register the nick, or change its password if it is already registered.
+    pub fn set_password(&self, nick: &str, password: &str) -> Result<(), io::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|err| io::Error::other(err.to_string()))?
+            .to_string();
+
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.insert(nick_key(nick), hash);
+
+        let mut text = String::new();
+        for (nick, hash) in accounts.iter() {
+            text.push_str(nick);
+            text.push(':');
+            text.push_str(hash);
+            text.push('\n');
+        }
+        fs::write(&self.path, text)
+    }
+}
+
+/// Nicks are case insensitive.
+pub fn nick_key(nick: &str) -> String {
+    nick.to_lowercase()
+}
+
+/// Nicks are 1 to 16 letters, digits, `-` or `_`. This holds for users of
+/// linked servers too, and for users of bridges before the `@<bridge>`.
+pub fn is_valid_nick(nick: &str) -> bool {
+    !nick.is_empty()
+        && nick.len() <= MAX_NICK_LEN
+        && nick.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
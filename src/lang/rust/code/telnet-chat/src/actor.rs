@@ -0,0 +1,359 @@
+//! The actor pattern used throughout this crate, in a form that doesn't know
+//! about chat.
+//!
+//! An actor is a task that owns some state and is only talked to through its
+//! mailbox. Anyone can send it messages through an `Addr`, but there is one
+//! `Owner`, and dropping the owner kills the actor. This is what makes it safe
+//! to forget about an actor: whoever owns it decides when it is gone.
+//!
+//! ```ignore
+//! enum Msg {
+//!     Add(u64),
+//!     Get(Reply<u64>),
+//! }
+//!
+//! let counter = actor::spawn(16, |mut mailbox| async move {
+//!     let mut total = 0;
+//!     while let Some(msg) = mailbox.recv().await {
+//!         match msg {
+//!             Msg::Add(n) => total += n,
+//!             Msg::Get(reply) => { let _ = reply.send(total); },
+//!         }
+//!     }
+//! });
+//!
+//! counter.send(Msg::Add(2)).await?;
+//! let total = counter.call(Duration::from_secs(1), Msg::Get).await?;
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::ops::Deref;
+use std::time::Duration;
+
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{sleep, timeout, Instant};
+
+/// The messages of an actor, in the order they were sent. `recv` returns
+/// `None` once every `Addr` is gone.
+pub type Mailbox<M> = Receiver<M>;
+
+/// Where the answer to a `call` goes. The actor answers with `send`, and the
+/// caller gets `CallError::Stopped` if it drops this instead.
+pub type Reply<R> = oneshot::Sender<R>;
+
+/// Why a message could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The mailbox is full, so the actor can't keep up.
+    Full,
+    /// The actor has stopped.
+    Stopped,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full => f.write_str("Can't keep up"),
+            SendError::Stopped => f.write_str("Actor has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<SendError> for io::Error {
+    fn from(err: SendError) -> io::Error {
+        io::Error::new(io::ErrorKind::BrokenPipe, err)
+    }
+}
+
+/// Why a `call` got no answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    /// The actor stopped, or dropped the `Reply` without answering.
+    Stopped,
+    TimedOut,
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Stopped => f.write_str("Actor has stopped"),
+            CallError::TimedOut => f.write_str("Actor did not answer in time"),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+impl From<CallError> for io::Error {
+    fn from(err: CallError) -> io::Error {
+        let kind = match err {
+            CallError::Stopped => io::ErrorKind::BrokenPipe,
+            CallError::TimedOut => io::ErrorKind::TimedOut,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Sends messages to an actor. Cloning it is cheap, and it does not keep the
+/// actor alive.
+pub struct Addr<M> {
+    chan: Sender<M>,
+}
+
+impl<M> Addr<M> {
+    /// Wait for room in the mailbox, then send the message.
+    pub async fn send(&self, msg: M) -> Result<(), SendError> {
+        self.chan.send(msg).await.map_err(|_| SendError::Stopped)
+    }
+
+    /// Send the message if there is room in the mailbox right now. Actors
+    /// that must never wait on another use this, and treat a full mailbox as
+    /// a sign that the other actor can't keep up.
+    pub fn try_send(&self, msg: M) -> Result<(), SendError> {
+        self.chan.try_send(msg).map_err(|err| match err {
+            TrySendError::Full(_) => SendError::Full,
+            TrySendError::Closed(_) => SendError::Stopped,
+        })
+    }
+
+    /// Send a message that carries a `Reply`, and wait at most `limit` for the
+    /// answer, including the time spent waiting for room in the mailbox.
+    pub async fn call<R>(
+        &self,
+        limit: Duration,
+        make: impl FnOnce(Reply<R>) -> M,
+    ) -> Result<R, CallError> {
+        let (send, recv) = oneshot::channel();
+        let msg = make(send);
+
+        let res = timeout(limit, async {
+            self.send(msg).await.map_err(|_| CallError::Stopped)?;
+            recv.await.map_err(|_| CallError::Stopped)
+        }).await;
+
+        match res {
+            Ok(res) => res,
+            Err(_) => Err(CallError::TimedOut),
+        }
+    }
+
+    /// Whether the actor has stopped. It may still stop right after this
+    /// returns false.
+    pub fn is_stopped(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<M> Clone for Addr<M> {
+    fn clone(&self) -> Self {
+        Addr { chan: self.chan.clone() }
+    }
+}
+
+impl<M> fmt::Debug for Addr<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Addr").field("stopped", &self.is_stopped()).finish()
+    }
+}
+
+/// A spawned task that is aborted when this is dropped, unless it was
+/// detached.
+#[derive(Debug)]
+pub struct Task<T = ()> {
+    join: Option<JoinHandle<T>>,
+}
+
+impl<T: Send + 'static> Task<T> {
+    pub fn spawn(fut: impl Future<Output = T> + Send + 'static) -> Task<T> {
+        Task { join: Some(tokio::spawn(fut)) }
+    }
+}
+
+impl<T> Task<T> {
+    /// Wait for the task to finish. If this future is dropped first, the
+    /// task is aborted.
+    pub async fn join(mut self) -> Result<T, JoinError> {
+        self.join.as_mut().expect("Only taken by detach.").await
+    }
+
+    /// Let the task run on its own. The returned handle can still be used to
+    /// wait for it.
+    pub fn detach(mut self) -> JoinHandle<T> {
+        self.join.take().expect("Only taken by detach.")
+    }
+}
+
+impl<T> Drop for Task<T> {
+    fn drop(&mut self) {
+        if let Some(join) = &self.join {
+            join.abort();
+        }
+    }
+}
+
+/// The one handle that keeps an actor alive. It can send messages like an
+/// `Addr`, and dropping it kills the actor.
+pub struct Owner<M> {
+    addr: Addr<M>,
+    task: Task,
+}
+
+impl<M> Owner<M> {
+    pub fn addr(&self) -> &Addr<M> {
+        &self.addr
+    }
+
+    /// Let the actor finish on its own, such as after it was told to write a
+    /// last message and stop.
+    pub fn detach(self) -> JoinHandle<()> {
+        self.task.detach()
+    }
+}
+
+impl<M> fmt::Debug for Owner<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Owner").field("addr", &self.addr).finish()
+    }
+}
+
+impl<M> Deref for Owner<M> {
+    type Target = Addr<M>;
+
+    fn deref(&self) -> &Addr<M> {
+        &self.addr
+    }
+}
+
+/// Spawn an actor with room for `capacity` messages in its mailbox.
+pub fn spawn<M, F, Fut>(capacity: usize, actor: F) -> Owner<M>
+where
+    F: FnOnce(Mailbox<M>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (send, recv) = channel(capacity);
+    Owner {
+        addr: Addr { chan: send },
+        task: Task::spawn(actor(recv)),
+    }
+}
+
+/// Spawn an actor that is given its own `Owner`, to hand to whoever should
+/// own it. This lets an actor register itself somewhere before it does
+/// anything else, so nothing it sends can arrive before it is known.
+pub fn spawn_owned<M, F, Fut>(capacity: usize, actor: F)
+where
+    M: Send + 'static,
+    F: FnOnce(Owner<M>, Mailbox<M>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (send, recv) = channel(capacity);
+
+    // The `JoinHandle` only exists once the task is spawned, so the owner is
+    // sent to the task through a oneshot channel.
+    let (owner_send, owner_recv) = oneshot::channel();
+    let task = Task::spawn(async move {
+        if let Ok(owner) = owner_recv.await {
+            actor(owner, recv).await;
+        }
+    });
+
+    let owner = Owner {
+        addr: Addr { chan: send },
+        task,
+    };
+    // Fails only if the runtime is shutting down.
+    let _ = owner_send.send(owner);
+}
+
+/// When a supervised actor is started again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    Never,
+    /// If it returned an error or panicked.
+    OnFailure,
+    /// Whenever it stops.
+    Always,
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub restart: Restart,
+    /// How long to wait before starting the actor again.
+    pub delay: Duration,
+    /// Give up after this many restarts within this long.
+    pub max_restarts: Option<(usize, Duration)>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            restart: Restart::OnFailure,
+            delay: Duration::from_secs(1),
+            max_restarts: None,
+        }
+    }
+}
+
+/// Run the actor returned by `start`, and start it again whenever `policy`
+/// says so. Failures are logged with `name`. Dropping the returned task stops
+/// both the supervisor and the actor.
+pub fn supervise<F, Fut, E>(name: impl Into<String>, policy: Policy, mut start: F) -> Task
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: fmt::Display + Send + 'static,
+{
+    let name = name.into();
+
+    Task::spawn(async move {
+        let mut restarts = VecDeque::new();
+
+        loop {
+            // In its own task, so a panic ends the actor and not us.
+            let failed = match Task::spawn(start()).join().await {
+                Ok(Ok(())) => false,
+                Ok(Err(err)) => {
+                    eprintln!("{} failed: {}.", name, err);
+                    true
+                },
+                Err(err) if err.is_panic() => {
+                    eprintln!("{} panicked.", name);
+                    true
+                },
+                // Cancelled, which only happens when the runtime shuts down.
+                Err(_) => return,
+            };
+
+            let restart = match policy.restart {
+                Restart::Never => false,
+                Restart::OnFailure => failed,
+                Restart::Always => true,
+            };
+            if !restart {
+                return;
+            }
+
+            if let Some((max, within)) = policy.max_restarts {
+                let now = Instant::now();
+                while restarts.front().map(|&at| now - at > within).unwrap_or(false) {
+                    restarts.pop_front();
+                }
+                if restarts.len() >= max {
+                    eprintln!("{} restarted too often, giving up.", name);
+                    return;
+                }
+                restarts.push_back(now);
+            }
+
+            sleep(policy.delay).await;
+        }
+    })
+}
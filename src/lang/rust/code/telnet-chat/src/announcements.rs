@@ -0,0 +1,92 @@
+//! What the server says on its own: the message of the day, shown when a
+//! user logs in, and the announcements of the config, sent to rooms on a
+//! schedule by a timer task.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use crate::actor::Task;
+use crate::config::{AnnouncementConfig, Config};
+use crate::main_loop::{ServerHandle, ToServer};
+use crate::moderation::unix_now;
+
+/// The message of the day, stored in a local file as plain text so it can
+/// also be edited by hand. It is read once, at startup.
+#[derive(Debug)]
+pub struct Motd {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl Motd {
+    /// A missing file means there is no message of the day.
+    pub fn load(config: &Config) -> Result<Motd, io::Error> {
+        let lines = match fs::read_to_string(&config.motd_file) {
+            Ok(text) => text.lines().map(str::to_string).collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Motd { path: config.motd_file.clone(), lines })
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Replace the message with a single line.
+    pub fn set(&mut self, line: String) -> Result<(), io::Error> {
+        self.lines = vec![line];
+        self.save()
+    }
+
+    pub fn add(&mut self, line: String) -> Result<(), io::Error> {
+        self.lines.push(line);
+        self.save()
+    }
+
+    pub fn clear(&mut self) -> Result<(), io::Error> {
+        self.lines.clear();
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        let text: String = self.lines.iter().map(|line| format!("{}\n", line)).collect();
+        fs::write(&self.path, text)
+    }
+}
+
+/// Start the task that sends the announcements of the config to the main
+/// loop when they are due. It runs until the main loop shuts down.
+pub fn start_announcements(config: &Config, handle: ServerHandle) {
+    if config.announcements.is_empty() {
+        return;
+    }
+    Task::spawn(announce(config.announcements.clone(), handle)).detach();
+}
+
+async fn announce(announcements: Vec<AnnouncementConfig>, mut handle: ServerHandle) {
+    // Counted in minutes since the unix epoch, and moved on one at a time,
+    // so a minute is never skipped or announced twice even if the task
+    // wakes up late.
+    let mut minute = unix_now() / 60;
+
+    loop {
+        minute += 1;
+        let wait = (minute * 60).saturating_sub(unix_now());
+        sleep(Duration::from_secs(wait)).await;
+
+        for announcement in &announcements {
+            if announcement.schedule.matches(minute) {
+                let msg = ToServer::Announce {
+                    rooms: announcement.rooms.clone(),
+                    text: announcement.text.clone(),
+                };
+                handle.send(msg).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Measures how many messages per second the server fans out.
+//!
+//! Logs in `--clients` connections, and lets one of them send `--messages`
+//! lines to the lobby. The time until every other client has read every line
+//! gives the throughput.
+//!
+//! By default the server runs in this process with `--shards` fan-out
+//! workers. Comparing `--shards 1` with the default shows what spreading the
+//! fan-out over several workers does, but both share payloads as `Bytes`, so
+//! neither is the old fan-out.
+//!
+//!     cargo run --release --bin fanout-bench -- --clients 2000 --shards 1
+//!
+//! To measure the old fan-out, where the main loop cloned every message for
+//! every client, start a server built from before the shards were added, with
+//! the default config, and point the benchmark at it with `--addr`. Do the
+//! same with a server of this version to compare the two.
+//!
+//!     cargo run --release --bin fanout-bench -- --clients 2000 --addr 127.0.0.1:3456
+
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::time::timeout;
+
+use telnet_chat::accounts::AccountStore;
+use telnet_chat::announcements::Motd;
+use telnet_chat::config::Config;
+use telnet_chat::memos::MemoStore;
+use telnet_chat::moderation::Moderation;
+use telnet_chat::plugins::Plugins;
+
+struct Args {
+    clients: usize,
+    messages: usize,
+    shards: usize,
+    /// A running server to measure instead of one in this process.
+    addr: Option<SocketAddr>,
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        clients: 1000,
+        messages: 50,
+        shards: Config::default().shards,
+        addr: None,
+    };
+
+    let mut iter = env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let value = iter.next().unwrap_or_else(|| panic!("{} needs a value.", flag));
+        let number = || value.parse().unwrap_or_else(|_| panic!("{} needs a number.", flag));
+        match flag.as_str() {
+            "--clients" => args.clients = number(),
+            "--messages" => args.messages = number(),
+            "--shards" => args.shards = number(),
+            "--addr" => args.addr = Some(value.parse().expect("--addr needs a host:port.")),
+            _ => panic!("Unknown flag {}.", flag),
+        }
+    }
+
+    args
+}
+
+#[tokio::main]
+async fn main() {
+    let args = parse_args();
+
+    let (addr, dir) = match args.addr {
+        Some(addr) => (addr, None),
+        None => {
+            let (addr, dir) = start_server(args.shards).await;
+            (addr, Some(dir))
+        },
+    };
+
+    println!("Logging in {} clients...", args.clients);
+    let mut clients = Vec::with_capacity(args.clients);
+    for i in 0..args.clients {
+        clients.push(login(addr, &format!("bench{}", i)).await);
+    }
+
+    let (_, mut sender) = clients.remove(0);
+    let expected = args.messages;
+    // Dropping a write half closes the connection, so keep them around.
+    let mut writers = Vec::new();
+    let receivers: Vec<_> = clients
+        .into_iter()
+        .map(|(read, write)| {
+            writers.push(write);
+            tokio::spawn(receive(read, expected))
+        })
+        .collect();
+
+    let start = Instant::now();
+    for i in 0..args.messages {
+        sender.write_all(format!("message {}\r\n", i).as_bytes()).await.unwrap();
+    }
+
+    let mut complete = 0;
+    for receiver in receivers {
+        if receiver.await.unwrap() {
+            complete += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let delivered = complete * args.messages;
+    match args.addr {
+        Some(addr) => println!("server:       {}", addr),
+        None => println!("shards:       {}", args.shards),
+    }
+    println!("receivers:    {} ({} dropped)", args.clients - 1, args.clients - 1 - complete);
+    println!("elapsed:      {:.3}s", elapsed.as_secs_f64());
+    println!("throughput:   {:.0} messages/s", delivered as f64 / elapsed.as_secs_f64());
+
+    if let Some(dir) = dir {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // Exit without waiting for the server to notice that everyone is gone.
+    std::process::exit(0);
+}
+
+/// Start a server in this process. Returns its address, and the directory of
+/// its files.
+async fn start_server(shards: usize) -> (SocketAddr, PathBuf) {
+    // Keep the server's files out of the way.
+    let dir = env::temp_dir().join(format!("fanout-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = Config {
+        accounts_file: dir.join("accounts.txt"),
+        bans_file: dir.join("bans.txt"),
+        audit_log: dir.join("audit.log"),
+        memos_file: dir.join("memos.txt"),
+        motd_file: dir.join("motd.txt"),
+        shards,
+        ..Config::default()
+    };
+
+    let accounts = Arc::new(AccountStore::load(config.accounts_file.clone()).unwrap());
+    let moderation = Moderation::load(&config).unwrap();
+    let memos = MemoStore::load(&config).unwrap();
+    let motd = Motd::load(&config).unwrap();
+    let (handle, _join) = telnet_chat::main_loop::spawn_main_loop(
+        &config,
+        accounts,
+        moderation,
+        memos,
+        motd,
+        Plugins::new(),
+    );
+
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listen.local_addr().unwrap();
+    tokio::spawn(telnet_chat::accept::start_accept_on(listen, Vec::new(), handle));
+    (addr, dir)
+}
+
+async fn login(
+    addr: SocketAddr,
+    nick: &str,
+) -> (BufReader<OwnedReadHalf>, tokio::net::tcp::OwnedWriteHalf) {
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let (read, mut write) = tcp.into_split();
+    let mut read = BufReader::new(read);
+
+    write.write_all(format!("{}\r\n", nick).as_bytes()).await.unwrap();
+
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        read.read_until(b'\n', &mut line).await.unwrap();
+        // The line may start with the unfinished "Nick: " prompt.
+        if line.ends_with(b"* You are now in #lobby.\r\n") {
+            return (read, write);
+        }
+    }
+}
+
+/// Returns true if every message arrived, false if the server dropped us.
+async fn receive(mut read: BufReader<OwnedReadHalf>, expected: usize) -> bool {
+    let mut seen = 0;
+    let mut line = Vec::new();
+    while seen < expected {
+        line.clear();
+        match timeout(Duration::from_secs(30), read.read_until(b'\n', &mut line)).await {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => return false,
+            Ok(Ok(_)) => {},
+        }
+        // Chat lines start with their id, such as `[12] <bench0> message 3`.
+        if line.windows(9).any(|w| w == b"<bench0> ") {
+            seen += 1;
+        }
+    }
+    true
+}
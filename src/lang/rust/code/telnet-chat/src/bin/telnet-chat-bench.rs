@@ -0,0 +1,340 @@
+//! Load test for a running telnet-chat server.
+//!
+//! Opens `--clients` telnet connections that negotiate options the way a real
+//! telnet client does, log in, and then send chat lines at `--rate` lines per
+//! second each for `--duration` seconds. Every line carries the time it was
+//! sent, so the receivers can measure the end-to-end broadcast latency.
+//!
+//!     cargo run --release --bin telnet-chat-bench -- --addr 127.0.0.1:3456 --clients 200 --rate 2
+//!
+//! The report lists the latency percentiles, how many of the expected lines
+//! arrived, and how many clients the server disconnected.
+
+use std::env;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use futures::stream::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval_at, sleep, timeout};
+use tokio_util::codec::FramedRead;
+
+use telnet_chat::telnet::{Item, TelnetCodec, option};
+
+const IAC: u8 = 255;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const TTYPE: u8 = 24;
+const NAWS: u8 = 31;
+
+struct Args {
+    addr: SocketAddr,
+    clients: usize,
+    senders: Option<usize>,
+    rate: f64,
+    duration: u64,
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        addr: ([127, 0, 0, 1], 3456).into(),
+        clients: 100,
+        senders: None,
+        rate: 1.0,
+        duration: 10,
+    };
+
+    let mut iter = env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let value = iter.next().unwrap_or_else(|| panic!("{} needs a value.", flag));
+        match flag.as_str() {
+            "--addr" => args.addr = parse(&flag, &value),
+            "--clients" => args.clients = parse(&flag, &value),
+            "--senders" => args.senders = Some(parse(&flag, &value)),
+            "--rate" => args.rate = parse(&flag, &value),
+            "--duration" => args.duration = parse(&flag, &value),
+            _ => panic!("Unknown flag {}.", flag),
+        }
+    }
+
+    args
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> T {
+    value.parse()
+        .unwrap_or_else(|_| panic!("Invalid value {} for {}.", value, flag))
+}
+
+/// Sent by the main task once every client has logged in (or failed to).
+#[derive(Clone, Copy)]
+struct Go {
+    /// Timestamps in the messages are relative to this.
+    epoch: Instant,
+    /// Stop sending at this time.
+    end: Instant,
+}
+
+#[derive(Default)]
+struct Stats {
+    logged_in: bool,
+    disconnected: bool,
+    sent: u64,
+    received: u64,
+    /// Latencies in microseconds.
+    latencies: Vec<u64>,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = parse_args();
+    let senders = args.senders.unwrap_or(args.clients).min(args.clients);
+    let tag = std::process::id() % 1000;
+
+    let (ready_send, mut ready) = mpsc::unbounded_channel();
+    let (go_send, go) = watch::channel(None);
+
+    println!(
+        "Connecting {} clients to {} ({} sending {} lines/s each)...",
+        args.clients, args.addr, senders, args.rate,
+    );
+
+    let tasks: Vec<_> = (0..args.clients)
+        .map(|i| {
+            let nick = format!("b{}x{}", tag, i);
+            let rate = if i < senders { args.rate } else { 0.0 };
+            tokio::spawn(run_client(args.addr, nick, rate, ready_send.clone(), go.clone()))
+        })
+        .collect();
+
+    // Wait until every client has logged in or failed.
+    let mut logged_in = 0;
+    for _ in 0..args.clients {
+        if let Some(true) = ready.recv().await {
+            logged_in += 1;
+        }
+    }
+
+    let epoch = Instant::now();
+    let end = epoch + Duration::from_secs(args.duration);
+    let _ = go_send.send(Some(Go { epoch, end }));
+
+    let mut total = Stats::default();
+    let mut disconnects = 0;
+    for task in tasks {
+        let stats = task.await.unwrap();
+        if stats.logged_in && stats.disconnected {
+            disconnects += 1;
+        }
+        total.sent += stats.sent;
+        total.received += stats.received;
+        total.latencies.extend(stats.latencies);
+    }
+
+    // Every line goes to everyone else in the lobby.
+    let expected = total.sent * (logged_in as u64).saturating_sub(1);
+
+    println!();
+    println!("clients:      {} logged in, {} failed", logged_in, args.clients - logged_in);
+    println!("duration:     {}s", args.duration);
+    println!("sent:         {} lines", total.sent);
+    println!(
+        "received:     {} of {} expected ({:.2}%)",
+        total.received,
+        expected,
+        percent(total.received, expected),
+    );
+    println!(
+        "disconnects:  {} ({:.2}%)",
+        disconnects,
+        percent(disconnects, logged_in as u64),
+    );
+
+    let lat = &mut total.latencies;
+    lat.sort_unstable();
+    if lat.is_empty() {
+        println!("latency:      no samples");
+    } else {
+        println!(
+            "latency:      p50 {}  p90 {}  p99 {}  p99.9 {}  max {}",
+            fmt_us(percentile(lat, 50.0)),
+            fmt_us(percentile(lat, 90.0)),
+            fmt_us(percentile(lat, 99.0)),
+            fmt_us(percentile(lat, 99.9)),
+            fmt_us(*lat.last().unwrap()),
+        );
+    }
+}
+
+async fn run_client(
+    addr: SocketAddr,
+    nick: String,
+    rate: f64,
+    ready: mpsc::UnboundedSender<bool>,
+    mut go: watch::Receiver<Option<Go>>,
+) -> Stats {
+    let mut stats = Stats::default();
+
+    let tcp = match timeout(Duration::from_secs(10), TcpStream::connect(addr)).await {
+        Ok(Ok(tcp)) => tcp,
+        _ => {
+            let _ = ready.send(false);
+            return stats;
+        },
+    };
+    let (read, mut write) = tcp.into_split();
+    let mut telnet = FramedRead::new(read, TelnetCodec::new());
+
+    // What a typical telnet client offers as soon as it connects.
+    let hello = [IAC, DO, option::SUPPRESS_GO_AHEAD, IAC, WILL, TTYPE, IAC, WILL, NAWS];
+    if write.write_all(&hello).await.is_err()
+        || write.write_all(format!("{}\r\n", nick).as_bytes()).await.is_err()
+    {
+        let _ = ready.send(false);
+        return stats;
+    }
+
+    // Log in. The server greets us before we are in the lobby. The line may
+    // start with the unfinished "Nick: " prompt.
+    let login = async {
+        while let Some(item) = telnet.next().await {
+            match item {
+                Ok(Item::Line(line)) if contains(&line, b"* You are now in #") => return true,
+                Ok(item) => {
+                    if negotiate(&mut write, item).await.is_err() {
+                        return false;
+                    }
+                },
+                Err(_) => return false,
+            }
+        }
+        false
+    };
+    stats.logged_in = matches!(timeout(Duration::from_secs(30), login).await, Ok(true));
+    let _ = ready.send(stats.logged_in);
+    if !stats.logged_in {
+        return stats;
+    }
+
+    // Wait for everyone else.
+    let Go { epoch, end } = loop {
+        if let Some(go) = *go.borrow() {
+            break go;
+        }
+        if go.changed().await.is_err() {
+            return stats;
+        }
+    };
+
+    let period = if rate > 0.0 {
+        Duration::from_secs_f64(1.0 / rate)
+    } else {
+        // Never ticks during the run.
+        Duration::from_secs(60 * 60 * 24)
+    };
+    // Spread the first line of each client over the first period.
+    let offset = period.mul_f64(rand_fraction(&nick));
+    let mut ticker = interval_at((Instant::now() + offset).into(), period);
+
+    // Keep reading for a while after the end, for lines still in flight.
+    let drain = sleep(end - Instant::now() + Duration::from_secs(2));
+    tokio::pin!(drain);
+
+    loop {
+        tokio::select! {
+            _ = &mut drain => break,
+            _ = ticker.tick(), if Instant::now() < end => {
+                let micros = epoch.elapsed().as_micros();
+                let line = format!("bench {} t={}\r\n", stats.sent, micros);
+                if write.write_all(line.as_bytes()).await.is_err() {
+                    stats.disconnected = true;
+                    break;
+                }
+                stats.sent += 1;
+            },
+            item = telnet.next() => match item {
+                Some(Ok(Item::Line(line))) => {
+                    if let Some(sent) = parse_timestamp(&line) {
+                        let now = epoch.elapsed().as_micros() as u64;
+                        stats.received += 1;
+                        stats.latencies.push(now.saturating_sub(sent));
+                    }
+                },
+                Some(Ok(item)) => {
+                    if negotiate(&mut write, item).await.is_err() {
+                        stats.disconnected = true;
+                        break;
+                    }
+                },
+                Some(Err(_)) | None => {
+                    stats.disconnected = true;
+                    break;
+                },
+            },
+        }
+    }
+
+    stats
+}
+
+/// Answer option negotiation like a client that only wants suppress go-ahead,
+/// and is happy to let the server echo.
+async fn negotiate(
+    write: &mut tokio::net::tcp::OwnedWriteHalf,
+    item: Item,
+) -> Result<(), std::io::Error> {
+    let reply = match item {
+        Item::Will(option::ECHO) => [IAC, DO, option::ECHO],
+        Item::Wont(option::ECHO) => [IAC, DONT, option::ECHO],
+        // Acknowledgements of what we asked for.
+        Item::Will(option::SUPPRESS_GO_AHEAD) | Item::Do(TTYPE) | Item::Do(NAWS) => return Ok(()),
+        Item::Will(opt) => [IAC, DONT, opt],
+        Item::Do(opt) => [IAC, WONT, opt],
+        _ => return Ok(()),
+    };
+    write.write_all(&reply).await
+}
+
+/// Find the `t=` timestamp of a bench line, such as `<b1x3> bench 7 t=1234`.
+fn parse_timestamp(line: &[u8]) -> Option<u64> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (_, rest) = line.split_once("> bench ")?;
+    let (_, t) = rest.split_once(" t=")?;
+    t.trim().parse().ok()
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// A number in `0..1` derived from the nick, so that clients don't all send
+/// at the same instant.
+fn rand_fraction(nick: &str) -> f64 {
+    let hash = nick.bytes().fold(2166136261u32, |h, b| (h ^ b as u32).wrapping_mul(16777619));
+    (hash % 1000) as f64 / 1000.0
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let idx = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[idx.min(sorted.len() - 1)]
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / whole as f64
+    }
+}
+
+fn fmt_us(us: u64) -> String {
+    if us >= 10_000 {
+        format!("{:.1}ms", us as f64 / 1000.0)
+    } else {
+        format!("{}us", us)
+    }
+}
@@ -0,0 +1,320 @@
+//! An interactive telnet client, and the reference client for testing the
+//! server.
+//!
+//!     cargo run --bin telnet-client -- 127.0.0.1:3456
+//!
+//! Lines are edited locally and sent when enter is pressed, so the server
+//! sees the same thing it gets from a telnet client in line mode. The window
+//! size is reported with NAWS whenever the terminal is resized. While the
+//! server claims to echo, which it does for passwords, the input is hidden.
+//!
+//! If the server also suppresses go-ahead, it draws the screen itself: keys
+//! are then sent as they are typed and its output is printed as it is.
+//!
+//! Keys: the arrows, Home, End, Backspace and Delete edit the line, Up and
+//! Down go through the history, Ctrl-U and Ctrl-K cut the line before and
+//! after the cursor, Ctrl-W cuts a word, Ctrl-C sends Interrupt Process, and
+//! Ctrl-] or Ctrl-D on an empty line quits.
+
+use std::env;
+use std::io::{self, Write};
+
+use crossterm::cursor::MoveToColumn;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType};
+use crossterm::queue;
+use futures::stream::StreamExt;
+use tokio::net::TcpStream;
+use tokio::select;
+
+use telnet_chat::telnet::Item;
+use telnet_chat::telnet_client::TelnetClient;
+
+#[tokio::main]
+async fn main() {
+    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:3456".to_string());
+
+    let mut client = match TelnetClient::connect(&addr).await {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("Unable to connect to {}: {}.", addr, err);
+            std::process::exit(1);
+        },
+    };
+    client.set_terminal_type(env::var("TERM").ok());
+
+    let res = match RawMode::enable() {
+        Ok(_raw) => run(&mut client).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = res {
+        eprintln!("{}.", err);
+        std::process::exit(1);
+    }
+}
+
+/// Puts the terminal back to normal when dropped, also on panic.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<RawMode, io::Error> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        println!();
+    }
+}
+
+async fn run(client: &mut TelnetClient<TcpStream>) -> Result<(), io::Error> {
+    if let Ok((cols, rows)) = terminal::size() {
+        client.set_window_size(cols, rows).await?;
+    }
+    client.start().await?;
+
+    let mut events = EventStream::new();
+    let mut editor = LineEditor::default();
+    let mut out = io::stdout();
+
+    loop {
+        select! {
+            item = client.next() => match item {
+                Some(Ok(Item::Data(text))) if client.char_mode() => {
+                    out.write_all(&text)?;
+                    out.flush()?;
+                },
+                Some(Ok(Item::Data(text))) => {
+                    editor.output(&mut out, &String::from_utf8_lossy(&text))?;
+                    editor.draw(&mut out, client.server_echoes())?;
+                },
+                Some(Ok(_)) => {},
+                Some(Err(err)) => return Err(err),
+                None => {
+                    editor.output(&mut out, "\nConnection closed by the server.")?;
+                    return Ok(());
+                },
+            },
+            event = events.next() => match event {
+                Some(Ok(Event::Resize(cols, rows))) => {
+                    client.set_window_size(cols, rows).await?;
+                },
+                Some(Ok(Event::Key(key))) if client.char_mode() => match char_mode_key(key) {
+                    Action::Send(keys) => client.send(Item::Data(keys.into_bytes())).await?,
+                    Action::Interrupt => client.send(Item::InterruptProcess).await?,
+                    Action::Quit => return Ok(()),
+                    Action::Nothing | Action::Redraw => {},
+                },
+                Some(Ok(Event::Key(key))) => match editor.key(key, client.server_echoes()) {
+                    Action::Nothing => {},
+                    Action::Redraw => editor.draw(&mut out, client.server_echoes())?,
+                    Action::Send(line) => {
+                        editor.commit(&mut out, &line, client.server_echoes())?;
+                        client.send_line(line.as_bytes()).await?;
+                    },
+                    Action::Interrupt => client.send(Item::InterruptProcess).await?,
+                    Action::Quit => return Ok(()),
+                },
+                Some(Ok(_)) => {},
+                Some(Err(err)) => return Err(err),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+enum Action {
+    Nothing,
+    Redraw,
+    Send(String),
+    Interrupt,
+    Quit,
+}
+
+/// The bytes a terminal sends for a key, for servers in character mode.
+fn char_mode_key(key: KeyEvent) -> Action {
+    if key.kind == KeyEventKind::Release {
+        return Action::Nothing;
+    }
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+    let keys = match key.code {
+        KeyCode::Char(']') if ctrl => return Action::Quit,
+        KeyCode::Char('c') if ctrl => return Action::Interrupt,
+        KeyCode::Char(c) if ctrl && c.is_ascii_alphabetic() => {
+            ((c.to_ascii_lowercase() as u8 - b'a' + 1) as char).to_string()
+        },
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::Enter => "\r\n".to_string(),
+        KeyCode::Backspace => "\x7f".to_string(),
+        KeyCode::Delete => "\x1b[3~".to_string(),
+        KeyCode::Left => "\x1b[D".to_string(),
+        KeyCode::Right => "\x1b[C".to_string(),
+        KeyCode::Up => "\x1b[A".to_string(),
+        KeyCode::Down => "\x1b[B".to_string(),
+        KeyCode::Home => "\x1b[H".to_string(),
+        KeyCode::End => "\x1b[F".to_string(),
+        KeyCode::PageUp => "\x1b[5~".to_string(),
+        KeyCode::PageDown => "\x1b[6~".to_string(),
+        _ => return Action::Nothing,
+    };
+    Action::Send(keys)
+}
+
+/// The bottom line of the terminal: the unfinished last line from the
+/// server, usually a prompt, followed by what the user is typing.
+#[derive(Default)]
+struct LineEditor {
+    prompt: String,
+    input: Vec<char>,
+    /// Position in `input`.
+    cursor: usize,
+    history: Vec<String>,
+    /// Position in `history` while browsing it with Up and Down.
+    history_pos: Option<usize>,
+}
+
+impl LineEditor {
+    fn key(&mut self, key: KeyEvent, hidden: bool) -> Action {
+        if key.kind == KeyEventKind::Release {
+            return Action::Nothing;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Char(']') if ctrl => return Action::Quit,
+            KeyCode::Char('c') if ctrl => return Action::Interrupt,
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => return Action::Quit,
+            KeyCode::Char('d') if ctrl => self.delete(),
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.input.len(),
+            KeyCode::Char('u') if ctrl => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+            },
+            KeyCode::Char('k') if ctrl => self.input.truncate(self.cursor),
+            KeyCode::Char('w') if ctrl => self.cut_word(),
+            KeyCode::Char('l') if ctrl => {},
+            KeyCode::Char(_) if ctrl => return Action::Nothing,
+            KeyCode::Char(c) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            },
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            },
+            KeyCode::Delete => self.delete(),
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up => self.browse_history(true),
+            KeyCode::Down => self.browse_history(false),
+            KeyCode::Enter => {
+                let line: String = self.input.drain(..).collect();
+                self.cursor = 0;
+                self.history_pos = None;
+                // Passwords stay out of the history.
+                if !hidden && !line.is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                return Action::Send(line);
+            },
+            _ => return Action::Nothing,
+        }
+
+        Action::Redraw
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.input.len() {
+            self.input.remove(self.cursor);
+        }
+    }
+
+    fn cut_word(&mut self) {
+        let mut start = self.cursor;
+        while start > 0 && self.input[start - 1] == ' ' {
+            start -= 1;
+        }
+        while start > 0 && self.input[start - 1] != ' ' {
+            start -= 1;
+        }
+        self.input.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    fn browse_history(&mut self, back: bool) {
+        let pos = match (self.history_pos, back) {
+            (None, true) if !self.history.is_empty() => Some(self.history.len() - 1),
+            (None, _) => return,
+            (Some(pos), true) => Some(pos.saturating_sub(1)),
+            (Some(pos), false) if pos + 1 < self.history.len() => Some(pos + 1),
+            (Some(_), false) => None,
+        };
+
+        self.history_pos = pos;
+        self.input = match pos {
+            Some(pos) => self.history[pos].chars().collect(),
+            None => Vec::new(),
+        };
+        self.cursor = self.input.len();
+    }
+
+    /// Print text from the server above the line being edited. Call `draw`
+    /// afterwards.
+    fn output(&mut self, out: &mut impl Write, text: &str) -> Result<(), io::Error> {
+        queue!(out, MoveToColumn(0), Clear(ClearType::CurrentLine))?;
+
+        self.prompt.push_str(text);
+        if let Some(end) = self.prompt.rfind('\n') {
+            let done: String = self.prompt.drain(..=end).collect();
+            // Raw mode doesn't turn a newline into a carriage return too.
+            let done = done.replace("\r\n", "\n").replace('\n', "\r\n");
+            queue!(out, Print(done))?;
+        }
+
+        out.flush()
+    }
+
+    fn draw(&self, out: &mut impl Write, hidden: bool) -> Result<(), io::Error> {
+        let input: String = if hidden {
+            String::new()
+        } else {
+            self.input.iter().collect()
+        };
+        let cursor = if hidden { 0 } else { self.cursor };
+        let column = self.prompt.chars().count() + cursor;
+
+        queue!(
+            out,
+            MoveToColumn(0),
+            Clear(ClearType::CurrentLine),
+            Print(&self.prompt),
+            Print(input),
+            MoveToColumn(column.min(u16::MAX as usize) as u16),
+        )?;
+        out.flush()
+    }
+
+    /// Leave the line that was just sent on the screen, since the server
+    /// doesn't echo it.
+    fn commit(&mut self, out: &mut impl Write, line: &str, hidden: bool) -> Result<(), io::Error> {
+        let line = if hidden { "" } else { line };
+        queue!(
+            out,
+            MoveToColumn(0),
+            Clear(ClearType::CurrentLine),
+            Print(&self.prompt),
+            Print(line),
+            Print("\r\n"),
+        )?;
+        self.prompt.clear();
+        out.flush()
+    }
+}
@@ -0,0 +1,255 @@
+//! Bridges, which link a room to an outside program so the chat can be
+//! joined from other tools without building each of them into the server.
+//!
+//! A bridge is a plugin. It connects to its endpoint over TCP or a Unix
+//! socket, and both sides send one JSON object per line:
+//!
+//! ```text
+//! {"type":"message","room":"ops","nick":"alice","text":"deploy is done"}
+//! {"type":"join","room":"ops","nick":"alice"}
+//! {"type":"part","room":"ops","nick":"alice"}
+//! ```
+//!
+//! Events from the endpoint need no `room`, since a bridge has only one. Its
+//! users are shown as puppets named `<nick>@<bridge>`, which no user of this
+//! server can have. Lines that are not valid events are logged and skipped.
+//!
+//! The bridge connects again whenever the connection is lost. What happens in
+//! the room meanwhile is not sent later, and the puppets of the endpoint leave
+//! the room until it says they joined again.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::time::sleep;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::accounts::is_valid_nick;
+use crate::actor::{self, Mailbox, Owner, SendError};
+use crate::client::Connection;
+use crate::config::BridgeConfig;
+use crate::federation::codec_error;
+use crate::plugins::{Plugin, Replies};
+use crate::room::room_key;
+
+const MAX_LINE: usize = 8 * 1024;
+/// Events that happen while the endpoint can't keep up are not sent.
+const BRIDGE_QUEUE: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event {
+    Message { nick: String, text: String },
+    Join { nick: String },
+    Part { nick: String },
+}
+
+/// An event as it is sent to the endpoint.
+#[derive(Serialize)]
+struct Outgoing<'a> {
+    room: &'a str,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+#[derive(Debug, Clone)]
+enum Endpoint {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A room linked to an endpoint. See the module docs.
+#[derive(Debug)]
+pub struct Bridge {
+    name: String,
+    room: String,
+    endpoint: Endpoint,
+    /// How long to wait before connecting again.
+    retry: Duration,
+    /// Started by `Plugin::start`.
+    actor: Option<Owner<Event>>,
+}
+
+impl Bridge {
+    /// Returns None if the room or the address is not valid.
+    pub fn new(config: &BridgeConfig, retry: Duration) -> Option<Bridge> {
+        let endpoint = match config.connect.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Endpoint::Unix(PathBuf::from(path)),
+            #[cfg(not(unix))]
+            Some(_) => return None,
+            None => Endpoint::Tcp(config.connect.clone()),
+        };
+
+        Some(Bridge {
+            name: config.name.clone(),
+            room: room_key(&config.room)?,
+            endpoint,
+            retry,
+            actor: None,
+        })
+    }
+
+    fn send(&self, room: &str, event: Event) {
+        let actor = match &self.actor {
+            Some(actor) if room == self.room => actor,
+            _ => return,
+        };
+        match actor.try_send(event) {
+            Ok(()) | Err(SendError::Stopped) => {},
+            Err(SendError::Full) => eprintln!("Bridge {} can't keep up, skipping an event.", self.name),
+        }
+    }
+}
+
+impl Plugin for Bridge {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, replies: &Replies) {
+        let relay = Relay {
+            name: self.name.clone(),
+            room: self.room.clone(),
+            replies: replies.clone(),
+            puppets: HashSet::new(),
+        };
+        let (endpoint, retry) = (self.endpoint.clone(), self.retry);
+        self.actor = Some(actor::spawn(BRIDGE_QUEUE, move |mailbox| {
+            relay.run(endpoint, retry, mailbox)
+        }));
+    }
+
+    fn on_message(&mut self, _replies: &Replies, room: &str, nick: &str, text: &str) {
+        self.send(room, Event::Message { nick: nick.to_string(), text: text.to_string() });
+    }
+
+    fn on_join(&mut self, _replies: &Replies, room: &str, nick: &str) {
+        self.send(room, Event::Join { nick: nick.to_string() });
+    }
+
+    fn on_part(&mut self, _replies: &Replies, room: &str, nick: &str) {
+        self.send(room, Event::Part { nick: nick.to_string() });
+    }
+}
+
+/// The state of the bridge actor.
+struct Relay {
+    name: String,
+    room: String,
+    replies: Replies,
+    /// The puppets in the room, by their full nick.
+    puppets: HashSet<String>,
+}
+
+impl Relay {
+    async fn run(mut self, endpoint: Endpoint, retry: Duration, mut mailbox: Mailbox<Event>) {
+        loop {
+            match connect(&endpoint).await {
+                Ok(conn) => {
+                    println!("Bridge {} is connected.", self.name);
+                    match self.serve(conn, &mut mailbox).await {
+                        Ok(true) => eprintln!("Bridge {} lost the connection.", self.name),
+                        Ok(false) => return,
+                        Err(err) => eprintln!("Bridge {} lost the connection: {}.", self.name, err),
+                    }
+                    for puppet in self.puppets.drain() {
+                        self.replies.part_as(&puppet, &self.room);
+                    }
+                },
+                Err(err) => eprintln!("Bridge {} failed to connect: {}.", self.name, err),
+            }
+
+            sleep(retry).await;
+            // Stale by now.
+            while mailbox.try_recv().is_ok() {}
+        }
+    }
+
+    /// Relay events until the connection is lost, which returns true, or the
+    /// plugin is gone.
+    async fn serve(&mut self, conn: Box<dyn Connection>, mailbox: &mut Mailbox<Event>) -> io::Result<bool> {
+        let mut framed = Framed::new(conn, LinesCodec::new_with_max_length(MAX_LINE));
+
+        loop {
+            select! {
+                event = mailbox.recv() => match event {
+                    Some(event) => {
+                        let line = serde_json::to_string(&Outgoing { room: &self.room, event: &event })?;
+                        framed.send(line).await.map_err(codec_error)?;
+                    },
+                    None => return Ok(false),
+                },
+                line = framed.next() => match line {
+                    Some(line) => self.received(&line.map_err(codec_error)?),
+                    None => return Ok(true),
+                },
+            }
+        }
+    }
+
+    fn received(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        let event: Event = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(err) => {
+                eprintln!("Bridge {} sent an invalid event: {}.", self.name, err);
+                return;
+            },
+        };
+
+        let nick = match &event {
+            Event::Message { nick, .. } | Event::Join { nick } | Event::Part { nick } => nick,
+        };
+        let puppet = match puppet_nick(nick, &self.name) {
+            Some(puppet) => puppet,
+            None => {
+                eprintln!("Bridge {} sent an invalid nick {:?}.", self.name, nick);
+                return;
+            },
+        };
+
+        match event {
+            Event::Message { text, .. } => {
+                let text: String = text.chars().filter(|c| !c.is_control()).collect();
+                if !text.trim().is_empty() {
+                    self.replies.say_as(&puppet, &self.room, text);
+                }
+            },
+            Event::Join { .. } => {
+                if self.puppets.insert(puppet.clone()) {
+                    self.replies.join_as(&puppet, &self.room);
+                }
+            },
+            Event::Part { .. } => {
+                if self.puppets.remove(&puppet) {
+                    self.replies.part_as(&puppet, &self.room);
+                }
+            },
+        }
+    }
+}
+
+/// The nick a user of the endpoint is shown with, or None if the nick would
+/// not be valid on this server without the `@<bridge>`.
+fn puppet_nick(nick: &str, bridge: &str) -> Option<String> {
+    if is_valid_nick(nick) { Some(format!("{}@{}", nick, bridge)) } else { None }
+}
+
+async fn connect(endpoint: &Endpoint) -> io::Result<Box<dyn Connection>> {
+    Ok(match endpoint {
+        Endpoint::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+        #[cfg(unix)]
+        Endpoint::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+    })
+}
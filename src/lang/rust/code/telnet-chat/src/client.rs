@@ -1,209 +1,280 @@
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
+use bytes::{Bytes, BytesMut};
+use futures::future::FutureExt;
 use futures::stream::StreamExt;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpStream, tcp::{ReadHalf, WriteHalf}};
+use tokio::io::{split, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender, UnboundedReceiver};
-use tokio::sync::mpsc::{channel, Sender, Receiver};
-use tokio::sync::oneshot;
 use tokio::{try_join, select};
-use tokio::task::JoinHandle;
-use tokio_util::codec::FramedRead;
+use tokio::time::timeout;
+use tokio_util::codec::{Encoder, FramedRead};
 
 use crate::ClientId;
-use crate::main_loop::{ServerHandle, ToServer};
+use crate::actor::{self, Mailbox, Owner, SendError};
+use crate::login::Login;
+use crate::markup;
+use crate::main_loop::{DisconnectReason, ServerHandle, ToServer};
+use crate::screen::Screen;
 use crate::telnet::{TelnetCodec, Item};
+use crate::terminal::Terminal;
 
 /// Messages received from the main loop.
 pub enum FromServer {
-    Message(Vec<u8>),
+    Message(Bytes),
+    /// Lines that are written together, without other lines in between.
+    Block(Vec<Bytes>),
+    /// The room the client is in, and how many users it has.
+    Status {
+        room: Arc<str>,
+        users: usize,
+    },
+    /// Who is typing in the room, for the status bar. Empty if nobody is.
+    Typing(String),
+    /// Write this line, then close the connection.
+    Disconnect(String),
 }
 
-/// A handle to this actor, used by the server.
+/// A handle to this actor, used by the server. Dropping it kills the actor.
 #[derive(Debug)]
 pub struct ClientHandle {
     pub id: ClientId,
     ip: SocketAddr,
-    chan: Sender<FromServer>,
-    kill: JoinHandle<()>,
+    actor: Owner<FromServer>,
 }
 
 impl ClientHandle {
     /// Send a message to this client actor. Will emit an error if sending does
     /// not succeed immediately, as this means that forwarding messages to the
     /// tcp connection cannot keep up.
+    ///
+    /// An actor that has stopped reading its mailbox is on its way out, and
+    /// is about to tell the main loop why, so that is not an error.
     pub fn send(&mut self, msg: FromServer) -> Result<(), io::Error> {
-        if self.chan.try_send(msg).is_err() {
-            Err(io::Error::new(io::ErrorKind::BrokenPipe, "Can't keep up or dead"))
-        } else {
-            Ok(())
+        match self.actor.try_send(msg) {
+            Ok(()) | Err(SendError::Stopped) => Ok(()),
+            Err(err @ SendError::Full) => Err(err.into()),
         }
     }
 
+    /// The address of the tcp connection.
+    pub fn ip(&self) -> SocketAddr {
+        self.ip
+    }
+
     /// Kill the actor.
     pub fn kill(self) {
         // run the destructor
         drop(self);
     }
-}
 
-impl Drop for ClientHandle {
-    fn drop(&mut self) {
-        self.kill.abort()
+    /// Tell the user why, then close the connection. If the actor cannot keep
+    /// up, it is killed right away instead.
+    pub fn disconnect(self, reason: String) {
+        if self.actor.try_send(FromServer::Disconnect(reason)).is_ok() {
+            // Let it write its last line.
+            self.actor.detach();
+        }
     }
 }
 
+/// A connection that speaks telnet: a tcp connection, or a stream from
+/// another front-end such as `ssh`.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection for T {}
+
 /// This struct is constructed by the accept loop and used as the argument to
 /// `spawn_client`.
 pub struct ClientInfo {
     pub ip: SocketAddr,
     pub id: ClientId,
     pub handle: ServerHandle,
-    pub tcp: TcpStream,
+    pub conn: Box<dyn Connection>,
 }
 
 /// This struct stores the information used internally by this client actor.
 struct ClientData {
     id: ClientId,
     handle: ServerHandle,
-    recv: Receiver<FromServer>,
-    tcp: TcpStream,
+    recv: Mailbox<FromServer>,
+    conn: Box<dyn Connection>,
 }
 
 /// Spawn a new client actor.
 pub fn spawn_client(info: ClientInfo) {
-    let (send, recv) = channel(64);
-
-    let data = ClientData {
-        id: info.id,
-        handle: info.handle.clone(),
-        tcp: info.tcp,
-        recv,
-    };
-
-    // This spawns the new task.
-    let (my_send, my_recv) = oneshot::channel();
-    let kill = tokio::spawn(start_client(my_recv, data));
-
-    // Then we create a ClientHandle to this new task, and use the oneshot
-    // channel to send it to the task.
-    let handle = ClientHandle {
-        id: info.id,
-        ip: info.ip,
-        chan: send,
-        kill,
-    };
-
-    // Ignore send errors here. Should only happen if the server is shutting
-    // down.
-    let _ = my_send.send(handle);
+    actor::spawn_owned(64, move |actor, recv| {
+        let my_handle = ClientHandle {
+            id: info.id,
+            ip: info.ip,
+            actor,
+        };
+        let data = ClientData {
+            id: info.id,
+            handle: info.handle,
+            conn: info.conn,
+            recv,
+        };
+        start_client(my_handle, data)
+    });
 }
 
-async fn start_client(my_handle: oneshot::Receiver<ClientHandle>, mut data: ClientData) {
-    // Wait for `spawn_client` to send us the `ClientHandle` so we can forward
-    // it to the main loop. We need the oneshot channel because we cannot
-    // otherwise get the `JoinHandle` returned by `tokio::spawn`. We forward it
-    // from here instead of in `spawn_client` because we want the server to see
-    // the NewClient message before this actor starts sending other messages.
-    let my_handle = match my_handle.await {
-        Ok(my_handle) => my_handle,
-        Err(_) => return,
-    };
-    data.handle.send(ToServer::NewClient(my_handle)).await;
+async fn start_client(my_handle: ClientHandle, mut data: ClientData) {
+    // We forward our handle from here instead of in `spawn_client` because we
+    // want the server to see the ClientConnected message before this actor
+    // starts sending other messages.
+    data.handle.send(ToServer::ClientConnected(my_handle)).await;
 
     // We sent the client handle to the main loop. Start talking to the tcp
     // connection.
-    let res = client_loop(data).await;
-    match res {
-        Ok(()) => {},
-        Err(err) => {
-            eprintln!("Something went wrong: {}.", err);
-        },
-    }
+    let id = data.id;
+    let mut handle = data.handle.clone();
+    let reason = match client_loop(data).await {
+        Ok(reason) => reason,
+        Err(err) => DisconnectReason::Error(err.to_string()),
+    };
+
+    // Let the main loop free our nick. If the main loop removed us instead,
+    // it no longer knows this id and ignores the message, or this task was
+    // aborted and never gets here.
+    handle.send(ToServer::ClientDisconnected(id, reason)).await;
 }
 
 /// This method performs the actual job of running the client actor.
-async fn client_loop(mut data: ClientData) -> Result<(), io::Error> {
-    let (read, write) = data.tcp.split();
+async fn client_loop(data: ClientData) -> Result<DisconnectReason, io::Error> {
+    let (mut read, mut write) = split(data.conn);
 
     // communication between tcp_read and tcp_write
     let (send, recv) = unbounded_channel();
 
-    let ((), ()) = try_join! {
-        tcp_read(data.id, read, data.handle, send),
-        tcp_write(write, data.recv, recv),
+    let (reason, ()) = try_join! {
+        tcp_read(data.id, &mut read, data.handle, send),
+        tcp_write(&mut write, data.recv, recv),
     }?;
 
-    let _ = data.tcp.shutdown().await;
+    let _ = read.unsplit(write).shutdown().await;
 
-    Ok(())
+    Ok(reason)
 }
 
 #[derive(Debug)]
-enum InternalMsg {
+pub(crate) enum InternalMsg {
     GotAreYouThere,
-    SendDont(u8),
-    SendWont(u8),
-    SendDo(u8),
+    /// A negotiation or subnegotiation to send to the client.
+    Telnet(Item),
+    /// Break or Abort Output: drop the lines waiting to be written.
+    FlushOutput,
+    /// Whether the terminal type says the client can show colours.
+    Colour(bool),
+    /// Text to write without ending the line.
+    Prompt(String),
+    /// A full line of text.
+    Line(String),
+    /// Show the full-screen layout at this size, or stop showing it.
+    Screen(Option<(u16, u16)>),
+    /// The input line changed, in full-screen mode.
+    Input {
+        text: String,
+        cursor: usize,
+        hidden: bool,
+    },
+    /// The user entered a line.
+    Submit {
+        line: String,
+        hidden: bool,
+    },
+    /// Page up or down through the scrollback.
+    Scroll(bool),
+    /// The user logged in with this nick.
+    Nick(String),
 }
 
 async fn tcp_read(
     id: ClientId,
-    read: ReadHalf<'_>,
-    mut handle: ServerHandle,
+    read: impl AsyncRead + Unpin,
+    handle: ServerHandle,
     to_tcp_write: UnboundedSender<InternalMsg>,
-) -> Result<(), io::Error> {
+) -> Result<DisconnectReason, io::Error> {
     let mut telnet = FramedRead::new(read, TelnetCodec::new());
+    let mut login = Login::new(id, handle, Terminal::new(to_tcp_write.clone()));
+    login.terminal().start();
+    login.start();
 
     while let Some(item) = telnet.next().await {
         match item? {
             Item::Line(line) => {
-                handle.send(ToServer::Message(id, line)).await;
+                if !login.on_line(line).await? {
+                    return Ok(DisconnectReason::LoginFailed);
+                }
+            },
+            Item::Data(keys) => {
+                for line in login.terminal().keys(&keys) {
+                    if !login.on_line(line.into_bytes()).await? {
+                        return Ok(DisconnectReason::LoginFailed);
+                    }
+                }
+                login.terminal().show_input();
+                login.report_typing().await;
             },
             Item::AreYouThere => {
                 to_tcp_write.send(InternalMsg::GotAreYouThere)
                     .expect("Should not be closed.");
             },
-            Item::GoAhead => { /* ignore */ },
-            Item::InterruptProcess => return Ok(()),
-            Item::Will(3) => { // suppress go-ahead
-                to_tcp_write.send(InternalMsg::SendDo(3))
+            // The decoder already dropped the rest of the line for a Data
+            // Mark.
+            Item::GoAhead | Item::DataMark => { /* ignore */ },
+            Item::InterruptProcess => return Ok(DisconnectReason::Quit),
+            Item::Break | Item::AbortOutput => {
+                to_tcp_write.send(InternalMsg::FlushOutput)
                     .expect("Should not be closed.");
             },
-            Item::Will(i) => {
-                to_tcp_write.send(InternalMsg::SendDont(i))
-                    .expect("Should not be closed.");
-            },
-            Item::Do(i) => {
-                to_tcp_write.send(InternalMsg::SendWont(i))
-                    .expect("Should not be closed.");
+            item @ (Item::Will(_) | Item::Wont(_) | Item::Do(_) | Item::Dont(_)) => {
+                login.terminal().negotiate(&item);
             },
-            item => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Unable to handle {:?}", item),
-                ));
+            Item::Subnegotiation(option, data) => {
+                login.terminal().subnegotiation(option, &data);
             },
         }
-    }
 
-    // disconnected
+        // Keys arrive one at a time in full-screen mode.
+        telnet.decoder_mut().set_lines(!login.terminal().is_full_screen());
+    }
 
-    Ok(())
+    // The user closed the connection.
+    Ok(DisconnectReason::Quit)
 }
 
 async fn tcp_write(
-    mut write: WriteHalf<'_>,
-    mut recv: Receiver<FromServer>,
+    mut write: impl AsyncWrite + Unpin,
+    mut recv: Mailbox<FromServer>,
     mut from_tcp_read: UnboundedReceiver<InternalMsg>,
 ) -> Result<(), io::Error> {
+    let mut screen = Screen::default();
+    // Whether the terminal type says the client can show colours.
+    let mut colour = false;
+
     loop {
         select! {
             msg = recv.recv() => match msg {
                 Some(FromServer::Message(msg)) => {
-                    write.write_all(&msg).await?;
-                    write.write_all(&[13, 10]).await?;
+                    write_line(&mut write, &mut screen, markup::render(&msg, colour)).await?;
+                },
+                Some(FromServer::Block(lines)) => {
+                    for line in lines {
+                        write_line(&mut write, &mut screen, markup::render(&line, colour)).await?;
+                    }
+                },
+                Some(FromServer::Status { room, users }) => {
+                    write.write_all(&screen.status(&room, users)).await?;
+                },
+                Some(FromServer::Typing(notice)) => {
+                    write.write_all(&screen.typing(&notice)).await?;
+                },
+                Some(FromServer::Disconnect(reason)) => {
+                    write.write_all(&screen.stop()).await?;
+                    return Err(say_goodbye(&mut write, reason).await);
                 },
                 None => {
                     break;
@@ -211,16 +282,75 @@ async fn tcp_write(
             },
             msg = from_tcp_read.recv() => match msg {
                 Some(InternalMsg::GotAreYouThere) => {
-                    write.write_all(b"Yes.\r\n").await?;
+                    write_line(&mut write, &mut screen, b"Yes.".to_vec()).await?;
+                },
+                Some(InternalMsg::Telnet(item)) => {
+                    let mut buf = BytesMut::new();
+                    TelnetCodec::new().encode(item, &mut buf)?;
+                    write.write_all(&buf).await?;
+                },
+                Some(InternalMsg::Colour(on)) => {
+                    colour = on;
+                },
+                Some(InternalMsg::FlushOutput) => {
+                    // Lines already handed to the kernel can't be taken back,
+                    // but the ones still in the mailbox can.
+                    while let Some(Some(msg)) = recv.recv().now_or_never() {
+                        match msg {
+                            FromServer::Disconnect(reason) => {
+                                write.write_all(&screen.stop()).await?;
+                                return Err(say_goodbye(&mut write, reason).await);
+                            },
+                            FromServer::Status { room, users } => {
+                                write.write_all(&screen.status(&room, users)).await?;
+                            },
+                            FromServer::Typing(notice) => {
+                                write.write_all(&screen.typing(&notice)).await?;
+                            },
+                            FromServer::Message(_) | FromServer::Block(_) => {},
+                        }
+                    }
+                    // Data Mark shows the client where output resumes.
+                    write.write_all(&[0xff, 242]).await?;
+                },
+                Some(InternalMsg::Prompt(text)) => {
+                    // The screen keeps the prompt to draw it on the input
+                    // line.
+                    let drawn = screen.prompt(&text);
+                    if screen.is_active() {
+                        write.write_all(&drawn).await?;
+                    } else {
+                        write.write_all(text.as_bytes()).await?;
+                    }
                 },
-                Some(InternalMsg::SendDont(i)) => {
-                    write.write_all(&[0xff, 254, i]).await?;
+                Some(InternalMsg::Line(text)) => {
+                    write_line(&mut write, &mut screen, text.into_bytes()).await?;
                 },
-                Some(InternalMsg::SendWont(i)) => {
-                    write.write_all(&[0xff, 252, i]).await?;
+                Some(InternalMsg::Screen(Some((cols, rows)))) => {
+                    write.write_all(&screen.start(cols, rows)).await?;
                 },
-                Some(InternalMsg::SendDo(i)) => {
-                    write.write_all(&[0xff, 253, i]).await?;
+                Some(InternalMsg::Screen(None)) => {
+                    write.write_all(&screen.stop()).await?;
+                },
+                Some(InternalMsg::Input { text, cursor, hidden }) => {
+                    write.write_all(&screen.input(&text, cursor, hidden)).await?;
+                },
+                Some(InternalMsg::Submit { line, hidden }) => {
+                    let active = screen.is_active();
+                    let drawn = screen.submit(&line, hidden);
+                    if active {
+                        write.write_all(&drawn).await?;
+                    } else if hidden {
+                        // The enter key of a password is not echoed either,
+                        // so we finish the line for the user.
+                        write.write_all(&[13, 10]).await?;
+                    }
+                },
+                Some(InternalMsg::Scroll(up)) => {
+                    write.write_all(&screen.scroll(up)).await?;
+                },
+                Some(InternalMsg::Nick(nick)) => {
+                    write.write_all(&screen.nick(&nick)).await?;
                 },
                 None => {
                     break;
@@ -231,3 +361,33 @@ async fn tcp_write(
 
     Ok(())
 }
+
+/// Write a line, to the scrollback pane if the screen is shown. The screen
+/// remembers it either way.
+async fn write_line(
+    write: &mut (impl AsyncWrite + Unpin),
+    screen: &mut Screen,
+    line: Vec<u8>,
+) -> Result<(), io::Error> {
+    if screen.is_active() {
+        let drawn = screen.line(line);
+        write.write_all(&drawn).await
+    } else {
+        write.write_all(&line).await?;
+        screen.line(line);
+        write.write_all(&[13, 10]).await
+    }
+}
+
+/// Write the reason for a disconnect. Returns the error that stops the actor.
+async fn say_goodbye(write: &mut (impl AsyncWrite + Unpin), reason: String) -> io::Error {
+    // Don't let a peer that doesn't read keep us around.
+    let last = async {
+        write.write_all(reason.as_bytes()).await?;
+        write.write_all(&[13, 10]).await
+    };
+    let _ = timeout(Duration::from_secs(5), last).await;
+
+    // An error is the only way to stop tcp_read too.
+    io::Error::new(io::ErrorKind::ConnectionAborted, "Disconnected by the server")
+}
@@ -0,0 +1,241 @@
+//! Parsing of the `/` commands handled by the main loop.
+
+use std::time::Duration;
+
+use crate::moderation::{parse_duration, DurationError};
+use crate::polls::{self, MAX_OPTIONS};
+
+/// Longest reaction `/react` accepts, in characters.
+const MAX_REACTION: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Join(String),
+    Kick { nick: String, reason: Option<String> },
+    /// A `None` duration mutes until `/unmute`.
+    Mute { nick: String, duration: Option<Duration> },
+    Unmute(String),
+    /// A `None` duration bans forever.
+    Ban { nick: String, duration: Option<Duration>, reason: Option<String> },
+    Unban(String),
+    /// Show the topic, or set it.
+    Topic(Option<String>),
+    Op(String),
+    Deop(String),
+    /// Mark yourself as away, with an optional reason.
+    Away(Option<String>),
+    Back,
+    Whois(String),
+    /// A private message.
+    Msg { nick: String, text: String },
+    /// Leave a message for a registered user who is offline.
+    Memo { nick: String, text: String },
+    /// Turn typing notices on or off.
+    Typing(bool),
+    /// Show a paste by its id.
+    Show(u64),
+    /// Show the poll of the room, or start one.
+    Poll(Option<NewPoll>),
+    /// Vote for an option of the poll, by number or by its text.
+    Vote(String),
+    /// React to a recent message by its id.
+    React { id: u64, reaction: String },
+    /// Show the message of the day, or change it.
+    Motd(Option<MotdChange>),
+    /// List the servers this one is linked with.
+    Links,
+    /// Close the link to a server.
+    Squit(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewPoll {
+    pub question: String,
+    pub options: Vec<String>,
+    /// How long the poll stays open, if not the default.
+    pub duration: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MotdChange {
+    /// Replace the message with this line.
+    Set(String),
+    /// Add a line to the message.
+    Add(String),
+    Clear,
+}
+
+/// Parse a chat line. Returns `None` if the line is not a command, and an
+/// error message meant for the user if it is not a valid one.
+pub fn parse(line: &[u8]) -> Option<Result<Command, String>> {
+    if line.first() != Some(&b'/') {
+        return None;
+    }
+
+    let line = String::from_utf8_lossy(&line[1..]);
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let args: Vec<&str> = words.collect();
+
+    // The question and options may be quoted, so they are split again.
+    if name == "poll" && !args.is_empty() {
+        let rest = &line.trim_start()[name.len()..];
+        return Some(parse_poll(rest));
+    }
+
+    Some(parse_command(name, &args))
+}
+
+fn parse_command(name: &str, args: &[&str]) -> Result<Command, String> {
+    let rest = |from: usize| {
+        if args.len() > from {
+            Some(args[from..].join(" "))
+        } else {
+            None
+        }
+    };
+
+    match (name, args) {
+        ("join", [room]) => Ok(Command::Join(room.to_string())),
+        ("join", _) => Err("Usage: /join <room>".to_string()),
+        ("kick", [nick, ..]) => Ok(Command::Kick {
+            nick: nick.to_string(),
+            reason: rest(1),
+        }),
+        ("kick", _) => Err("Usage: /kick <nick> [reason]".to_string()),
+        ("mute", [nick]) => Ok(Command::Mute {
+            nick: nick.to_string(),
+            duration: None,
+        }),
+        ("mute", [nick, duration]) => Ok(Command::Mute {
+            nick: nick.to_string(),
+            duration: duration_arg(duration)?,
+        }),
+        ("mute", _) => Err("Usage: /mute <nick> [duration]".to_string()),
+        ("unmute", [nick]) => Ok(Command::Unmute(nick.to_string())),
+        ("unmute", _) => Err("Usage: /unmute <nick>".to_string()),
+        ("ban", [nick, duration, ..]) => Ok(Command::Ban {
+            nick: nick.to_string(),
+            duration: duration_arg(duration)?,
+            reason: rest(2),
+        }),
+        ("ban", _) => Err("Usage: /ban <nick> <duration|perm> [reason]".to_string()),
+        ("unban", [nick]) => Ok(Command::Unban(nick.to_string())),
+        ("unban", _) => Err("Usage: /unban <nick>".to_string()),
+        ("topic", _) => Ok(Command::Topic(rest(0))),
+        ("op", [nick]) => Ok(Command::Op(nick.to_string())),
+        ("op", _) => Err("Usage: /op <nick>".to_string()),
+        ("deop", [nick]) => Ok(Command::Deop(nick.to_string())),
+        ("deop", _) => Err("Usage: /deop <nick>".to_string()),
+        ("away", _) => Ok(Command::Away(rest(0))),
+        ("back", []) => Ok(Command::Back),
+        ("back", _) => Err("Usage: /back".to_string()),
+        ("whois", [nick]) => Ok(Command::Whois(nick.to_string())),
+        ("whois", _) => Err("Usage: /whois <nick>".to_string()),
+        ("msg", [nick, _, ..]) => Ok(Command::Msg {
+            nick: nick.to_string(),
+            text: rest(1).unwrap_or_default(),
+        }),
+        ("msg", _) => Err("Usage: /msg <nick> <text>".to_string()),
+        ("memo", [nick, _, ..]) => Ok(Command::Memo {
+            nick: nick.to_string(),
+            text: rest(1).unwrap_or_default(),
+        }),
+        ("memo", _) => Err("Usage: /memo <nick> <text>".to_string()),
+        ("typing", ["on"]) => Ok(Command::Typing(true)),
+        ("typing", ["off"]) => Ok(Command::Typing(false)),
+        ("typing", _) => Err("Usage: /typing on|off".to_string()),
+        ("show", [id]) => match id.trim_start_matches('#').parse() {
+            Ok(id) => Ok(Command::Show(id)),
+            Err(_) => Err("Usage: /show <id>".to_string()),
+        },
+        ("show", _) => Err("Usage: /show <id>".to_string()),
+        ("poll", []) => Ok(Command::Poll(None)),
+        ("vote", [_, ..]) => Ok(Command::Vote(rest(0).unwrap_or_default())),
+        ("vote", _) => Err("Usage: /vote <option>".to_string()),
+        ("react", [id, reaction]) => match id.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(_) if reaction.chars().count() > MAX_REACTION => {
+                Err(format!("A reaction can be at most {} characters.", MAX_REACTION))
+            },
+            Ok(id) => Ok(Command::React { id, reaction: reaction.to_string() }),
+            Err(_) => Err("Usage: /react <id> <reaction>".to_string()),
+        },
+        ("react", _) => Err("Usage: /react <id> <reaction>".to_string()),
+        ("motd", []) => Ok(Command::Motd(None)),
+        ("motd", ["set", _, ..]) => Ok(Command::Motd(Some(MotdChange::Set(rest(1).unwrap_or_default())))),
+        ("motd", ["add", _, ..]) => Ok(Command::Motd(Some(MotdChange::Add(rest(1).unwrap_or_default())))),
+        ("motd", ["clear"]) => Ok(Command::Motd(Some(MotdChange::Clear))),
+        ("motd", _) => Err("Usage: /motd [set <text> | add <text> | clear]".to_string()),
+        ("links", []) => Ok(Command::Links),
+        ("links", _) => Err("Usage: /links".to_string()),
+        ("squit", [server]) => Ok(Command::Squit(server.to_string())),
+        ("squit", _) => Err("Usage: /squit <server>".to_string()),
+        (name, _) => Err(format!("Unknown command /{}.", name)),
+    }
+}
+
+const POLL_USAGE: &str = "Usage: /poll [duration] \"question\" <option> <option> ...";
+
+/// `[duration] question option option ...`, where any of them may be quoted.
+fn parse_poll(text: &str) -> Result<Command, String> {
+    let mut words = quoted_words(text).ok_or_else(|| POLL_USAGE.to_string())?;
+
+    // Only a duration if a question and two options follow it.
+    let mut duration = None;
+    if words.len() > 3 {
+        match parse_duration(&words[0]) {
+            Ok(Some(d)) if d <= polls::MAX_DURATION => {
+                duration = Some(d);
+                words.remove(0);
+            },
+            Ok(Some(_)) | Err(DurationError::TooLong) => {
+                return Err("A poll can be open for at most 7d.".to_string());
+            },
+            Ok(None) | Err(DurationError::Invalid) => {},
+        }
+    }
+
+    if words.len() < 3 {
+        return Err(POLL_USAGE.to_string());
+    }
+    if words.len() > MAX_OPTIONS + 1 {
+        return Err(format!("A poll can have at most {} options.", MAX_OPTIONS));
+    }
+
+    let question = words.remove(0);
+    Ok(Command::Poll(Some(NewPoll { question, options: words, duration })))
+}
+
+/// Split on whitespace, except inside double quotes. Returns `None` if a
+/// quote is not closed, or a quoted word is empty.
+fn quoted_words(text: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        let (word, after) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (quoted[..end].trim(), &quoted[end + 1..])
+            },
+            None => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            },
+        };
+        if word.is_empty() {
+            return None;
+        }
+        words.push(word.to_string());
+        rest = after.trim_start();
+    }
+
+    Some(words)
+}
+
+fn duration_arg(text: &str) -> Result<Option<Duration>, String> {
+    parse_duration(text).map_err(|err| match err {
+        DurationError::Invalid => format!("Invalid duration {}. Try 30s, 10m, 2h, 7d or perm.", text),
+        DurationError::TooLong => "Durations can be at most 365d. Use perm for longer.".to_string(),
+    })
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::Cidr;
+use crate::schedule::Schedule;
+
+/// The server configuration, read from a TOML file. Every field has a default,
+/// so an empty file (or no file at all) gives a working server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// The address the accept loop listens on.
+    pub bind: SocketAddr,
+    /// Connections from these addresses, such as `10.0.0.0/8`, come through
+    /// a proxy that sends a PROXY header with the address of the client.
+    pub trusted_proxies: Vec<Cidr>,
+    /// The address the SSH front-end listens on. It is off if unset.
+    pub ssh_bind: Option<SocketAddr>,
+    /// The SSH host key, in OpenSSH format. A new key is made if the file
+    /// doesn't exist.
+    pub ssh_host_key: PathBuf,
+    /// The public keys that may log in over SSH, in the format of
+    /// `~/.ssh/authorized_keys`. It is read again for every login.
+    pub ssh_authorized_keys: PathBuf,
+    /// Where registered accounts and their password hashes are stored.
+    pub accounts_file: PathBuf,
+    /// Registered nicks with operator rights in every room.
+    pub operators: Vec<String>,
+    /// Where bans are stored, so they survive restarts.
+    pub bans_file: PathBuf,
+    /// Moderation actions are appended to this file.
+    pub audit_log: PathBuf,
+    /// Checks on chat messages, applied in order before they are sent.
+    pub filters: Vec<FilterConfig>,
+    /// How many seconds a filter with the `mute` action mutes for.
+    pub filter_mute_secs: u64,
+    /// How many worker tasks send messages to the client actors.
+    pub shards: usize,
+    /// The message of the day, shown when a user logs in. Server operators
+    /// can change it with `/motd`.
+    pub motd_file: PathBuf,
+    /// Messages the server sends to rooms on a schedule.
+    pub announcements: Vec<AnnouncementConfig>,
+    /// Where memos for offline users are stored.
+    pub memos_file: PathBuf,
+    /// The most unread memos a user can have waiting, or have sent.
+    pub memo_quota: usize,
+    /// Memos are deleted this many days after they were sent.
+    pub memo_expiry_days: u64,
+    /// How many pastes are kept for `/show`. The oldest are forgotten
+    /// first.
+    pub max_pastes: usize,
+    /// Mark users as away after this many seconds without activity. 0 turns
+    /// it off.
+    pub auto_away_secs: u64,
+    /// A user whose connection is lost keeps their nick and room for this
+    /// many seconds, and can pick up where they left off. 0 turns it off.
+    pub resume_secs: u64,
+    /// The name of this server, as other servers know it.
+    pub server_name: String,
+    /// The address other servers link to. No links are accepted if unset.
+    pub link_bind: Option<SocketAddr>,
+    /// The servers that may link with this one.
+    pub links: Vec<LinkConfig>,
+    /// How many seconds to wait before connecting again to a server after
+    /// the link was lost, or to a bridge endpoint.
+    pub link_retry_secs: u64,
+    /// Built-in plugins that are told about messages.
+    pub bots: Vec<BotConfig>,
+    /// Rooms linked to outside programs. See `crate::bridge`.
+    pub bridges: Vec<BridgeConfig>,
+}
+
+/// A server that may link with this one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LinkConfig {
+    pub name: String,
+    /// Both servers must have the same password for the link.
+    pub password: String,
+    /// Connect to the server at this address, and keep the link up. Without
+    /// it, this server waits for the other one to connect.
+    #[serde(default)]
+    pub connect: Option<SocketAddr>,
+}
+
+/// A built-in plugin, such as
+///
+/// ```toml
+/// [[bots]]
+/// kind = "run-command"
+/// name = "ci"
+/// command = ["./notify.sh"]
+/// rooms = ["builds"]
+/// contains = "deploy"
+/// ```
+///
+/// Both kinds only handle messages in `rooms`, or in every room if it is
+/// empty, and only those containing `contains` (ignoring case) if it is set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum BotConfig {
+    /// Append matching messages to a file.
+    AppendToFile {
+        name: String,
+        file: PathBuf,
+        #[serde(default)]
+        rooms: Vec<String>,
+        #[serde(default)]
+        contains: Option<String>,
+    },
+    /// Run a command with each matching message on stdin. What it prints is
+    /// said in the room.
+    RunCommand {
+        name: String,
+        /// The program and its arguments.
+        command: Vec<String>,
+        #[serde(default)]
+        rooms: Vec<String>,
+        #[serde(default)]
+        contains: Option<String>,
+    },
+}
+
+/// A message sent by the server on a schedule, such as
+///
+/// ```toml
+/// [[announcements]]
+/// schedule = "45 9 * * 1-5"
+/// rooms = ["dev"]
+/// text = "Stand-up in 15 minutes."
+/// ```
+///
+/// The schedule is like cron, in UTC. See `crate::schedule`. Without
+/// `rooms`, the message goes to every room.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AnnouncementConfig {
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    pub text: String,
+}
+
+/// A room linked to an endpoint that speaks the bridge protocol, such as
+///
+/// ```toml
+/// [[bridges]]
+/// name = "tools"
+/// room = "ops"
+/// connect = "127.0.0.1:4000"
+/// ```
+///
+/// `connect` is a `host:port`, or `unix:<path>` for a Unix socket. The users
+/// of the endpoint show up in the room as `<nick>@<name>`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BridgeConfig {
+    pub name: String,
+    pub room: String,
+    pub connect: String,
+}
+
+/// A check on what users write for others to see, such as
+///
+/// ```toml
+/// [[filters]]
+/// kind = "blocklist"
+/// words = ["darn", "heck"]
+/// action = "mask"
+/// rooms = { kids = "mute", offtopic = "allow" }
+/// ```
+///
+/// The other kinds are `regex` with a `pattern` and an optional `name`,
+/// `max-length` with a `max` in characters for each line, `duplicates` with
+/// `within_secs`, and `links`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FilterConfig {
+    #[serde(flatten)]
+    pub rule: FilterRule,
+    pub action: FilterAction,
+    /// A different action in some rooms.
+    #[serde(default)]
+    pub rooms: HashMap<String, FilterAction>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum FilterRule {
+    /// Words that may not be used, ignoring case.
+    Blocklist { words: Vec<String> },
+    Regex {
+        pattern: String,
+        /// What users are told the rule is about.
+        #[serde(default)]
+        name: Option<String>,
+    },
+    MaxLength { max: usize },
+    /// The same message from the same user again.
+    Duplicates { within_secs: u64 },
+    Links,
+}
+
+/// What happens to a message that breaks a filter rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilterAction {
+    /// The rule doesn't apply.
+    Allow,
+    Drop,
+    /// Hide what broke the rule, or cut the message short. Rules that can't
+    /// do either drop the message.
+    Mask,
+    /// Send the message, but warn the user.
+    Warn,
+    /// Drop the message, and mute the user in the room.
+    Mute,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: ([0, 0, 0, 0], 3456).into(),
+            trusted_proxies: Vec::new(),
+            ssh_bind: None,
+            ssh_host_key: PathBuf::from("ssh_host_key"),
+            ssh_authorized_keys: PathBuf::from("authorized_keys"),
+            accounts_file: PathBuf::from("accounts.txt"),
+            operators: Vec::new(),
+            bans_file: PathBuf::from("bans.txt"),
+            audit_log: PathBuf::from("audit.log"),
+            filters: Vec::new(),
+            filter_mute_secs: 5 * 60,
+            shards: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            motd_file: PathBuf::from("motd.txt"),
+            announcements: Vec::new(),
+            memos_file: PathBuf::from("memos.txt"),
+            memo_quota: 20,
+            memo_expiry_days: 30,
+            max_pastes: 100,
+            auto_away_secs: 15 * 60,
+            resume_secs: 5 * 60,
+            server_name: "telnet-chat".to_string(),
+            link_bind: None,
+            links: Vec::new(),
+            link_retry_secs: 10,
+            bots: Vec::new(),
+            bridges: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, io::Error> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
@@ -0,0 +1,245 @@
+//! Fan-out of messages to client actors, spread over several worker tasks.
+//!
+//! The main loop decides who should receive a message, but sending it to
+//! thousands of client actors is done by the shards. Each client actor belongs
+//! to exactly one shard, which owns its `ClientHandle` and knows which rooms
+//! it is in. A broadcast is therefore one message per shard, and every shard
+//! sends it to its own members of the room. Payloads are `Bytes`, so this only
+//! copies a reference count, never the message itself.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::ClientId;
+use crate::client::{ClientHandle, FromServer};
+
+/// Messages sent from the main loop to a shard.
+enum ShardMsg {
+    Add(ClientHandle),
+    Remove(ClientId),
+    Disconnect(ClientId, String),
+    Join(ClientId, Arc<str>),
+    Leave(ClientId, Arc<str>),
+    SendTo(ClientId, Bytes),
+    SendBlock(ClientId, Vec<Bytes>),
+    Typing(ClientId, String),
+    Broadcast {
+        room: Arc<str>,
+        except: Option<ClientId>,
+        msg: Bytes,
+    },
+    Status {
+        room: Arc<str>,
+        users: usize,
+    },
+}
+
+/// Used by the main loop to talk to the shards.
+///
+/// The channels to the shards are unbounded so the main loop never waits on a
+/// shard. A shard never waits on anything, as client actors that can't keep up
+/// are removed instead, so they drain their channel faster than the main loop
+/// can fill it.
+#[derive(Debug)]
+pub struct Fanout {
+    shards: Vec<UnboundedSender<ShardMsg>>,
+}
+
+impl Fanout {
+    /// Spawn `count` shards. When a shard drops a client actor because it
+    /// cannot keep up, its id is sent on `removed`.
+    pub fn spawn(count: usize, removed: UnboundedSender<ClientId>) -> Fanout {
+        let shards = (0..count.max(1))
+            .map(|_| {
+                let (send, recv) = unbounded_channel();
+                tokio::spawn(shard_loop(recv, removed.clone()));
+                send
+            })
+            .collect();
+
+        Fanout { shards }
+    }
+
+    pub fn add(&self, handle: ClientHandle) {
+        let id = handle.id;
+        self.send(id, ShardMsg::Add(handle));
+    }
+
+    /// Drop the handle of this client, which kills the actor.
+    pub fn remove(&self, id: ClientId) {
+        self.send(id, ShardMsg::Remove(id));
+    }
+
+    /// See `ClientHandle::disconnect`.
+    pub fn disconnect(&self, id: ClientId, reason: String) {
+        self.send(id, ShardMsg::Disconnect(id, reason));
+    }
+
+    pub fn join(&self, id: ClientId, room: &str) {
+        self.send(id, ShardMsg::Join(id, room.into()));
+    }
+
+    pub fn leave(&self, id: ClientId, room: &str) {
+        self.send(id, ShardMsg::Leave(id, room.into()));
+    }
+
+    pub fn send_to(&self, id: ClientId, msg: Bytes) {
+        self.send(id, ShardMsg::SendTo(id, msg));
+    }
+
+    /// Send several lines to a single client, with nothing in between.
+    pub fn send_block(&self, id: ClientId, lines: Vec<Bytes>) {
+        self.send(id, ShardMsg::SendBlock(id, lines));
+    }
+
+    /// Tell a client who is typing in its room.
+    pub fn typing(&self, id: ClientId, notice: String) {
+        self.send(id, ShardMsg::Typing(id, notice));
+    }
+
+    /// Send a line to every member of a room except `except`.
+    pub fn broadcast(&self, room: &str, except: Option<ClientId>, msg: Bytes) {
+        let room: Arc<str> = room.into();
+        for shard in &self.shards {
+            let msg = ShardMsg::Broadcast {
+                room: room.clone(),
+                except,
+                msg: msg.clone(),
+            };
+            // Shards only stop if the runtime is shutting down.
+            let _ = shard.send(msg);
+        }
+    }
+
+    /// Tell every member of a room how many users it has, for the status bar.
+    pub fn status(&self, room: &str, users: usize) {
+        let room: Arc<str> = room.into();
+        for shard in &self.shards {
+            let _ = shard.send(ShardMsg::Status { room: room.clone(), users });
+        }
+    }
+
+    fn send(&self, id: ClientId, msg: ShardMsg) {
+        let shard = &self.shards[id.0 % self.shards.len()];
+        let _ = shard.send(msg);
+    }
+}
+
+#[derive(Default)]
+struct Shard {
+    clients: HashMap<ClientId, ClientHandle>,
+    /// The members of each room that belong to this shard.
+    rooms: HashMap<Arc<str>, HashSet<ClientId>>,
+}
+
+impl Shard {
+    fn leave(&mut self, id: ClientId, room: &str) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: ClientId) {
+        // The destructor of ClientHandle will kill the actor.
+        self.clients.remove(&id);
+        self.rooms.retain(|_, members| {
+            members.remove(&id);
+            !members.is_empty()
+        });
+    }
+}
+
+async fn shard_loop(
+    mut recv: UnboundedReceiver<ShardMsg>,
+    removed: UnboundedSender<ClientId>,
+) {
+    let mut shard = Shard::default();
+
+    while let Some(msg) = recv.recv().await {
+        // If we fail to send messages to any actor, we need to remove it, but
+        // we can't do so while iterating.
+        let mut to_remove = Vec::new();
+
+        match msg {
+            ShardMsg::Add(handle) => {
+                shard.clients.insert(handle.id, handle);
+            },
+            ShardMsg::Remove(id) => shard.remove(id),
+            ShardMsg::Disconnect(id, reason) => {
+                if let Some(handle) = shard.clients.remove(&id) {
+                    handle.disconnect(reason);
+                }
+                shard.remove(id);
+            },
+            ShardMsg::Join(id, room) => {
+                shard.rooms.entry(room).or_default().insert(id);
+            },
+            ShardMsg::Leave(id, room) => shard.leave(id, &room),
+            ShardMsg::SendTo(id, msg) => {
+                if let Some(handle) = shard.clients.get_mut(&id) {
+                    if handle.send(FromServer::Message(msg)).is_err() {
+                        to_remove.push(id);
+                    }
+                }
+            },
+            ShardMsg::SendBlock(id, lines) => {
+                if let Some(handle) = shard.clients.get_mut(&id) {
+                    if handle.send(FromServer::Block(lines)).is_err() {
+                        to_remove.push(id);
+                    }
+                }
+            },
+            ShardMsg::Typing(id, notice) => {
+                if let Some(handle) = shard.clients.get_mut(&id) {
+                    if handle.send(FromServer::Typing(notice)).is_err() {
+                        to_remove.push(id);
+                    }
+                }
+            },
+            ShardMsg::Broadcast { room, except, msg } => {
+                let members = match shard.rooms.get(&room) {
+                    Some(members) => members,
+                    None => continue,
+                };
+
+                for id in members {
+                    // Don't send it to the client who sent it to us.
+                    if Some(*id) == except { continue; }
+
+                    if let Some(handle) = shard.clients.get_mut(id) {
+                        if handle.send(FromServer::Message(msg.clone())).is_err() {
+                            to_remove.push(*id);
+                        }
+                    }
+                }
+            },
+            ShardMsg::Status { room, users } => {
+                let members = match shard.rooms.get(&room) {
+                    Some(members) => members,
+                    None => continue,
+                };
+
+                for id in members {
+                    if let Some(handle) = shard.clients.get_mut(id) {
+                        let msg = FromServer::Status { room: room.clone(), users };
+                        if handle.send(msg).is_err() {
+                            to_remove.push(*id);
+                        }
+                    }
+                }
+            },
+        }
+
+        // Remove those clients, and let the main loop know.
+        for id in to_remove {
+            shard.remove(id);
+            let _ = removed.send(id);
+        }
+    }
+}
@@ -0,0 +1,493 @@
+//! Links between telnet-chat servers, so users on either side share rooms.
+//!
+//! Each link is an actor like a client actor: it owns the tcp connection and
+//! talks to the main loop, which keeps track of the users on the other
+//! servers and relays what happens in the rooms.
+//!
+//! The protocol is one line per message. A link starts with a handshake in
+//! which both sides prove that they know the password of the link without
+//! sending it:
+//!
+//! ```text
+//! HELLO <server name> <nonce>
+//! AUTH <argon2 of the password, salted with our side, both nonces and our name>
+//! ```
+//!
+//! The server that connected sends its `AUTH` first, and the one that
+//! accepted answers with its own only once the first is right. As the side
+//! is part of the salt, nobody can get a proof from one server by pretending
+//! to connect to it and hand it on to another.
+//!
+//! Then each side sends a burst of `SERVER` and `USER` lines with everything
+//! it knows, followed by the events below as they happen. `PING` and `PONG`
+//! keep the link alive, and `ERROR <reason>` closes it.
+//!
+//! Servers form a tree. A server that is already reachable can't be linked a
+//! second time, which is what keeps events from going around in circles: an
+//! event is relayed to every link except the one it came from. When a link
+//! is lost, everything behind it is gone too (a netsplit), and the burst of
+//! the next link brings it back.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+use tokio::time::{interval, sleep, timeout};
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::actor::{self, Mailbox, Owner, Policy, Restart, Task};
+use crate::config::{Config, LinkConfig};
+use crate::main_loop::{ServerHandle, ToServer};
+
+const MAX_LINE: usize = 8 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// A link that sent nothing for this long is considered dead.
+const PING_TIMEOUT: Duration = Duration::from_secs(90);
+/// Events waiting to be written. The burst of a big server has to fit.
+const LINK_QUEUE: usize = 16 * 1024;
+
+static NEXT_LINK: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct LinkId(usize);
+
+/// What servers tell each other about their users.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// This server is reachable through the sender.
+    Server(String),
+    /// This server is no longer reachable, and neither are its users.
+    Squit(String),
+    /// A user of `server`, who logged in at `since` (unix time) and is in
+    /// `room`.
+    User { server: String, nick: String, room: String, since: u64 },
+    /// The user moved to another room.
+    Join { nick: String, room: String },
+    /// The user left, for this reason. It is empty if the user quit.
+    Quit { server: String, nick: String, reason: String },
+    /// A line said in a room.
+    Msg { nick: String, room: String, text: String },
+    /// A private message.
+    Priv { from: String, to: String, text: String },
+}
+
+impl Event {
+    pub fn parse(line: &str) -> Option<Event> {
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let event = match kind {
+            "SERVER" => Event::Server(word(rest)?),
+            "SQUIT" => Event::Squit(word(rest)?),
+            "USER" => {
+                let mut parts = rest.split(' ');
+                let event = Event::User {
+                    server: word(parts.next()?)?,
+                    nick: word(parts.next()?)?,
+                    room: word(parts.next()?)?,
+                    since: parts.next()?.parse().ok()?,
+                };
+                if parts.next().is_some() {
+                    return None;
+                }
+                event
+            },
+            "JOIN" => {
+                let (nick, room) = rest.split_once(' ')?;
+                Event::Join { nick: word(nick)?, room: word(room)? }
+            },
+            "QUIT" => {
+                let mut parts = rest.splitn(3, ' ');
+                Event::Quit {
+                    server: word(parts.next()?)?,
+                    nick: word(parts.next()?)?,
+                    reason: parts.next().unwrap_or("").to_string(),
+                }
+            },
+            "MSG" => {
+                let mut parts = rest.splitn(3, ' ');
+                Event::Msg {
+                    nick: word(parts.next()?)?,
+                    room: word(parts.next()?)?,
+                    text: parts.next()?.to_string(),
+                }
+            },
+            "PRIV" => {
+                let mut parts = rest.splitn(3, ' ');
+                Event::Priv {
+                    from: word(parts.next()?)?,
+                    to: word(parts.next()?)?,
+                    text: parts.next()?.to_string(),
+                }
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Server(name) => write!(f, "SERVER {}", name),
+            Event::Squit(name) => write!(f, "SQUIT {}", name),
+            Event::User { server, nick, room, since } => {
+                write!(f, "USER {} {} {} {}", server, nick, room, since)
+            },
+            Event::Join { nick, room } => write!(f, "JOIN {} {}", nick, room),
+            Event::Quit { server, nick, reason } => write!(f, "QUIT {} {} {}", server, nick, reason),
+            Event::Msg { nick, room, text } => write!(f, "MSG {} {} {}", nick, room, text),
+            Event::Priv { from, to, text } => write!(f, "PRIV {} {} {}", from, to, text),
+        }
+    }
+}
+
+/// A single non-empty word.
+fn word(text: &str) -> Option<String> {
+    if text.is_empty() || text.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+enum ToLink {
+    Event(Event),
+    /// Send this reason, then close the link.
+    Close(String),
+}
+
+/// A handle to a link actor, used by the main loop. Dropping it kills the
+/// actor.
+#[derive(Debug)]
+pub struct LinkHandle {
+    pub id: LinkId,
+    actor: Owner<ToLink>,
+}
+
+impl LinkHandle {
+    /// Send an event to the other server. Fails if the link can't keep up,
+    /// in which case it should be dropped.
+    pub fn send(&mut self, event: Event) -> Result<(), io::Error> {
+        self.actor.try_send(ToLink::Event(event)).map_err(io::Error::from)
+    }
+
+    /// Tell the other server why, then close the link.
+    pub fn close(self, reason: String) {
+        if self.actor.try_send(ToLink::Close(reason)).is_ok() {
+            self.actor.detach();
+        }
+    }
+}
+
+/// Listen for links from other servers, and connect to the ones that have
+/// an address in the config.
+pub async fn start_links(config: &Config, mut handle: ServerHandle) {
+    let config = Arc::new(config.clone());
+    start_connectors(&config, handle.clone());
+
+    if let Some(bind) = config.link_bind {
+        match TcpListener::bind(bind).await {
+            Ok(listen) => {
+                tokio::spawn(link_accept_loop(listen, config, handle));
+            },
+            Err(err) => handle.send(ToServer::FatalError(err)).await,
+        }
+    }
+}
+
+/// Like `start_links`, but with a listener that is already bound.
+pub fn start_links_on(listen: TcpListener, config: &Config, handle: ServerHandle) {
+    let config = Arc::new(config.clone());
+    start_connectors(&config, handle.clone());
+    tokio::spawn(link_accept_loop(listen, config, handle));
+}
+
+fn start_connectors(config: &Arc<Config>, handle: ServerHandle) {
+    for link in &config.links {
+        if let Some(addr) = link.connect {
+            keep_linked(addr, link.name.clone(), config.clone(), handle.clone()).detach();
+        }
+    }
+}
+
+/// Keep a link to the server `name` at this address up, whether or not the
+/// config says to connect to it.
+pub fn connect_link(addr: SocketAddr, name: String, config: &Config, handle: ServerHandle) {
+    keep_linked(addr, name, Arc::new(config.clone()), handle).detach();
+}
+
+async fn link_accept_loop(listen: TcpListener, config: Arc<Config>, handle: ServerHandle) {
+    loop {
+        match listen.accept().await {
+            Ok((tcp, _)) => {
+                spawn_link(tcp, None, config.clone(), handle.clone());
+            },
+            Err(err) => {
+                eprintln!("Failed to accept a link: {}.", err);
+                sleep(Duration::from_secs(1)).await;
+            },
+        }
+    }
+}
+
+/// Keep a link to this server up, connecting again whenever it is lost.
+fn keep_linked(addr: SocketAddr, name: String, config: Arc<Config>, handle: ServerHandle) -> Task {
+    let policy = Policy {
+        restart: Restart::Always,
+        delay: Duration::from_secs(config.link_retry_secs),
+        ..Policy::default()
+    };
+    let task = format!("Link to {} at {}", name, addr);
+
+    actor::supervise(task, policy, move || {
+        let (name, config, handle) = (name.clone(), config.clone(), handle.clone());
+        async move {
+            let tcp = TcpStream::connect(addr).await?;
+            let done = spawn_link(tcp, Some(name), config, handle);
+            // Resolves when the link actor is gone, either way.
+            let _ = done.await;
+            Ok::<(), io::Error>(())
+        }
+    })
+}
+
+/// Spawn a link actor. If `expected` is set, the other server must have
+/// that name. The returned channel closes when the actor stops.
+fn spawn_link(
+    tcp: TcpStream,
+    expected: Option<String>,
+    config: Arc<Config>,
+    handle: ServerHandle,
+) -> oneshot::Receiver<()> {
+    let (done_send, done) = oneshot::channel();
+    let id = LinkId(NEXT_LINK.fetch_add(1, Ordering::Relaxed));
+
+    actor::spawn_owned(LINK_QUEUE, move |actor, recv| async move {
+        let _done = done_send;
+        let my_handle = LinkHandle { id, actor };
+        start_link(my_handle, tcp, expected, config, handle, recv).await;
+    });
+    done
+}
+
+async fn start_link(
+    my_handle: LinkHandle,
+    tcp: TcpStream,
+    expected: Option<String>,
+    config: Arc<Config>,
+    mut handle: ServerHandle,
+    recv: Mailbox<ToLink>,
+) {
+    let id = my_handle.id;
+
+    let mut framed = Framed::new(tcp, LinesCodec::new_with_max_length(MAX_LINE));
+    let peer = match timeout(HANDSHAKE_TIMEOUT, handshake(&mut framed, &config, expected)).await {
+        Ok(Ok(peer)) => peer,
+        Ok(Err(err)) => {
+            eprintln!("Link handshake failed: {}.", err);
+            let _ = framed.send(format!("ERROR {}", err)).await;
+            return;
+        },
+        Err(_) => {
+            eprintln!("Link handshake timed out.");
+            return;
+        },
+    };
+
+    println!("Linked with {}.", peer);
+    handle.send(ToServer::LinkUp(my_handle, peer.clone())).await;
+
+    if let Err(err) = link_loop(id, &mut framed, recv, handle.clone()).await {
+        eprintln!("Link to {} lost: {}.", peer, err);
+    }
+
+    handle.send(ToServer::LinkDown(id)).await;
+}
+
+async fn link_loop(
+    id: LinkId,
+    framed: &mut Framed<TcpStream, LinesCodec>,
+    mut recv: Mailbox<ToLink>,
+    mut handle: ServerHandle,
+) -> Result<(), io::Error> {
+    let mut pings = interval(PING_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        select! {
+            line = framed.next() => {
+                let line = match line {
+                    Some(line) => line.map_err(codec_error)?,
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Closed")),
+                };
+                last_seen = Instant::now();
+
+                if line == "PING" {
+                    framed.send("PONG".to_string()).await.map_err(codec_error)?;
+                } else if line == "PONG" {
+                    // Only resets `last_seen`.
+                } else if let Some(reason) = line.strip_prefix("ERROR ") {
+                    return Err(io::Error::other(reason.to_string()));
+                } else if let Some(event) = Event::parse(&line) {
+                    handle.send(ToServer::FromLink(id, event)).await;
+                }
+            },
+            msg = recv.recv() => match msg {
+                Some(ToLink::Event(event)) => {
+                    framed.send(event.to_string()).await.map_err(codec_error)?;
+                },
+                Some(ToLink::Close(reason)) => {
+                    let _ = timeout(Duration::from_secs(5), framed.send(format!("ERROR {}", reason))).await;
+                    return Err(io::Error::other(reason));
+                },
+                None => return Ok(()),
+            },
+            _ = pings.tick() => {
+                if last_seen.elapsed() > PING_TIMEOUT {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "Ping timeout"));
+                }
+                framed.send("PING".to_string()).await.map_err(codec_error)?;
+            },
+        }
+    }
+}
+
+/// Exchange names and prove that both sides know the password. Returns the
+/// name of the other server.
+async fn handshake(
+    framed: &mut Framed<TcpStream, LinesCodec>,
+    config: &Config,
+    expected: Option<String>,
+) -> Result<String, io::Error> {
+    let mut nonce = [0; 16];
+    OsRng.fill_bytes(&mut nonce);
+    framed.send(format!("HELLO {} {}", config.server_name, hex(&nonce))).await.map_err(codec_error)?;
+
+    let hello = next_line(framed).await?;
+    let (peer, peer_nonce) = match hello.strip_prefix("HELLO ").and_then(|rest| rest.split_once(' ')) {
+        Some((peer, nonce)) => (peer.to_string(), unhex(nonce)),
+        None => return Err(invalid("Expected HELLO")),
+    };
+    let peer_nonce = peer_nonce.filter(|nonce| nonce.len() == 16).ok_or_else(|| invalid("Bad nonce"))?;
+
+    if expected.as_ref().map(|name| *name != peer).unwrap_or(false) {
+        return Err(invalid("Unexpected server name"));
+    }
+    if peer == config.server_name {
+        return Err(invalid("Server names must differ"));
+    }
+    let link: &LinkConfig = config.links.iter()
+        .find(|link| link.name == peer)
+        .ok_or_else(|| invalid("Unknown server"))?;
+
+    // Only the side that connected knows which server it wants.
+    let (side, peer_side) = if expected.is_some() {
+        (Side::Connected, Side::Accepted)
+    } else {
+        (Side::Accepted, Side::Connected)
+    };
+    let nonces = match side {
+        Side::Connected => [nonce.to_vec(), peer_nonce],
+        Side::Accepted => [peer_nonce, nonce.to_vec()],
+    };
+
+    if side == Side::Connected {
+        let ours = auth_hash(&link.password, side, &nonces, &config.server_name).await?;
+        framed.send(format!("AUTH {}", hex(&ours))).await.map_err(codec_error)?;
+    }
+
+    let auth = next_line(framed).await?;
+    let theirs = auth.strip_prefix("AUTH ").and_then(unhex).ok_or_else(|| invalid("Expected AUTH"))?;
+    let expected = auth_hash(&link.password, peer_side, &nonces, &peer).await?;
+    if !constant_time_eq(&theirs, &expected) {
+        return Err(invalid("Authentication failed"));
+    }
+
+    if side == Side::Accepted {
+        let ours = auth_hash(&link.password, side, &nonces, &config.server_name).await?;
+        framed.send(format!("AUTH {}", hex(&ours))).await.map_err(codec_error)?;
+    }
+
+    Ok(peer)
+}
+
+/// Which end of the link a server is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Side {
+    Connected,
+    Accepted,
+}
+
+async fn next_line(framed: &mut Framed<TcpStream, LinesCodec>) -> Result<String, io::Error> {
+    match framed.next().await {
+        Some(line) => line.map_err(codec_error),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Closed during handshake")),
+    }
+}
+
+/// Argon2 of the password, salted with the side and name of the server that
+/// sends it and the nonces of the one that connected and the one that
+/// accepted, in that order.
+async fn auth_hash(password: &str, side: Side, nonces: &[Vec<u8>; 2], name: &str) -> Result<Vec<u8>, io::Error> {
+    let password = password.as_bytes().to_vec();
+    let mut salt = match side {
+        Side::Connected => b"connected ".to_vec(),
+        Side::Accepted => b"accepted ".to_vec(),
+    };
+    salt.extend_from_slice(&nonces[0]);
+    salt.extend_from_slice(&nonces[1]);
+    salt.extend_from_slice(name.as_bytes());
+
+    spawn_blocking(move || {
+        // The nonce is fresh every time, so this doesn't need to be as slow
+        // as a stored password hash.
+        let params = Params::new(4 * 1024, 1, 1, Some(32)).map_err(io::Error::other)?;
+        let mut out = vec![0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(&password, &salt, &mut out)
+            .map_err(io::Error::other)?;
+        Ok(out)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn codec_error(err: tokio_util::codec::LinesCodecError) -> io::Error {
+    match err {
+        tokio_util::codec::LinesCodecError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    }
+}
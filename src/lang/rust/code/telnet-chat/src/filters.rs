@@ -0,0 +1,212 @@
+//! Content filters, applied by the main loop to everything users write for
+//! others to see: chat messages, pastes, private messages, topics, polls,
+//! and messages from linked servers and plugins. See `FilterConfig`.
+//!
+//! A text of several lines, such as a paste, is checked line by line, except
+//! that the duplicates rule compares it as a whole. Masking keeps the number
+//! of lines, so a title or a poll question stays where it was.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+use crate::accounts::nick_key;
+use crate::config::{Config, FilterAction, FilterConfig, FilterRule};
+use crate::room::room_key;
+
+/// What masked links are replaced with.
+const LINK_MASK: &str = "[link removed]";
+
+#[derive(Debug)]
+pub struct Filters {
+    rules: Vec<Rule>,
+    /// How long the `mute` action mutes for.
+    mute_for: Duration,
+    /// The last message of each user, by `nick_key`, for the duplicates
+    /// rule.
+    last: HashMap<String, (String, Instant)>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    check: Check,
+    action: FilterAction,
+    /// By room key.
+    rooms: HashMap<String, FilterAction>,
+}
+
+#[derive(Debug)]
+enum Check {
+    Blocklist(Regex),
+    Regex { regex: Regex, name: String },
+    MaxLength(usize),
+    Duplicates(Duration),
+    Links(Regex),
+}
+
+/// What the filters made of a message.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// The lines to send, as many as were checked, or None if the message
+    /// was dropped.
+    pub lines: Option<Vec<String>>,
+    /// Every rule the message broke, and what was done about it.
+    pub broken: Vec<(FilterAction, String)>,
+    /// Mute the user in the room for this long.
+    pub mute: Option<Duration>,
+}
+
+impl Filters {
+    /// Fails if a pattern is not a valid regex.
+    pub fn from_config(config: &Config) -> Result<Filters, io::Error> {
+        let rules = config.filters.iter()
+            .map(Rule::new)
+            .collect::<Result<Vec<_>, regex::Error>>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        Ok(Filters {
+            rules,
+            mute_for: Duration::from_secs(config.filter_mute_secs),
+            last: HashMap::new(),
+        })
+    }
+
+    /// Check a message of one or more lines by `nick` in `room`, masking it
+    /// if a rule says so.
+    pub fn apply(&mut self, room: &str, nick: &str, lines: &[String]) -> Outcome {
+        let mut outcome = Outcome::default();
+        let mut lines = lines.to_vec();
+        let key = nick_key(nick);
+
+        for rule in &self.rules {
+            let action = rule.rooms.get(room).copied().unwrap_or(rule.action);
+            if action == FilterAction::Allow || !rule.check.matches(&lines, self.last.get(&key)) {
+                continue;
+            }
+            outcome.broken.push((action, rule.check.to_string()));
+
+            match action {
+                FilterAction::Allow | FilterAction::Warn => {},
+                FilterAction::Mask => {
+                    let masked: Option<Vec<String>> = lines.iter().map(|line| rule.check.mask(line)).collect();
+                    match masked {
+                        Some(masked) => lines = masked,
+                        None => return outcome,
+                    }
+                },
+                FilterAction::Drop => return outcome,
+                FilterAction::Mute => {
+                    outcome.mute = Some(self.mute_for);
+                    return outcome;
+                },
+            }
+        }
+
+        if self.rules.iter().any(|rule| matches!(rule.check, Check::Duplicates(_))) {
+            self.last.insert(key, (lines.join("\n"), Instant::now()));
+        }
+        outcome.lines = Some(lines);
+        outcome
+    }
+
+    /// Forget last messages that are too old to be duplicated.
+    pub fn tick(&mut self) {
+        let longest = self.rules.iter()
+            .filter_map(|rule| match rule.check {
+                Check::Duplicates(within) => Some(within),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default();
+        self.last.retain(|_, (_, at)| at.elapsed() < longest);
+    }
+}
+
+impl Rule {
+    fn new(config: &FilterConfig) -> Result<Rule, regex::Error> {
+        let check = match &config.rule {
+            FilterRule::Blocklist { words } => {
+                let words: Vec<String> = words.iter().map(|word| regex::escape(word)).collect();
+                let pattern = if words.is_empty() {
+                    // Matches nothing.
+                    r"[^\s\S]".to_string()
+                } else {
+                    format!(r"(?i)\b(?:{})\b", words.join("|"))
+                };
+                Check::Blocklist(Regex::new(&pattern)?)
+            },
+            FilterRule::Regex { pattern, name } => Check::Regex {
+                regex: Regex::new(pattern)?,
+                name: name.clone().unwrap_or_else(|| pattern.clone()),
+            },
+            FilterRule::MaxLength { max } => Check::MaxLength(*max),
+            FilterRule::Duplicates { within_secs } => {
+                Check::Duplicates(Duration::from_secs(*within_secs))
+            },
+            FilterRule::Links => Check::Links(Regex::new(r"(?i)\b(?:[a-z][a-z0-9+.-]*://|www\.)\S+")?),
+        };
+
+        let rooms = config.rooms.iter()
+            .filter_map(|(room, action)| Some((room_key(room)?, *action)))
+            .collect();
+
+        Ok(Rule { check, action: config.action, rooms })
+    }
+}
+
+impl Check {
+    fn matches(&self, lines: &[String], last: Option<&(String, Instant)>) -> bool {
+        match self {
+            Check::Blocklist(regex) | Check::Regex { regex, .. } | Check::Links(regex) => {
+                lines.iter().any(|line| regex.is_match(line))
+            },
+            Check::MaxLength(max) => lines.iter().any(|line| line.chars().count() > *max),
+            Check::Duplicates(within) => match last {
+                Some((last, at)) => at.elapsed() < *within && last.eq_ignore_ascii_case(&lines.join("\n")),
+                None => false,
+            },
+        }
+    }
+
+    /// The line with what broke the rule hidden, or None if that can't be
+    /// done.
+    fn mask(&self, text: &str) -> Option<String> {
+        let masked = match self {
+            Check::Blocklist(regex) | Check::Regex { regex, .. } => {
+                regex.replace_all(text, |caps: &regex::Captures| "*".repeat(caps[0].chars().count()))
+            },
+            Check::Links(regex) => regex.replace_all(text, LINK_MASK),
+            Check::MaxLength(max) => return Some(text.chars().take(*max).collect()),
+            Check::Duplicates(_) => return None,
+        };
+        Some(masked.into_owned())
+    }
+}
+
+/// What users are told the rule is about.
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Blocklist(_) => f.write_str("blocked words"),
+            Check::Regex { name, .. } => f.write_str(name),
+            Check::MaxLength(max) => write!(f, "longer than {} characters", max),
+            Check::Duplicates(_) => f.write_str("repeated message"),
+            Check::Links(_) => f.write_str("links"),
+        }
+    }
+}
+
+impl fmt::Display for FilterAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FilterAction::Allow => "allow",
+            FilterAction::Drop => "drop",
+            FilterAction::Mask => "mask",
+            FilterAction::Warn => "warn",
+            FilterAction::Mute => "mute",
+        })
+    }
+}
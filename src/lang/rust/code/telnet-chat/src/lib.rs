@@ -1,8 +1,31 @@
 pub mod accept;
+pub mod actor;
+pub mod announcements;
+pub mod accounts;
+pub mod bridge;
 pub mod client;
+pub mod commands;
+pub mod config;
+pub mod fanout;
+pub mod federation;
+pub mod filters;
+mod login;
+pub mod markup;
+pub mod memos;
+pub mod moderation;
+pub mod pastes;
+pub mod plugins;
+pub mod polls;
+pub mod presence;
+pub mod proxy;
+pub mod room;
+pub mod schedule;
+pub mod screen;
+pub mod ssh;
 pub mod telnet;
+pub mod telnet_client;
+mod terminal;
 pub mod main_loop;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct ClientId(usize);
-
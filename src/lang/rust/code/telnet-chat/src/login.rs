@@ -0,0 +1,354 @@
+//! The login prompt of a client actor.
+//!
+//! Every line received from the tcp connection goes through `Login::on_line`,
+//! which decides what the line means depending on the current state: the nick
+//! the user wants, a password, or a chat message for the main loop.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::spawn_blocking;
+
+use crate::ClientId;
+use crate::accounts::{AccountStore, is_valid_nick};
+use crate::client::InternalMsg;
+use crate::main_loop::{NickError, ServerHandle, ToServer};
+use crate::terminal::Terminal;
+
+const MAX_PASSWORD_ATTEMPTS: u32 = 3;
+/// How long to wait for the main loop to answer before giving up.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longer pastes are discarded.
+const MAX_PASTE_LINES: usize = 500;
+const MAX_PASTE_BYTES: usize = 64 * 1024;
+
+enum State {
+    /// Waiting for the user to pick a nick.
+    Nick,
+    /// The nick is registered, so we wait for its password.
+    Password { nick: String, attempts: u32 },
+    /// Logged in. Lines are chat messages or commands.
+    Chat { nick: String },
+    /// `/passwd` was used, so we wait for the current password.
+    OldPassword { nick: String },
+    /// `/register` or `/passwd`: waiting for the new password.
+    NewPassword { nick: String },
+    /// Waiting for the new password to be typed a second time.
+    ConfirmPassword { nick: String, password: String },
+    /// `/paste`: collecting lines until one with only a `.`.
+    Paste { nick: String, paste: Paste },
+}
+
+struct Paste {
+    title: Option<String>,
+    lines: Vec<String>,
+    bytes: usize,
+    /// Set once the paste is over the limits. The rest of it is still read,
+    /// so it doesn't end up in the room line by line.
+    too_long: bool,
+}
+
+pub(crate) struct Login {
+    id: ClientId,
+    handle: ServerHandle,
+    accounts: Arc<AccountStore>,
+    terminal: Terminal,
+    state: State,
+    echo_off: bool,
+}
+
+impl Login {
+    pub fn new(
+        id: ClientId,
+        handle: ServerHandle,
+        terminal: Terminal,
+    ) -> Self {
+        Login {
+            id,
+            accounts: handle.accounts(),
+            handle,
+            terminal,
+            state: State::Nick,
+            echo_off: false,
+        }
+    }
+
+    pub fn terminal(&mut self) -> &mut Terminal {
+        &mut self.terminal
+    }
+
+    /// Tell the main loop if the user started or stopped typing.
+    pub async fn report_typing(&mut self) {
+        if let State::Chat { .. } = self.state {
+            if let Some(typing) = self.terminal.typing_changed() {
+                self.handle.send(ToServer::Typing(self.id, typing)).await;
+            }
+        }
+    }
+
+    /// Greet the user and ask for a nick.
+    pub fn start(&mut self) {
+        self.line("Welcome to telnet-chat.");
+        self.prompt("Nick: ");
+    }
+
+    /// Handle a line from the user. Returns false if the connection should be
+    /// closed.
+    pub async fn on_line(&mut self, line: Vec<u8>) -> Result<bool, io::Error> {
+        let text = String::from_utf8_lossy(&line).trim().to_string();
+        self.terminal.entered(&String::from_utf8_lossy(&line));
+
+        match std::mem::replace(&mut self.state, State::Nick) {
+            State::Nick => return Ok(self.choose_nick(text).await),
+            State::Password { nick, attempts } => {
+                if self.verify(&nick, text).await? {
+                    if !self.claim_nick(nick).await {
+                        return Ok(false);
+                    }
+                } else if attempts + 1 >= MAX_PASSWORD_ATTEMPTS {
+                    self.line("Too many failed attempts.");
+                    return Ok(false);
+                } else {
+                    self.line("Wrong password.");
+                    self.ask_password("Password: ");
+                    self.state = State::Password {
+                        nick,
+                        attempts: attempts + 1,
+                    };
+                }
+            },
+            State::Chat { nick } => match text.as_str() {
+                "/register" => {
+                    if self.accounts.is_registered(&nick) {
+                        self.line("Your nick is already registered. Use /passwd to change the password.");
+                        self.state = State::Chat { nick };
+                    } else {
+                        self.ask_password("New password: ");
+                        self.state = State::NewPassword { nick };
+                    }
+                },
+                "/passwd" => {
+                    if self.accounts.is_registered(&nick) {
+                        self.ask_password("Current password: ");
+                        self.state = State::OldPassword { nick };
+                    } else {
+                        self.line("Your nick is not registered. Use /register first.");
+                        self.state = State::Chat { nick };
+                    }
+                },
+                _ if text == "/paste" || text.starts_with("/paste ") => {
+                    let title = text["/paste".len()..].trim();
+                    self.line("Paste mode. End with a line with only a '.', or cancel with /cancel.");
+                    let paste = Paste {
+                        title: if title.is_empty() { None } else { Some(title.to_string()) },
+                        lines: Vec::new(),
+                        bytes: 0,
+                        too_long: false,
+                    };
+                    self.state = State::Paste { nick, paste };
+                },
+                _ => {
+                    self.handle.send(ToServer::Message(self.id, line)).await;
+                    self.state = State::Chat { nick };
+                },
+            },
+            State::Paste { nick, mut paste } => match text.as_str() {
+                "." if paste.too_long => {
+                    self.line(&format!(
+                        "The paste was discarded, as it is over {} lines or {} KiB.",
+                        MAX_PASTE_LINES,
+                        MAX_PASTE_BYTES / 1024,
+                    ));
+                    self.state = State::Chat { nick };
+                },
+                "." if paste.lines.is_empty() => {
+                    self.line("Nothing was pasted.");
+                    self.state = State::Chat { nick };
+                },
+                "." => {
+                    let msg = ToServer::Paste { id: self.id, title: paste.title, lines: paste.lines };
+                    self.handle.send(msg).await;
+                    self.state = State::Chat { nick };
+                },
+                "/cancel" => {
+                    self.line("Cancelled.");
+                    self.state = State::Chat { nick };
+                },
+                _ => {
+                    // Tabs are control characters, which are not shown, so
+                    // keep the indentation of code with spaces.
+                    let line = String::from_utf8_lossy(&line).trim_end().replace('\t', "    ");
+                    paste.bytes += line.len();
+                    paste.too_long |= paste.lines.len() >= MAX_PASTE_LINES || paste.bytes > MAX_PASTE_BYTES;
+                    if !paste.too_long {
+                        paste.lines.push(line);
+                    }
+                    self.state = State::Paste { nick, paste };
+                },
+            },
+            State::OldPassword { nick } => {
+                if self.verify(&nick, text).await? {
+                    self.ask_password("New password: ");
+                    self.state = State::NewPassword { nick };
+                } else {
+                    self.line("Wrong password.");
+                    self.state = State::Chat { nick };
+                }
+            },
+            State::NewPassword { nick } => {
+                if text.is_empty() {
+                    self.line("Cancelled.");
+                    self.state = State::Chat { nick };
+                } else {
+                    self.ask_password("Repeat password: ");
+                    self.state = State::ConfirmPassword { nick, password: text };
+                }
+            },
+            State::ConfirmPassword { nick, password } => {
+                if text != password {
+                    self.line("The passwords do not match.");
+                } else {
+                    let accounts = self.accounts.clone();
+                    let who = nick.clone();
+                    spawn_blocking(move || accounts.set_password(&who, &password))
+                        .await
+                        .map_err(io::Error::other)??;
+                    self.line("Password saved. Your nick is now reserved.");
+                }
+                self.state = State::Chat { nick };
+            },
+        }
+
+        if self.echo_off && !self.is_reading_password() {
+            self.set_echo(true);
+        }
+
+        Ok(true)
+    }
+
+    fn is_reading_password(&self) -> bool {
+        matches!(
+            self.state,
+            State::Password { .. }
+                | State::OldPassword { .. }
+                | State::NewPassword { .. }
+                | State::ConfirmPassword { .. }
+        )
+    }
+
+    /// Returns false if the connection should be closed.
+    async fn choose_nick(&mut self, nick: String) -> bool {
+        if let Some(token) = nick.strip_prefix("/resume ") {
+            return self.resume(token.trim().to_string()).await;
+        }
+        if !is_valid_nick(&nick) {
+            self.line("Nicks are 1 to 16 letters, digits, '-' or '_'.");
+            self.prompt("Nick: ");
+        } else if self.accounts.is_registered(&nick) {
+            self.ask_password("Password: ");
+            self.state = State::Password { nick, attempts: 0 };
+        } else {
+            return self.claim_nick(nick).await;
+        }
+        true
+    }
+
+    /// Ask the main loop for the nick. On success we are logged in, otherwise
+    /// we go back to the nick prompt. Returns false if the user is banned, or
+    /// if the main loop does not answer.
+    async fn claim_nick(&mut self, nick: String) -> bool {
+        let id = self.id;
+        let res = self.handle.call(SERVER_TIMEOUT, |reply| ToServer::SetNick(id, nick.clone(), reply)).await;
+
+        let res = match res {
+            Ok(res) => res,
+            Err(_) => {
+                // The main loop may still give us the nick later, so start
+                // over with a new connection.
+                self.line("The server is not responding. Try again later.");
+                return false;
+            },
+        };
+        match res {
+            Ok(()) => {
+                self.terminal.set_nick(&nick);
+                if self.accounts.is_registered(&nick) {
+                    self.line(&format!("Logged in as {}.", nick));
+                } else {
+                    self.line(&format!("Hello {}. Use /register to reserve your nick.", nick));
+                }
+                self.state = State::Chat { nick };
+            },
+            Err(NickError::InUse) | Err(NickError::UnknownToken) => {
+                self.line("That nick is already in use.");
+                self.prompt("Nick: ");
+                self.state = State::Nick;
+            },
+            Err(NickError::Banned(msg)) => {
+                self.line(&msg);
+                return false;
+            },
+        }
+        true
+    }
+
+    /// Take over the session with this resume token. Returns false if the
+    /// user is banned, or if the main loop does not answer.
+    async fn resume(&mut self, token: String) -> bool {
+        let id = self.id;
+        let res = self.handle.call(SERVER_TIMEOUT, |reply| ToServer::Resume(id, token, reply)).await;
+
+        match res {
+            Ok(Ok(nick)) => {
+                self.terminal.set_nick(&nick);
+                self.state = State::Chat { nick };
+            },
+            Ok(Err(NickError::Banned(msg))) => {
+                self.line(&msg);
+                return false;
+            },
+            Ok(Err(_)) => {
+                self.line("There is no session to resume with that token.");
+                self.prompt("Nick: ");
+            },
+            Err(_) => {
+                self.line("The server is not responding. Try again later.");
+                return false;
+            },
+        }
+        true
+    }
+
+    async fn verify(&self, nick: &str, password: String) -> Result<bool, io::Error> {
+        let accounts = self.accounts.clone();
+        let nick = nick.to_string();
+        spawn_blocking(move || accounts.verify(&nick, &password))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    fn ask_password(&mut self, prompt: &str) {
+        if !self.echo_off {
+            self.set_echo(false);
+        }
+        self.prompt(prompt);
+    }
+
+    fn set_echo(&mut self, on: bool) {
+        self.echo_off = !on;
+        self.terminal.set_echo(on);
+    }
+
+    fn line(&self, text: &str) {
+        self.send(InternalMsg::Line(text.to_string()));
+    }
+
+    fn prompt(&self, text: &str) {
+        self.send(InternalMsg::Prompt(text.to_string()));
+    }
+
+    fn send(&self, msg: InternalMsg) {
+        self.terminal.send(msg);
+    }
+}
@@ -1,13 +1,59 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use telnet_chat::accounts::AccountStore;
+use telnet_chat::announcements::Motd;
+use telnet_chat::config::Config;
+use telnet_chat::memos::MemoStore;
+use telnet_chat::moderation::Moderation;
+use telnet_chat::plugins::Plugins;
+
 #[tokio::main]
 async fn main() {
-    let (handle, join) = telnet_chat::main_loop::spawn_main_loop();
+    // The config file is optional, and is given as the first argument.
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::load(Path::new(&path))
+            .expect("Failed to read the config file."),
+        None => Config::default(),
+    };
+
+    let accounts = AccountStore::load(config.accounts_file.clone())
+        .expect("Failed to read the accounts file.");
+
+    let moderation = Moderation::load(&config)
+        .expect("Failed to read the bans file or the filters.");
+
+    let memos = MemoStore::load(&config)
+        .expect("Failed to read the memos file.");
+
+    let motd = Motd::load(&config)
+        .expect("Failed to read the message of the day.");
+
+    let plugins = Plugins::from_config(&config);
+
+    let (handle, join) = telnet_chat::main_loop::spawn_main_loop(
+        &config,
+        Arc::new(accounts),
+        moderation,
+        memos,
+        motd,
+        plugins,
+    );
+
+    telnet_chat::announcements::start_announcements(&config, handle.clone());
+    telnet_chat::federation::start_links(&config, handle.clone()).await;
+    telnet_chat::ssh::start_ssh(&config, handle.clone()).await;
 
+    let bind = config.bind;
+    let proxies = config.trusted_proxies.clone();
     tokio::spawn(async move {
-        let bind = ([0, 0, 0, 0], 3456).into();
-        telnet_chat::accept::start_accept(bind, handle).await;
+        telnet_chat::accept::start_accept(bind, proxies, handle).await;
     });
 
-    println!("Starting on port 3456");
+    println!("Starting on {}", bind);
+    if let Some(ssh_bind) = config.ssh_bind {
+        println!("SSH on {}", ssh_bind);
+    }
 
     join.await.unwrap();
 }
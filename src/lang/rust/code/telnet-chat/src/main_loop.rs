@@ -1,20 +1,46 @@
+use std::fmt;
 use std::io;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
 use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
-use tokio::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use bytes::Bytes;
 
-use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 use tokio::task::JoinHandle;
+use tokio::select;
+use tokio::time::interval;
 
 use crate::ClientId;
-use crate::client::{ClientHandle, FromServer};
+use crate::actor::{self, Addr, CallError, Mailbox};
+use crate::accounts::{AccountStore, is_valid_nick, nick_key};
+use crate::announcements::Motd;
+use crate::client::ClientHandle;
+use crate::commands::{self, Command, MotdChange};
+use crate::config::{Config, FilterAction};
+use crate::fanout::Fanout;
+use crate::federation::{Event, LinkHandle, LinkId};
+use crate::markup;
+use crate::memos::{MemoError, MemoStore};
+use crate::moderation::{Ban, BanTarget, Moderation, unix_now};
+use crate::pastes::Pastes;
+use crate::plugins::{Plugins, Reply, ReplyKind};
+use crate::polls::{self, Poll};
+use crate::presence::{Away, Presence, TYPING_TIMEOUT, format_idle, typing_notice};
+use crate::room::{Room, LOBBY, room_key};
+
+/// A lost session keeps at most this many lines for when the user comes
+/// back.
+const MAX_MISSED: usize = 500;
 
 /// This struct is used by client actors to send messages to the main loop. The
 /// message type is `ToServer`.
 #[derive(Clone, Debug)]
 pub struct ServerHandle {
-    chan: Sender<ToServer>,
+    chan: Addr<ToServer>,
     next_id: Arc<AtomicUsize>,
+    accounts: Arc<AccountStore>,
 }
 impl ServerHandle {
     pub async fn send(&mut self, msg: ToServer) {
@@ -22,29 +48,145 @@ impl ServerHandle {
             panic!("Main loop has shut down.");
         }
     }
+    /// Send a message that carries a `Reply`, and wait at most `limit` for the
+    /// main loop to answer it.
+    pub async fn call<R>(
+        &self,
+        limit: Duration,
+        make: impl FnOnce(actor::Reply<R>) -> ToServer,
+    ) -> Result<R, CallError> {
+        self.chan.call(limit, make).await
+    }
     pub fn next_id(&self) -> ClientId {
         let id = self.next_id.fetch_add(1, Ordering::Relaxed);
         ClientId(id)
     }
+    pub fn accounts(&self) -> Arc<AccountStore> {
+        self.accounts.clone()
+    }
 }
 
 /// The message type used when a client actor sends messages to the main loop.
 pub enum ToServer {
-    NewClient(ClientHandle),
+    /// A client actor was spawned. Messages with its id are ignored before
+    /// this and after it is removed.
+    ClientConnected(ClientHandle),
+    /// A client actor has logged in and wants to use this nick. Registered
+    /// nicks are only asked for once the password was checked.
+    SetNick(ClientId, String, actor::Reply<Result<(), NickError>>),
+    /// A client actor wants to take over the session with this resume token.
+    /// The answer is the nick of the session.
+    Resume(ClientId, String, actor::Reply<Result<String, NickError>>),
     Message(ClientId, Vec<u8>),
+    /// Lines collected with `/paste`, to be kept and announced in the room.
+    Paste { id: ClientId, title: Option<String>, lines: Vec<String> },
+    /// The user started or stopped typing. Only sent by character-mode
+    /// clients.
+    Typing(ClientId, bool),
+    /// The tcp connection of a client actor was closed.
+    ClientDisconnected(ClientId, DisconnectReason),
+    /// A link actor has authenticated the server with this name.
+    LinkUp(LinkHandle, String),
+    /// Something happened on the other side of a link.
+    FromLink(LinkId, Event),
+    /// The connection of a link actor was closed.
+    LinkDown(LinkId),
+    /// An announcement of the config is due. Goes to every room if `rooms`
+    /// is empty.
+    Announce { rooms: Vec<String>, text: String },
     FatalError(io::Error),
 }
 
-pub fn spawn_main_loop() -> (ServerHandle, JoinHandle<()>) {
-    let (send, recv) = channel(64);
+impl ToServer {
+    /// The client actor that sent this message, if one did.
+    fn client_id(&self) -> Option<ClientId> {
+        match self {
+            ToServer::SetNick(id, ..)
+            | ToServer::Resume(id, ..)
+            | ToServer::Message(id, _)
+            | ToServer::Paste { id, .. }
+            | ToServer::Typing(id, _)
+            | ToServer::ClientDisconnected(id, _) => Some(*id),
+            _ => None,
+        }
+    }
+}
 
-    let handle = ServerHandle {
-        chan: send,
-        next_id: Default::default(),
+/// Why a client is gone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The user closed the connection.
+    Quit,
+    /// Too many wrong passwords, or a banned nick or address.
+    LoginFailed,
+    /// Reading or writing the tcp connection failed.
+    Error(String),
+    /// The client couldn't keep up with the messages sent to it.
+    TooSlow,
+    /// The server closed the connection, such as for a kick or a ban.
+    Server(String),
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Quit => f.write_str("quit"),
+            DisconnectReason::LoginFailed => f.write_str("login failed"),
+            DisconnectReason::Error(err) => write!(f, "error: {}", err),
+            DisconnectReason::TooSlow => f.write_str("can't keep up"),
+            DisconnectReason::Server(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// Why the main loop refused a nick.
+#[derive(Debug)]
+pub enum NickError {
+    InUse,
+    /// The nick or address is banned. Contains a message for the user.
+    Banned(String),
+    /// No session has this resume token.
+    UnknownToken,
+}
+
+pub fn spawn_main_loop(
+    config: &Config,
+    accounts: Arc<AccountStore>,
+    moderation: Moderation,
+    memos: MemoStore,
+    motd: Motd,
+    mut plugins: Plugins,
+) -> (ServerHandle, JoinHandle<()>) {
+    let (removed_send, removed) = unbounded_channel();
+    let replies = plugins.take_replies().expect("The plugins are used by another main loop.");
+
+    let data = Data {
+        clients: HashMap::new(),
+        nicks: HashMap::new(),
+        rooms: HashMap::new(),
+        accounts: accounts.clone(),
+        moderation,
+        memos,
+        motd,
+        fanout: Fanout::spawn(config.shards, removed_send),
+        server_name: config.server_name.clone(),
+        links: HashMap::new(),
+        remote: HashMap::new(),
+        plugins,
+        pastes: Pastes::new(config.max_pastes),
+        next_message_id: 1,
+        auto_away: match config.auto_away_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        resume_after: match config.resume_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
     };
 
-    let join = tokio::spawn(async move {
-        let res = main_loop(recv).await;
+    let actor = actor::spawn(64, move |recv| async move {
+        let res = main_loop(recv, removed, replies, data).await;
         match res {
             Ok(()) => {},
             Err(err) => {
@@ -53,51 +195,1548 @@ pub fn spawn_main_loop() -> (ServerHandle, JoinHandle<()>) {
         }
     });
 
-    (handle, join)
+    let handle = ServerHandle {
+        chan: actor.addr().clone(),
+        next_id: Default::default(),
+        accounts,
+    };
+
+    // The main loop runs until the runtime shuts down.
+    (handle, actor.detach())
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
+struct Client {
+    ip: SocketAddr,
+    /// None until the client actor has logged in.
+    nick: Option<String>,
+    /// The key of the room this client is in. Only meaningful once logged in.
+    room: String,
+    presence: Presence,
+    /// When the client logged in, in seconds since the unix epoch. Decides
+    /// who keeps a nick that is in use on two servers.
+    since: u64,
+    /// Lets the user take this session over from another connection. Only
+    /// set once logged in, and only if sessions can be resumed.
+    token: Option<String>,
+    /// Set once the connection is lost, while the session waits to be
+    /// resumed.
+    detached: Option<Detached>,
+}
+
+/// A session whose connection was lost. It stays in its room, so the others
+/// see no leave and join if the user comes back in time.
+#[derive(Debug)]
+struct Detached {
+    at: Instant,
+    reason: DisconnectReason,
+    /// What the user would have been sent, up to `MAX_MISSED` lines.
+    missed: VecDeque<Bytes>,
+    /// Lines that didn't fit in `missed`.
+    dropped: usize,
+}
+
+impl Detached {
+    fn push(&mut self, line: Bytes) {
+        if self.missed.len() >= MAX_MISSED {
+            self.missed.pop_front();
+            self.dropped += 1;
+        }
+        self.missed.push_back(line);
+    }
+}
+
+/// A linked server, and the servers behind it.
+#[derive(Debug)]
+struct Link {
+    handle: LinkHandle,
+    name: String,
+    /// Every server reachable through this link, including `name`.
+    servers: HashSet<String>,
+}
+
+/// A user of another server.
+#[derive(Debug)]
+struct RemoteUser {
+    nick: String,
+    server: String,
+    /// The link the user is reachable through.
+    link: LinkId,
+    room: String,
+    since: u64,
+}
+
+#[derive(Debug)]
 struct Data {
-    clients: HashMap<ClientId, ClientHandle>,
+    clients: HashMap<ClientId, Client>,
+    /// Maps the `nick_key` of each nick in use to its owner.
+    nicks: HashMap<String, ClientId>,
+    rooms: HashMap<String, Room>,
+    accounts: Arc<AccountStore>,
+    moderation: Moderation,
+    memos: MemoStore,
+    motd: Motd,
+    /// Owns the `ClientHandle` of every client actor.
+    fanout: Fanout,
+    /// Mark users as away after this long without activity.
+    auto_away: Option<Duration>,
+    /// Keep the sessions of users whose connection was lost for this long.
+    resume_after: Option<Duration>,
+    server_name: String,
+    links: HashMap<LinkId, Link>,
+    /// Users of other servers, by `nick_key`.
+    remote: HashMap<String, RemoteUser>,
+    plugins: Plugins,
+    pastes: Pastes,
+    /// The id of the next chat message. Ids are never reused, even across
+    /// rooms.
+    next_message_id: u64,
 }
 
-async fn main_loop(
-    mut recv: Receiver<ToServer>,
-) -> Result<(), io::Error> {
-    let mut data = Data::default();
+impl Data {
+    /// Send a line to every member of a room except `from`. Clients that
+    /// can't keep up are dropped by the shards, which tell us through the
+    /// `removed` channel.
+    fn broadcast(&mut self, room: &str, from: Option<ClientId>, msg: impl Into<Bytes>) {
+        let msg = msg.into();
+        if let Some(state) = self.rooms.get(room) {
+            for id in &state.members {
+                if Some(*id) == from {
+                    continue;
+                }
+                if let Some(detached) = self.clients.get_mut(id).and_then(|c| c.detached.as_mut()) {
+                    detached.push(msg.clone());
+                }
+            }
+        }
+        self.fanout.broadcast(room, from, msg);
+    }
 
-    while let Some(msg) = recv.recv().await {
-        match msg {
-            ToServer::NewClient(handle) => {
-                data.clients.insert(handle.id, handle);
+    /// Send a chat message to a room, with an id before it so it can be
+    /// reacted to.
+    fn post(&mut self, room: &str, from: Option<ClientId>, nick: &str, text: &str) {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        if let Some(state) = self.rooms.get_mut(room) {
+            state.remember(id, nick);
+        }
+
+        let line = format!("[{}] <{}> {}", id, markup::nick(nick), text);
+        self.broadcast(room, from, line.into_bytes());
+    }
+
+    /// Send a line to a single client.
+    fn send_to(&mut self, id: ClientId, msg: String) {
+        if let Some(detached) = self.clients.get_mut(&id).and_then(|c| c.detached.as_mut()) {
+            return detached.push(msg.into());
+        }
+        self.fanout.send_to(id, msg.into());
+    }
+
+    fn remove(&mut self, id: ClientId, reason: DisconnectReason) {
+        // The shard drops the ClientHandle, whose destructor kills the actor.
+        self.fanout.remove(id);
+
+        // A lost connection may come back, so its session is kept for a
+        // while.
+        let keep = matches!(reason, DisconnectReason::Error(_)) && self.resume_after.is_some();
+        match self.clients.get_mut(&id) {
+            Some(Client { ip, nick: Some(nick), detached: detached @ None, .. }) if keep => {
+                println!("{} ({}) lost the connection: {}.", ip, nick, reason);
+                *detached = Some(Detached {
+                    at: Instant::now(),
+                    reason,
+                    missed: VecDeque::new(),
+                    dropped: 0,
+                });
+                let client = self.clients.get_mut(&id).expect("Matched above.");
+                if client.presence.typing.take().is_some() {
+                    let room = client.room.clone();
+                    self.show_typing(&room);
+                }
             },
-            ToServer::Message(from_id, msg) => {
-                // If we fail to send messages to any actor, we need to remove
-                // it, but we can't do so while iterating.
-                let mut to_remove = Vec::new();
+            _ => self.forget(id, reason),
+        }
+    }
+
+    /// Like `remove`, but the client is told `notice` before the connection
+    /// is closed.
+    fn disconnect(&mut self, id: ClientId, notice: String, reason: DisconnectReason) {
+        self.fanout.disconnect(id, notice);
+        self.forget(id, reason);
+    }
 
-                // Iterate through clients so we can send the message.
-                for (id, handle) in data.clients.iter_mut() {
-                    let id = *id;
+    /// Announce that a client the fanout no longer has has left, and why.
+    fn forget(&mut self, id: ClientId, reason: DisconnectReason) {
+        let client = match self.clients.remove(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        let nick = match client.nick {
+            Some(nick) => nick,
+            None => return println!("{} disconnected: {}.", client.ip, reason),
+        };
+        println!("{} ({}) disconnected: {}.", client.ip, nick, reason);
 
-                    // Don't send it to the client who sent it to us.
-                    if id == from_id { continue; }
+        // Quitting is the usual way to leave, so it goes without saying.
+        let reason = match reason {
+            DisconnectReason::Quit => String::new(),
+            reason => reason.to_string(),
+        };
+        self.nicks.remove(&nick_key(&nick));
+        self.leave_room(id, &client.room);
+        self.left(&client.room, &nick, &why(&reason));
+        self.relay(None, Event::Quit { server: self.server_name.clone(), nick, reason });
+    }
+
+    fn leave_room(&mut self, id: ClientId, room: &str) {
+        self.fanout.leave(id, room);
+        if let Some(state) = self.rooms.get_mut(room) {
+            state.members.remove(&id);
+            if state.is_empty() && room != LOBBY {
+                self.rooms.remove(room);
+            } else {
+                self.fanout.status(room, state.users());
+            }
+        }
+
+        // A client that is already removed may have been typing too.
+        let was_typing = match self.clients.get_mut(&id) {
+            Some(client) => client.presence.typing.take().is_some(),
+            None => true,
+        };
+        if was_typing {
+            self.show_typing(room);
+        }
+    }
 
-                    let msg = FromServer::Message(msg.clone());
+    fn join_room(&mut self, id: ClientId, room: String) {
+        let (nick, old) = match self.clients.get_mut(&id) {
+            Some(Client { nick: Some(nick), room: old, .. }) => {
+                let old = std::mem::replace(old, room.clone());
+                (nick.clone(), old)
+            },
+            _ => return,
+        };
+
+        if self.rooms.get(&old).map(|r| r.members.contains(&id)).unwrap_or(false) {
+            self.leave_room(id, &old);
+            self.left(&old, &nick, "");
+            let event = Event::Join { nick: nick.clone(), room: room.clone() };
+            self.relay(None, event);
+        }
+
+        // Whoever creates a room is its operator, if their nick is
+        // registered.
+        let is_new = !self.rooms.contains_key(&room);
+        let registered = self.accounts.is_registered(&nick);
+        let state = self.rooms.entry(room.clone()).or_default();
+        if is_new && room != LOBBY && registered {
+            state.ops.insert(nick_key(&nick));
+        }
+        state.members.insert(id);
+        let topic = state.topic.clone();
+        let users = state.users();
+        self.fanout.join(id, &room);
+        self.fanout.status(&room, users);
 
-                    if handle.send(msg).is_err() {
-                        // Remove this client.
-                        to_remove.push(id);
+        self.joined(&room, &nick, Some(id));
+        self.send_to(id, format!("* You are now in #{}.", room));
+        if let Some(topic) = topic {
+            self.send_to(id, format!("* Topic: {}", topic));
+        }
+        if self.clients[&id].presence.typing_notices {
+            let notice = self.typing_notice_for(&room, id);
+            self.fanout.typing(id, notice);
+        }
+    }
+
+    /// The user did something, which ends an automatic away.
+    fn touch(&mut self, id: ClientId) {
+        let back = match self.clients.get_mut(&id) {
+            Some(client) => client.presence.touch(),
+            None => return,
+        };
+        if back {
+            self.send_to(id, "* You are no longer marked as away.".to_string());
+        }
+    }
+
+    fn set_typing(&mut self, id: ClientId, typing: bool) {
+        let (was_typing, is_typing, room) = match self.clients.get_mut(&id) {
+            Some(Client { nick: Some(_), room, presence, .. }) => {
+                let was_typing = presence.is_typing();
+                presence.typing = if typing { Some(Instant::now()) } else { None };
+                (was_typing, presence.is_typing(), room.clone())
+            },
+            _ => return,
+        };
+
+        if typing {
+            self.touch(id);
+        }
+        if was_typing != is_typing {
+            self.show_typing(&room);
+        }
+    }
+
+    /// Send the typing notice of a room to its members that want it.
+    fn show_typing(&self, room: &str) {
+        let members = match self.rooms.get(room) {
+            Some(state) => &state.members,
+            None => return,
+        };
+        for &member in members {
+            let wants = self.clients.get(&member)
+                .map(|client| client.presence.typing_notices)
+                .unwrap_or(false);
+            if wants {
+                self.fanout.typing(member, self.typing_notice_for(room, member));
+            }
+        }
+    }
+
+    /// Who is typing in the room, not counting `viewer`.
+    fn typing_notice_for(&self, room: &str, viewer: ClientId) -> String {
+        let mut nicks: Vec<&str> = match self.rooms.get(room) {
+            Some(state) => state.members.iter()
+                .filter(|&&member| member != viewer)
+                .filter_map(|member| self.clients.get(member))
+                .filter(|client| client.presence.is_typing())
+                .filter_map(|client| client.nick.as_deref())
+                .collect(),
+            None => Vec::new(),
+        };
+        nicks.sort_unstable();
+        typing_notice(&nicks)
+    }
+
+    /// Called every second: marks idle users as away, ends typing notices
+    /// that were not renewed, and closes polls.
+    fn tick(&mut self) {
+        let mut now_away = Vec::new();
+        let mut stopped_typing = HashSet::new();
+
+        for (&id, client) in &mut self.clients {
+            if client.nick.is_none() {
+                continue;
+            }
+            let presence = &mut client.presence;
+            if let Some(after) = self.auto_away {
+                if presence.away.is_none() && presence.idle() >= after {
+                    presence.away = Some(Away { reason: None, auto: true });
+                    now_away.push(id);
+                }
+            }
+            if let Some(since) = presence.typing {
+                if since.elapsed() >= TYPING_TIMEOUT {
+                    presence.typing = None;
+                    stopped_typing.insert(client.room.clone());
+                }
+            }
+        }
+
+        if let Some(after) = self.resume_after {
+            let expired: Vec<(ClientId, DisconnectReason)> = self.clients.iter()
+                .filter_map(|(&id, client)| match &client.detached {
+                    Some(detached) if detached.at.elapsed() >= after => Some((id, detached.reason.clone())),
+                    _ => None,
+                })
+                .collect();
+            for (id, reason) in expired {
+                self.forget(id, reason);
+            }
+        }
+
+        let closed: Vec<(String, Poll)> = self.rooms.iter_mut()
+            .filter(|(_, state)| state.poll.as_ref().map(Poll::is_closed).unwrap_or(false))
+            .filter_map(|(room, state)| Some((room.clone(), state.poll.take()?)))
+            .collect();
+        for (room, poll) in closed {
+            self.broadcast(&room, None, poll.outcome().into_bytes());
+        }
+
+        self.moderation.filters.tick();
+
+        for id in now_away {
+            let idle = format_idle(self.auto_away.unwrap_or_default());
+            self.send_to(id, format!("* You are marked as away after {} of inactivity.", idle));
+        }
+        for room in stopped_typing {
+            self.show_typing(&room);
+        }
+    }
+
+    fn is_server_op(&self, nick: &str) -> bool {
+        self.moderation.operators.contains(&nick_key(nick))
+            && self.accounts.is_registered(nick)
+    }
+
+    fn is_room_op(&self, room: &str, nick: &str) -> bool {
+        self.is_server_op(nick)
+            || self.rooms.get(room).map(|r| r.is_op(nick)).unwrap_or(false)
+    }
+
+    fn find_nick(&self, nick: &str) -> Option<ClientId> {
+        self.nicks.get(&nick_key(nick)).copied()
+    }
+
+    fn set_nick(&mut self, id: ClientId, nick: String) -> Result<(), NickError> {
+        let key = nick_key(&nick);
+        if let Some(&old) = self.nicks.get(&key) {
+            // The password of a registered nick was checked, so its owner
+            // may take over the session.
+            if self.resume_after.is_some() && self.accounts.is_registered(&nick) {
+                return self.resume(old, id).map(|_| ());
+            }
+            return Err(NickError::InUse);
+        }
+        if self.remote.contains_key(&key) {
+            return Err(NickError::InUse);
+        }
+
+        let ip = match self.clients.get(&id) {
+            Some(client) => client.ip.ip(),
+            None => return Err(NickError::InUse),
+        };
+        if let Some(ban) = self.moderation.bans.find(&nick, ip) {
+            return Err(NickError::Banned(describe_ban(ban)));
+        }
+
+        let client = self.clients.get_mut(&id).unwrap();
+        println!("{} logged in as {}.", client.ip, nick);
+        client.nick = Some(nick.clone());
+        client.since = unix_now();
+        let since = client.since;
+        self.nicks.insert(key, id);
+        let server = self.server_name.clone();
+        self.relay(None, Event::User { server, nick, room: LOBBY.to_string(), since });
+        self.show_motd(id);
+        self.join_room(id, LOBBY.to_string());
+        self.deliver_memos(id);
+        self.give_token(id);
+        Ok(())
+    }
+
+    fn show_motd(&mut self, id: ClientId) {
+        if self.motd.lines().is_empty() {
+            return;
+        }
+        let mut block = vec![Bytes::from("* Message of the day:")];
+        block.extend(self.motd.lines().iter().map(|line| Bytes::from(format!("| {}", markup::escape(line)))));
+        self.fanout.send_block(id, block);
+    }
+
+    /// Send an announcement of the config to its rooms, or to every room.
+    fn announce(&mut self, rooms: &[String], text: &str) {
+        let rooms: Vec<String> = if rooms.is_empty() {
+            self.rooms.keys().cloned().collect()
+        } else {
+            rooms.iter().filter_map(|room| room_key(room)).collect()
+        };
+        for room in rooms {
+            self.broadcast(&room, None, format!("* Announcement: {}", text).into_bytes());
+        }
+    }
+
+    /// Give the client a token to resume its session with, and tell the user.
+    fn give_token(&mut self, id: ClientId) {
+        let after = match self.resume_after {
+            Some(after) => after,
+            None => return,
+        };
+        let token = new_token();
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.token = Some(token.clone());
+        }
+        let msg = format!(
+            "* If your connection drops, log in with /resume {} within {} to pick up where you left off.",
+            token,
+            format_idle(after),
+        );
+        self.send_to(id, msg);
+    }
+
+    /// A registered user logged in with a nick that is still in use here,
+    /// or a client presented a resume token. The session of `old` moves to
+    /// the connection of `new`, and the connection of `old` is closed if it
+    /// is still open.
+    fn resume(&mut self, old: ClientId, new: ClientId) -> Result<String, NickError> {
+        let ip = match self.clients.get(&new) {
+            Some(client) => client.ip,
+            None => return Err(NickError::UnknownToken),
+        };
+        let nick = match self.clients.get(&old).and_then(|client| client.nick.clone()) {
+            Some(nick) => nick,
+            None => return Err(NickError::UnknownToken),
+        };
+        if let Some(ban) = self.moderation.bans.find(&nick, ip.ip()) {
+            return Err(NickError::Banned(describe_ban(ban)));
+        }
+
+        let mut client = self.clients.remove(&old).expect("Checked above.");
+        self.clients.remove(&new);
+        let detached = client.detached.take();
+        if detached.is_some() {
+            println!("{} resumed the session of {}.", ip, nick);
+        } else {
+            println!("{} took over the session of {}.", ip, nick);
+            let notice = "* Your session was resumed from another connection.".to_string();
+            self.fanout.disconnect(old, notice);
+        }
+
+        let room = client.room.clone();
+        client.ip = ip;
+        self.move_session(old, new, client);
+        self.send_to(new, format!("* Welcome back. You are still in #{}.", room));
+        if let Some(detached) = detached {
+            self.send_missed(new, detached);
+        }
+        self.touch(new);
+        self.give_token(new);
+        Ok(nick)
+    }
+
+    /// Give the session of `old` the id of the connection that resumed it.
+    fn move_session(&mut self, old: ClientId, new: ClientId, mut client: Client) {
+        let room = client.room.clone();
+        let typing_notices = client.presence.typing_notices;
+        if let Some(nick) = &client.nick {
+            self.nicks.insert(nick_key(nick), new);
+        }
+        client.presence.typing = None;
+        self.clients.insert(new, client);
+
+        let users = match self.rooms.get_mut(&room) {
+            Some(state) => {
+                state.members.remove(&old);
+                state.members.insert(new);
+                state.users()
+            },
+            None => 0,
+        };
+        self.fanout.join(new, &room);
+        self.fanout.status(&room, users);
+        if typing_notices {
+            let notice = self.typing_notice_for(&room, new);
+            self.fanout.typing(new, notice);
+        }
+    }
+
+    /// Send what a user missed while their connection was lost.
+    fn send_missed(&mut self, id: ClientId, detached: Detached) {
+        if detached.missed.is_empty() {
+            return self.send_to(id, "* You missed nothing.".to_string());
+        }
+        let count = detached.missed.len() + detached.dropped;
+        let header = match (count, detached.dropped) {
+            (1, _) => "* While you were gone (1 line):".to_string(),
+            (count, 0) => format!("* While you were gone ({} lines):", count),
+            (count, dropped) => format!("* While you were gone ({} lines, the first {} were not kept):", count, dropped),
+        };
+        let mut block = vec![Bytes::from(header)];
+        block.extend(detached.missed);
+        block.push(Bytes::from("* End of missed lines."));
+        self.fanout.send_block(id, block);
+    }
+
+    /// Resume the session with this token from the connection `new`.
+    fn resume_token(&mut self, new: ClientId, token: &str) -> Result<String, NickError> {
+        let old = self.clients.iter()
+            .find(|(&id, client)| id != new && client.token.as_deref() == Some(token))
+            .map(|(&id, _)| id);
+        match old {
+            Some(old) => self.resume(old, new),
+            None => Err(NickError::UnknownToken),
+        }
+    }
+
+    /// Send the unread memos of a registered user who just logged in.
+    fn deliver_memos(&mut self, id: ClientId) {
+        let nick = match self.clients.get(&id).and_then(|client| client.nick.clone()) {
+            Some(nick) if self.accounts.is_registered(&nick) => nick,
+            _ => return,
+        };
+        let memos = match self.memos.take_unread(&nick) {
+            Ok(memos) => memos,
+            Err(err) => {
+                eprintln!("Failed to save the memos: {}.", err);
+                return;
+            },
+        };
+        if memos.is_empty() {
+            return;
+        }
+
+        let s = if memos.len() == 1 { "" } else { "s" };
+        self.send_to(id, format!("* You have {} memo{}:", memos.len(), s));
+        for memo in memos {
+            let age = Duration::from_secs(unix_now().saturating_sub(memo.sent));
+            let line = format!(
+                "* Memo from {}, {} ago: {}",
+                markup::nick(&memo.from),
+                format_idle(age),
+                memo.text,
+            );
+            self.send_to(id, line);
+        }
+    }
+
+    fn on_message(&mut self, from: ClientId, msg: Vec<u8>) {
+        let (nick, room) = match self.clients.get(&from) {
+            Some(Client { nick: Some(nick), room, .. }) => (nick.clone(), room.clone()),
+            _ => return,
+        };
+        self.touch(from);
+
+        match commands::parse(&msg) {
+            Some(Ok(cmd)) => return self.on_command(from, nick, room, cmd),
+            Some(Err(err)) => return self.send_to(from, err),
+            None => {},
+        }
+
+        let text = String::from_utf8_lossy(&msg);
+        let text = match self.check(Some(from), &nick, &room, &text) {
+            Some(text) => text,
+            None => return,
+        };
+        self.post(&room, Some(from), &nick, &text);
+        self.plugins.message(&room, &nick, &text);
+        self.relay(None, Event::Msg { nick, room, text });
+    }
+
+    /// Check text that `nick` wrote for others to see against the mutes of
+    /// `room` and the content filters, and do what they say. Returns the text
+    /// to send, if any. The user is told why if `id` is given, which it is
+    /// for users of this server.
+    fn check(&mut self, id: Option<ClientId>, nick: &str, room: &str, text: &str) -> Option<String> {
+        let lines = self.check_lines(id, nick, room, &[text.to_string()])?;
+        lines.into_iter().next()
+    }
+
+    /// `check` for a text of several lines. The same number of lines comes
+    /// back, if any.
+    fn check_lines(&mut self, id: Option<ClientId>, nick: &str, room: &str, lines: &[String]) -> Option<Vec<String>> {
+        let muted = self.rooms.get_mut(room).map(|r| r.is_muted(nick)).unwrap_or(false);
+        if muted {
+            if let Some(id) = id {
+                self.send_to(id, "* You are muted in this room.".to_string());
+            }
+            return None;
+        }
+
+        let outcome = self.moderation.filters.apply(room, nick, lines);
+
+        for (action, rule) in &outcome.broken {
+            self.audit("filter", &format!("{} message by {} in #{}: {}", action, nick, room, rule));
+            let msg = match action {
+                FilterAction::Drop => format!("* Your message was not sent: {}.", rule),
+                FilterAction::Mask => format!("* Parts of your message were hidden: {}.", rule),
+                FilterAction::Warn => format!("* Please keep to the rules of this room: {}.", rule),
+                FilterAction::Mute | FilterAction::Allow => continue,
+            };
+            if let Some(id) = id {
+                self.send_to(id, msg);
+            }
+        }
+
+        if let Some(duration) = outcome.mute {
+            // Too long for the clock is as good as until unmuted.
+            let state = self.rooms.entry(room.to_string()).or_default();
+            state.muted.insert(nick_key(nick), Instant::now().checked_add(duration));
+
+            let rule = outcome.broken.last().map(|(_, rule)| rule.as_str()).unwrap_or_default();
+            self.audit("filter", &format!("mute {} in #{} for {}s", nick, room, duration.as_secs()));
+            let msg = format!("* {} was muted for {}: {}", nick, format_idle(duration), rule);
+            self.broadcast(room, None, msg.into_bytes());
+        }
+
+        outcome.lines
+    }
+
+    fn on_paste(&mut self, from: ClientId, title: Option<String>, lines: Vec<String>) {
+        let (nick, room) = match self.clients.get(&from) {
+            Some(Client { nick: Some(nick), room, .. }) => (nick.clone(), room.clone()),
+            _ => return,
+        };
+        self.touch(from);
+
+        // The title is checked as the first line, empty if there is none.
+        let mut text = vec![title.unwrap_or_default()];
+        text.extend(lines);
+        let mut lines = match self.check_lines(Some(from), &nick, &room, &text) {
+            Some(lines) => lines,
+            None => return,
+        };
+        let title = Some(lines.remove(0)).filter(|title| !title.is_empty());
+
+        let count = lines.len();
+        let id = self.pastes.add(nick.clone(), title.clone(), lines);
+        let s = if count == 1 { "" } else { "s" };
+        let title = title.map(|title| format!(": {}", title)).unwrap_or_default();
+        let msg = format!(
+            "* {} pasted #{} ({} line{}){}. Use /show {} to see it.",
+            markup::nick(&nick), id, count, s, title, id,
+        );
+        self.broadcast(&room, None, msg.into_bytes());
+    }
+
+    fn on_command(&mut self, id: ClientId, nick: String, room: String, cmd: Command) {
+        let needs_room_op = matches!(
+            cmd,
+            Command::Kick { .. } | Command::Mute { .. } | Command::Unmute(_)
+                | Command::Topic(Some(_)) | Command::Op(_) | Command::Deop(_)
+        );
+        let needs_server_op = matches!(
+            cmd,
+            Command::Ban { .. } | Command::Unban(_) | Command::Squit(_) | Command::Motd(Some(_))
+        );
+
+        if needs_server_op && !self.is_server_op(&nick) {
+            return self.send_to(id, "* Only server operators can do that.".to_string());
+        }
+        if needs_room_op && !self.is_room_op(&room, &nick) {
+            return self.send_to(id, "* Only operators of this room can do that.".to_string());
+        }
+
+        match cmd {
+            Command::Join(name) => match room_key(&name) {
+                Some(new_room) if new_room == room => {
+                    self.send_to(id, format!("* You are already in #{}.", room));
+                },
+                Some(new_room) => self.join_room(id, new_room),
+                None => self.send_to(id, "* Invalid room name.".to_string()),
+            },
+            Command::Kick { nick: target, reason } => {
+                let target_id = match self.member(&room, &target) {
+                    Some(target_id) => target_id,
+                    None => return self.send_to(id, format!("* {} is not in this room.", target)),
+                };
+                let reason = reason.unwrap_or_else(|| "no reason".to_string());
+                self.audit(&nick, &format!("kick {} from #{}: {}", target, room, reason));
+
+                let msg = format!("* {} was kicked by {} ({})", target, nick, reason);
+                self.broadcast(&room, Some(target_id), msg.into_bytes());
+
+                let notice = format!("* You were kicked from #{} by {} ({}).", room, nick, reason);
+                if room == LOBBY {
+                    self.disconnect(target_id, notice, DisconnectReason::Server("kicked".to_string()));
+                } else {
+                    self.send_to(target_id, notice);
+                    self.join_room(target_id, LOBBY.to_string());
+                }
+            },
+            Command::Mute { nick: target, duration } => {
+                let until = match duration.map(|duration| Instant::now().checked_add(duration)) {
+                    Some(None) => return self.send_to(id, "* That mute is too long.".to_string()),
+                    until => until.flatten(),
+                };
+                let state = self.rooms.entry(room.clone()).or_default();
+                state.muted.insert(nick_key(&target), until);
+
+                let length = match duration {
+                    Some(duration) => format!("for {}s", duration.as_secs()),
+                    None => "until unmuted".to_string(),
+                };
+                self.audit(&nick, &format!("mute {} in #{} {}", target, room, length));
+                let msg = format!("* {} was muted by {} {}", target, nick, length);
+                self.broadcast(&room, None, msg.into_bytes());
+            },
+            Command::Unmute(target) => {
+                let state = self.rooms.entry(room.clone()).or_default();
+                if state.muted.remove(&nick_key(&target)).is_none() {
+                    return self.send_to(id, format!("* {} is not muted.", target));
+                }
+                self.audit(&nick, &format!("unmute {} in #{}", target, room));
+                let msg = format!("* {} was unmuted by {}", target, nick);
+                self.broadcast(&room, None, msg.into_bytes());
+            },
+            Command::Ban { nick: target, duration, reason } => {
+                let reason = reason.unwrap_or_else(|| "no reason".to_string());
+                let expires = match duration.map(|duration| unix_now().checked_add(duration.as_secs())) {
+                    Some(None) => return self.send_to(id, "* That ban is too long.".to_string()),
+                    expires => expires.flatten(),
+                };
+                let target_id = self.find_nick(&target);
+
+                let mut bans = vec![BanTarget::Nick(nick_key(&target))];
+                if let Some(client) = target_id.and_then(|t| self.clients.get(&t)) {
+                    bans.push(BanTarget::Ip(client.ip.ip()));
+                }
+                for ban in bans {
+                    let ban = Ban {
+                        target: ban,
+                        nick: nick_key(&target),
+                        expires,
+                        reason: reason.clone(),
+                    };
+                    if let Err(err) = self.moderation.bans.add(ban) {
+                        eprintln!("Failed to save the bans: {}.", err);
+                    }
+                }
+
+                let length = match duration {
+                    Some(duration) => format!("for {}s", duration.as_secs()),
+                    None => "permanently".to_string(),
+                };
+                self.audit(&nick, &format!("ban {} {}: {}", target, length, reason));
+                self.send_to(id, format!("* {} is banned {}.", target, length));
+
+                if let Some(target_id) = target_id {
+                    let notice = format!("* You were banned by {} ({}).", nick, reason);
+                    self.disconnect(target_id, notice, DisconnectReason::Server("banned".to_string()));
+                }
+            },
+            Command::Unban(target) => match self.moderation.bans.remove_nick(&target) {
+                Ok(true) => {
+                    self.audit(&nick, &format!("unban {}", target));
+                    self.send_to(id, format!("* {} is no longer banned.", target));
+                },
+                Ok(false) => self.send_to(id, format!("* {} is not banned.", target)),
+                Err(err) => {
+                    eprintln!("Failed to save the bans: {}.", err);
+                    self.send_to(id, "* Failed to save the bans.".to_string());
+                },
+            },
+            Command::Topic(None) => {
+                let topic = self.rooms.get(&room).and_then(|r| r.topic.clone());
+                match topic {
+                    Some(topic) => self.send_to(id, format!("* Topic: {}", topic)),
+                    None => self.send_to(id, "* This room has no topic.".to_string()),
+                }
+            },
+            Command::Topic(Some(topic)) => {
+                let topic = match self.check(Some(id), &nick, &room, &topic) {
+                    Some(topic) => topic,
+                    None => return,
+                };
+                self.audit(&nick, &format!("topic #{}: {}", room, topic));
+                let state = self.rooms.entry(room.clone()).or_default();
+                state.topic = Some(topic.clone());
+                let msg = format!("* {} set the topic: {}", nick, topic);
+                self.broadcast(&room, None, msg.into_bytes());
+            },
+            Command::Op(target) => {
+                // Operators are identified by nick, so only registered nicks
+                // can be trusted with it.
+                if !self.accounts.is_registered(&target) {
+                    return self.send_to(id, format!("* {} is not a registered nick.", target));
+                }
+                self.audit(&nick, &format!("op {} in #{}", target, room));
+                let state = self.rooms.entry(room.clone()).or_default();
+                state.ops.insert(nick_key(&target));
+                let msg = format!("* {} made {} an operator", nick, target);
+                self.broadcast(&room, None, msg.into_bytes());
+            },
+            Command::Deop(target) => {
+                let state = self.rooms.entry(room.clone()).or_default();
+                if !state.ops.remove(&nick_key(&target)) {
+                    return self.send_to(id, format!("* {} is not an operator here.", target));
+                }
+                self.audit(&nick, &format!("deop {} in #{}", target, room));
+                let msg = format!("* {} removed {} as operator", nick, target);
+                self.broadcast(&room, None, msg.into_bytes());
+            },
+            Command::Away(reason) => {
+                let msg = match &reason {
+                    Some(reason) => format!("* You are marked as away: {}", reason),
+                    None => "* You are marked as away.".to_string(),
+                };
+                if let Some(client) = self.clients.get_mut(&id) {
+                    client.presence.away = Some(Away { reason, auto: false });
+                }
+                self.send_to(id, msg);
+            },
+            Command::Back => {
+                let was_away = self.clients.get_mut(&id)
+                    .and_then(|client| client.presence.away.take())
+                    .is_some();
+                if was_away {
+                    self.send_to(id, "* You are no longer marked as away.".to_string());
+                } else {
+                    self.send_to(id, "* You are not marked as away.".to_string());
+                }
+            },
+            Command::Whois(target) => {
+                if let Some(user) = self.remote.get(&nick_key(&target)) {
+                    let info = format!("* {}: in #{}, on {}", user.nick, user.room, user.server);
+                    return self.send_to(id, info);
+                }
+                let client = match self.find_nick(&target).and_then(|t| self.clients.get(&t)) {
+                    Some(client) => client,
+                    None => return self.send_to(id, format!("* {} is not online.", target)),
+                };
+                let target = client.nick.clone().unwrap_or(target);
+
+                let mut info = format!("* {}: in #{}", target, client.room);
+                if self.accounts.is_registered(&target) {
+                    info.push_str(", registered");
+                }
+                info.push_str(&format!(", idle {}", format_idle(client.presence.idle())));
+                if client.detached.is_some() {
+                    info.push_str(", connection lost");
+                }
+                let away = client.presence.away.as_ref().map(|away| away.describe(&target));
+
+                self.send_to(id, info);
+                if let Some(away) = away {
+                    self.send_to(id, format!("* {}.", away));
+                }
+            },
+            Command::Msg { nick: target, text } => {
+                let text = match self.check(Some(id), &nick, &room, &text) {
+                    Some(text) => text,
+                    None => return,
+                };
+                if let Some(user) = self.remote.get(&nick_key(&target)) {
+                    let (link, to) = (user.link, user.nick.clone());
+                    self.send_to(id, format!("[you -> {}] {}", markup::nick(&to), text));
+                    return self.send_link(link, Event::Priv { from: nick, to, text });
+                }
+                let target_id = match self.find_nick(&target) {
+                    Some(target_id) => target_id,
+                    None if self.accounts.is_registered(&target) => {
+                        let msg = format!("* {} is not online. Use /memo to leave a message.", target);
+                        return self.send_to(id, msg);
+                    },
+                    None => return self.send_to(id, format!("* {} is not online.", target)),
+                };
+                let client = &self.clients[&target_id];
+                let target = client.nick.clone().unwrap_or(target);
+                let away = client.presence.away.as_ref().map(|away| away.describe(&target));
+
+                self.send_to(target_id, format!("[{} -> you] {}", markup::nick(&nick), text));
+                self.send_to(id, format!("[you -> {}] {}", markup::nick(&target), text));
+                // Let the sender know not to expect an answer soon.
+                if let Some(away) = away {
+                    self.send_to(id, format!("* {}.", away));
+                }
+            },
+            Command::Memo { nick: target, text } => {
+                let text = match self.check(Some(id), &nick, &room, &text) {
+                    Some(text) => text,
+                    None => return,
+                };
+                // Anyone could log in with an unregistered nick and read them.
+                if !self.accounts.is_registered(&target) {
+                    let msg = format!("* {} is not a registered nick, so memos can't be kept for them.", target);
+                    return self.send_to(id, msg);
+                }
+                if self.find_nick(&target).is_some() || self.remote.contains_key(&nick_key(&target)) {
+                    return self.send_to(id, format!("* {} is online. Use /msg instead.", target));
+                }
+
+                let msg = match self.memos.add(&nick, &target, text) {
+                    Ok(()) => format!("* Memo saved. {} will get it when they log in.", target),
+                    Err(MemoError::InboxFull) => format!("* {} has too many unread memos.", target),
+                    Err(MemoError::TooManySent) => {
+                        "* You have too many memos that were not read yet.".to_string()
+                    },
+                    Err(MemoError::Io(err)) => {
+                        eprintln!("Failed to save the memos: {}.", err);
+                        "* Failed to save the memo.".to_string()
+                    },
+                };
+                self.send_to(id, msg);
+            },
+            Command::Typing(on) => {
+                let was_typing = match self.clients.get_mut(&id) {
+                    Some(client) => {
+                        let was_typing = client.presence.is_typing();
+                        client.presence.typing_notices = on;
+                        was_typing != client.presence.is_typing()
+                    },
+                    None => return,
+                };
+                let notice = if on { self.typing_notice_for(&room, id) } else { String::new() };
+                self.fanout.typing(id, notice);
+                if was_typing {
+                    self.show_typing(&room);
+                }
+
+                let msg = if on {
+                    "* Typing notices are on. They show in the status bar of full-screen clients."
+                } else {
+                    "* Typing notices are off."
+                };
+                self.send_to(id, msg.to_string());
+            },
+            Command::Show(paste_id) => {
+                let paste = match self.pastes.get(paste_id) {
+                    Some(paste) => paste,
+                    None => return self.send_to(id, format!("* There is no paste #{}.", paste_id)),
+                };
+                let mut header = format!("* Paste #{} by {}", paste.id, markup::nick(&paste.nick));
+                if let Some(title) = &paste.title {
+                    header.push_str(&format!(": {}", title));
+                }
+                let mut block = vec![Bytes::from(header)];
+                block.extend(paste.lines.iter().map(|line| Bytes::from(format!("| {}", markup::escape(line)))));
+                block.push(Bytes::from(format!("* End of paste #{}.", paste.id)));
+                self.fanout.send_block(id, block);
+            },
+            Command::Poll(None) => {
+                let msg = match self.rooms.get(&room).and_then(|r| r.poll.as_ref()) {
+                    Some(poll) => poll.status(),
+                    None => format!("* There is no poll in #{}.", room),
+                };
+                self.send_to(id, msg);
+            },
+            Command::Poll(Some(new)) => {
+                if let Some(poll) = self.rooms.get(&room).and_then(|r| r.poll.as_ref()) {
+                    let left = poll.closes.saturating_duration_since(Instant::now());
+                    let msg = format!("* There is already a poll in #{}. It closes in {}.", room, format_idle(left));
+                    return self.send_to(id, msg);
+                }
+                // The question is checked as the first line.
+                let mut text = vec![new.question];
+                text.extend(new.options);
+                let mut options = match self.check_lines(Some(id), &nick, &room, &text) {
+                    Some(lines) => lines,
+                    None => return,
+                };
+                let question = options.remove(0);
+
+                let duration = new.duration.unwrap_or(polls::DEFAULT_DURATION);
+                let mut lines = vec![format!(
+                    "* {} started a poll: \"{}\", closing in {}.",
+                    markup::nick(&nick), question, format_idle(duration),
+                )];
+                lines.extend(options.iter().enumerate().map(|(i, option)| {
+                    format!("*   {}. {}", i + 1, option)
+                }));
+                lines.push("* Vote with /vote <number>.".to_string());
+
+                let poll = match Poll::new(nick, question, options, duration) {
+                    Some(poll) => poll,
+                    None => return self.send_to(id, "* That poll is too long.".to_string()),
+                };
+                match self.rooms.get_mut(&room) {
+                    Some(state) => state.poll = Some(poll),
+                    None => return,
+                }
+                for line in lines {
+                    self.broadcast(&room, None, line.into_bytes());
+                }
+            },
+            Command::Vote(choice) => {
+                let poll = match self.rooms.get_mut(&room).and_then(|r| r.poll.as_mut()) {
+                    Some(poll) => poll,
+                    None => return self.send_to(id, format!("* There is no poll in #{}.", room)),
+                };
+                let option = match poll.find_option(&choice) {
+                    Some(option) => option,
+                    None => {
+                        let msg = format!("* There is no option {}. Choose 1 to {}.", choice, poll.options.len());
+                        return self.send_to(id, msg);
+                    },
+                };
+                if !poll.vote(&nick, option) {
+                    let msg = format!("* You already voted for {}.", poll.options[option]);
+                    return self.send_to(id, msg);
+                }
+                let status = poll.status();
+                self.broadcast(&room, None, status.into_bytes());
+            },
+            Command::React { id: message_id, reaction } => {
+                let state = match self.rooms.get_mut(&room) {
+                    Some(state) => state,
+                    None => return,
+                };
+                if state.is_muted(&nick) {
+                    return self.send_to(id, "* You are muted in this room.".to_string());
+                }
+                let message = match state.message_mut(message_id) {
+                    Some(message) => message,
+                    None => {
+                        let msg = format!("* There is no recent message [{}] in #{}.", message_id, room);
+                        return self.send_to(id, msg);
+                    },
+                };
+                if !message.react(&nick, &reaction) {
+                    let msg = format!("* You already reacted with {} to [{}].", reaction, message_id);
+                    return self.send_to(id, msg);
+                }
+                let msg = format!(
+                    "* {} reacted with {} to [{}] by {}. Reactions: {}",
+                    markup::nick(&nick), reaction, message_id,
+                    markup::nick(&message.nick), message.reaction_summary(),
+                );
+                self.broadcast(&room, None, msg.into_bytes());
+            },
+            Command::Motd(None) => {
+                if self.motd.lines().is_empty() {
+                    return self.send_to(id, "* There is no message of the day.".to_string());
+                }
+                self.show_motd(id);
+            },
+            Command::Motd(Some(change)) => {
+                let (res, action) = match change {
+                    MotdChange::Set(line) => (self.motd.set(line.clone()), format!("motd set: {}", line)),
+                    MotdChange::Add(line) => (self.motd.add(line.clone()), format!("motd add: {}", line)),
+                    MotdChange::Clear => (self.motd.clear(), "motd clear".to_string()),
+                };
+                match res {
+                    Ok(()) => {
+                        self.audit(&nick, &action);
+                        self.send_to(id, "* The message of the day was changed.".to_string());
+                    },
+                    Err(err) => {
+                        eprintln!("Failed to save the message of the day: {}.", err);
+                        self.send_to(id, "* Failed to save the message of the day.".to_string());
+                    },
+                }
+            },
+            Command::Links => {
+                let mut servers: Vec<String> = self.links.values()
+                    .flat_map(|link| link.servers.iter().map(move |server| {
+                        if *server == link.name {
+                            server.clone()
+                        } else {
+                            format!("{} (via {})", server, link.name)
+                        }
+                    }))
+                    .collect();
+                servers.sort_unstable();
+                if servers.is_empty() {
+                    self.send_to(id, "* This server is not linked to others.".to_string());
+                } else {
+                    self.send_to(id, format!("* Linked servers: {}", servers.join(", ")));
+                }
+            },
+            Command::Squit(server) => {
+                let link = self.links.iter()
+                    .find(|(_, link)| link.name == server)
+                    .map(|(&link, _)| link);
+                let link = match link {
+                    Some(link) => link,
+                    None => return self.send_to(id, format!("* {} is not linked directly.", server)),
+                };
+                self.audit(&nick, &format!("squit {}", server));
+                self.split(link, format!("Closed by {}", nick));
+                self.send_to(id, format!("* Closed the link to {}.", server));
+            },
+        }
+    }
+
+    /// Send an event to every linked server, except the one it came from.
+    fn relay(&mut self, except: Option<LinkId>, event: Event) {
+        let mut failed = Vec::new();
+        for (&id, link) in &mut self.links {
+            if Some(id) != except && link.handle.send(event.clone()).is_err() {
+                failed.push(id);
+            }
+        }
+        for id in failed {
+            self.split(id, "Can't keep up".to_string());
+        }
+    }
+
+    /// Send an event to one linked server.
+    fn send_link(&mut self, id: LinkId, event: Event) {
+        let failed = match self.links.get_mut(&id) {
+            Some(link) => link.handle.send(event).is_err(),
+            None => false,
+        };
+        if failed {
+            self.split(id, "Can't keep up".to_string());
+        }
+    }
+
+    /// The link a server is reachable through.
+    fn route(&self, server: &str) -> Option<LinkId> {
+        self.links.iter()
+            .find(|(_, link)| link.servers.contains(server))
+            .map(|(&id, _)| id)
+    }
+
+    /// A link is up. Both sides send each other everything they know, so
+    /// after a netsplit the rooms are whole again.
+    fn link_up(&mut self, handle: LinkHandle, name: String) {
+        // Servers form a tree, so there is only one way to reach a server.
+        if name == self.server_name || self.route(&name).is_some() {
+            return handle.close(format!("{} is already linked", name));
+        }
+        let id = handle.id;
+
+        let mut burst = Vec::new();
+        for link in self.links.values() {
+            burst.extend(link.servers.iter().cloned().map(Event::Server));
+        }
+        for client in self.clients.values() {
+            if let Some(nick) = &client.nick {
+                burst.push(Event::User {
+                    server: self.server_name.clone(),
+                    nick: nick.clone(),
+                    room: client.room.clone(),
+                    since: client.since,
+                });
+            }
+        }
+        for user in self.remote.values() {
+            burst.push(Event::User {
+                server: user.server.clone(),
+                nick: user.nick.clone(),
+                room: user.room.clone(),
+                since: user.since,
+            });
+        }
+
+        self.relay(None, Event::Server(name.clone()));
+        let servers = std::iter::once(name.clone()).collect();
+        self.links.insert(id, Link { handle, name, servers });
+        for event in burst {
+            self.send_link(id, event);
+        }
+    }
+
+    /// Drop a link. Everything behind it is gone too: its users leave their
+    /// rooms, and the other servers are told.
+    fn split(&mut self, id: LinkId, reason: String) {
+        let link = match self.links.remove(&id) {
+            Some(link) => link,
+            None => return,
+        };
+        link.handle.close(reason);
+
+        let lost: Vec<String> = self.remote.iter()
+            .filter(|(_, user)| user.link == id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in lost {
+            self.remove_remote(&key, " (netsplit)");
+        }
+        for server in link.servers {
+            self.relay(None, Event::Squit(server));
+        }
+    }
+
+    fn on_link_event(&mut self, from: LinkId, event: Event) {
+        if !self.links.contains_key(&from) {
+            return;
+        }
+
+        match event {
+            Event::Server(name) => {
+                if name == self.server_name || self.route(&name).is_some() {
+                    return self.split(from, format!("Loop: {} is already linked", name));
+                }
+                if let Some(link) = self.links.get_mut(&from) {
+                    link.servers.insert(name.clone());
+                }
+                self.relay(Some(from), Event::Server(name));
+            },
+            Event::Squit(name) => {
+                let removed = match self.links.get_mut(&from) {
+                    Some(link) if link.name != name => link.servers.remove(&name),
+                    _ => false,
+                };
+                if !removed {
+                    return;
+                }
+                let lost: Vec<String> = self.remote.iter()
+                    .filter(|(_, user)| user.server == name)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in lost {
+                    self.remove_remote(&key, " (netsplit)");
+                }
+                self.relay(Some(from), Event::Squit(name));
+            },
+            Event::User { server, nick, room, since } => {
+                // Other servers may not check nicks the way we do, and the
+                // nick is shown here as is.
+                let room = match room_key(&room) {
+                    Some(room) if self.route(&server) == Some(from) && is_valid_nick(&nick) => room,
+                    _ => return,
+                };
+
+                // A nick in use on both sides of a link is kept by whoever
+                // logged in first. Every server decides the same way, so none
+                // have to be told.
+                let key = nick_key(&nick);
+                let theirs = (since, server.as_str());
+                if let Some(&id) = self.nicks.get(&key) {
+                    if (self.clients[&id].since, self.server_name.as_str()) <= theirs {
+                        return;
                     }
+                    let msg = format!("* Nick collision: {} logged in on {} first.", nick, server);
+                    self.disconnect(id, msg, DisconnectReason::Server("nick collision".to_string()));
+                } else if let Some(user) = self.remote.get(&key) {
+                    if (user.since, user.server.as_str()) <= theirs {
+                        return;
+                    }
+                    self.remove_remote(&key, "");
+                }
+
+                let user = RemoteUser {
+                    nick: nick.clone(),
+                    server: server.clone(),
+                    link: from,
+                    room: room.clone(),
+                    since,
+                };
+                self.remote.insert(key.clone(), user);
+                self.join_remote(&key, &room);
+                self.relay(Some(from), Event::User { server, nick, room, since });
+            },
+            Event::Join { nick, room } => {
+                let key = nick_key(&nick);
+                let room = match room_key(&room) {
+                    Some(room) => room,
+                    None => return,
+                };
+                let old = match self.remote.get_mut(&key) {
+                    Some(user) if user.link == from && user.room != room => {
+                        std::mem::replace(&mut user.room, room.clone())
+                    },
+                    _ => return,
+                };
+                self.leave_remote(&key, &old);
+                self.left(&old, &nick, "");
+                self.join_remote(&key, &room);
+                self.relay(Some(from), Event::Join { nick, room });
+            },
+            Event::Quit { server, nick, reason } => {
+                let key = nick_key(&nick);
+                match self.remote.get(&key) {
+                    Some(user) if user.link == from && user.server == server => {},
+                    _ => return,
                 }
+                self.remove_remote(&key, &why(&reason));
+                self.relay(Some(from), Event::Quit { server, nick, reason });
+            },
+            Event::Msg { nick, room, text } => {
+                let nick = match self.remote.get(&nick_key(&nick)) {
+                    Some(user) if user.link == from && user.room == room => user.nick.clone(),
+                    _ => return,
+                };
+                // Each server applies its own rules, so the message goes on
+                // as it came even if it is not shown here.
+                if let Some(text) = self.check(None, &nick, &room, &text) {
+                    self.post(&room, None, &nick, &text);
+                    self.plugins.message(&room, &nick, &text);
+                }
+                self.relay(Some(from), Event::Msg { nick, room, text });
+            },
+            Event::Priv { from: sender, to, text } => {
+                let room = match self.remote.get(&nick_key(&sender)) {
+                    Some(user) if user.link == from => user.room.clone(),
+                    _ => return,
+                };
+                if let Some(id) = self.find_nick(&to) {
+                    // Checked like a message of the sender's room, as users of
+                    // this server are.
+                    if let Some(text) = self.check(None, &sender, &room, &text) {
+                        self.send_to(id, format!("[{} -> you] {}", markup::nick(&sender), text));
+                    }
+                } else if let Some(user) = self.remote.get(&nick_key(&to)) {
+                    if user.link != from {
+                        let link = user.link;
+                        self.send_link(link, Event::Priv { from: sender, to, text });
+                    }
+                }
+            },
+        }
+    }
+
+    /// Add a user of another server to a room.
+    fn join_remote(&mut self, key: &str, room: &str) {
+        let nick = match self.remote.get(key) {
+            Some(user) => user.nick.clone(),
+            None => return,
+        };
+        let state = self.rooms.entry(room.to_string()).or_default();
+        state.remote.insert(key.to_string());
+        let users = state.users();
+        self.fanout.status(room, users);
+        self.joined(room, &nick, None);
+    }
+
+    fn leave_remote(&mut self, key: &str, room: &str) {
+        if let Some(state) = self.rooms.get_mut(room) {
+            state.remote.remove(key);
+            if state.is_empty() && room != LOBBY {
+                self.rooms.remove(room);
+            } else {
+                self.fanout.status(room, state.users());
+            }
+        }
+    }
+
+    /// Forget a user of another server, telling the room why.
+    fn remove_remote(&mut self, key: &str, why: &str) {
+        if let Some(user) = self.remote.remove(key) {
+            self.leave_remote(key, &user.room);
+            self.left(&user.room, &user.nick, why);
+        }
+    }
+
+    /// Announce a join to the room, except to the user who joined, and to
+    /// the plugins.
+    fn joined(&mut self, room: &str, nick: &str, except: Option<ClientId>) {
+        self.broadcast(room, except, format!("* {} has joined", nick).into_bytes());
+        self.plugins.join(room, nick);
+    }
+
+    /// Announce a part to the room and the plugins. `why` follows the
+    /// announcement, as in `* bob has left (netsplit)`.
+    fn left(&mut self, room: &str, nick: &str, why: &str) {
+        self.broadcast(room, None, format!("* {} has left{}", nick, why).into_bytes());
+        self.plugins.part(room, nick);
+    }
 
-                // Remove those clients.
-                for id in to_remove {
-                    // The destructor of ClientHandle will kill the actor when
-                    // we remove it from the HashMap.
-                    data.clients.remove(&id);
+    /// A plugin replied. The room may be gone by now. What bots say goes
+    /// through the mutes of the room and the filters like what users say,
+    /// but it is not relayed to linked servers, where the bot is not a user.
+    /// Plugins are not told about replies either, so bots can't keep
+    /// answering each other and a bridge never hears its own messages back.
+    fn bot_reply(&mut self, reply: Reply) {
+        let room = match room_key(&reply.room) {
+            Some(room) if self.rooms.contains_key(&room) => room,
+            _ => return,
+        };
+        match reply.kind {
+            ReplyKind::Say(text) => {
+                if let Some(text) = self.check(None, &reply.bot, &room, &text) {
+                    self.post(&room, None, &reply.bot, &text);
                 }
             },
+            ReplyKind::Join => {
+                self.broadcast(&room, None, format!("* {} has joined", reply.bot).into_bytes());
+            },
+            ReplyKind::Part => {
+                self.broadcast(&room, None, format!("* {} has left", reply.bot).into_bytes());
+            },
+        }
+    }
+
+    /// Find a logged in member of the room by nick.
+    fn member(&self, room: &str, nick: &str) -> Option<ClientId> {
+        let id = self.find_nick(nick)?;
+        let in_room = self.rooms.get(room)?.members.contains(&id);
+        if in_room { Some(id) } else { None }
+    }
+
+    fn audit(&self, actor: &str, action: &str) {
+        self.moderation.audit.record(actor, action);
+    }
+}
+
+/// The reason in a leave notice, as in `* bob has left (can't keep up)`.
+fn why(reason: &str) -> String {
+    if reason.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", reason)
+    }
+}
+
+/// A random token that is hard to guess.
+fn new_token() -> String {
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn describe_ban(ban: &Ban) -> String {
+    match ban.expires {
+        Some(expires) => {
+            let mins = expires.saturating_sub(unix_now()).div_ceil(60);
+            format!("You are banned for {} more minutes ({}).", mins, ban.reason)
+        },
+        None => format!("You are banned ({}).", ban.reason),
+    }
+}
+
+async fn main_loop(
+    mut recv: Mailbox<ToServer>,
+    mut removed: UnboundedReceiver<ClientId>,
+    mut replies: UnboundedReceiver<Reply>,
+    mut data: Data,
+) -> Result<(), io::Error> {
+    let mut ticks = interval(Duration::from_secs(1));
+
+    loop {
+        let msg = select! {
+            msg = recv.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            // A shard dropped a client actor that couldn't keep up.
+            Some(id) = removed.recv() => {
+                data.remove(id, DisconnectReason::TooSlow);
+                continue;
+            },
+            Some(reply) = replies.recv() => {
+                data.bot_reply(reply);
+                continue;
+            },
+            _ = ticks.tick() => {
+                data.tick();
+                continue;
+            },
+        };
+
+        // A client actor may still be sending after it was removed, and its
+        // id is never used again.
+        if let Some(id) = msg.client_id() {
+            if !data.clients.contains_key(&id) {
+                continue;
+            }
+        }
+
+        match msg {
+            ToServer::ClientConnected(handle) => {
+                if data.clients.contains_key(&handle.id) {
+                    // Dropping the handle kills the actor.
+                    eprintln!("Client id {:?} is already in use.", handle.id);
+                    continue;
+                }
+                let client = Client {
+                    ip: handle.ip(),
+                    nick: None,
+                    room: LOBBY.to_string(),
+                    presence: Presence::new(),
+                    since: 0,
+                    token: None,
+                    detached: None,
+                };
+                data.clients.insert(handle.id, client);
+                data.fanout.add(handle);
+            },
+            ToServer::SetNick(id, nick, reply) => {
+                let res = data.set_nick(id, nick);
+                let _ = reply.send(res);
+            },
+            ToServer::Resume(id, token, reply) => {
+                let res = data.resume_token(id, &token);
+                let _ = reply.send(res);
+            },
+            ToServer::Message(from_id, msg) => {
+                data.on_message(from_id, msg);
+            },
+            ToServer::Paste { id, title, lines } => {
+                data.on_paste(id, title, lines);
+            },
+            ToServer::Typing(id, typing) => {
+                data.set_typing(id, typing);
+            },
+            ToServer::ClientDisconnected(id, reason) => {
+                data.remove(id, reason);
+            },
+            ToServer::LinkUp(handle, name) => {
+                data.link_up(handle, name);
+            },
+            ToServer::FromLink(id, event) => {
+                data.on_link_event(id, event);
+            },
+            ToServer::LinkDown(id) => {
+                data.split(id, "Link lost".to_string());
+            },
+            ToServer::Announce { rooms, text } => {
+                data.announce(&rooms, &text);
+            },
             // This message comes only from the accept loop.
             ToServer::FatalError(err) => return Err(err),
         }
@@ -0,0 +1,176 @@
+//! Formatting markup for chat lines.
+//!
+//! Lines sent to clients may contain markup, which each client actor renders
+//! for its own terminal: as ANSI SGR sequences if the terminal type says it
+//! supports colour, or as plain text otherwise.
+//!
+//!  * `*bold*` makes a word or phrase bold. The asterisks stay in plain text.
+//!  * `{red}text{/}` colours the text. The colours are black, red, green,
+//!    yellow, blue, magenta, cyan and white, and `{/}` ends every style.
+//!  * `{@nick}` shows a nick in a colour derived from the nick, so it is the
+//!    same for everyone.
+//!  * `{{` is a literal `{`, and `{*}` a literal `*`. Other braces are left
+//!    alone.
+//!
+//! Control characters are always removed, so nobody can send their own
+//! escape sequences.
+
+const COLOURS: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+/// The colours used for nicks: red to cyan, leaving out the ones that are
+/// hard to read on a black or white background.
+const NICK_COLOURS: [u8; 6] = [31, 32, 33, 34, 35, 36];
+
+/// Markup that shows the nick in its colour.
+pub fn nick(nick: &str) -> String {
+    format!("{{@{}}}", nick)
+}
+
+/// Markup that shows the text as it is, such as pasted code.
+pub fn escape(text: &str) -> String {
+    text.replace('{', "{{").replace('*', "{*}")
+}
+
+/// Whether a terminal type, as reported by TTYPE, supports ANSI colour.
+pub fn supports_colour(terminal_type: &str) -> bool {
+    let ty = terminal_type.to_ascii_lowercase();
+    let prefixes = [
+        "xterm", "screen", "tmux", "rxvt", "linux", "ansi", "putty", "konsole", "alacritty", "kitty",
+    ];
+    ty.contains("color") || ty.contains("256") || prefixes.iter().any(|p| ty.starts_with(p))
+}
+
+/// Render a line of markup, with ANSI sequences if `colour` is set, or as
+/// plain text otherwise.
+pub fn render(line: &[u8], colour: bool) -> Vec<u8> {
+    let text = String::from_utf8_lossy(line);
+    let chars: Vec<char> = text.chars().filter(|c| !c.is_control()).collect();
+
+    let mut out = String::with_capacity(line.len());
+    let mut styled = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '{' if chars.get(i + 1) == Some(&'{') => {
+                out.push('{');
+                i += 2;
+            },
+            '{' if chars[i..].starts_with(&['{', '*', '}']) => {
+                out.push('*');
+                i += 3;
+            },
+            '{' => match parse_tag(&chars[i..]) {
+                Some((tag, len)) => {
+                    if colour {
+                        styled = tag.render(&mut out).unwrap_or(styled);
+                    } else if let Tag::Nick(nick) = tag {
+                        out.push_str(&nick);
+                    }
+                    i += len;
+                },
+                None => {
+                    out.push('{');
+                    i += 1;
+                },
+            },
+            '*' => match bold_len(&chars[i..]) {
+                Some(len) => {
+                    let inner: String = chars[i + 1..i + len - 1].iter().collect();
+                    if colour {
+                        out.push_str("\x1b[1m");
+                        out.push_str(&inner);
+                        out.push_str("\x1b[22m");
+                    } else {
+                        out.push('*');
+                        out.push_str(&inner);
+                        out.push('*');
+                    }
+                    i += len;
+                },
+                None => {
+                    out.push('*');
+                    i += 1;
+                },
+            },
+            c => {
+                out.push(c);
+                i += 1;
+            },
+        }
+    }
+
+    // Don't let a colour leak into the next line.
+    if styled {
+        out.push_str("\x1b[0m");
+    }
+    out.into_bytes()
+}
+
+enum Tag {
+    Colour(u8),
+    Nick(String),
+    Reset,
+}
+
+impl Tag {
+    /// Returns whether a style is left on, if the tag changes that.
+    fn render(&self, out: &mut String) -> Option<bool> {
+        match self {
+            Tag::Colour(code) => {
+                out.push_str(&format!("\x1b[{}m", code));
+                Some(true)
+            },
+            Tag::Nick(nick) => {
+                out.push_str(&format!("\x1b[{}m{}\x1b[39m", nick_colour(nick), nick));
+                None
+            },
+            Tag::Reset => {
+                out.push_str("\x1b[0m");
+                Some(false)
+            },
+        }
+    }
+}
+
+/// Parse a tag at the start of `chars`. Returns the tag and its length.
+fn parse_tag(chars: &[char]) -> Option<(Tag, usize)> {
+    let end = chars.iter().take(32).position(|&c| c == '}')?;
+    let name: String = chars[1..end].iter().collect();
+
+    let tag = if name == "/" {
+        Tag::Reset
+    } else if let Some(nick) = name.strip_prefix('@') {
+        if nick.is_empty() {
+            return None;
+        }
+        Tag::Nick(nick.to_string())
+    } else {
+        let idx = COLOURS.iter().position(|&colour| colour == name)?;
+        Tag::Colour(30 + idx as u8)
+    };
+    Some((tag, end + 1))
+}
+
+/// If `chars` starts with `*text*`, where the text neither starts nor ends
+/// with a space, returns its length including both asterisks.
+fn bold_len(chars: &[char]) -> Option<usize> {
+    let first = *chars.get(1)?;
+    if first == ' ' || first == '*' {
+        return None;
+    }
+    let close = chars[2..].iter().position(|&c| c == '*')? + 2;
+    if chars[close - 1] == ' ' {
+        return None;
+    }
+    Some(close + 1)
+}
+
+fn nick_colour(nick: &str) -> u8 {
+    let hash = nick.to_lowercase()
+        .bytes()
+        .fold(2166136261u32, |h, b| (h ^ b as u32).wrapping_mul(16777619));
+    NICK_COLOURS[hash as usize % NICK_COLOURS.len()]
+}
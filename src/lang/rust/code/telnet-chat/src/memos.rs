@@ -0,0 +1,152 @@
+//! Memos: messages left for registered users who are offline, delivered when
+//! they next log in.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::accounts::nick_key;
+use crate::config::Config;
+use crate::moderation::unix_now;
+
+#[derive(Debug, Clone)]
+pub struct Memo {
+    /// The `nick_key` of the recipient.
+    pub to: String,
+    pub from: String,
+    /// Seconds since the unix epoch.
+    pub sent: u64,
+    pub read: bool,
+    pub text: String,
+}
+
+/// Why a memo was not stored.
+#[derive(Debug)]
+pub enum MemoError {
+    /// The recipient has too many unread memos.
+    InboxFull,
+    /// The sender has too many memos that were not read yet.
+    TooManySent,
+    Io(io::Error),
+}
+
+/// The memos, stored in a local file so they survive restarts.
+///
+/// Each line is `<sent> <read> <to> <from> <text>` where `read` is 0 or 1.
+/// Read memos are kept until they expire like the others.
+#[derive(Debug)]
+pub struct MemoStore {
+    path: PathBuf,
+    memos: Vec<Memo>,
+    /// The most unread memos a user can have waiting, and also the most a
+    /// user can have sent that were not read yet.
+    quota: usize,
+    expiry: Duration,
+}
+
+impl MemoStore {
+    /// Load the memos. A missing file means there are none.
+    pub fn load(config: &Config) -> Result<MemoStore, io::Error> {
+        let mut memos = Vec::new();
+
+        match fs::read_to_string(&config.memos_file) {
+            Ok(text) => {
+                for line in text.lines() {
+                    if let Some(memo) = parse_memo(line) {
+                        memos.push(memo);
+                    }
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+
+        let mut store = MemoStore {
+            path: config.memos_file.clone(),
+            memos,
+            quota: config.memo_quota,
+            expiry: Duration::from_secs(config.memo_expiry_days * 24 * 60 * 60),
+        };
+        store.prune();
+        Ok(store)
+    }
+
+    /// Store a memo from `from` to `to`, unless a quota is used up.
+    pub fn add(&mut self, from: &str, to: &str, text: String) -> Result<(), MemoError> {
+        self.prune();
+        let to = nick_key(to);
+        let from_key = nick_key(from);
+
+        let unread = self.memos.iter().filter(|memo| !memo.read);
+        if unread.clone().filter(|memo| memo.to == to).count() >= self.quota {
+            return Err(MemoError::InboxFull);
+        }
+        if unread.filter(|memo| nick_key(&memo.from) == from_key).count() >= self.quota {
+            return Err(MemoError::TooManySent);
+        }
+
+        self.memos.push(Memo {
+            to,
+            from: from.to_string(),
+            sent: unix_now(),
+            read: false,
+            text,
+        });
+        self.save().map_err(MemoError::Io)
+    }
+
+    /// The unread memos of this user, oldest first. They are marked read.
+    pub fn take_unread(&mut self, nick: &str) -> Result<Vec<Memo>, io::Error> {
+        self.prune();
+        let nick = nick_key(nick);
+
+        let mut unread = Vec::new();
+        for memo in &mut self.memos {
+            if memo.to == nick && !memo.read {
+                memo.read = true;
+                unread.push(memo.clone());
+            }
+        }
+
+        if !unread.is_empty() {
+            self.save()?;
+        }
+        Ok(unread)
+    }
+
+    fn prune(&mut self) {
+        let oldest = unix_now().saturating_sub(self.expiry.as_secs());
+        self.memos.retain(|memo| memo.sent > oldest);
+    }
+
+    fn save(&mut self) -> Result<(), io::Error> {
+        let mut text = String::new();
+        for memo in &self.memos {
+            text.push_str(&format!(
+                "{} {} {} {} {}\n",
+                memo.sent,
+                memo.read as u8,
+                memo.to,
+                memo.from,
+                memo.text,
+            ));
+        }
+        fs::write(&self.path, text)
+    }
+}
+
+fn parse_memo(line: &str) -> Option<Memo> {
+    let mut parts = line.splitn(5, ' ');
+    let sent = parts.next()?.parse().ok()?;
+    let read = match parts.next()? {
+        "0" => false,
+        "1" => true,
+        _ => return None,
+    };
+    let to = nick_key(parts.next()?);
+    let from = parts.next()?.to_string();
+    let text = parts.next().unwrap_or("").to_string();
+
+    Some(Memo { to, from, sent, read, text })
+}
@@ -0,0 +1,239 @@
+//! State used by the moderation commands and the content filters: the ban
+//! list, the filters and the audit log.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::accounts::nick_key;
+use crate::config::Config;
+use crate::filters::Filters;
+
+/// Everything the main loop needs to enforce moderation.
+#[derive(Debug)]
+pub struct Moderation {
+    /// The `nick_key` of every server operator. Only counts for registered
+    /// nicks, as anyone can log in with an unregistered one.
+    pub operators: HashSet<String>,
+    pub bans: BanList,
+    pub filters: Filters,
+    pub audit: AuditLog,
+}
+
+impl Moderation {
+    pub fn load(config: &Config) -> Result<Moderation, io::Error> {
+        Ok(Moderation {
+            operators: config.operators.iter().map(|nick| nick_key(nick)).collect(),
+            bans: BanList::load(config.bans_file.clone())?,
+            filters: Filters::from_config(config)?,
+            audit: AuditLog::new(config.audit_log.clone()),
+        })
+    }
+}
+
+/// What a ban applies to. A `/ban` adds one of each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanTarget {
+    Nick(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// The `nick_key` of the banned user. Address bans keep it so that
+    /// `/unban` can find them.
+    pub nick: String,
+    /// Seconds since the unix epoch, or None for a permanent ban.
+    pub expires: Option<u64>,
+    pub reason: String,
+}
+
+/// The server-wide bans, stored in a local file so they survive restarts.
+///
+/// Each line is `<kind> <target> <expires> <nick> <reason>` where `kind` is
+/// `nick` or `ip`, and `expires` is a unix timestamp, or 0 for a permanent ban.
+#[derive(Debug)]
+pub struct BanList {
+    path: PathBuf,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Load the bans from the given file. A missing file is an empty list.
+    pub fn load(path: PathBuf) -> Result<BanList, io::Error> {
+        let mut bans = Vec::new();
+
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for line in text.lines() {
+                    if let Some(ban) = parse_ban(line) {
+                        bans.push(ban);
+                    }
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+
+        let mut list = BanList { path, bans };
+        list.prune();
+        Ok(list)
+    }
+
+    /// Returns the active ban matching this nick or address, if any.
+    pub fn find(&mut self, nick: &str, ip: IpAddr) -> Option<&Ban> {
+        self.prune();
+        let nick = BanTarget::Nick(nick_key(nick));
+        let ip = BanTarget::Ip(ip);
+        self.bans.iter().find(|ban| ban.target == nick || ban.target == ip)
+    }
+
+    pub fn add(&mut self, ban: Ban) -> Result<(), io::Error> {
+        self.bans.retain(|old| old.target != ban.target);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Remove all bans on this nick, and on the addresses banned with it.
+    /// Returns false if the nick was not banned.
+    pub fn remove_nick(&mut self, nick: &str) -> Result<bool, io::Error> {
+        let nick = nick_key(nick);
+        let before = self.bans.len();
+        self.bans.retain(|ban| ban.nick != nick);
+        if self.bans.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn prune(&mut self) {
+        let now = unix_now();
+        self.bans.retain(|ban| ban.expires.map(|at| at > now).unwrap_or(true));
+    }
+
+    fn save(&mut self) -> Result<(), io::Error> {
+        self.prune();
+
+        let mut text = String::new();
+        for ban in &self.bans {
+            let (kind, target) = match &ban.target {
+                BanTarget::Nick(nick) => ("nick", nick.clone()),
+                BanTarget::Ip(ip) => ("ip", ip.to_string()),
+            };
+            text.push_str(&format!(
+                "{} {} {} {} {}\n",
+                kind,
+                target,
+                ban.expires.unwrap_or(0),
+                ban.nick,
+                ban.reason,
+            ));
+        }
+        fs::write(&self.path, text)
+    }
+}
+
+fn parse_ban(line: &str) -> Option<Ban> {
+    let mut parts = line.splitn(5, ' ');
+    let kind = parts.next()?;
+    let target = parts.next()?;
+    let expires: u64 = parts.next()?.parse().ok()?;
+    let nick = nick_key(parts.next()?);
+    let reason = parts.next().unwrap_or("").to_string();
+
+    let target = match kind {
+        "nick" => BanTarget::Nick(nick_key(target)),
+        "ip" => BanTarget::Ip(target.parse().ok()?),
+        _ => return None,
+    };
+
+    Some(Ban {
+        target,
+        nick,
+        expires: if expires == 0 { None } else { Some(expires) },
+        reason,
+    })
+}
+
+/// Appends one line per moderation action to a local file.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog { path }
+    }
+
+    /// Record that `actor` did `action`. Failing to write the log is reported
+    /// but does not stop the action.
+    pub fn record(&self, actor: &str, action: &str) {
+        let line = format!("{} {} {}\n", unix_now(), actor, action);
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+
+        if let Err(err) = res {
+            eprintln!("Failed to write the audit log: {}.", err);
+        }
+    }
+}
+
+/// The longest duration `parse_duration` accepts. Anything longer should be
+/// `perm`.
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Why a duration was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurationError {
+    /// Not a number followed by a unit.
+    Invalid,
+    /// Longer than `MAX_DURATION`.
+    TooLong,
+}
+
+/// Parse durations such as `30s`, `10m`, `2h` or `7d`, up to `MAX_DURATION`.
+/// The word `perm` gives `Ok(None)`, meaning forever.
+pub fn parse_duration(text: &str) -> Result<Option<Duration>, DurationError> {
+    if text == "perm" {
+        return Ok(None);
+    }
+
+    let split = text.len().checked_sub(1).ok_or(DurationError::Invalid)?;
+    if !text.is_char_boundary(split) {
+        return Err(DurationError::Invalid);
+    }
+    let (num, unit) = text.split_at(split);
+    if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(DurationError::Invalid);
+    }
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(DurationError::Invalid),
+    };
+    // Only digits are left, so a number that doesn't parse is too big.
+    let secs = num.parse::<u64>().ok()
+        .and_then(|num| num.checked_mul(unit))
+        .filter(|&secs| secs <= MAX_DURATION.as_secs())
+        .ok_or(DurationError::TooLong)?;
+
+    Ok(Some(Duration::from_secs(secs)))
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,48 @@
+//! Blocks of lines pasted with `/paste`, kept so they can be shown whole
+//! with `/show <id>`.
+
+use std::collections::VecDeque;
+
+#[derive(Debug)]
+pub struct Paste {
+    pub id: u64,
+    pub nick: String,
+    pub title: Option<String>,
+    pub lines: Vec<String>,
+}
+
+/// The most recent pastes, in memory. The oldest are forgotten once there
+/// are too many.
+#[derive(Debug)]
+pub struct Pastes {
+    pastes: VecDeque<Paste>,
+    next_id: u64,
+    max: usize,
+}
+
+impl Pastes {
+    pub fn new(max: usize) -> Self {
+        Pastes {
+            pastes: VecDeque::new(),
+            next_id: 1,
+            max,
+        }
+    }
+
+    /// Keep a paste. Returns its id.
+    pub fn add(&mut self, nick: String, title: Option<String>, lines: Vec<String>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.pastes.push_back(Paste { id, nick, title, lines });
+        while self.pastes.len() > self.max {
+            self.pastes.pop_front();
+        }
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Paste> {
+        // The ids are in order, and mostly without gaps.
+        self.pastes.iter().rev().find(|paste| paste.id == id)
+    }
+}
@@ -0,0 +1,371 @@
+//! Bots and outbound integrations. A plugin is told about every message,
+//! join and part in the rooms of this server, and may reply into a room.
+//!
+//! The main loop calls plugins directly, so they must not block. Slow work
+//! belongs in a task, which can reply later through its `Replies`. Bridges
+//! (see `crate::bridge`) are plugins too.
+//!
+//! ```ignore
+//! struct Echo;
+//! impl Plugin for Echo {
+//!     fn name(&self) -> &str { "echo" }
+//!     fn on_message(&mut self, replies: &Replies, room: &str, _nick: &str, text: &str) {
+//!         if let Some(rest) = text.strip_prefix("!echo ") {
+//!             replies.say(room, rest);
+//!         }
+//!     }
+//! }
+//!
+//! let mut plugins = Plugins::from_config(&config);
+//! plugins.add(Echo);
+//! ```
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+
+use crate::actor::{self, Mailbox, Owner, SendError};
+use crate::bridge::Bridge;
+use crate::config::{BotConfig, Config};
+use crate::moderation::unix_now;
+use crate::room::room_key;
+
+/// How many configured commands may run at once. Messages that arrive while
+/// all are busy are not passed to the command.
+const MAX_RUNNING: usize = 4;
+/// A command that runs longer than this is killed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// At most this many lines of the output of a command are said in the room.
+const MAX_REPLY_LINES: usize = 5;
+/// Lines waiting to be appended to a file. More are skipped.
+const APPEND_QUEUE: usize = 1024;
+
+pub trait Plugin: Send {
+    /// The nick the replies of this plugin are shown with.
+    fn name(&self) -> &str;
+
+    /// Called once, when the plugin is added. Plugins that get replies from
+    /// outside, and not only in answer to a message, start their tasks here.
+    fn start(&mut self, _replies: &Replies) {}
+
+    /// Someone said `text` in `room`.
+    fn on_message(&mut self, _replies: &Replies, _room: &str, _nick: &str, _text: &str) {}
+
+    fn on_join(&mut self, _replies: &Replies, _room: &str, _nick: &str) {}
+
+    fn on_part(&mut self, _replies: &Replies, _room: &str, _nick: &str) {}
+}
+
+/// Something a plugin wants shown in a room.
+#[derive(Debug)]
+pub struct Reply {
+    /// The nick it is shown with.
+    pub bot: Arc<str>,
+    pub room: String,
+    pub kind: ReplyKind,
+}
+
+#[derive(Debug)]
+pub enum ReplyKind {
+    Say(String),
+    /// Announce that `bot` joined the room, although it is not a member.
+    Join,
+    Part,
+}
+
+/// Lets a plugin reply into rooms, right away or later from a task. Replies
+/// are shown on this server only, and other plugins are not told about them.
+/// The mutes of the room and the content filters apply to what is said, with
+/// the nick it is said as.
+#[derive(Clone, Debug)]
+pub struct Replies {
+    bot: Arc<str>,
+    chan: UnboundedSender<Reply>,
+}
+
+impl Replies {
+    pub fn say(&self, room: &str, text: impl Into<String>) {
+        self.send(self.bot.clone(), room, ReplyKind::Say(text.into()));
+    }
+
+    /// Say something as another nick, such as a user on the other side of a
+    /// bridge. The nick is not checked, so it must not look like a user of
+    /// this server.
+    pub fn say_as(&self, nick: &str, room: &str, text: impl Into<String>) {
+        self.send(nick.into(), room, ReplyKind::Say(text.into()));
+    }
+
+    pub fn join_as(&self, nick: &str, room: &str) {
+        self.send(nick.into(), room, ReplyKind::Join);
+    }
+
+    pub fn part_as(&self, nick: &str, room: &str) {
+        self.send(nick.into(), room, ReplyKind::Part);
+    }
+
+    fn send(&self, bot: Arc<str>, room: &str, kind: ReplyKind) {
+        let reply = Reply { bot, room: room.to_string(), kind };
+        // Fails only once the main loop has shut down.
+        let _ = self.chan.send(reply);
+    }
+}
+
+/// The plugins of a server, given to `spawn_main_loop`.
+pub struct Plugins {
+    plugins: Vec<(Box<dyn Plugin>, Replies)>,
+    chan: UnboundedSender<Reply>,
+    recv: Option<UnboundedReceiver<Reply>>,
+}
+
+impl Plugins {
+    pub fn new() -> Self {
+        let (chan, recv) = unbounded_channel();
+        Plugins {
+            plugins: Vec::new(),
+            chan,
+            recv: Some(recv),
+        }
+    }
+
+    /// The built-in plugins set up in the config.
+    pub fn from_config(config: &Config) -> Self {
+        let mut plugins = Plugins::new();
+        for bot in &config.bots {
+            match bot.clone() {
+                BotConfig::AppendToFile { name, file, rooms, contains } => {
+                    let filter = Filter::new(&rooms, contains);
+                    plugins.add(AppendToFile { name, file, filter, writer: None });
+                },
+                BotConfig::RunCommand { name, command, rooms, contains } => {
+                    let filter = Filter::new(&rooms, contains);
+                    plugins.add(RunCommand::new(name, command, filter));
+                },
+            }
+        }
+        let retry = Duration::from_secs(config.link_retry_secs);
+        for bridge in &config.bridges {
+            match Bridge::new(bridge, retry) {
+                Some(bridge) => plugins.add(bridge),
+                None => eprintln!("Bridge {} has an invalid room or address.", bridge.name),
+            }
+        }
+        plugins
+    }
+
+    pub fn add(&mut self, mut plugin: impl Plugin + 'static) {
+        let replies = Replies {
+            bot: plugin.name().into(),
+            chan: self.chan.clone(),
+        };
+        plugin.start(&replies);
+        self.plugins.push((Box::new(plugin), replies));
+    }
+
+    /// The replies of all plugins. Can only be taken once, by the main loop.
+    pub(crate) fn take_replies(&mut self) -> Option<UnboundedReceiver<Reply>> {
+        self.recv.take()
+    }
+
+    pub(crate) fn message(&mut self, room: &str, nick: &str, text: &str) {
+        for (plugin, replies) in &mut self.plugins {
+            plugin.on_message(replies, room, nick, text);
+        }
+    }
+
+    pub(crate) fn join(&mut self, room: &str, nick: &str) {
+        for (plugin, replies) in &mut self.plugins {
+            plugin.on_join(replies, room, nick);
+        }
+    }
+
+    pub(crate) fn part(&mut self, room: &str, nick: &str) {
+        for (plugin, replies) in &mut self.plugins {
+            plugin.on_part(replies, room, nick);
+        }
+    }
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.plugins.iter().map(|(plugin, _)| plugin.name())).finish()
+    }
+}
+
+impl Default for Plugins {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+struct Filter {
+    rooms: Vec<String>,
+    contains: Option<String>,
+}
+
+impl Filter {
+    fn new(rooms: &[String], contains: Option<String>) -> Self {
+        Filter {
+            rooms: rooms.iter().filter_map(|room| room_key(room)).collect(),
+            contains: contains.map(|text| text.to_lowercase()),
+        }
+    }
+
+    fn matches(&self, room: &str, text: &str) -> bool {
+        (self.rooms.is_empty() || self.rooms.iter().any(|r| r == room))
+            && self.contains.as_ref().map(|c| text.to_lowercase().contains(c)).unwrap_or(true)
+    }
+}
+
+/// Appends matching messages to a file, one per line:
+/// `<unix time> #<room> <nick> <text>`.
+#[derive(Debug)]
+struct AppendToFile {
+    name: String,
+    file: PathBuf,
+    filter: Filter,
+    /// Writes the lines, so a slow disk doesn't hold up the main loop.
+    writer: Option<Owner<String>>,
+}
+
+impl Plugin for AppendToFile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, _replies: &Replies) {
+        let (name, file) = (self.name.clone(), self.file.clone());
+        self.writer = Some(actor::spawn(APPEND_QUEUE, move |mailbox| append_lines(name, file, mailbox)));
+    }
+
+    fn on_message(&mut self, _replies: &Replies, room: &str, nick: &str, text: &str) {
+        let writer = match &self.writer {
+            Some(writer) if self.filter.matches(room, text) => writer,
+            _ => return,
+        };
+        let line = format!("{} #{} {} {}\n", unix_now(), room, nick, text);
+        match writer.try_send(line) {
+            Ok(()) | Err(SendError::Stopped) => {},
+            Err(SendError::Full) => {
+                eprintln!("{}: can't keep up with {}, skipping a line.", self.name, self.file.display());
+            },
+        }
+    }
+}
+
+/// Write lines to the end of the file. It is kept open, and opened again
+/// after an error.
+async fn append_lines(name: String, path: PathBuf, mut lines: Mailbox<String>) {
+    let mut file = None;
+    while let Some(line) = lines.recv().await {
+        if file.is_none() {
+            match OpenOptions::new().create(true).append(true).open(&path).await {
+                Ok(opened) => file = Some(opened),
+                Err(err) => {
+                    eprintln!("{}: failed to open {}: {}.", name, path.display(), err);
+                    continue;
+                },
+            }
+        }
+        if let Some(open) = &mut file {
+            // Flushed, or an error would only show up on the next line.
+            let res = match open.write_all(line.as_bytes()).await {
+                Ok(()) => open.flush().await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                eprintln!("{}: failed to write {}: {}.", name, path.display(), err);
+                file = None;
+            }
+        }
+    }
+}
+
+/// Runs a command for each matching message, with the message on stdin and
+/// `CHAT_ROOM` and `CHAT_NICK` in the environment. The first lines it
+/// prints are said in the room.
+#[derive(Debug)]
+struct RunCommand {
+    name: String,
+    command: Vec<String>,
+    filter: Filter,
+    running: Arc<Semaphore>,
+}
+
+impl RunCommand {
+    fn new(name: String, command: Vec<String>, filter: Filter) -> Self {
+        RunCommand {
+            name,
+            command,
+            filter,
+            running: Arc::new(Semaphore::new(MAX_RUNNING)),
+        }
+    }
+}
+
+impl Plugin for RunCommand {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_message(&mut self, replies: &Replies, room: &str, nick: &str, text: &str) {
+        if !self.filter.matches(room, text) || self.command.is_empty() {
+            return;
+        }
+        let permit = match self.running.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                eprintln!("{}: too many commands running, skipping a message.", self.name);
+                return;
+            },
+        };
+
+        let mut command = Command::new(&self.command[0]);
+        command.args(&self.command[1..])
+            .env("CHAT_ROOM", room)
+            .env("CHAT_NICK", nick)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        let (name, replies, room, text) = (self.name.clone(), replies.clone(), room.to_string(), text.to_string());
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let res = timeout(COMMAND_TIMEOUT, async {
+                let mut child = command.spawn()?;
+                // Commands that don't read the message may exit before it is
+                // written, which is fine.
+                if let Some(mut stdin) = child.stdin.take() {
+                    let _ = stdin.write_all(format!("{}\n", text).as_bytes()).await;
+                }
+                if let Some(stdout) = child.stdout.take() {
+                    let mut lines = BufReader::new(stdout).lines();
+                    let mut said = 0;
+                    while let Some(line) = lines.next_line().await? {
+                        if said < MAX_REPLY_LINES && !line.trim().is_empty() {
+                            replies.say(&room, line);
+                            said += 1;
+                        }
+                    }
+                }
+                child.wait().await
+            }).await;
+
+            match res {
+                Ok(Ok(status)) if status.success() => {},
+                Ok(Ok(status)) => eprintln!("{}: command failed: {}.", name, status),
+                Ok(Err(err)) => eprintln!("{}: failed to run the command: {}.", name, err),
+                Err(_) => eprintln!("{}: the command timed out.", name),
+            }
+        });
+    }
+}
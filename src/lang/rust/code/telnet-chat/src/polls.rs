@@ -0,0 +1,107 @@
+//! Polls started with `/poll`, one at a time in each room, and voted on with
+//! `/vote`. Like the rest of a room's state, a poll is kept by the main loop
+//! of one server, and is not shared over links.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::accounts::nick_key;
+use crate::presence::format_idle;
+
+/// How long a poll stays open if `/poll` is not given a duration.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Polls can't be open for longer than this.
+pub const MAX_DURATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+pub const MAX_OPTIONS: usize = 10;
+
+#[derive(Debug)]
+pub struct Poll {
+    /// Who started the poll.
+    pub nick: String,
+    pub question: String,
+    pub options: Vec<String>,
+    pub closes: Instant,
+    /// The option each voter chose, by `nick_key`.
+    votes: HashMap<String, usize>,
+}
+
+impl Poll {
+    /// Returns `None` if the poll would close too far in the future for the
+    /// clock.
+    pub fn new(nick: String, question: String, options: Vec<String>, duration: Duration) -> Option<Poll> {
+        Some(Poll {
+            nick,
+            question,
+            options,
+            closes: Instant::now().checked_add(duration)?,
+            votes: HashMap::new(),
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closes <= Instant::now()
+    }
+
+    /// Find the option a `/vote` is for: its number, or its text ignoring
+    /// case.
+    pub fn find_option(&self, choice: &str) -> Option<usize> {
+        if let Ok(number) = choice.parse::<usize>() {
+            return number.checked_sub(1).filter(|&i| i < self.options.len());
+        }
+        self.options.iter().position(|option| option.eq_ignore_ascii_case(choice))
+    }
+
+    /// Record a vote, replacing any earlier vote by the same nick. Returns
+    /// false if the nick already voted for this option.
+    pub fn vote(&mut self, nick: &str, option: usize) -> bool {
+        self.votes.insert(nick_key(nick), option) != Some(option)
+    }
+
+    /// The options with their votes, such as `1. pizza (2), 2. sushi (1)`.
+    pub fn results(&self) -> String {
+        let tally = self.tally();
+        self.options.iter()
+            .zip(&tally)
+            .enumerate()
+            .map(|(i, (option, votes))| format!("{}. {} ({})", i + 1, option, votes))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// The line shown while the poll is open.
+    pub fn status(&self) -> String {
+        let left = self.closes.saturating_duration_since(Instant::now());
+        format!(
+            "* Poll \"{}\": {}. Closes in {}.",
+            self.question, self.results(), format_idle(left),
+        )
+    }
+
+    /// The line shown when the poll closes.
+    pub fn outcome(&self) -> String {
+        let tally = self.tally();
+        let most = tally.iter().copied().max().unwrap_or(0);
+        let winners: Vec<&str> = self.options.iter()
+            .zip(&tally)
+            .filter(|(_, &votes)| votes == most)
+            .map(|(option, _)| option.as_str())
+            .collect();
+
+        let verdict = match winners.as_slice() {
+            _ if most == 0 => "Nobody voted.".to_string(),
+            [winner] => format!("{} wins.", winner),
+            _ => format!("It's a tie between {}.", winners.join(" and ")),
+        };
+        format!("* The poll \"{}\" has closed: {}. {}", self.question, self.results(), verdict)
+    }
+
+    fn tally(&self) -> Vec<usize> {
+        let mut tally = vec![0; self.options.len()];
+        for &option in self.votes.values() {
+            tally[option] += 1;
+        }
+        tally
+    }
+}
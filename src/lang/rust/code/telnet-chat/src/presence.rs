@@ -0,0 +1,102 @@
+//! Presence of logged in users: when they were last active, whether they are
+//! away, and whether they are typing.
+
+use std::time::{Duration, Instant};
+
+/// A typing notice that isn't renewed ends after this long. Clients renew it
+/// at half this interval while the user keeps typing.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The presence of one client, owned by the main loop.
+#[derive(Debug)]
+pub struct Presence {
+    pub last_active: Instant,
+    pub away: Option<Away>,
+    /// When the client last said the user is typing.
+    pub typing: Option<Instant>,
+    /// `/typing on`: show the typing of others, and tell them about ours.
+    pub typing_notices: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Away {
+    pub reason: Option<String>,
+    /// Set after a period of inactivity rather than with `/away`, so it ends
+    /// as soon as the user does something.
+    pub auto: bool,
+}
+
+impl Away {
+    /// Such as `bob is away: lunch`.
+    pub fn describe(&self, nick: &str) -> String {
+        match &self.reason {
+            _ if self.auto => format!("{} is away (idle)", nick),
+            Some(reason) => format!("{} is away: {}", nick, reason),
+            None => format!("{} is away", nick),
+        }
+    }
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        Presence {
+            last_active: Instant::now(),
+            away: None,
+            typing: None,
+            typing_notices: false,
+        }
+    }
+
+    /// The user did something. Returns true if this ended an automatic away.
+    pub fn touch(&mut self) -> bool {
+        self.last_active = Instant::now();
+        if self.away.as_ref().map(|away| away.auto).unwrap_or(false) {
+            self.away = None;
+            return true;
+        }
+        false
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    /// Whether the user was typing recently, and opted in to tell others.
+    pub fn is_typing(&self) -> bool {
+        self.typing_notices
+            && self.typing.map(|since| since.elapsed() < TYPING_TIMEOUT).unwrap_or(false)
+    }
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Format a duration the way people say it: `45s`, `3m 12s`, `2h 5m` or
+/// `3d 4h`.
+pub fn format_idle(idle: Duration) -> String {
+    let secs = idle.as_secs();
+    let (mins, hours, days) = (secs / 60, secs / 3600, secs / 86400);
+    if days > 0 {
+        format!("{}d {}h", days, hours % 24)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins % 60)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// The typing notice for the status bar, such as `bob is typing`.
+pub fn typing_notice(nicks: &[&str]) -> String {
+    match nicks {
+        [] => String::new(),
+        [nick] => format!("{} is typing", nick),
+        [a, b] => format!("{} and {} are typing", a, b),
+        [a, b, _] => format!("{}, {} and 1 other are typing", a, b),
+        [a, b, rest @ ..] => format!("{}, {} and {} others are typing", a, b, rest.len()),
+    }
+}
@@ -0,0 +1,178 @@
+//! The PROXY protocol, version 1 and 2, as sent by HAProxy and other load
+//! balancers in front of the server. The header comes before anything else
+//! on the connection and carries the address of the real client.
+//!
+//! Anyone can send a header, so it is only read from connections whose peer
+//! is in `Config::trusted_proxies`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest version 1 header, with the line ending.
+const V1_MAX_LEN: usize = 107;
+/// A trusted proxy that doesn't send the header in time is cut off.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A range of addresses, such as `10.0.0.0/8` or `::1/128`. A single
+/// address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid address range {:?}.", text);
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&p| p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Cidr { addr: addr.to_canonical(), prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> String {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Read a PROXY header, and nothing after it. Returns the address of the
+/// client, or `None` if the proxy says the connection is its own, such as
+/// for a health check.
+pub async fn read_header<R: AsyncRead + Unpin>(read: &mut R) -> Result<Option<SocketAddr>, io::Error> {
+    // Both versions are at least this long.
+    let mut start = [0; 12];
+    read.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut rest = [0; 4];
+        read.read_exact(&mut rest).await?;
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        let mut addrs = vec![0; len];
+        read.read_exact(&mut addrs).await?;
+        parse_v2(rest[0], rest[1], &addrs)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY header too long"));
+            }
+            line.push(read.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else {
+        Err(invalid("Expected a PROXY header"))
+    }
+}
+
+/// Like `read_header`, but gives up after `HEADER_TIMEOUT`.
+pub async fn read_header_timeout<R: AsyncRead + Unpin>(read: &mut R) -> Result<Option<SocketAddr>, io::Error> {
+    match timeout(HEADER_TIMEOUT, read_header(read)).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "No PROXY header")),
+    }
+}
+
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>`,
+/// without the line ending.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY header is not text"))?;
+    let words: Vec<&str> = line.split(' ').collect();
+
+    match words.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("Bad address in PROXY header"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("Bad port in PROXY header"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("Wrong address family in PROXY header"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("Bad PROXY header")),
+    }
+}
+
+/// The version and command, the family and protocol, and the addresses.
+fn parse_v2(version: u8, family: u8, addrs: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+    if version >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY version"));
+    }
+    match version & 0xf {
+        // LOCAL: the proxy's own connection.
+        0 => return Ok(None),
+        1 => {},
+        _ => return Err(invalid("Unsupported PROXY command")),
+    }
+
+    // Any TLVs after the addresses are ignored.
+    match family >> 4 {
+        1 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        },
+        2 if addrs.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        },
+        // Unix sockets and unspecified families have no address to use.
+        0 | 3 => Ok(None),
+        _ => Err(invalid("Bad addresses in PROXY header")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+
+use crate::ClientId;
+use crate::accounts::nick_key;
+use crate::polls::Poll;
+
+/// Every client joins this room after logging in.
+pub const LOBBY: &str = "lobby";
+
+/// How many of the latest messages in a room can be reacted to.
+pub const RECENT_MESSAGES: usize = 100;
+
+/// The state of a chat room, owned by the main loop. It is kept in memory
+/// only, and forgotten when the last user leaves, except for the lobby. That
+/// includes its operators and topic: whoever creates the room again is its
+/// new operator.
+#[derive(Debug, Default)]
+pub struct Room {
+    pub members: HashSet<ClientId>,
+    /// The `nick_key` of every member on other servers.
+    pub remote: HashSet<String>,
+    /// The `nick_key` of every room operator.
+    pub ops: HashSet<String>,
+    /// Muted nicks, with the time the mute ends, or None until `/unmute`.
+    pub muted: HashMap<String, Option<Instant>>,
+    pub topic: Option<String>,
+    /// The latest messages, oldest first.
+    pub recent: VecDeque<Message>,
+    /// The poll of this room, until it closes.
+    pub poll: Option<Poll>,
+}
+
+/// A chat message, with the id shown before it.
+#[derive(Debug)]
+pub struct Message {
+    pub id: u64,
+    pub nick: String,
+    /// Each reaction, in the order they were first used, with the
+    /// `nick_key` of everyone who reacted with it.
+    pub reactions: Vec<(String, Vec<String>)>,
+}
+
+impl Room {
+    /// How many users are in the room, on this server and others.
+    pub fn users(&self) -> usize {
+        self.members.len() + self.remote.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty() && self.remote.is_empty()
+    }
+
+    pub fn is_op(&self, nick: &str) -> bool {
+        self.ops.contains(&nick_key(nick))
+    }
+
+    pub fn is_muted(&mut self, nick: &str) -> bool {
+        let key = nick_key(nick);
+        match self.muted.get(&key) {
+            Some(Some(until)) if *until <= Instant::now() => {
+                self.muted.remove(&key);
+                false
+            },
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Keep a message so it can be reacted to, forgetting the oldest if there
+    /// are too many.
+    pub fn remember(&mut self, id: u64, nick: &str) {
+        self.recent.push_back(Message {
+            id,
+            nick: nick.to_string(),
+            reactions: Vec::new(),
+        });
+        while self.recent.len() > RECENT_MESSAGES {
+            self.recent.pop_front();
+        }
+    }
+
+    pub fn message_mut(&mut self, id: u64) -> Option<&mut Message> {
+        self.recent.iter_mut().rev().find(|message| message.id == id)
+    }
+}
+
+impl Message {
+    /// Returns false if the nick already reacted this way.
+    pub fn react(&mut self, nick: &str, reaction: &str) -> bool {
+        let key = nick_key(nick);
+        match self.reactions.iter_mut().find(|(r, _)| r == reaction) {
+            Some((_, nicks)) if nicks.contains(&key) => false,
+            Some((_, nicks)) => {
+                nicks.push(key);
+                true
+            },
+            None => {
+                self.reactions.push((reaction.to_string(), vec![key]));
+                true
+            },
+        }
+    }
+
+    /// Such as `+1 (2), :) (1)`.
+    pub fn reaction_summary(&self) -> String {
+        self.reactions.iter()
+            .map(|(reaction, nicks)| format!("{} ({})", reaction, nicks.len()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Room names are case insensitive, and may be written with a leading `#`.
+pub fn room_key(name: &str) -> Option<String> {
+    let name = name.strip_prefix('#').unwrap_or(name);
+    let valid = !name.is_empty()
+        && name.len() <= 32
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Some(name.to_lowercase())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,150 @@
+//! Cron-like schedules, as used by announcements: five fields for the
+//! minute, hour, day of the month, month and day of the week, such as
+//! `0 9 * * 1-5` for 9:00 on weekdays. Times are in UTC.
+//!
+//! Each field is `*`, a number, a range such as `1-5`, or a list of those
+//! such as `0,30`, and each of those can take a step such as `*/15`. Days of
+//! the week go from 0 for Sunday to 6, and 7 is Sunday too. As with cron, if
+//! both the day of the month and the day of the week are set, either one
+//! matching is enough. `@hourly`, `@daily` and `@weekly` are short for the
+//! obvious schedules.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    /// As written, to show it and to save it again.
+    source: String,
+    /// Bit `n` is set if the field matches `n`.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month and the day of the week were `*`.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// Returns an error message meant for whoever wrote the schedule.
+    pub fn parse(text: &str) -> Result<Schedule, String> {
+        let fields = match text.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            fields => fields,
+        };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("The schedule {:?} does not have 5 fields.", text));
+        }
+
+        let field = |i: usize, min: u32, max: u32| {
+            parse_field(fields[i], min, max)
+                .ok_or_else(|| format!("Invalid field {:?} in the schedule {:?}.", fields[i], text))
+        };
+        let mut weekdays = field(4, 0, 7)?;
+        // Sunday is 0 and 7.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Schedule {
+            source: text.to_string(),
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// Whether the schedule matches the minute that starts `minute` minutes
+    /// after the unix epoch.
+    pub fn matches(&self, minute: u64) -> bool {
+        let days = minute / (24 * 60);
+        let (month, day) = month_and_day(days);
+        // 1970-01-01 was a Thursday.
+        let weekday = (days + 4) % 7;
+
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => has(self.days, day) || has(self.weekdays, weekday),
+            _ => has(self.days, day) && has(self.weekdays, weekday),
+        };
+        has(self.minutes, minute % 60)
+            && has(self.hours, minute / 60 % 24)
+            && has(self.months, month)
+            && day_matches
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Schedule, String> {
+        Schedule::parse(&text)
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> String {
+        schedule.source
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn has(bits: u64, n: u64) -> bool {
+    bits & (1 << n) != 0
+}
+
+/// The bits of a field, or None if it is not valid.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|&step| step > 0)?),
+            None => (part, 1),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (from.parse().ok()?, to.parse().ok()?),
+            // With a step, `5/15` is short for `5-59/15`.
+            None if step > 1 => (range.parse().ok()?, max),
+            None => {
+                let n = range.parse().ok()?;
+                (n, n)
+            },
+        };
+        if from < min || to > max || from > to {
+            return None;
+        }
+        for n in (from..=to).step_by(step) {
+            bits |= 1 << n;
+        }
+    }
+    Some(bits)
+}
+
+/// The month and day of a day counted from the unix epoch. See
+/// <http://howardhinnant.github.io/date_algorithms.html>.
+fn month_and_day(days: u64) -> (u64, u64) {
+    let day_of_era = (days + 719_468) % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (month, day)
+}
@@ -0,0 +1,295 @@
+//! Full-screen layout for terminals that can show it.
+//!
+//! The screen has three parts: the scrollback pane with the chat, a status bar
+//! with the room, the nick and the number of users, and the input line at the
+//! bottom. The pane is a scrolling region, so a new line costs one scroll
+//! instead of a redraw. Everything is redrawn when the window is resized.
+//!
+//! The `Screen` only produces the bytes to write. It remembers the recent
+//! lines even while inactive, so the pane isn't empty when it starts.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+/// How many lines the scrollback pane remembers.
+const SCROLLBACK: usize = 500;
+
+/// Smallest window we can draw the layout in.
+pub const MIN_COLS: u16 = 20;
+pub const MIN_ROWS: u16 = 5;
+
+/// Whether a terminal type, as reported by TTYPE, understands the cursor
+/// addressing and scrolling regions of a VT100.
+pub fn supports_screen(terminal_type: &str) -> bool {
+    let ty = terminal_type.to_ascii_lowercase();
+    crate::markup::supports_colour(&ty) || ty.starts_with("vt1") || ty.starts_with("vt2")
+}
+
+#[derive(Default)]
+pub struct Screen {
+    /// Columns and rows, if the layout is shown.
+    size: Option<(usize, usize)>,
+    /// Rendered lines, oldest first.
+    lines: VecDeque<Vec<u8>>,
+    /// How many lines the user scrolled back.
+    scroll: usize,
+    /// The unfinished line from the server, such as `Nick: `.
+    prompt: String,
+    input: Vec<char>,
+    cursor: usize,
+    hidden: bool,
+    room: String,
+    nick: String,
+    users: usize,
+    /// Who else is typing, such as `bob is typing`.
+    typing: String,
+}
+
+impl Screen {
+    pub fn is_active(&self) -> bool {
+        self.size.is_some()
+    }
+
+    /// Show the layout, or draw it again at a new size.
+    pub fn start(&mut self, cols: u16, rows: u16) -> Vec<u8> {
+        let cols = cols.max(MIN_COLS) as usize;
+        let rows = rows.max(MIN_ROWS) as usize;
+        self.size = Some((cols, rows));
+        self.redraw()
+    }
+
+    /// Go back to a plain terminal.
+    pub fn stop(&mut self) -> Vec<u8> {
+        if self.size.take().is_none() {
+            return Vec::new();
+        }
+        // Reset the scrolling region and start on a clean screen.
+        b"\x1b[r\x1b[2J\x1b[H".to_vec()
+    }
+
+    /// Add a rendered line to the scrollback pane.
+    pub fn line(&mut self, line: Vec<u8>) -> Vec<u8> {
+        if self.lines.len() == SCROLLBACK {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+
+        let (_, rows) = match self.size {
+            Some(size) => size,
+            None => return Vec::new(),
+        };
+
+        // Keep the view still while the user reads older lines.
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.lines.len() - 1);
+            let mut out = String::new();
+            self.draw_status(&mut out);
+            self.draw_input(&mut out);
+            return out.into_bytes();
+        }
+
+        // A line feed on the bottom row of the pane scrolls only the pane.
+        let mut out = format!("\x1b[{};1H\r\n", pane_rows(rows)).into_bytes();
+        out.extend_from_slice(self.lines.back().unwrap());
+        let mut rest = String::new();
+        self.draw_input(&mut rest);
+        out.extend_from_slice(rest.as_bytes());
+        out
+    }
+
+    pub fn prompt(&mut self, prompt: &str) -> Vec<u8> {
+        self.prompt.push_str(prompt);
+        self.input_changed()
+    }
+
+    pub fn input(&mut self, input: &str, cursor: usize, hidden: bool) -> Vec<u8> {
+        self.input = input.chars().collect();
+        self.cursor = cursor.min(self.input.len());
+        self.hidden = hidden;
+        self.input_changed()
+    }
+
+    /// The user pressed enter. The prompt and what they typed move to the
+    /// pane, as they would on a terminal that echoes by itself.
+    pub fn submit(&mut self, line: &str, hidden: bool) -> Vec<u8> {
+        let mut done = std::mem::take(&mut self.prompt);
+        if !hidden {
+            done.push_str(line);
+        }
+        self.input.clear();
+        self.cursor = 0;
+        self.scroll = 0;
+        self.line(done.into_bytes())
+    }
+
+    pub fn status(&mut self, room: &str, users: usize) -> Vec<u8> {
+        self.room = room.to_string();
+        self.users = users;
+        self.status_changed()
+    }
+
+    pub fn nick(&mut self, nick: &str) -> Vec<u8> {
+        self.nick = nick.to_string();
+        self.status_changed()
+    }
+
+    pub fn typing(&mut self, notice: &str) -> Vec<u8> {
+        self.typing = notice.to_string();
+        self.status_changed()
+    }
+
+    /// Page up or down through the scrollback.
+    pub fn scroll(&mut self, up: bool) -> Vec<u8> {
+        let (_, rows) = match self.size {
+            Some(size) => size,
+            None => return Vec::new(),
+        };
+        let page = (pane_rows(rows) / 2).max(1);
+        let max = self.lines.len().saturating_sub(1);
+
+        self.scroll = if up {
+            (self.scroll + page).min(max)
+        } else {
+            self.scroll.saturating_sub(page)
+        };
+        self.redraw()
+    }
+
+    fn input_changed(&self) -> Vec<u8> {
+        let mut out = String::new();
+        if self.is_active() {
+            self.draw_input(&mut out);
+        }
+        out.into_bytes()
+    }
+
+    fn status_changed(&self) -> Vec<u8> {
+        let mut out = String::new();
+        if self.is_active() {
+            self.draw_status(&mut out);
+            self.draw_input(&mut out);
+        }
+        out.into_bytes()
+    }
+
+    fn redraw(&self) -> Vec<u8> {
+        let (cols, rows) = match self.size {
+            Some(size) => size,
+            None => return Vec::new(),
+        };
+        let pane = pane_rows(rows);
+
+        // Find the lines that fit in the pane, counting wrapped lines as
+        // several rows.
+        let end = self.lines.len() - self.scroll.min(self.lines.len());
+        let mut used = 0;
+        let mut start = end;
+        while start > 0 {
+            let height = height(&self.lines[start - 1], cols);
+            if used + height > pane {
+                break;
+            }
+            used += height;
+            start -= 1;
+        }
+
+        let mut out = format!("\x1b[r\x1b[2J\x1b[1;{}r", pane).into_bytes();
+        let mut row = pane - used + 1;
+        for line in self.lines.range(start..end) {
+            out.extend_from_slice(format!("\x1b[{};1H", row).as_bytes());
+            out.extend_from_slice(line);
+            row += height(line, cols);
+        }
+
+        let mut rest = String::new();
+        self.draw_status(&mut rest);
+        self.draw_input(&mut rest);
+        out.extend_from_slice(rest.as_bytes());
+        out
+    }
+
+    fn draw_status(&self, out: &mut String) {
+        let (cols, rows) = match self.size {
+            Some(size) => size,
+            None => return,
+        };
+
+        let mut text = String::new();
+        if !self.room.is_empty() {
+            let _ = write!(text, " #{}", self.room);
+        }
+        if !self.nick.is_empty() {
+            let _ = write!(text, "  {}", self.nick);
+        }
+        if self.users > 0 {
+            let s = if self.users == 1 { "" } else { "s" };
+            let _ = write!(text, "  {} user{}", self.users, s);
+        }
+        if !self.typing.is_empty() {
+            let _ = write!(text, "  {}", self.typing);
+        }
+        if self.scroll > 0 {
+            text.push_str("  -- more --");
+        }
+
+        let text: String = text.chars().take(cols).collect();
+        let pad = cols - text.chars().count();
+        let _ = write!(out, "\x1b[{};1H\x1b[7m{}{}\x1b[0m", rows - 1, text, " ".repeat(pad));
+    }
+
+    /// Draw the input line and leave the cursor in it.
+    fn draw_input(&self, out: &mut String) {
+        let (cols, rows) = match self.size {
+            Some(size) => size,
+            None => return,
+        };
+
+        let mut line: Vec<char> = self.prompt.chars().filter(|c| !c.is_control()).collect();
+        let mut cursor = line.len();
+        if !self.hidden {
+            line.extend_from_slice(&self.input);
+            cursor += self.cursor;
+        }
+
+        // Scroll sideways to keep the cursor on the screen.
+        let start = (cursor + 1).saturating_sub(cols);
+        let shown: String = line.iter().skip(start).take(cols).collect();
+        let _ = write!(
+            out,
+            "\x1b[{row};1H\x1b[K{}\x1b[{row};{col}H",
+            shown,
+            row = rows,
+            col = cursor - start + 1,
+        );
+    }
+}
+
+/// The rows of the scrollback pane: all but the status bar and input line.
+fn pane_rows(rows: usize) -> usize {
+    rows - 2
+}
+
+/// How many rows a line takes once wrapped.
+fn height(line: &[u8], cols: usize) -> usize {
+    visible_width(line).max(1).div_ceil(cols)
+}
+
+/// The number of characters a line shows, not counting escape sequences.
+fn visible_width(line: &[u8]) -> usize {
+    let text = String::from_utf8_lossy(line);
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip to the final byte of the CSI sequence.
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
@@ -0,0 +1,384 @@
+//! The SSH front-end. Users log in with a key from the authorized keys file,
+//! and then chat just like over telnet.
+//!
+//! Every shell gets a client actor of its own, which talks telnet over an
+//! in-memory pipe. Our end of the pipe is a `TelnetClient` that plays the part
+//! of the user's terminal: it reports the terminal type and the window size
+//! from the PTY request, and reports every window change with NAWS. So the
+//! client actor can't tell an SSH user from a telnet user, and the full-screen
+//! layout works the same for both.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use russh::keys::ssh_key::{AuthorizedKeys, LineEnding};
+use russh::keys::{Algorithm, PrivateKey, PublicKey};
+use russh::server::{Auth, Handler, Msg, Session};
+use russh::{Channel, ChannelId, ChannelMsg, MethodKind, MethodSet};
+use tokio::io::{duplex, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+use argon2::password_hash::rand_core::OsRng;
+
+use crate::client::{spawn_client, ClientInfo};
+use crate::config::Config;
+use crate::main_loop::{ServerHandle, ToServer};
+use crate::telnet::Item;
+use crate::telnet_client::TelnetClient;
+
+/// How much output the pipe to the client actor holds before the actor has
+/// to wait for the SSH connection.
+const PIPE_SIZE: usize = 16 * 1024;
+
+/// Listen for SSH connections on `ssh_bind`, if it is set.
+pub async fn start_ssh(config: &Config, mut handle: ServerHandle) {
+    let bind = match config.ssh_bind {
+        Some(bind) => bind,
+        None => return,
+    };
+
+    let res = match TcpListener::bind(bind).await {
+        Ok(listen) => start_ssh_on(listen, config, handle.clone()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = res {
+        handle.send(ToServer::FatalError(err)).await;
+    }
+}
+
+/// Like `start_ssh`, but with a listener that is already bound. Fails if the
+/// host key can't be read or made.
+pub fn start_ssh_on(
+    listen: TcpListener,
+    config: &Config,
+    handle: ServerHandle,
+) -> Result<(), io::Error> {
+    let host_key = load_host_key(&config.ssh_host_key)?;
+
+    let ssh_config = russh::server::Config {
+        methods: MethodSet::from(&[MethodKind::PublicKey][..]),
+        keys: vec![host_key],
+        // Clients start by asking which methods they may use. Only wrong
+        // keys need to take a while to be rejected.
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        inactivity_timeout: None,
+        nodelay: true,
+        ..russh::server::Config::default()
+    };
+
+    let authorized_keys = config.ssh_authorized_keys.clone();
+    tokio::spawn(ssh_accept_loop(listen, Arc::new(ssh_config), authorized_keys, handle));
+    Ok(())
+}
+
+/// Read the host key, or make one and save it if there is none yet.
+fn load_host_key(path: &Path) -> Result<PrivateKey, io::Error> {
+    if path.exists() {
+        return russh::keys::load_secret_key(path, None)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+
+    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).map_err(io::Error::other)?;
+    key.write_openssh_file(path, LineEnding::LF).map_err(io::Error::other)?;
+    println!("Made a new SSH host key in {}.", path.display());
+    Ok(key)
+}
+
+async fn ssh_accept_loop(
+    listen: TcpListener,
+    config: Arc<russh::server::Config>,
+    authorized_keys: PathBuf,
+    handle: ServerHandle,
+) {
+    loop {
+        let (tcp, ip) = match listen.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                eprintln!("Failed to accept an SSH connection: {}.", err);
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            },
+        };
+        let _ = tcp.set_nodelay(true);
+
+        let connection = SshConnection {
+            ip,
+            authorized_keys: authorized_keys.clone(),
+            handle: handle.clone(),
+        };
+        let config = config.clone();
+        tokio::spawn(async move {
+            let res = match russh::server::run_stream(config, tcp, connection).await {
+                Ok(session) => session.await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                eprintln!("SSH connection from {} failed: {}.", ip, err);
+            }
+        });
+    }
+}
+
+/// Answers the requests of one SSH connection.
+struct SshConnection {
+    ip: SocketAddr,
+    authorized_keys: PathBuf,
+    handle: ServerHandle,
+}
+
+impl SshConnection {
+    fn check_key(&self, user: &str, key: &PublicKey) -> Auth {
+        // The file is read every time, so keys can be added and removed
+        // without a restart.
+        let text = match std::fs::read_to_string(&self.authorized_keys) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("Failed to read {}: {}.", self.authorized_keys.display(), err);
+                return Auth::reject();
+            },
+        };
+
+        let known = AuthorizedKeys::new(&text)
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.public_key().key_data() == key.key_data());
+
+        if known {
+            Auth::Accept
+        } else {
+            eprintln!("{} ({}) offered an SSH key that is not authorized.", self.ip, user);
+            Auth::reject()
+        }
+    }
+}
+
+impl Handler for SshConnection {
+    type Error = russh::Error;
+
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        key: &PublicKey,
+    ) -> Result<Auth, russh::Error> {
+        Ok(self.check_key(user, key))
+    }
+
+    async fn auth_publickey(&mut self, user: &str, key: &PublicKey) -> Result<Auth, russh::Error> {
+        Ok(self.check_key(user, key))
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, russh::Error> {
+        let ip = self.ip;
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            if let Err(err) = run_shell(channel, ip, handle).await {
+                eprintln!("SSH shell of {} failed: {}.", ip, err);
+            }
+        });
+        Ok(true)
+    }
+
+    // These requests are also passed to the channel, and handled there.
+
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        _term: &str,
+        _cols: u32,
+        _rows: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(russh::Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), russh::Error> {
+        session.channel_success(channel)
+    }
+
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        _cols: u32,
+        _rows: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        session: &mut Session,
+    ) -> Result<(), russh::Error> {
+        session.channel_success(channel)
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), russh::Error> {
+        session.channel_success(channel)
+    }
+}
+
+/// Wait for the shell request, then connect the channel to a new client
+/// actor until either side closes.
+async fn run_shell(
+    mut channel: Channel<Msg>,
+    ip: SocketAddr,
+    handle: ServerHandle,
+) -> Result<(), io::Error> {
+    let mut terminal_type = None;
+    let mut window = None;
+
+    loop {
+        match channel.wait().await {
+            Some(ChannelMsg::RequestPty { term, col_width, row_height, .. }) => {
+                terminal_type = Some(term);
+                window = Some(window_size(col_width, row_height));
+            },
+            Some(ChannelMsg::WindowChange { col_width, row_height, .. }) => {
+                window = Some(window_size(col_width, row_height));
+            },
+            Some(ChannelMsg::RequestShell { .. }) => break,
+            Some(_) => {},
+            None => return Ok(()),
+        }
+    }
+
+    let (ours, theirs) = duplex(PIPE_SIZE);
+    spawn_client(ClientInfo {
+        ip,
+        id: handle.next_id(),
+        handle,
+        conn: Box::new(theirs),
+    });
+
+    let mut telnet = TelnetClient::new(ours);
+    telnet.set_terminal_type(terminal_type);
+    if let Some((cols, rows)) = window {
+        telnet.set_window_size(cols, rows).await?;
+    }
+    telnet.start().await?;
+
+    let mut input = LineInput::default();
+    loop {
+        tokio::select! {
+            item = telnet.next() => match item {
+                Some(Ok(Item::Data(data))) => {
+                    channel.data(&data[..]).await.map_err(io::Error::other)?;
+                },
+                Some(Ok(_)) => {},
+                Some(Err(err)) => return Err(err),
+                None => break,
+            },
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Data { data }) => {
+                    input.keys(&mut telnet, &channel, &data).await?;
+                },
+                Some(ChannelMsg::WindowChange { col_width, row_height, .. }) => {
+                    let (cols, rows) = window_size(col_width, row_height);
+                    telnet.set_window_size(cols, rows).await?;
+                },
+                Some(ChannelMsg::Eof) | Some(ChannelMsg::Close) | None => break,
+                Some(_) => {},
+            },
+        }
+    }
+
+    // Dropping our end of the pipe tells the client actor that we are gone.
+    drop(telnet);
+    let _ = channel.eof().await;
+    let _ = channel.close().await;
+    Ok(())
+}
+
+fn window_size(cols: u32, rows: u32) -> (u16, u16) {
+    let clamp = |n: u32| n.min(u16::MAX.into()) as u16;
+    (clamp(cols), clamp(rows))
+}
+
+/// An SSH terminal sends keys as they are typed, and shows only what the
+/// server sends back. In line mode, a telnet terminal edits the line itself,
+/// so that is done here.
+#[derive(Default)]
+struct LineInput {
+    line: Vec<u8>,
+    /// The last key was a CR, so an LF right after it is part of the same
+    /// enter.
+    after_cr: bool,
+}
+
+impl LineInput {
+    async fn keys(
+        &mut self,
+        telnet: &mut TelnetClient<DuplexStream>,
+        channel: &Channel<Msg>,
+        keys: &[u8],
+    ) -> Result<(), io::Error> {
+        // The server echoes and edits the line itself. Telnet sends enter as
+        // CR LF.
+        if telnet.char_mode() {
+            let mut data = Vec::with_capacity(keys.len());
+            for &key in keys {
+                data.push(key);
+                if key == b'\r' {
+                    data.push(b'\n');
+                }
+            }
+            return telnet.send(Item::Data(data)).await;
+        }
+
+        let mut echo = Vec::new();
+        for &key in keys {
+            let after_cr = std::mem::replace(&mut self.after_cr, key == b'\r');
+            match key {
+                b'\n' if after_cr => {},
+                b'\r' | b'\n' => {
+                    echo.extend_from_slice(b"\r\n");
+                    let line = std::mem::take(&mut self.line);
+                    telnet.send_line(&line).await?;
+                },
+                // Backspace removes a whole character, not a byte.
+                0x7f | 0x08 => {
+                    if self.pop_char() {
+                        echo.extend_from_slice(b"\x08 \x08");
+                    }
+                },
+                // Ctrl-U
+                0x15 => {
+                    while self.pop_char() {
+                        echo.extend_from_slice(b"\x08 \x08");
+                    }
+                },
+                // Ctrl-C
+                0x03 => telnet.send(Item::InterruptProcess).await?,
+                key if key < 0x20 => {},
+                key => {
+                    self.line.push(key);
+                    echo.push(key);
+                },
+            }
+        }
+
+        if !echo.is_empty() && !telnet.server_echoes() {
+            channel.data(&echo[..]).await.map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn pop_char(&mut self) -> bool {
+        let len = match std::str::from_utf8(&self.line) {
+            Ok(line) => line.chars().next_back().map(char::len_utf8),
+            Err(_) => self.line.last().map(|_| 1),
+        };
+        match len {
+            Some(len) => {
+                self.line.truncate(self.line.len() - len);
+                true
+            },
+            None => false,
+        }
+    }
+}
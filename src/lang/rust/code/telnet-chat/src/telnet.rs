@@ -1,31 +1,72 @@
 use std::io;
-use tokio_util::codec::Decoder;
+use tokio_util::codec::{Decoder, Encoder};
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
+
+/// Telnet option codes used by the server.
+pub mod option {
+    pub const ECHO: u8 = 1;
+    pub const SUPPRESS_GO_AHEAD: u8 = 3;
+    pub const TERMINAL_TYPE: u8 = 24;
+    pub const NAWS: u8 = 31;
+}
+
+/// Longest subnegotiation we buffer while waiting for its `IAC SE`.
+const MAX_SUBNEGOTIATION: usize = 1024;
 
 pub struct TelnetCodec {
     current_line: Vec<u8>,
+    /// Whether text is split into `Item::Line`s, or passed on as
+    /// `Item::Data`.
+    lines: bool,
 }
 
 impl TelnetCodec {
+    /// A codec that yields text one line at a time, as the server wants it.
     pub fn new() -> Self {
         TelnetCodec {
             current_line: Vec::with_capacity(1024),
+            lines: true,
         }
     }
+
+    /// A codec that yields text as soon as it arrives, line endings included,
+    /// so that a client can show prompts that don't end the line.
+    pub fn data() -> Self {
+        TelnetCodec {
+            current_line: Vec::with_capacity(1024),
+            lines: false,
+        }
+    }
+
+    /// Switch between `Item::Line` and `Item::Data`. A partial line is handed
+    /// out as data after switching.
+    pub fn set_lines(&mut self, lines: bool) {
+        self.lines = lines;
+    }
 }
 
-#[derive(Debug)]
+impl Default for TelnetCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum Item {
     Line(Vec<u8>),
-    SE,
+    /// Text from a codec made with `TelnetCodec::data`.
+    Data(Vec<u8>),
+    /// The end of a Synch. The part of the line typed before it is
+    /// discarded.
     DataMark,
     Break,
     InterruptProcess,
     AbortOutput,
     AreYouThere,
     GoAhead,
-    SB,
+    /// `IAC SB option data IAC SE`, with escaped IACs in the data undone.
+    Subnegotiation(u8, Vec<u8>),
     Will(u8),
     Wont(u8),
     Do(u8),
@@ -40,6 +81,10 @@ impl Decoder for TelnetCodec {
         &mut self,
         src: &mut BytesMut
     ) -> Result<Option<Self::Item>, Self::Error> {
+        if !self.lines {
+            return self.decode_data(src);
+        }
+
         loop {
             if src.is_empty() {
                 return Ok(None);
@@ -57,8 +102,15 @@ impl Decoder for TelnetCodec {
                         ));
                     },
                     ParseIacResult::NeedMore => return Ok(None),
+                    ParseIacResult::Item(Item::DataMark) => {
+                        // We can't see the TCP urgent notification that
+                        // starts a Synch, so treat everything the user typed
+                        // on this line as sent before it.
+                        self.current_line.clear();
+                        return Ok(Some(Item::DataMark));
+                    },
                     ParseIacResult::Item(item) => return Ok(Some(item)),
-                    ParseIacResult::NOP => { /* go around loop */ },
+                    ParseIacResult::Nop => { /* go around loop */ },
                     ParseIacResult::EraseCharacter => {
                         self.current_line.pop();
                     },
@@ -79,6 +131,8 @@ impl Decoder for TelnetCodec {
 
                         return Ok(Some(Item::Line(line)));
                     },
+                    // Tabs are kept, for the indentation of pasted code.
+                    9 => self.current_line.push(byte),
                     0 ..= 31 => {
                         // ignore
                     },
@@ -89,32 +143,135 @@ impl Decoder for TelnetCodec {
     }
 }
 
+impl TelnetCodec {
+    fn decode_data(&mut self, src: &mut BytesMut) -> Result<Option<Item>, io::Error> {
+        while let Some(&byte) = src.first() {
+            if byte != 0xff {
+                // A NUL follows a bare carriage return.
+                if byte != 0 {
+                    self.current_line.push(byte);
+                }
+                src.advance(1);
+                continue;
+            }
+
+            let (res, consume) = try_parse_iac(src.chunk());
+            match res {
+                ParseIacResult::Escaped => self.current_line.push(0xff),
+                // Editing the peer's line means nothing in this direction.
+                ParseIacResult::Nop
+                | ParseIacResult::EraseCharacter
+                | ParseIacResult::EraseLine => {},
+                ParseIacResult::NeedMore => break,
+                // Hand out the text before the command first.
+                _ if !self.current_line.is_empty() => break,
+                ParseIacResult::Item(item) => {
+                    src.advance(consume);
+                    return Ok(Some(item));
+                },
+                ParseIacResult::Invalid(err) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                },
+            }
+            src.advance(consume);
+        }
+
+        if self.current_line.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Item::Data(std::mem::take(&mut self.current_line))))
+        }
+    }
+}
+
+impl Encoder<Item> for TelnetCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Item, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let cmd = match item {
+            Item::Line(line) => {
+                put_escaped(dst, &line);
+                dst.put_slice(b"\r\n");
+                return Ok(());
+            },
+            Item::Data(data) => {
+                put_escaped(dst, &data);
+                return Ok(());
+            },
+            Item::Subnegotiation(option, data) => {
+                dst.put_slice(&[0xff, 250, option]);
+                put_escaped(dst, &data);
+                dst.put_slice(&[0xff, 240]);
+                return Ok(());
+            },
+            Item::Will(_) | Item::Wont(_) | Item::Do(_) | Item::Dont(_) => {
+                dst.put_slice(&item.negotiation_bytes().unwrap_or_default());
+                return Ok(());
+            },
+            Item::DataMark => 242,
+            Item::Break => 243,
+            Item::InterruptProcess => 244,
+            Item::AbortOutput => 245,
+            Item::AreYouThere => 246,
+            Item::GoAhead => 249,
+        };
+        dst.put_slice(&[0xff, cmd]);
+        Ok(())
+    }
+}
+
+/// Write the bytes, doubling every IAC.
+fn put_escaped(dst: &mut BytesMut, bytes: &[u8]) {
+    dst.reserve(bytes.len());
+    for &byte in bytes {
+        if byte == 0xff {
+            dst.put_slice(&[0xff, 0xff]);
+        } else {
+            dst.put_u8(byte);
+        }
+    }
+}
+
 enum ParseIacResult {
     Invalid(String),
     NeedMore,
     Item(Item),
-    NOP,
+    Nop,
     EraseCharacter,
     EraseLine,
     Escaped,
 }
 
 /// Returns the parsed result of the first few bytes, as well as how many bytes
-/// to consume.
+/// to consume. Never consumes anything unless it returns a complete command.
 fn try_parse_iac(bytes: &[u8]) -> (ParseIacResult, usize) {
-    if bytes.len() < 2 {
-        return (ParseIacResult::NeedMore, 0);
-    }
-    if bytes[0] != 0xff {
-        unreachable!();
-    }
-    if is_three_byte_iac(bytes[1]) && bytes.len() < 3 {
-        return (ParseIacResult::NeedMore, 0);
+    let cmd = match bytes {
+        [0xff, cmd, ..] => *cmd,
+        [0xff] | [] => return (ParseIacResult::NeedMore, 0),
+        [byte, ..] => {
+            let err = format!("Expected IAC, got {}.", byte);
+            return (ParseIacResult::Invalid(err), 0);
+        },
+    };
+
+    // The option byte of WILL, WONT, DO and DONT.
+    let option = if is_three_byte_iac(cmd) {
+        match bytes.get(2) {
+            Some(option) => *option,
+            None => return (ParseIacResult::NeedMore, 0),
+        }
+    } else {
+        0
+    };
+
+    if cmd == 250 {
+        return try_parse_subnegotiation(bytes);
     }
 
-    match bytes[1] {
-        240 => (ParseIacResult::Item(Item::SE), 2),
-        241 => (ParseIacResult::NOP, 2),
+    match cmd {
+        // An SE without an SB.
+        240 => (ParseIacResult::Nop, 2),
+        241 => (ParseIacResult::Nop, 2),
         242 => (ParseIacResult::Item(Item::DataMark), 2),
         243 => (ParseIacResult::Item(Item::Break), 2),
         244 => (ParseIacResult::Item(Item::InterruptProcess), 2),
@@ -123,19 +280,215 @@ fn try_parse_iac(bytes: &[u8]) -> (ParseIacResult, usize) {
         247 => (ParseIacResult::EraseCharacter, 2),
         248 => (ParseIacResult::EraseLine, 2),
         249 => (ParseIacResult::Item(Item::GoAhead), 2),
-        250 => (ParseIacResult::Item(Item::SB), 2),
-        251 => (ParseIacResult::Item(Item::Will(bytes[2])), 3),
-        252 => (ParseIacResult::Item(Item::Wont(bytes[2])), 3),
-        253 => (ParseIacResult::Item(Item::Do(bytes[2])), 3),
-        254 => (ParseIacResult::Item(Item::Dont(bytes[2])), 3),
+        251 => (ParseIacResult::Item(Item::Will(option)), 3),
+        252 => (ParseIacResult::Item(Item::Wont(option)), 3),
+        253 => (ParseIacResult::Item(Item::Do(option)), 3),
+        254 => (ParseIacResult::Item(Item::Dont(option)), 3),
         255 => (ParseIacResult::Escaped, 2),
-        cmd => (ParseIacResult::Invalid(format!("Unknown IAC command {}.", cmd)), 0),
+        // Not a telnet command. Skip it rather than give up on the
+        // connection.
+        _ => (ParseIacResult::Nop, 2),
+    }
+}
+
+/// Parse `IAC SB option data IAC SE`. Inside the data, `IAC IAC` is a single
+/// 255 byte, and other commands are dropped.
+fn try_parse_subnegotiation(bytes: &[u8]) -> (ParseIacResult, usize) {
+    let option = match bytes.get(2) {
+        Some(option) => *option,
+        None => return (ParseIacResult::NeedMore, 0),
+    };
+
+    let mut data = Vec::new();
+    let mut i = 3;
+    loop {
+        if i > MAX_SUBNEGOTIATION {
+            let err = format!("Subnegotiation of option {} is too long.", option);
+            return (ParseIacResult::Invalid(err), 0);
+        }
+
+        match bytes[i..] {
+            [0xff, 240, ..] => {
+                let item = Item::Subnegotiation(option, data);
+                return (ParseIacResult::Item(item), i + 2);
+            },
+            [0xff, 0xff, ..] => {
+                data.push(0xff);
+                i += 2;
+            },
+            [0xff, _, ..] => i += 2,
+            [0xff] | [] => return (ParseIacResult::NeedMore, 0),
+            [byte, ..] => {
+                data.push(byte);
+                i += 1;
+            },
+        }
     }
 }
 
 fn is_three_byte_iac(byte: u8) -> bool {
-    match byte {
-        251 ..= 254 => true,
-        _ => false,
+    matches!(byte, 251 ..= 254)
+}
+
+/// The state of option negotiation on one connection.
+///
+/// Following RFC 854, a request is only answered if it changes the state of
+/// the option, so the two sides can never get into a loop of agreeing with
+/// each other. Requests we sent are remembered as in flight as in RFC 1143, so
+/// the answer to them is not taken for a new request. Options that were never
+/// offered or accepted are refused.
+pub struct Options {
+    /// Options we perform, such as ECHO.
+    local: [OptionState; 256],
+    /// Options the peer performs.
+    remote: [OptionState; 256],
+    /// Options we perform when the peer asks.
+    accepted_local: Vec<u8>,
+    /// Options we let the peer enable.
+    accepted_remote: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OptionState {
+    No,
+    Yes,
+    /// We asked for the option and wait for the answer.
+    WantYes,
+    /// We asked to stop the option and wait for the answer.
+    WantNo,
+}
+
+impl Options {
+    /// Agree to perform the `accepted_local` options and let the peer
+    /// perform the `accepted_remote` ones. Everything else is refused, unless
+    /// we asked for it with `enable` or `request`.
+    pub fn new(accepted_local: &[u8], accepted_remote: &[u8]) -> Self {
+        Options {
+            local: [OptionState::No; 256],
+            remote: [OptionState::No; 256],
+            accepted_local: accepted_local.to_vec(),
+            accepted_remote: accepted_remote.to_vec(),
+        }
+    }
+
+    /// Whether we perform the option and the peer agreed.
+    pub fn is_local(&self, option: u8) -> bool {
+        self.local[option as usize] == OptionState::Yes
+    }
+
+    /// Whether we offered the option and wait for the answer.
+    pub fn is_local_offered(&self, option: u8) -> bool {
+        self.local[option as usize] == OptionState::WantYes
+    }
+
+    /// Whether the peer performs the option.
+    pub fn is_remote(&self, option: u8) -> bool {
+        self.remote[option as usize] == OptionState::Yes
+    }
+
+    /// Start performing an option. Returns the WILL to send, if any.
+    pub fn enable(&mut self, option: u8) -> Option<Item> {
+        let state = &mut self.local[option as usize];
+        match *state {
+            OptionState::No => {
+                *state = OptionState::WantYes;
+                Some(Item::Will(option))
+            },
+            _ => None,
+        }
+    }
+
+    /// Stop performing an option. Returns the WONT to send, if any.
+    pub fn disable(&mut self, option: u8) -> Option<Item> {
+        let state = &mut self.local[option as usize];
+        match *state {
+            OptionState::Yes | OptionState::WantYes => {
+                *state = OptionState::WantNo;
+                Some(Item::Wont(option))
+            },
+            _ => None,
+        }
+    }
+
+    /// Ask the peer to perform an option. Returns the DO to send, if any.
+    pub fn request(&mut self, option: u8) -> Option<Item> {
+        let state = &mut self.remote[option as usize];
+        match *state {
+            OptionState::No => {
+                *state = OptionState::WantYes;
+                Some(Item::Do(option))
+            },
+            _ => None,
+        }
+    }
+
+    /// Handle a WILL, WONT, DO or DONT from the peer. Returns the reply to
+    /// send, if any.
+    pub fn receive(&mut self, item: &Item) -> Option<Item> {
+        match *item {
+            Item::Will(option) => {
+                let accept = self.accepted_remote.contains(&option);
+                let state = &mut self.remote[option as usize];
+                answer(state, true, accept).map(|yes| {
+                    if yes { Item::Do(option) } else { Item::Dont(option) }
+                })
+            },
+            Item::Wont(option) => {
+                let state = &mut self.remote[option as usize];
+                answer(state, false, false).map(|_| Item::Dont(option))
+            },
+            Item::Do(option) => {
+                let accept = self.accepted_local.contains(&option);
+                let state = &mut self.local[option as usize];
+                answer(state, true, accept).map(|yes| {
+                    if yes { Item::Will(option) } else { Item::Wont(option) }
+                })
+            },
+            Item::Dont(option) => {
+                let state = &mut self.local[option as usize];
+                answer(state, false, false).map(|_| Item::Wont(option))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Update the state of one option after the peer said it wants it `on` or
+/// off. Returns whether to reply yes or no, or `None` to stay quiet.
+fn answer(state: &mut OptionState, on: bool, accept: bool) -> Option<bool> {
+    match (*state, on) {
+        (OptionState::No, true) if accept => {
+            *state = OptionState::Yes;
+            Some(true)
+        },
+        (OptionState::No, true) => Some(false),
+        (OptionState::Yes, false) => {
+            *state = OptionState::No;
+            Some(false)
+        },
+        (OptionState::WantYes, true) => {
+            *state = OptionState::Yes;
+            None
+        },
+        // Already in that state, or the answer to what we asked for. A yes to
+        // our request to stop is a protocol error; the option stays off.
+        (OptionState::No, false) | (OptionState::Yes, true) => None,
+        (OptionState::WantYes, false) | (OptionState::WantNo, _) => {
+            *state = OptionState::No;
+            None
+        },
+    }
+}
+
+impl Item {
+    /// The bytes of a WILL, WONT, DO or DONT.
+    pub fn negotiation_bytes(&self) -> Option<[u8; 3]> {
+        match *self {
+            Item::Will(option) => Some([0xff, 251, option]),
+            Item::Wont(option) => Some([0xff, 252, option]),
+            Item::Do(option) => Some([0xff, 253, option]),
+            Item::Dont(option) => Some([0xff, 254, option]),
+            _ => None,
+        }
     }
 }
@@ -0,0 +1,187 @@
+//! An async telnet client, built on the same codec as the server.
+//!
+//! The client answers option negotiation by itself. It lets the server echo
+//! and suppress go-ahead, reports the window size with NAWS, and tells the
+//! terminal type to servers that ask for it.
+//!
+//! ```no_run
+//! # use telnet_chat::telnet::Item;
+//! # use telnet_chat::telnet_client::TelnetClient;
+//! # async fn chat() -> Result<(), std::io::Error> {
+//! let mut client = TelnetClient::connect("127.0.0.1:3456").await?;
+//! client.start().await?;
+//! client.send_line(b"alice").await?;
+//! while let Some(item) = client.next().await {
+//!     if let Item::Data(text) = item? {
+//!         print!("{}", String::from_utf8_lossy(&text));
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::{Encoder, Framed};
+
+use crate::telnet::{Item, Options, TelnetCodec, option};
+
+/// Sent by the server in a terminal type subnegotiation.
+const TERMINAL_TYPE_SEND: u8 = 1;
+/// Sent by us in the reply.
+const TERMINAL_TYPE_IS: u8 = 0;
+
+pub struct TelnetClient<T> {
+    framed: Framed<T, TelnetCodec>,
+    options: Options,
+    terminal_type: Option<String>,
+    /// Columns and rows.
+    window: Option<(u16, u16)>,
+}
+
+impl TelnetClient<TcpStream> {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, io::Error> {
+        let tcp = TcpStream::connect(addr).await?;
+        Ok(TelnetClient::new(tcp))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> TelnetClient<T> {
+    pub fn new(io: T) -> Self {
+        TelnetClient {
+            framed: Framed::new(io, TelnetCodec::data()),
+            options: Options::new(
+                &[option::NAWS, option::TERMINAL_TYPE],
+                &[option::ECHO, option::SUPPRESS_GO_AHEAD],
+            ),
+            terminal_type: None,
+            window: None,
+        }
+    }
+
+    /// The terminal type to report, such as `xterm-256color`. Without one,
+    /// the client reports `UNKNOWN` to servers that ask.
+    pub fn set_terminal_type(&mut self, terminal_type: Option<String>) {
+        self.terminal_type = terminal_type;
+    }
+
+    /// Offer the options a terminal client usually offers as soon as it
+    /// connects. Call `set_terminal_type` and `set_window_size` first.
+    pub async fn start(&mut self) -> Result<(), io::Error> {
+        let mut offers = vec![self.options.request(option::SUPPRESS_GO_AHEAD)];
+        if self.terminal_type.is_some() {
+            offers.push(self.options.enable(option::TERMINAL_TYPE));
+        }
+        if self.window.is_some() {
+            offers.push(self.options.enable(option::NAWS));
+        }
+
+        for item in offers.into_iter().flatten() {
+            self.framed.feed(item).await?;
+        }
+        self.framed.flush().await
+    }
+
+    /// Whether the server echoes what we type. Servers ask for this while a
+    /// password is typed, and then don't echo, so the input should be hidden.
+    pub fn server_echoes(&self) -> bool {
+        self.options.is_remote(option::ECHO)
+    }
+
+    /// Whether the server echoes and suppresses go-ahead, so keys should be
+    /// sent as they are typed and its output shown as it is. This is how the
+    /// server draws its full-screen layout.
+    pub fn char_mode(&self) -> bool {
+        self.options.is_remote(option::ECHO) && self.options.is_remote(option::SUPPRESS_GO_AHEAD)
+    }
+
+    /// Read the next text or command from the server. Option negotiation is
+    /// answered here and not returned.
+    ///
+    /// This is cancel safe: replies are queued before anything is awaited,
+    /// and a flush that was cut short is finished by the next write.
+    pub async fn next(&mut self) -> Option<Result<Item, io::Error>> {
+        loop {
+            let item = match self.framed.next().await? {
+                Ok(item) => item,
+                Err(err) => return Some(Err(err)),
+            };
+
+            match item {
+                Item::Will(_) | Item::Wont(_) | Item::Do(_) | Item::Dont(_) => {
+                    self.negotiate(item);
+                },
+                Item::Subnegotiation(option::TERMINAL_TYPE, data) => {
+                    self.queue_terminal_type(&data);
+                },
+                item => return Some(Ok(item)),
+            }
+            if let Err(err) = self.framed.flush().await {
+                return Some(Err(err));
+            }
+        }
+    }
+
+    /// Send a line of text. Any IAC bytes are escaped.
+    pub async fn send_line(&mut self, line: &[u8]) -> Result<(), io::Error> {
+        self.send(Item::Line(line.to_vec())).await
+    }
+
+    /// Send text or a command, such as `Item::InterruptProcess`.
+    pub async fn send(&mut self, item: Item) -> Result<(), io::Error> {
+        self.framed.send(item).await
+    }
+
+    /// Remember the window size, and report it if the server wants to know.
+    pub async fn set_window_size(&mut self, cols: u16, rows: u16) -> Result<(), io::Error> {
+        self.window = Some((cols, rows));
+        self.queue_window_size();
+        self.framed.flush().await
+    }
+
+    fn negotiate(&mut self, item: Item) {
+        let was_naws = self.options.is_local(option::NAWS);
+        if let Some(reply) = self.options.receive(&item) {
+            self.queue(reply);
+        }
+
+        // The server agreed to NAWS, either by answering our WILL or by
+        // asking first.
+        if !was_naws && self.options.is_local(option::NAWS) {
+            self.queue_window_size();
+        }
+    }
+
+    fn queue_window_size(&mut self) {
+        let (cols, rows) = match self.window {
+            Some(window) if self.options.is_local(option::NAWS) => window,
+            _ => return,
+        };
+
+        let mut data = Vec::with_capacity(4);
+        data.extend_from_slice(&cols.to_be_bytes());
+        data.extend_from_slice(&rows.to_be_bytes());
+        self.queue(Item::Subnegotiation(option::NAWS, data));
+    }
+
+    fn queue_terminal_type(&mut self, data: &[u8]) {
+        if data != [TERMINAL_TYPE_SEND] || !self.options.is_local(option::TERMINAL_TYPE) {
+            return;
+        }
+
+        let mut reply = vec![TERMINAL_TYPE_IS];
+        reply.extend_from_slice(self.terminal_type.as_deref().unwrap_or("UNKNOWN").as_bytes());
+        self.queue(Item::Subnegotiation(option::TERMINAL_TYPE, reply));
+    }
+
+    /// Add an item to the write buffer without waiting for it to be written.
+    fn queue(&mut self, item: Item) {
+        // The encoder keeps no state, so a fresh one does the same job as the
+        // one inside `framed`, which we can't borrow alongside its buffer.
+        let _ = TelnetCodec::new().encode(item, self.framed.write_buffer_mut());
+    }
+}
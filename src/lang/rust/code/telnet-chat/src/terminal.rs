@@ -0,0 +1,448 @@
+//! The client's terminal, as seen by the reading half of a client actor.
+//!
+//! `Terminal` answers option negotiation and learns the terminal type and
+//! window size. If the terminal can show it, it offers the full-screen layout
+//! drawn by the writing half (see `screen`): we claim to echo and to suppress
+//! go-ahead, which puts telnet clients in character mode. From then on every
+//! key arrives as it is typed, and `Terminal` edits the input line.
+
+use std::time::Instant;
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::client::InternalMsg;
+use crate::markup;
+use crate::presence::TYPING_TIMEOUT;
+use crate::screen::{self, MIN_COLS, MIN_ROWS};
+use crate::telnet::{Item, Options, option};
+
+const TERMINAL_TYPE_IS: u8 = 0;
+const TERMINAL_TYPE_SEND: u8 = 1;
+
+/// Longest input line we accept in full-screen mode.
+const MAX_INPUT: usize = 1024;
+
+pub(crate) struct Terminal {
+    to_tcp_write: UnboundedSender<InternalMsg>,
+    options: Options,
+    terminal_type: Option<String>,
+    /// Columns and rows, from NAWS.
+    window: Option<(u16, u16)>,
+    /// We offered ECHO and SUPPRESS_GO_AHEAD and wait for the answers.
+    offered_screen: bool,
+    /// The client said no, so don't offer again.
+    refused_screen: bool,
+    full_screen: bool,
+    /// A password is being typed.
+    echo_off: bool,
+    editor: Editor,
+    /// When we last told the main loop that the user is typing.
+    typing_reported: Option<Instant>,
+}
+
+impl Terminal {
+    pub fn new(to_tcp_write: UnboundedSender<InternalMsg>) -> Self {
+        Terminal {
+            to_tcp_write,
+            options: Options::new(
+                &[option::SUPPRESS_GO_AHEAD],
+                &[option::SUPPRESS_GO_AHEAD, option::TERMINAL_TYPE, option::NAWS],
+            ),
+            terminal_type: None,
+            window: None,
+            offered_screen: false,
+            refused_screen: false,
+            full_screen: false,
+            echo_off: false,
+            editor: Editor::default(),
+            typing_reported: None,
+        }
+    }
+
+    /// Ask for the terminal type and window size.
+    pub fn start(&mut self) {
+        let ttype = self.options.request(option::TERMINAL_TYPE);
+        self.send_item(ttype);
+        let naws = self.options.request(option::NAWS);
+        self.send_item(naws);
+    }
+
+    /// Whether keys arrive one at a time, so the codec should yield data
+    /// instead of lines.
+    pub fn is_full_screen(&self) -> bool {
+        self.full_screen
+    }
+
+    /// Handle a WILL, WONT, DO or DONT from the client.
+    pub fn negotiate(&mut self, item: &Item) {
+        let had_type = self.options.is_remote(option::TERMINAL_TYPE);
+        let reply = self.options.receive(item);
+        self.send_item(reply);
+
+        // Ask for the terminal type as soon as the client agrees to tell.
+        if !had_type && self.options.is_remote(option::TERMINAL_TYPE) {
+            let send = Item::Subnegotiation(option::TERMINAL_TYPE, vec![TERMINAL_TYPE_SEND]);
+            self.send_item(Some(send));
+        }
+
+        self.update();
+    }
+
+    pub fn subnegotiation(&mut self, option: u8, data: &[u8]) {
+        match (option, data) {
+            (option::TERMINAL_TYPE, [TERMINAL_TYPE_IS, name @ ..]) => {
+                let ty = String::from_utf8_lossy(name).into_owned();
+                self.send(InternalMsg::Colour(markup::supports_colour(&ty)));
+                self.terminal_type = Some(ty);
+            },
+            (option::NAWS, &[c1, c2, r1, r2]) => {
+                let size = (u16::from_be_bytes([c1, c2]), u16::from_be_bytes([r1, r2]));
+                self.window = Some(size);
+                if self.full_screen {
+                    self.send(InternalMsg::Screen(Some(size)));
+                }
+            },
+            _ => return,
+        }
+        self.update();
+    }
+
+    /// Hide the input while a password is typed.
+    pub fn set_echo(&mut self, on: bool) {
+        self.echo_off = !on;
+        self.update();
+    }
+
+    /// Show the nick in the status bar.
+    pub fn set_nick(&self, nick: &str) {
+        self.send(InternalMsg::Nick(nick.to_string()));
+    }
+
+    /// A line was entered. `Login` calls this before handling it.
+    pub fn entered(&mut self, line: &str) {
+        self.send(InternalMsg::Submit {
+            line: line.to_string(),
+            hidden: self.echo_off,
+        });
+    }
+
+    /// Keys typed in full-screen mode. Returns the lines that were entered.
+    /// Call `show_input` once they are handled.
+    pub fn keys(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for key in self.editor.keys(data) {
+            match key {
+                Key::Enter => {
+                    let line = self.editor.take(!self.echo_off);
+                    lines.push(line);
+                },
+                Key::PageUp => self.send(InternalMsg::Scroll(true)),
+                Key::PageDown => self.send(InternalMsg::Scroll(false)),
+                key => self.editor.edit(key),
+            }
+        }
+        lines
+    }
+
+    /// Draw the input line again.
+    pub fn show_input(&mut self) {
+        if self.full_screen {
+            self.send(InternalMsg::Input {
+                text: self.editor.input.iter().collect(),
+                cursor: self.editor.cursor,
+                hidden: self.echo_off,
+            });
+        }
+    }
+
+    /// Whether the user started or stopped typing a chat line since the last
+    /// call. While they keep typing, this says so again before the notice
+    /// times out.
+    pub fn typing_changed(&mut self) -> Option<bool> {
+        let input = &self.editor.input;
+        let typing = self.full_screen
+            && !self.echo_off
+            && !input.is_empty()
+            && input[0] != '/';
+
+        match self.typing_reported {
+            Some(at) if typing && at.elapsed() < TYPING_TIMEOUT / 2 => None,
+            None if !typing => None,
+            _ if typing => {
+                self.typing_reported = Some(Instant::now());
+                Some(true)
+            },
+            _ => {
+                self.typing_reported = None;
+                Some(false)
+            },
+        }
+    }
+
+    /// Move between full-screen and line mode as the negotiation goes, and
+    /// otherwise use ECHO to hide passwords.
+    fn update(&mut self) {
+        let echoing = self.options.is_local(option::ECHO)
+            && self.options.is_local(option::SUPPRESS_GO_AHEAD);
+
+        if self.full_screen && !echoing {
+            // The client took back its agreement.
+            self.full_screen = false;
+            self.refused_screen = true;
+            self.send(InternalMsg::Screen(None));
+        } else if self.offered_screen && echoing {
+            self.offered_screen = false;
+            self.full_screen = true;
+            self.send(InternalMsg::Screen(self.window));
+            self.show_input();
+        } else if self.offered_screen
+            && !self.options.is_local_offered(option::ECHO)
+            && !self.options.is_local_offered(option::SUPPRESS_GO_AHEAD)
+        {
+            self.offered_screen = false;
+            self.refused_screen = true;
+        } else if !self.full_screen && !self.offered_screen && self.can_show_screen() {
+            self.offered_screen = true;
+            let sga = self.options.enable(option::SUPPRESS_GO_AHEAD);
+            self.send_item(sga);
+            let echo = self.options.enable(option::ECHO);
+            self.send_item(echo);
+        }
+
+        if self.full_screen || self.offered_screen {
+            return;
+        }
+
+        // Turning echo off is done by claiming that the server will echo, and
+        // then not doing so.
+        let echo = if self.echo_off {
+            self.options.enable(option::ECHO)
+        } else {
+            self.options.disable(option::ECHO)
+        };
+        self.send_item(echo);
+    }
+
+    fn can_show_screen(&self) -> bool {
+        let big_enough = match self.window {
+            Some((cols, rows)) => cols >= MIN_COLS && rows >= MIN_ROWS,
+            None => false,
+        };
+        let capable = self.terminal_type.as_deref().map(screen::supports_screen).unwrap_or(false);
+        big_enough && capable && !self.refused_screen
+    }
+
+    fn send_item(&self, item: Option<Item>) {
+        if let Some(item) = item {
+            self.send(InternalMsg::Telnet(item));
+        }
+    }
+
+    pub fn send(&self, msg: InternalMsg) {
+        self.to_tcp_write.send(msg)
+            .expect("Should not be closed.");
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    /// Ctrl-U.
+    CutLine,
+    /// Ctrl-W.
+    CutWord,
+}
+
+/// The input line in full-screen mode.
+#[derive(Default)]
+struct Editor {
+    input: Vec<char>,
+    /// Position in `input`.
+    cursor: usize,
+    history: Vec<String>,
+    /// Position in `history` while browsing it with Up and Down.
+    history_pos: Option<usize>,
+    /// The start of an escape sequence or UTF-8 character that was split
+    /// between two reads.
+    pending: Vec<u8>,
+    /// Enter sends CR LF or CR NUL, so skip the LF after a CR.
+    after_cr: bool,
+}
+
+impl Editor {
+    fn keys(&mut self, data: &[u8]) -> Vec<Key> {
+        self.pending.extend_from_slice(data);
+        let bytes = std::mem::take(&mut self.pending);
+        let mut keys = Vec::new();
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let (key, len) = match parse_key(&bytes[i..]) {
+                Some(parsed) => parsed,
+                // Terminals send escape sequences whole, so an ESC at the end
+                // of a read is the Esc key. Ignore it, so the next key is not
+                // taken for part of a sequence.
+                None if bytes[i..] == [0x1b] => (None, 1),
+                None => {
+                    self.pending = bytes[i..].to_vec();
+                    break;
+                },
+            };
+            i += len;
+
+            let after_cr = std::mem::replace(&mut self.after_cr, bytes[i - 1] == b'\r');
+            if after_cr && bytes[i - 1] == b'\n' {
+                continue;
+            }
+            if let Some(key) = key {
+                keys.push(key);
+            }
+        }
+
+        keys
+    }
+
+    fn edit(&mut self, key: Key) {
+        match key {
+            Key::Char(c) if self.input.len() < MAX_INPUT => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            },
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            },
+            Key::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            },
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.input.len(),
+            Key::Up => self.browse_history(true),
+            Key::Down => self.browse_history(false),
+            Key::CutLine => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+            },
+            Key::CutWord => {
+                let mut start = self.cursor;
+                while start > 0 && self.input[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.input[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.input.drain(start..self.cursor);
+                self.cursor = start;
+            },
+            _ => {},
+        }
+    }
+
+    /// Empty the line and return it. Remembered in the history unless it is
+    /// a password.
+    fn take(&mut self, remember: bool) -> String {
+        let line: String = self.input.drain(..).collect();
+        self.cursor = 0;
+        self.history_pos = None;
+        if remember && !line.is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        line
+    }
+
+    fn browse_history(&mut self, back: bool) {
+        let pos = match (self.history_pos, back) {
+            (None, true) if !self.history.is_empty() => Some(self.history.len() - 1),
+            (None, _) => return,
+            (Some(pos), true) => Some(pos.saturating_sub(1)),
+            (Some(pos), false) if pos + 1 < self.history.len() => Some(pos + 1),
+            (Some(_), false) => None,
+        };
+
+        self.history_pos = pos;
+        self.input = match pos {
+            Some(pos) => self.history[pos].chars().collect(),
+            None => Vec::new(),
+        };
+        self.cursor = self.input.len();
+    }
+}
+
+/// Parse one key at the start of `bytes`. Returns the key, or `None` for
+/// bytes we ignore, and how many bytes it took. Returns `None` if the key is
+/// not complete yet.
+fn parse_key(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    let key = match bytes {
+        [] => return None,
+        [b'\r', ..] | [b'\n', ..] => Key::Enter,
+        [0x7f, ..] | [0x08, ..] => Key::Backspace,
+        [0x01, ..] => Key::Home,
+        [0x05, ..] => Key::End,
+        [0x15, ..] => Key::CutLine,
+        [0x17, ..] => Key::CutWord,
+        [0x1b, rest @ ..] => return parse_escape(rest).map(|(key, len)| (key, len + 1)),
+        [byte, ..] if *byte < 0x20 => return Some((None, 1)),
+        [byte, ..] => {
+            let len = match byte {
+                0x00..=0x7f => 1,
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                // Not the start of a character.
+                _ => return Some((None, 1)),
+            };
+            if bytes.len() < len {
+                return None;
+            }
+            return match std::str::from_utf8(&bytes[..len]) {
+                Ok(s) => Some((s.chars().next().map(Key::Char), len)),
+                Err(_) => Some((None, 1)),
+            };
+        },
+    };
+    Some((Some(key), 1))
+}
+
+/// Parse the rest of an escape sequence, after the ESC.
+fn parse_escape(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    match bytes {
+        [] => None,
+        [b'[', rest @ ..] | [b'O', rest @ ..] => {
+            // Parameters, then a final byte in 0x40..=0x7e.
+            let end = match rest.iter().position(|b| (0x40..=0x7e).contains(b)) {
+                Some(end) => end,
+                None if rest.len() < 16 => return None,
+                None => return Some((None, 1 + rest.len())),
+            };
+            let key = match &rest[..=end] {
+                b"A" => Some(Key::Up),
+                b"B" => Some(Key::Down),
+                b"C" => Some(Key::Right),
+                b"D" => Some(Key::Left),
+                b"H" | b"1~" | b"7~" => Some(Key::Home),
+                b"F" | b"4~" | b"8~" => Some(Key::End),
+                b"3~" => Some(Key::Delete),
+                b"5~" => Some(Key::PageUp),
+                b"6~" => Some(Key::PageDown),
+                _ => None,
+            };
+            Some((key, end + 2))
+        },
+        // The Esc key, or Alt and a key. Ignore the ESC, and read what
+        // follows as a key of its own.
+        _ => Some((None, 0)),
+    }
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::TestServer;
+use telnet_chat::telnet::{Item, option};
+
+#[tokio::test]
+async fn registering_reserves_the_nick() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+
+    // Echo is turned off by claiming to echo, and back on once the password
+    // is entered.
+    alice.send("/register").await;
+    alice.expect_next_item(Item::Will(option::ECHO)).await;
+    alice.send("hunter2").await;
+    alice.send("hunter2").await;
+    alice.expect_line("Password saved. Your nick is now reserved.").await;
+    alice.expect_next_item(Item::Wont(option::ECHO)).await;
+    assert!(server.accounts.verify("alice", "hunter2"));
+
+    alice.send("/register").await;
+    alice.expect_line("Your nick is already registered. Use /passwd to change the password.").await;
+    drop(alice);
+
+    // Now the nick asks for the password, with echo off.
+    let mut again = server.connect().await;
+    again.send("Alice").await;
+    again.expect_item(Item::Will(option::ECHO)).await;
+    again.send("wrong").await;
+    again.expect_line("Wrong password.").await;
+    again.send("hunter2").await;
+    again.expect_line("Logged in as Alice.").await;
+    again.expect_next_item(Item::Wont(option::ECHO)).await;
+}
+
+#[tokio::test]
+async fn new_passwords_are_typed_twice() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+
+    alice.send("/register").await;
+    alice.send("hunter2").await;
+    alice.send("hunter3").await;
+    alice.expect_line("The passwords do not match.").await;
+    alice.expect_next_item(Item::Wont(option::ECHO)).await;
+
+    // An empty password cancels.
+    alice.send("/register").await;
+    alice.send("").await;
+    alice.expect_line("Cancelled.").await;
+    assert!(!server.accounts.is_registered("alice"));
+
+    // Chat works again once the prompts are done.
+    let mut bob = server.login("bob").await;
+    alice.send("still here").await;
+    bob.expect_line("<alice> still here").await;
+}
+
+#[tokio::test]
+async fn passwords_are_changed_with_the_current_one() {
+    let server = TestServer::start().await;
+    server.accounts.set_password("alice", "hunter2").unwrap();
+
+    // Answer the negotiation as a telnet client would, or the server keeps
+    // waiting for the answers and doesn't offer ECHO again.
+    let mut alice = server.connect().await;
+    alice.send("alice").await;
+    alice.expect_item(Item::Will(option::ECHO)).await;
+    alice.send_raw(&[255, 253, option::ECHO]).await;
+    alice.send("hunter2").await;
+    alice.expect_line("Logged in as alice.").await;
+    alice.expect_next_item(Item::Wont(option::ECHO)).await;
+    alice.send_raw(&[255, 254, option::ECHO]).await;
+
+    alice.send("/passwd").await;
+    alice.expect_next_item(Item::Will(option::ECHO)).await;
+    alice.send_raw(&[255, 253, option::ECHO]).await;
+    alice.send("wrong").await;
+    alice.expect_line("Wrong password.").await;
+    alice.expect_next_item(Item::Wont(option::ECHO)).await;
+    assert!(server.accounts.verify("alice", "hunter2"));
+
+    // Each prompt ends once the password typed after it is entered, which
+    // waits for the one before to be checked.
+    alice.send("/passwd").await;
+    alice.send("hunter2").await;
+    alice.send("correct horse").await;
+    alice.expect_line("New password: ").await;
+    alice.send("correct horse").await;
+    alice.expect_line("Password saved. Your nick is now reserved.").await;
+    assert!(server.accounts.verify("alice", "correct horse"));
+    assert!(!server.accounts.verify("alice", "hunter2"));
+
+    let mut bob = server.login("bob").await;
+    bob.send("/passwd").await;
+    bob.expect_line("Your nick is not registered. Use /register first.").await;
+}
+
+#[tokio::test]
+async fn wrong_passwords_end_the_connection() {
+    let server = TestServer::start().await;
+    server.accounts.set_password("alice", "hunter2").unwrap();
+
+    let mut client = server.connect().await;
+    client.send("alice").await;
+    for _ in 0..2 {
+        client.send("wrong").await;
+        client.expect_line("Wrong password.").await;
+    }
+    client.send("wrong").await;
+    client.expect_line("Too many failed attempts.").await;
+    client.expect_closed().await;
+}
+
+#[tokio::test]
+async fn registered_nicks_in_use_are_refused_without_sessions() {
+    let server = TestServer::start_with(|config| config.resume_secs = 0).await;
+    server.accounts.set_password("alice", "hunter2").unwrap();
+    let mut alice = server.login_registered("alice", "hunter2").await;
+
+    // The right password doesn't take the nick over if sessions can't be
+    // resumed.
+    let mut other = server.connect().await;
+    other.send("alice").await;
+    other.send("hunter2").await;
+    other.expect_line("That nick is already in use.").await;
+
+    alice.send("/whois alice").await;
+    alice.expect_line("* alice: in #lobby, registered, idle 0s").await;
+}
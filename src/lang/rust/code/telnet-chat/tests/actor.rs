@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout};
+
+use telnet_chat::actor::{self, CallError, Policy, Reply, Restart, SendError};
+
+enum Msg {
+    Add(u64),
+    Get(Reply<u64>),
+    /// Never answered.
+    Ignore(Reply<u64>),
+}
+
+fn counter() -> actor::Owner<Msg> {
+    actor::spawn(1, |mut mailbox| async move {
+        let mut total = 0;
+        let mut ignored = Vec::new();
+        while let Some(msg) = mailbox.recv().await {
+            match msg {
+                Msg::Add(n) => total += n,
+                Msg::Get(reply) => {
+                    let _ = reply.send(total);
+                },
+                Msg::Ignore(reply) => ignored.push(reply),
+            }
+        }
+    })
+}
+
+#[tokio::test]
+async fn calls_are_answered_or_time_out() {
+    let counter = counter();
+    counter.send(Msg::Add(2)).await.unwrap();
+    counter.send(Msg::Add(3)).await.unwrap();
+    assert_eq!(counter.call(Duration::from_secs(5), Msg::Get).await, Ok(5));
+
+    let res = counter.call(Duration::from_millis(50), Msg::Ignore).await;
+    assert_eq!(res, Err(CallError::TimedOut));
+
+    let addr = counter.addr().clone();
+    drop(counter);
+    sleep(Duration::from_millis(50)).await;
+    assert!(addr.is_stopped());
+    assert_eq!(addr.send(Msg::Add(1)).await, Err(SendError::Stopped));
+    assert_eq!(addr.call(Duration::from_secs(5), Msg::Get).await, Err(CallError::Stopped));
+}
+
+#[tokio::test]
+async fn full_mailboxes_are_reported() {
+    // Never reads its mailbox.
+    let stuck = actor::spawn(1, |mailbox| async move {
+        let _mailbox = mailbox;
+        sleep(Duration::from_secs(60)).await;
+    });
+    assert_eq!(stuck.try_send(()), Ok(()));
+    assert_eq!(stuck.try_send(()), Err(SendError::Full));
+}
+
+#[tokio::test]
+async fn dropping_the_owner_kills_the_actor() {
+    let (send, done) = oneshot::channel::<()>();
+    let owner = actor::spawn(1, |mailbox: actor::Mailbox<()>| async move {
+        let _keep = (send, mailbox);
+        sleep(Duration::from_secs(60)).await;
+    });
+    drop(owner);
+    // The sender is dropped with the task.
+    assert!(timeout(Duration::from_secs(5), done).await.unwrap().is_err());
+
+    // A detached actor runs to the end.
+    let owner = actor::spawn(1, |mut mailbox: actor::Mailbox<u32>| async move {
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(mailbox.recv().await, Some(7));
+    });
+    owner.try_send(7).unwrap();
+    let join = owner.detach();
+    timeout(Duration::from_secs(5), join).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn actors_can_be_given_their_owner() {
+    let (send, recv) = oneshot::channel();
+    actor::spawn_owned(1, move |owner, mut mailbox| async move {
+        let _ = send.send(owner);
+        while let Some(Msg::Get(reply)) = mailbox.recv().await {
+            let _ = reply.send(42);
+        }
+    });
+
+    let owner = recv.await.unwrap();
+    assert_eq!(owner.call(Duration::from_secs(5), Msg::Get).await, Ok(42));
+}
+
+#[tokio::test]
+async fn failed_actors_are_restarted() {
+    let starts = Arc::new(AtomicUsize::new(0));
+    let policy = Policy {
+        restart: Restart::OnFailure,
+        delay: Duration::from_millis(10),
+        ..Policy::default()
+    };
+    let counted = starts.clone();
+    let task = actor::supervise("flaky", policy, move || {
+        let n = counted.fetch_add(1, Ordering::SeqCst);
+        async move {
+            match n {
+                0 => Err("first"),
+                1 => panic!("second"),
+                _ => Ok(()),
+            }
+        }
+    });
+
+    // It stops after the run that succeeds.
+    timeout(Duration::from_secs(5), task.join()).await.unwrap().unwrap();
+    assert_eq!(starts.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn supervisors_give_up() {
+    let starts = Arc::new(AtomicUsize::new(0));
+    let policy = Policy {
+        restart: Restart::Always,
+        delay: Duration::from_millis(1),
+        max_restarts: Some((3, Duration::from_secs(60))),
+    };
+    let counted = starts.clone();
+    let task = actor::supervise("short-lived", policy, move || {
+        counted.fetch_add(1, Ordering::SeqCst);
+        async { Ok::<(), String>(()) }
+    });
+
+    timeout(Duration::from_secs(5), task.join()).await.unwrap().unwrap();
+    assert_eq!(starts.load(Ordering::SeqCst), 4);
+
+    // Dropping the supervisor stops the actor it runs.
+    let (send, done) = oneshot::channel::<()>();
+    let send = Arc::new(std::sync::Mutex::new(Some(send)));
+    let task = actor::supervise("sleeper", Policy::default(), move || {
+        let send = send.lock().unwrap().take();
+        async move {
+            let _send = send;
+            sleep(Duration::from_secs(60)).await;
+            Ok::<(), String>(())
+        }
+    });
+    sleep(Duration::from_millis(50)).await;
+    drop(task);
+    assert!(timeout(Duration::from_secs(5), done).await.unwrap().is_err());
+}
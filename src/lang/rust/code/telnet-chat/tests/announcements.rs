@@ -0,0 +1,127 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use telnet_chat::config::{AnnouncementConfig, Config};
+use telnet_chat::schedule::Schedule;
+
+use common::TestServer;
+use common::sim::Sim;
+
+/// Minutes since the unix epoch.
+fn minute(unix_time: u64) -> u64 {
+    unix_time / 60
+}
+
+#[test]
+fn schedules_match_like_cron() {
+    // 2024-01-01 was a Monday.
+    let monday_nine = minute(1_704_099_600);
+    let saturday_nine = minute(1_704_531_600);
+
+    let weekdays = Schedule::parse("0 9 * * 1-5").unwrap();
+    assert!(weekdays.matches(monday_nine));
+    assert!(!weekdays.matches(monday_nine + 1));
+    assert!(!weekdays.matches(saturday_nine));
+
+    let quarters = Schedule::parse("*/15 * * * *").unwrap();
+    assert!(quarters.matches(monday_nine + 45));
+    assert!(!quarters.matches(monday_nine + 46));
+
+    let leap_day = Schedule::parse("0 0 29 2 *").unwrap();
+    assert!(leap_day.matches(minute(1_709_164_800)));
+
+    // Either the day of the month or the day of the week.
+    let firsts_and_mondays = Schedule::parse("0 9 1 * 1").unwrap();
+    assert!(firsts_and_mondays.matches(monday_nine));
+    assert!(firsts_and_mondays.matches(monday_nine + 7 * 24 * 60));
+    assert!(!firsts_and_mondays.matches(saturday_nine));
+
+    let sundays = Schedule::parse("0 9 * * 7").unwrap();
+    assert!(sundays.matches(saturday_nine + 24 * 60));
+    assert!(Schedule::parse("@daily").unwrap().matches(monday_nine - 9 * 60));
+
+    for bad in ["60 * * * *", "* * *", "*/0 * * * *", "1-x * * * *", "5-1 * * * *", "* * 0 * *"] {
+        assert!(Schedule::parse(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn announcements_are_read_from_the_config() {
+    let config: Config = toml::from_str(r#"
+        [[announcements]]
+        schedule = "45 9 * * 1-5"
+        rooms = ["dev"]
+        text = "Stand-up in 15 minutes."
+    "#).unwrap();
+    assert_eq!(config.announcements[0].schedule.to_string(), "45 9 * * 1-5");
+    assert_eq!(config.announcements[0].rooms, ["dev"]);
+
+    let bad: Result<Config, _> = toml::from_str(r#"
+        [[announcements]]
+        schedule = "45 25 * * *"
+        text = "Never."
+    "#);
+    assert!(bad.is_err());
+}
+
+#[tokio::test]
+async fn operators_can_change_the_motd() {
+    let server = TestServer::start_with(|config| {
+        config.operators = vec!["root".to_string()];
+        std::fs::write(&config.motd_file, "Welcome!\nBe nice.\n").unwrap();
+    }).await;
+    server.accounts.set_password("root", "hunter2").unwrap();
+
+    let mut alice = server.connect().await;
+    alice.send("alice").await;
+    alice.expect_line("* Message of the day:").await;
+    alice.expect_line("| Welcome!").await;
+    alice.expect_line("| Be nice.").await;
+    alice.expect_line("* You are now in #lobby.").await;
+
+    alice.send("/motd clear").await;
+    alice.expect_line("* Only server operators can do that.").await;
+
+    let mut root = server.login_registered("root", "hunter2").await;
+    root.send("/motd set Maintenance at 22:00 UTC.").await;
+    root.expect_line("* The message of the day was changed.").await;
+    root.send("/motd add Thanks for your patience.").await;
+    root.expect_line("* The message of the day was changed.").await;
+
+    alice.send("/motd").await;
+    alice.expect_line("| Maintenance at 22:00 UTC.").await;
+    alice.expect_line("| Thanks for your patience.").await;
+    let motd = std::fs::read_to_string(&server.config.motd_file).unwrap();
+    assert_eq!(motd, "Maintenance at 22:00 UTC.\nThanks for your patience.\n");
+
+    root.send("/motd clear").await;
+    root.expect_line("* The message of the day was changed.").await;
+    alice.send("/motd").await;
+    alice.expect_line("* There is no message of the day.").await;
+
+    let audit = std::fs::read_to_string(&server.config.audit_log).unwrap();
+    assert!(audit.contains("motd set: Maintenance at 22:00 UTC."), "{}", audit);
+}
+
+#[tokio::test(start_paused = true)]
+async fn announcements_are_sent_on_schedule() {
+    let sim = Sim::start_with(|config| {
+        config.announcements = vec![AnnouncementConfig {
+            schedule: Schedule::parse("* * * * *").unwrap(),
+            rooms: vec!["#lobby".to_string()],
+            text: "Stretch your legs.".to_string(),
+        }];
+    });
+    let mut alice = sim.login("alice").await;
+    let mut bob = sim.login("bob").await;
+    bob.send("/join dev").await;
+    bob.expect_line("* You are now in #dev.").await;
+
+    // A minute on the paused clock takes no time.
+    sleep(Duration::from_secs(60)).await;
+    alice.expect_line("* Announcement: Stretch your legs.").await;
+    bob.expect_no_line("* Announcement: Stretch your legs.", Duration::from_secs(1)).await;
+}
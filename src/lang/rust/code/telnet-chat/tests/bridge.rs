@@ -0,0 +1,122 @@
+mod common;
+
+use std::time::Duration;
+
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use telnet_chat::config::{BridgeConfig, Config};
+
+use common::{TestServer, EXPECT_TIMEOUT};
+
+fn bridge(room: &str, connect: String) -> BridgeConfig {
+    BridgeConfig { name: "tools".to_string(), room: room.to_string(), connect }
+}
+
+/// The other end of a bridge, as a test plays it.
+struct Endpoint<T> {
+    framed: Framed<T, LinesCodec>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Endpoint<T> {
+    fn new(conn: T) -> Self {
+        Endpoint { framed: Framed::new(conn, LinesCodec::new()) }
+    }
+
+    async fn send(&mut self, event: Value) {
+        self.framed.send(event.to_string()).await.unwrap();
+    }
+
+    async fn send_raw(&mut self, line: &str) {
+        self.framed.send(line).await.unwrap();
+    }
+
+    async fn expect(&mut self, expected: Value) {
+        let line = timeout(EXPECT_TIMEOUT, self.framed.next())
+            .await
+            .unwrap_or_else(|_| panic!("Timed out waiting for {}", expected))
+            .unwrap()
+            .unwrap();
+        let event: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(event, expected);
+    }
+}
+
+#[test]
+fn bridges_are_read_from_the_config() {
+    let config: Config = toml::from_str(r#"
+        [[bridges]]
+        name = "tools"
+        room = "ops"
+        connect = "unix:/run/tools.sock"
+    "#).unwrap();
+
+    assert_eq!(config.bridges[0].name, "tools");
+    assert_eq!(config.bridges[0].connect, "unix:/run/tools.sock");
+}
+
+#[tokio::test]
+async fn messages_flow_both_ways() {
+    let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connect = listen.local_addr().unwrap().to_string();
+    let server = TestServer::start_with(|config| {
+        config.bridges = vec![bridge("lobby", connect)];
+        config.link_retry_secs = 1;
+    }).await;
+    let (tcp, _) = timeout(EXPECT_TIMEOUT, listen.accept()).await.unwrap().unwrap();
+    let mut endpoint = Endpoint::new(tcp);
+
+    let mut alice = server.login("alice").await;
+    endpoint.expect(json!({"type": "join", "room": "lobby", "nick": "alice"})).await;
+    alice.send("hi tools").await;
+    endpoint.expect(json!({"type": "message", "room": "lobby", "nick": "alice", "text": "hi tools"})).await;
+
+    endpoint.send(json!({"type": "join", "nick": "zoe"})).await;
+    alice.expect_line("* zoe@tools has joined").await;
+    endpoint.send_raw("not json").await;
+    endpoint.send(json!({"type": "message", "nick": "bad nick", "text": "hello"})).await;
+    endpoint.send(json!({"type": "message", "nick": "zoe", "text": "hello\u{7} alice"})).await;
+    alice.expect_line("<zoe@tools> hello alice").await;
+
+    // Puppets leave when the connection is lost, and the bridge connects
+    // again.
+    drop(endpoint);
+    alice.expect_line("* zoe@tools has left").await;
+    let (tcp, _) = timeout(Duration::from_secs(3), listen.accept()).await.unwrap().unwrap();
+    let mut endpoint = Endpoint::new(tcp);
+    endpoint.send(json!({"type": "message", "nick": "zoe", "text": "back"})).await;
+    alice.expect_line("<zoe@tools> back").await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn bridges_only_see_their_room() {
+    let path = std::env::temp_dir().join(format!("telnet-chat-bridge-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listen = tokio::net::UnixListener::bind(&path).unwrap();
+    let connect = format!("unix:{}", path.display());
+    let server = TestServer::start_with(|config| config.bridges = vec![bridge("#ops", connect)]).await;
+    let (conn, _) = timeout(EXPECT_TIMEOUT, listen.accept()).await.unwrap().unwrap();
+    let mut endpoint = Endpoint::new(conn);
+
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+    alice.send("not for the bridge").await;
+    bob.send("/join ops").await;
+    endpoint.expect(json!({"type": "join", "room": "ops", "nick": "bob"})).await;
+    bob.send("deploy is done").await;
+    endpoint.expect(json!({"type": "message", "room": "ops", "nick": "bob", "text": "deploy is done"})).await;
+
+    endpoint.send(json!({"type": "message", "room": "lobby", "nick": "ci", "text": "thanks"})).await;
+    bob.expect_line("<ci@tools> thanks").await;
+    alice.expect_no_line("<ci@tools> thanks", Duration::from_millis(200)).await;
+
+    bob.send("/join lobby").await;
+    endpoint.expect(json!({"type": "part", "room": "ops", "nick": "bob"})).await;
+    let _ = std::fs::remove_file(&path);
+}
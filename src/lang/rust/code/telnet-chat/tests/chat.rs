@@ -0,0 +1,122 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::time::{timeout, Instant};
+
+use common::TestServer;
+use telnet_chat::telnet::{Item, option};
+
+#[tokio::test]
+async fn join_is_announced() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let _bob = server.login("bob").await;
+
+    alice.expect_line("* bob has joined").await;
+}
+
+#[tokio::test]
+async fn broadcast_skips_sender() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+    alice.expect_line("* bob has joined").await;
+
+    alice.send("hello").await;
+    bob.expect_line("<alice> hello").await;
+    alice.expect_no_line("<alice> hello", Duration::from_millis(200)).await;
+}
+
+#[tokio::test]
+async fn nick_in_use_is_refused() {
+    let server = TestServer::start().await;
+    let _alice = server.login("alice").await;
+
+    let mut other = server.connect().await;
+    other.send("alice").await;
+    other.expect_line("That nick is already in use.").await;
+}
+
+#[tokio::test]
+async fn leave_notices_say_why() {
+    let server = TestServer::start_with(|config| config.operators = vec!["root".to_string()]).await;
+    server.accounts.set_password("root", "hunter2").unwrap();
+    let mut root = server.login_registered("root", "hunter2").await;
+
+    // Quitting goes without saying.
+    let bob = server.login("bob").await;
+    drop(bob);
+    root.expect_line("* bob has left").await;
+
+    let _carol = server.login("carol").await;
+    root.send("/ban carol 1h spam").await;
+    root.expect_line("* carol has left (banned)").await;
+}
+
+#[tokio::test]
+async fn slow_consumer_is_removed() {
+    let server = TestServer::start().await;
+    let mut fast = server.login("fast").await;
+    // Never reads, so its socket buffers and then its mailbox fill up.
+    let _slow = server.login("slow").await;
+    fast.expect_line("* slow has joined").await;
+
+    let line = "x".repeat(1000);
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        assert!(Instant::now() < deadline, "The slow client was never removed.");
+
+        for _ in 0..100 {
+            fast.send(&line).await;
+        }
+        let left = timeout(
+            Duration::from_millis(50),
+            fast.expect_line("* slow has left (can't keep up)"),
+        ).await;
+        if left.is_ok() {
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn telnet_negotiation_replies() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    // WILL suppress go-ahead is accepted.
+    client.send_raw(&[255, 251, option::SUPPRESS_GO_AHEAD]).await;
+    client.expect_item(Item::Do(option::SUPPRESS_GO_AHEAD)).await;
+
+    // Other options are refused.
+    client.send_raw(&[255, 251, 34]).await;
+    client.expect_item(Item::Dont(34)).await;
+    client.send_raw(&[255, 253, 24]).await;
+    client.expect_item(Item::Wont(24)).await;
+
+    // Agreeing again, or refusing what was never enabled, is not answered,
+    // so the reply to the next request is the next item.
+    client.send_raw(&[255, 251, option::SUPPRESS_GO_AHEAD]).await;
+    client.send_raw(&[255, 252, 34]).await;
+    client.send_raw(&[255, 252, option::SUPPRESS_GO_AHEAD]).await;
+    client.expect_next_item(Item::Dont(option::SUPPRESS_GO_AHEAD)).await;
+
+    // Are you there?
+    client.send_raw(&[255, 246]).await;
+    client.expect_line("Yes.").await;
+}
+
+#[tokio::test]
+async fn unknown_commands_are_survived() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    // An unknown command, a stray SE, a subnegotiation, a Break and a Data
+    // Mark that discards the typo before it.
+    alice.send_raw(b"\xff\x07\xff\xf0\xff\xfa\x18\x00xterm\xff\xf0\xff\xf3").await;
+    alice.send_raw(b"typo\xff\xf2").await;
+    alice.send("still here").await;
+    bob.expect_line("<alice> still here").await;
+}
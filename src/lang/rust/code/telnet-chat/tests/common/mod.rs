@@ -0,0 +1,368 @@
+//! Test harness: an in-process server on an ephemeral port, and scripted
+//! clients with expect-style assertions.
+//!
+//!     let server = TestServer::start().await;
+//!     let mut alice = server.login("alice").await;
+//!     let mut bob = server.login("bob").await;
+//!     alice.send("hello").await;
+//!     bob.expect_line("<alice> hello").await;
+
+#![allow(dead_code)]
+
+pub mod sim;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::stream::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::{timeout, Instant};
+use tokio_util::codec::FramedRead;
+
+use telnet_chat::accounts::AccountStore;
+use telnet_chat::announcements::Motd;
+use telnet_chat::config::{Config, LinkConfig};
+use telnet_chat::main_loop::ServerHandle;
+use telnet_chat::memos::MemoStore;
+use telnet_chat::moderation::Moderation;
+use telnet_chat::plugins::Plugins;
+use telnet_chat::telnet::{Item, TelnetCodec};
+use telnet_chat::telnet_client::TelnetClient;
+
+/// How long `expect_*` waits before failing.
+pub const EXPECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A server running in this test's runtime. It stops with the runtime, and
+/// its files are deleted when this is dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    /// Where other servers link to this one.
+    pub link_addr: SocketAddr,
+    /// Where SSH clients connect, if the config has an `ssh_bind`.
+    pub ssh_addr: Option<SocketAddr>,
+    pub config: Config,
+    pub accounts: Arc<AccountStore>,
+    handle: ServerHandle,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    pub async fn start() -> TestServer {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Start a server, letting the test change the config first. The file
+    /// paths already point into a fresh temporary directory.
+    pub async fn start_with(tweak: impl FnOnce(&mut Config)) -> TestServer {
+        Self::start_with_plugins(tweak, |_| {}).await
+    }
+
+    /// Like `start_with`, but the test can also add plugins to the ones in
+    /// the config.
+    pub async fn start_with_plugins(
+        tweak: impl FnOnce(&mut Config),
+        add: impl FnOnce(&mut Plugins),
+    ) -> TestServer {
+        let (config, accounts, handle, dir) = start_main_loop(tweak, add);
+
+        let links = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let link_addr = links.local_addr().unwrap();
+        telnet_chat::federation::start_links_on(links, &config, handle.clone());
+
+        let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listen.local_addr().unwrap();
+        let proxies = config.trusted_proxies.clone();
+        tokio::spawn(telnet_chat::accept::start_accept_on(listen, proxies, handle.clone()));
+
+        let ssh_addr = match config.ssh_bind {
+            Some(bind) => {
+                let listen = TcpListener::bind(bind).await.unwrap();
+                let ssh_addr = listen.local_addr().unwrap();
+                telnet_chat::ssh::start_ssh_on(listen, &config, handle.clone()).unwrap();
+                Some(ssh_addr)
+            },
+            None => None,
+        };
+
+        TestServer { addr, link_addr, ssh_addr, config, accounts, handle, dir }
+    }
+
+    /// Connect to another server, and keep the link up.
+    pub fn link_to(&self, other: &TestServer) {
+        let name = other.config.server_name.clone();
+        telnet_chat::federation::connect_link(other.link_addr, name, &self.config, self.handle.clone());
+    }
+
+    pub async fn connect(&self) -> TestClient {
+        let tcp = TcpStream::connect(self.addr).await.unwrap();
+        let (read, write) = tcp.into_split();
+        TestClient::new(read, write)
+    }
+
+    /// Connect and log in with an unregistered nick.
+    pub async fn login(&self, nick: &str) -> TestClient {
+        let mut client = self.connect().await;
+        client.send(nick).await;
+        client.expect_line("* You are now in #lobby.").await;
+        client
+    }
+
+    /// Connect and log in with a nick registered with this password.
+    pub async fn login_registered(&self, nick: &str, password: &str) -> TestClient {
+        let mut client = self.connect().await;
+        // The password prompt doesn't end the line, so it can't be awaited.
+        client.send(nick).await;
+        client.send(password).await;
+        client.expect_line("* You are now in #lobby.").await;
+        client
+    }
+
+    /// Connect with a `TelnetClient` that can show the full-screen layout,
+    /// and wait for it to be drawn.
+    pub async fn connect_full_screen(&self) -> TelnetClient<TcpStream> {
+        let mut client = TelnetClient::connect(self.addr).await.unwrap();
+        client.set_terminal_type(Some("xterm".to_string()));
+        client.set_window_size(60, 12).await.unwrap();
+        client.start().await.unwrap();
+        expect_output(&mut client, "\x1b[1;10r").await;
+        client
+    }
+
+    /// Path of a file in this server's temporary directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Make a temporary directory and a config whose files are in it, let the
+/// test change the config, and start the main loop.
+pub fn start_main_loop(
+    tweak: impl FnOnce(&mut Config),
+    add: impl FnOnce(&mut Plugins),
+) -> (Config, Arc<AccountStore>, ServerHandle, PathBuf) {
+    let dir = std::env::temp_dir().join(format!(
+        "telnet-chat-test-{}-{}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::Relaxed),
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut config = Config {
+        accounts_file: dir.join("accounts.txt"),
+        bans_file: dir.join("bans.txt"),
+        audit_log: dir.join("audit.log"),
+        memos_file: dir.join("memos.txt"),
+        motd_file: dir.join("motd.txt"),
+        ssh_host_key: dir.join("ssh_host_key"),
+        ssh_authorized_keys: dir.join("authorized_keys"),
+        shards: 2,
+        ..Config::default()
+    };
+    tweak(&mut config);
+
+    let accounts = Arc::new(AccountStore::load(config.accounts_file.clone()).unwrap());
+    let moderation = Moderation::load(&config).unwrap();
+    let memos = MemoStore::load(&config).unwrap();
+    let motd = Motd::load(&config).unwrap();
+    let mut plugins = Plugins::from_config(&config);
+    add(&mut plugins);
+    let (handle, _join) = telnet_chat::main_loop::spawn_main_loop(
+        &config,
+        accounts.clone(),
+        moderation,
+        memos,
+        motd,
+        plugins,
+    );
+    telnet_chat::announcements::start_announcements(&config, handle.clone());
+
+    (config, accounts, handle, dir)
+}
+
+/// A telnet connection to the test server. What the server sends is decoded
+/// with the server's own `TelnetCodec`, so lines and option negotiation come
+/// out as `Item`s. The simulation tests use it over in-memory connections.
+pub struct TestClient<R = OwnedReadHalf, W = OwnedWriteHalf> {
+    read: FramedRead<R, TelnetCodec>,
+    write: W,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> TestClient<R, W> {
+    pub fn new(read: R, write: W) -> Self {
+        TestClient {
+            read: FramedRead::new(read, TelnetCodec::new()),
+            write,
+        }
+    }
+
+    /// Send a line.
+    pub async fn send(&mut self, line: &str) {
+        self.write.write_all(line.as_bytes()).await.unwrap();
+        self.write.write_all(b"\r\n").await.unwrap();
+    }
+
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.write.write_all(bytes).await.unwrap();
+    }
+
+    /// Read until a line matches. Prompts such as `Nick: ` don't end the
+    /// line, so a line also matches if it ends with `expected`. Other lines
+    /// are skipped.
+    pub async fn expect_line(&mut self, expected: &str) {
+        let found = self.expect(|item| match item {
+            Item::Line(line) => line.ends_with(expected.as_bytes()),
+            _ => false,
+        }).await;
+
+        if let Err(seen) = found {
+            panic!("Expected line {:?}, got {:#?}", expected, seen);
+        }
+    }
+
+    /// Read until a line contains `part`, and return it.
+    pub async fn expect_line_containing(&mut self, part: &str) -> String {
+        let found = self.expect(|item| match item {
+            Item::Line(line) => String::from_utf8_lossy(line).contains(part),
+            _ => false,
+        }).await;
+
+        match found {
+            Ok(Item::Line(line)) => String::from_utf8_lossy(&line).into_owned(),
+            Ok(item) => unreachable!("{:?} is not a line", item),
+            Err(seen) => panic!("Expected a line with {:?}, got {:#?}", part, seen),
+        }
+    }
+
+    /// Read until this item arrives, skipping others.
+    pub async fn expect_item(&mut self, expected: Item) {
+        let found = self.expect(|item| *item == expected).await;
+
+        if let Err(seen) = found {
+            panic!("Expected {:?}, got {:#?}", expected, seen);
+        }
+    }
+
+    /// Fail unless the next item, skipping lines, is this one.
+    pub async fn expect_next_item(&mut self, expected: Item) {
+        let next = timeout(EXPECT_TIMEOUT, async {
+            loop {
+                match self.read.next().await {
+                    Some(Ok(Item::Line(_))) => {},
+                    Some(Ok(item)) => return Some(item),
+                    Some(Err(_)) | None => return None,
+                }
+            }
+        }).await;
+
+        match next {
+            Ok(Some(item)) if item == expected => {},
+            next => panic!("Expected {:?} next, got {:?}", expected, next),
+        }
+    }
+
+    /// Fail if a matching line arrives within `wait`.
+    pub async fn expect_no_line(&mut self, unexpected: &str, wait: Duration) {
+        let deadline = Instant::now() + wait;
+        while let Ok(item) = tokio::time::timeout_at(deadline, self.read.next()).await {
+            match item {
+                Some(Ok(Item::Line(line))) if line.ends_with(unexpected.as_bytes()) => {
+                    panic!("Did not expect line {:?}", unexpected);
+                },
+                Some(Ok(_)) => {},
+                Some(Err(_)) | None => return,
+            }
+        }
+    }
+
+    /// Read until the server closes the connection.
+    pub async fn expect_closed(&mut self) {
+        let res = timeout(EXPECT_TIMEOUT, async {
+            loop {
+                match self.read.next().await {
+                    Some(Ok(_)) => {},
+                    Some(Err(_)) | None => return,
+                }
+            }
+        }).await;
+
+        if res.is_err() {
+            panic!("Expected the connection to be closed.");
+        }
+    }
+
+    /// Returns the matching item, or the items read before the timeout if
+    /// nothing matched.
+    async fn expect(
+        &mut self,
+        mut matches: impl FnMut(&Item) -> bool,
+    ) -> Result<Item, Vec<Item>> {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        let mut seen = Vec::new();
+
+        while let Ok(item) = tokio::time::timeout_at(deadline, self.read.next()).await {
+            match item {
+                Some(Ok(item)) if matches(&item) => return Ok(item),
+                Some(Ok(item)) => seen.push(item),
+                Some(Err(_)) | None => break,
+            }
+        }
+
+        Err(seen)
+    }
+}
+
+impl TestClient {
+    /// Close the connection with a reset instead of a goodbye, as when the
+    /// network fails.
+    pub fn reset(self) {
+        let tcp = self.read.into_inner().reunite(self.write).unwrap();
+        tcp.set_linger(Some(Duration::from_secs(0))).unwrap();
+    }
+}
+
+/// Name the server, and let it link with the servers in `peers`. The links
+/// all use the password `secret`, and are made again after a second.
+pub fn linkable(config: &mut Config, name: &str, peers: &[&str]) {
+    config.server_name = name.to_string();
+    config.link_retry_secs = 1;
+    config.links = peers.iter()
+        .map(|peer| LinkConfig {
+            name: peer.to_string(),
+            password: "secret".to_string(),
+            connect: None,
+        })
+        .collect();
+}
+
+/// Read from a `TelnetClient` until the output so far contains `expected`.
+/// Returns the output after it.
+pub async fn expect_output(client: &mut TelnetClient<TcpStream>, expected: &str) -> String {
+    let mut seen = String::new();
+    let res = timeout(EXPECT_TIMEOUT, async {
+        while let Some(item) = client.next().await {
+            if let Item::Data(data) = item.unwrap() {
+                seen.push_str(&String::from_utf8_lossy(&data));
+                if let Some(i) = seen.find(expected) {
+                    return seen[i + expected.len()..].to_string();
+                }
+            }
+        }
+        panic!("Disconnected");
+    }).await;
+
+    res.unwrap_or_else(|_| panic!("Expected {:?}, got {:?}", expected, seen))
+}
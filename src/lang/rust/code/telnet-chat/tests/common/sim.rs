@@ -0,0 +1,188 @@
+//! Simulation harness: the accept loop, client actors and main loop of a real
+//! server, but with in-memory connections, meant to run on tokio's paused
+//! clock. Time only moves when every task is waiting, so a test sees the same
+//! interleaving every run, and `expect_*` timeouts cost no real time.
+//!
+//!     #[tokio::test(start_paused = true)]
+//!     async fn slow_readers_are_dropped() {
+//!         let sim = Sim::start();
+//!         let mut alice = sim.login("alice").await;
+//!         let slow = sim.connect_with(64);
+//!         ...
+//!     }
+//!
+//! Faults are injected from the client side: a client that never reads from
+//! a small connection is a slow reader, dropping a client closes the
+//! connection abruptly, and `send_raw` can split telnet commands anywhere.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::StreamExt;
+use tokio::io::{duplex, split, AsyncRead, AsyncWrite, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep, timeout};
+
+use telnet_chat::accept::{self, Listener};
+use telnet_chat::config::Config;
+use telnet_chat::telnet::Item;
+
+use super::{start_main_loop, TestClient, EXPECT_TIMEOUT};
+
+/// How many bytes a connection holds in each direction, unless the test
+/// asks for less.
+const BUFFER: usize = 64 * 1024;
+
+pub type SimClient = TestClient<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+/// A server whose clients connect in memory. Its files are deleted when this
+/// is dropped.
+pub struct Sim {
+    pub config: Config,
+    conns: UnboundedSender<(SimConn, SocketAddr)>,
+    open: Arc<AtomicUsize>,
+    next_ip: AtomicU32,
+    dir: PathBuf,
+}
+
+impl Sim {
+    pub fn start() -> Sim {
+        Self::start_with(|_| {})
+    }
+
+    /// Start a server, letting the test change the config first.
+    pub fn start_with(tweak: impl FnOnce(&mut Config)) -> Sim {
+        let (config, _, handle, dir) = start_main_loop(tweak, |_| {});
+        let (conns, recv) = unbounded_channel();
+        let proxies = config.trusted_proxies.clone();
+        tokio::spawn(accept::start_accept_on(SimListener(recv), proxies, handle));
+
+        Sim {
+            config,
+            conns,
+            open: Arc::new(AtomicUsize::new(0)),
+            next_ip: AtomicU32::new(1),
+            dir,
+        }
+    }
+
+    pub fn connect(&self) -> SimClient {
+        self.connect_with(BUFFER)
+    }
+
+    /// Connect with room for only `buffer` bytes in each direction. Each
+    /// client gets an address of its own.
+    pub fn connect_with(&self, buffer: usize) -> SimClient {
+        let (client, server) = duplex(buffer);
+        // 10.0.0.1, 10.0.0.2 and so on.
+        let ip = Ipv4Addr::from(0x0a00_0000 + self.next_ip.fetch_add(1, Ordering::Relaxed));
+        let addr = SocketAddr::from((ip, 50000));
+
+        self.open.fetch_add(1, Ordering::Relaxed);
+        let conn = SimConn { stream: server, open: self.open.clone() };
+        self.conns.send((conn, addr)).expect("The accept loop stopped.");
+
+        let (read, write) = split(client);
+        TestClient::new(read, write)
+    }
+
+    /// Connect and log in with an unregistered nick.
+    pub async fn login(&self, nick: &str) -> SimClient {
+        let mut client = self.connect();
+        client.send(nick).await;
+        client.expect_line("* You are now in #lobby.").await;
+        client
+    }
+
+    /// Wait until the server has nothing left to do. The paused clock only
+    /// moves once every task is waiting, so a short sleep is enough.
+    pub async fn settle(&self) {
+        sleep(Duration::from_millis(1)).await;
+    }
+
+    /// How many connections the server has not dropped yet. A client actor
+    /// owns its connection, so this counts the actors that are still alive.
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> TestClient<R, W> {
+    /// Read lines until none arrive for a while. On the paused clock that is
+    /// as soon as the server has nothing left to do. Stops early if the
+    /// connection is closed.
+    pub async fn lines_until_idle(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(Some(Ok(item))) = timeout(EXPECT_TIMEOUT, self.read.next()).await {
+            if let Item::Line(line) = item {
+                lines.push(String::from_utf8_lossy(&line).into_owned());
+            }
+        }
+        lines
+    }
+}
+
+/// The nick and text of a chat message, from a line such as
+/// `[12] <alice> hello`.
+pub fn said(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let rest = &rest[rest.find("] <")? + 3..];
+    let end = rest.find("> ")?;
+    Some((&rest[..end], &rest[end + 2..]))
+}
+
+struct SimListener(UnboundedReceiver<(SimConn, SocketAddr)>);
+
+impl Listener for SimListener {
+    type Conn = SimConn;
+
+    async fn accept(&mut self) -> io::Result<(SimConn, SocketAddr)> {
+        self.0.recv().await.ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// The server end of a connection, which counts itself as open until it is
+/// dropped.
+#[derive(Debug)]
+struct SimConn {
+    stream: DuplexStream,
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for SimConn {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for SimConn {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SimConn {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,258 @@
+mod common;
+
+use std::time::Duration;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use common::{TestServer, linkable};
+use telnet_chat::config::{FilterAction, FilterConfig, FilterRule};
+use telnet_chat::federation::Event;
+
+/// Link to `server` as the server `name`, with the password `linkable` sets,
+/// so the test can send what a real server wouldn't.
+async fn fake_peer(server: &TestServer, name: &str) -> Framed<TcpStream, LinesCodec> {
+    let (mut peer, nonce) = hello(server, name).await;
+
+    // The same hash as `federation::auth_hash`, for the side that connected.
+    let mut salt = b"connected ".to_vec();
+    salt.extend_from_slice(&[0; 16]);
+    salt.extend_from_slice(&nonce);
+    salt.extend_from_slice(name.as_bytes());
+    let params = Params::new(4 * 1024, 1, 1, Some(32)).unwrap();
+    let mut auth = vec![0; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(b"secret", &salt, &mut auth)
+        .unwrap();
+    let auth: String = auth.iter().map(|b| format!("{:02x}", b)).collect();
+    peer.send(format!("AUTH {}", auth)).await.unwrap();
+
+    let answer = peer.next().await.unwrap().unwrap();
+    assert!(answer.starts_with("AUTH "), "{}", answer);
+    peer
+}
+
+/// Connect to the link port of `server` as the server `name`, with a nonce
+/// of zeros. Returns the nonce of `server`.
+async fn hello(server: &TestServer, name: &str) -> (Framed<TcpStream, LinesCodec>, Vec<u8>) {
+    let tcp = TcpStream::connect(server.link_addr).await.unwrap();
+    let mut peer = Framed::new(tcp, LinesCodec::new());
+
+    let hello = peer.next().await.unwrap().unwrap();
+    let nonce = hello.rsplit(' ').next().unwrap();
+    let nonce = (0..nonce.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&nonce[i..i + 2], 16).unwrap())
+        .collect();
+    peer.send(format!("HELLO {} {}", name, "00".repeat(16))).await.unwrap();
+    (peer, nonce)
+}
+
+#[test]
+fn events_round_trip() {
+    let events = [
+        Event::Server("west".to_string()),
+        Event::Squit("west".to_string()),
+        Event::User {
+            server: "west".to_string(),
+            nick: "bob".to_string(),
+            room: "lobby".to_string(),
+            since: 1700000000,
+        },
+        Event::Join { nick: "bob".to_string(), room: "den".to_string() },
+        Event::Quit { server: "west".to_string(), nick: "bob".to_string(), reason: "can't keep up".to_string() },
+        Event::Msg { nick: "bob".to_string(), room: "den".to_string(), text: "hi  there".to_string() },
+        Event::Priv { from: "bob".to_string(), to: "alice".to_string(), text: "psst".to_string() },
+    ];
+    for event in events {
+        assert_eq!(Event::parse(&event.to_string()), Some(event));
+    }
+
+    assert_eq!(Event::parse("USER west bob lobby"), None);
+    assert_eq!(Event::parse("JOIN bob"), None);
+    assert_eq!(Event::parse("NICK bob"), None);
+}
+
+#[tokio::test]
+async fn users_on_linked_servers_share_rooms() {
+    let east = TestServer::start_with(|config| linkable(config, "east", &["west"])).await;
+    let west = TestServer::start_with(|config| linkable(config, "west", &["east"])).await;
+    let mut alice = east.login("alice").await;
+    west.link_to(&east);
+    let mut bob = west.login("bob").await;
+    alice.expect_line("* bob has joined").await;
+
+    bob.send("hi").await;
+    alice.expect_line("<bob> hi").await;
+    alice.send("hello").await;
+    bob.expect_line("<alice> hello").await;
+
+    alice.send("/join den").await;
+    bob.expect_line("* alice has left").await;
+    bob.send("/join den").await;
+    alice.expect_line("* bob has joined").await;
+    alice.send("/whois bob").await;
+    alice.expect_line("* bob: in #den, on west").await;
+
+    bob.send("/msg Alice psst").await;
+    bob.expect_line("[you -> alice] psst").await;
+    alice.expect_line("[bob -> you] psst").await;
+
+    // The nick is taken on the other server.
+    let mut client = east.connect().await;
+    client.send("bob").await;
+    client.expect_line("That nick is already in use.").await;
+}
+
+#[tokio::test]
+async fn nick_collisions_keep_the_first_login() {
+    let east = TestServer::start_with(|config| linkable(config, "east", &["west"])).await;
+    let west = TestServer::start_with(|config| linkable(config, "west", &["east"])).await;
+    let mut first = east.login("alice").await;
+    let mut second = west.login("alice").await;
+    let mut bob = west.login("bob").await;
+
+    west.link_to(&east);
+    second.expect_line("* Nick collision: alice logged in on east first.").await;
+    second.expect_closed().await;
+    bob.expect_line("* alice has joined").await;
+    first.expect_line("* bob has joined").await;
+
+    bob.send("hi alice").await;
+    first.expect_line("<bob> hi alice").await;
+}
+
+#[tokio::test]
+async fn netsplits_are_announced_and_healed() {
+    let east = TestServer::start_with(|config| {
+        linkable(config, "east", &["west"]);
+        config.operators = vec!["root".to_string()];
+    }).await;
+    let west = TestServer::start_with(|config| linkable(config, "west", &["east"])).await;
+    east.accounts.set_password("root", "hunter2").unwrap();
+    let mut root = east.login_registered("root", "hunter2").await;
+    west.link_to(&east);
+    let mut bob = west.login("bob").await;
+    root.expect_line("* bob has joined").await;
+
+    root.send("/links").await;
+    root.expect_line("* Linked servers: west").await;
+    root.send("/squit west").await;
+    root.expect_line("* bob has left (netsplit)").await;
+    root.expect_line("* Closed the link to west.").await;
+    bob.expect_line("* root has left (netsplit)").await;
+
+    // West connects again a second later, and both sides send who they have.
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    bob.expect_line("* root has joined").await;
+    root.expect_line("* bob has joined").await;
+}
+
+#[tokio::test]
+async fn servers_are_linked_only_once() {
+    let east = TestServer::start_with(|config| linkable(config, "east", &["west", "north"])).await;
+    let west = TestServer::start_with(|config| linkable(config, "west", &["east", "north"])).await;
+    let north = TestServer::start_with(|config| linkable(config, "north", &["west", "east"])).await;
+    let mut alice = east.login("alice").await;
+    west.link_to(&east);
+    north.link_to(&west);
+    let mut carol = north.login("carol").await;
+    alice.expect_line("* carol has joined").await;
+
+    alice.send("/links").await;
+    alice.expect_line("* Linked servers: north (via west), west").await;
+
+    // North is already reachable through west, so a direct link would make
+    // a loop. It is refused, and the rooms stay as they are.
+    east.link_to(&north);
+    alice.expect_no_line("* carol has left (netsplit)", Duration::from_millis(500)).await;
+    alice.send("/links").await;
+    alice.expect_line("* Linked servers: north (via west), west").await;
+    alice.send("hi carol").await;
+    carol.expect_line("<alice> hi carol").await;
+    carol.expect_no_line("<alice> hi carol", Duration::from_millis(200)).await;
+}
+
+#[tokio::test]
+async fn remote_nicks_follow_the_local_rules() {
+    let east = TestServer::start_with(|config| linkable(config, "east", &["west"])).await;
+    let mut alice = east.login("alice").await;
+    let mut west = fake_peer(&east, "west").await;
+
+    for nick in ["{red}bob", "*bob*", &"x".repeat(17)] {
+        west.send(format!("USER west {} lobby 1700000000", nick)).await.unwrap();
+    }
+    west.send("USER west carol lobby 1700000000".to_string()).await.unwrap();
+
+    let lines = alice.lines_until_idle().await;
+    let joins: Vec<&String> = lines.iter().filter(|line| line.ends_with(" has joined")).collect();
+    assert_eq!(joins, ["* carol has joined"]);
+
+    // Nor can the others be talked to, or talk.
+    west.send("MSG *bob* lobby hi".to_string()).await.unwrap();
+    alice.expect_no_line("hi", Duration::from_millis(200)).await;
+    alice.send("/whois *bob*").await;
+    alice.expect_line("* *bob* is not online.").await;
+    alice.send("/whois carol").await;
+    alice.expect_line("* carol: in #lobby, on west").await;
+}
+
+#[tokio::test]
+async fn messages_from_linked_servers_are_filtered_here_only() {
+    let east = TestServer::start_with(|config| {
+        linkable(config, "east", &["west", "north"]);
+        config.filters = vec![FilterConfig {
+            rule: FilterRule::Blocklist { words: vec!["heck".to_string()] },
+            action: FilterAction::Drop,
+            rooms: Default::default(),
+        }];
+    }).await;
+    let mut alice = east.login("alice").await;
+    let mut west = fake_peer(&east, "west").await;
+    let mut north = fake_peer(&east, "north").await;
+    // East is linked with north once it sends its burst.
+    while !north.next().await.unwrap().unwrap().starts_with("USER east alice ") {}
+
+    west.send("USER west carol lobby 1700000000".to_string()).await.unwrap();
+    alice.expect_line("* carol has joined").await;
+    for event in ["MSG carol lobby oh heck", "MSG carol lobby fine", "PRIV carol alice heck no", "PRIV carol alice ok"] {
+        west.send(event.to_string()).await.unwrap();
+    }
+    let lines = alice.lines_until_idle().await;
+    assert!(lines.iter().any(|line| line.ends_with("<carol> fine")), "{:?}", lines);
+    assert!(lines.iter().any(|line| line == "[carol -> you] ok"), "{:?}", lines);
+    assert!(!lines.iter().any(|line| line.contains("heck")), "{:?}", lines);
+
+    // The other servers have their own rules.
+    let mut relayed = Vec::new();
+    while !relayed.iter().any(|line| line == "MSG carol lobby fine") {
+        let line = tokio::time::timeout(Duration::from_secs(1), north.next()).await.unwrap();
+        relayed.push(line.unwrap().unwrap());
+    }
+    assert!(relayed.iter().any(|line| line == "MSG carol lobby oh heck"), "{:?}", relayed);
+}
+
+#[tokio::test]
+async fn proofs_cant_be_passed_on_between_servers() {
+    let east = TestServer::start_with(|config| linkable(config, "east", &["west"])).await;
+    let west = TestServer::start_with(|config| linkable(config, "west", &["east"])).await;
+    let mut alice = west.login("alice").await;
+
+    // Pretend to be east to west, and to be west to east, to get a proof out
+    // of east that west would take.
+    let (mut to_west, _) = hello(&west, "east").await;
+    let (mut to_east, _) = hello(&east, "west").await;
+    let answer = tokio::time::timeout(Duration::from_millis(500), to_east.next()).await;
+    assert!(answer.is_err(), "east answered first: {:?}", answer);
+
+    // Without one, both links fail.
+    for peer in [&mut to_west, &mut to_east] {
+        peer.send(format!("AUTH {}", "00".repeat(32))).await.unwrap();
+        assert!(!matches!(peer.next().await, Some(Ok(line)) if line.starts_with("AUTH ")));
+    }
+    alice.send("/links").await;
+    alice.expect_line("* This server is not linked to others.").await;
+}
@@ -0,0 +1,213 @@
+mod common;
+
+use std::time::Duration;
+
+use telnet_chat::config::{Config, FilterAction, FilterConfig, FilterRule};
+
+use common::TestServer;
+
+fn filter(rule: FilterRule, action: FilterAction, rooms: &[(&str, FilterAction)]) -> FilterConfig {
+    FilterConfig {
+        rule,
+        action,
+        rooms: rooms.iter().map(|(room, action)| (room.to_string(), *action)).collect(),
+    }
+}
+
+fn blocklist() -> FilterRule {
+    FilterRule::Blocklist { words: vec!["heck".to_string(), "darn it".to_string()] }
+}
+
+#[test]
+fn filters_are_read_from_the_config() {
+    let config: Config = toml::from_str(r#"
+        [[filters]]
+        kind = "blocklist"
+        words = ["heck"]
+        action = "mask"
+        rooms = { kids = "mute", offtopic = "allow" }
+
+        [[filters]]
+        kind = "max-length"
+        max = 300
+        action = "drop"
+    "#).unwrap();
+
+    assert!(matches!(&config.filters[0].rule, FilterRule::Blocklist { words } if words == &["heck"]));
+    assert_eq!(config.filters[0].rooms["kids"], FilterAction::Mute);
+    assert!(matches!(config.filters[1].rule, FilterRule::MaxLength { max: 300 }));
+    assert_eq!(config.filters[1].action, FilterAction::Drop);
+
+    let bad: Result<Config, _> = toml::from_str(r#"
+        [[filters]]
+        kind = "links"
+        action = "explode"
+    "#);
+    assert!(bad.is_err());
+}
+
+#[tokio::test]
+async fn messages_are_masked_and_dropped() {
+    let server = TestServer::start_with(|config| {
+        config.filters = vec![
+            filter(blocklist(), FilterAction::Mask, &[]),
+            filter(FilterRule::Links, FilterAction::Mask, &[]),
+            filter(FilterRule::MaxLength { max: 40 }, FilterAction::Drop, &[]),
+            filter(FilterRule::Duplicates { within_secs: 60 }, FilterAction::Drop, &[]),
+        ];
+    }).await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    alice.send("Heck, darn it! Checking is fine").await;
+    bob.expect_line("<alice> ****, *******! Checking is fine").await;
+    alice.expect_line("* Parts of your message were hidden: blocked words.").await;
+
+    alice.send("see https://example.com/x?y=1 or www.example.org").await;
+    bob.expect_line("<alice> see [link removed] or [link removed]").await;
+
+    alice.send(&"a".repeat(41)).await;
+    alice.expect_line("* Your message was not sent: longer than 40 characters.").await;
+
+    alice.send("hello").await;
+    bob.expect_line("<alice> hello").await;
+    alice.send("Hello").await;
+    alice.expect_line("* Your message was not sent: repeated message.").await;
+    bob.expect_no_line("<alice> Hello", Duration::from_millis(200)).await;
+
+    let audit = std::fs::read_to_string(&server.config.audit_log).unwrap();
+    assert!(audit.contains("filter mask message by alice in #lobby: blocked words"), "{}", audit);
+    assert!(audit.contains("filter drop message by alice in #lobby: repeated message"), "{}", audit);
+}
+
+#[tokio::test]
+async fn everything_written_for_others_is_filtered() {
+    let server = TestServer::start_with(|config| {
+        config.filters = vec![
+            filter(blocklist(), FilterAction::Mask, &[]),
+            filter(FilterRule::MaxLength { max: 20 }, FilterAction::Mask, &[]),
+        ];
+    }).await;
+    server.accounts.set_password("alice", "hunter2").unwrap();
+    let mut alice = server.login_registered("alice", "hunter2").await;
+    let mut bob = server.login("bob").await;
+    // Whoever creates a room can set its topic.
+    alice.send("/join dev").await;
+    alice.expect_line("* You are now in #dev.").await;
+    bob.send("/join dev").await;
+    alice.expect_line("* bob has joined").await;
+
+    alice.send("/msg bob heck").await;
+    bob.expect_line("[alice -> you] ****").await;
+    alice.send("/topic what the heck").await;
+    bob.expect_line("* alice set the topic: what the ****").await;
+    alice.send("/poll heck? yes heck").await;
+    bob.expect_line_containing(r#"* alice started a poll: "****?""#).await;
+    bob.expect_line("*   2. ****").await;
+
+    // Each line of a paste is checked on its own, and they stay apart.
+    alice.send("/paste oh heck").await;
+    alice.send("heck").await;
+    alice.send(&"a".repeat(30)).await;
+    alice.send(".").await;
+    bob.expect_line("* alice pasted #1 (2 lines): oh ****. Use /show 1 to see it.").await;
+    bob.send("/show 1").await;
+    bob.expect_line("| ****").await;
+    bob.expect_line(&format!("| {}", "a".repeat(20))).await;
+}
+
+#[tokio::test]
+async fn lines_are_checked_on_their_own() {
+    let regex = |pattern: &str, name: &str| FilterRule::Regex {
+        pattern: pattern.to_string(),
+        name: Some(name.to_string()),
+    };
+    let server = TestServer::start_with(|config| {
+        config.filters = vec![
+            filter(regex(r"\n", "newlines"), FilterAction::Drop, &[]),
+            filter(regex(r"\s+", "spaces"), FilterAction::Mask, &[]),
+            filter(FilterRule::Duplicates { within_secs: 60 }, FilterAction::Drop, &[]),
+        ];
+    }).await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    // No line has a newline to match, and masks stay within their line.
+    alice.send("/paste my code").await;
+    alice.send("a b").await;
+    alice.send("}").await;
+    alice.send("}").await;
+    alice.send(".").await;
+    bob.expect_line("* alice pasted #1 (3 lines): my*code. Use /show 1 to see it.").await;
+    bob.send("/show 1").await;
+    bob.expect_line("| a*b").await;
+    bob.expect_line("| }").await;
+    bob.expect_line("| }").await;
+
+    alice.send(r#"/poll "where to" "the park" beach"#).await;
+    bob.expect_line_containing(r#"* alice started a poll: "where*to""#).await;
+    bob.expect_line("*   1. the*park").await;
+    bob.expect_line("*   2. beach").await;
+}
+
+#[tokio::test]
+async fn muted_users_can_write_nothing_for_others() {
+    let server = TestServer::start_with(|config| {
+        config.filters = vec![filter(blocklist(), FilterAction::Mute, &[])];
+    }).await;
+    server.accounts.set_password("alice", "hunter2").unwrap();
+    let mut alice = server.login_registered("alice", "hunter2").await;
+    let mut bob = server.login("bob").await;
+    // Whoever creates a room can set its topic.
+    alice.send("/join dev").await;
+    alice.expect_line("* You are now in #dev.").await;
+    bob.send("/join dev").await;
+    alice.expect_line("* bob has joined").await;
+
+    alice.send("heck").await;
+    bob.expect_line_containing("* alice was muted for").await;
+    for command in ["/msg bob hi", "/topic hi", "/poll hi? yes no"] {
+        alice.send(command).await;
+        alice.expect_line("* You are muted in this room.").await;
+    }
+    alice.send("/paste").await;
+    alice.send("hi").await;
+    alice.send(".").await;
+    alice.expect_line("* You are muted in this room.").await;
+    let lines = bob.lines_until_idle().await;
+    assert!(!lines.iter().any(|line| line.contains("hi")), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn rooms_can_override_the_action() {
+    let server = TestServer::start_with(|config| {
+        config.filters = vec![
+            filter(blocklist(), FilterAction::Drop, &[("#kids", FilterAction::Mute), ("offtopic", FilterAction::Allow)]),
+            filter(
+                FilterRule::Regex { pattern: r"\d{3}-\d{4}".to_string(), name: Some("phone numbers".to_string()) },
+                FilterAction::Warn,
+                &[],
+            ),
+        ];
+    }).await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    alice.send("call 555-1234").await;
+    bob.expect_line("<alice> call 555-1234").await;
+    alice.expect_line("* Please keep to the rules of this room: phone numbers.").await;
+
+    alice.send("/join offtopic").await;
+    bob.send("/join offtopic").await;
+    bob.expect_line("* You are now in #offtopic.").await;
+    alice.send("what the heck").await;
+    bob.expect_line("<alice> what the heck").await;
+
+    alice.send("/join kids").await;
+    bob.send("/join kids").await;
+    bob.expect_line("* You are now in #kids.").await;
+    alice.send("what the heck").await;
+    bob.expect_line("* alice was muted for 5m 0s: blocked words").await;
+    alice.send("sorry").await;
+    alice.expect_line("* You are muted in this room.").await;
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use common::TestServer;
+use telnet_chat::markup::{escape, render, supports_colour};
+use telnet_chat::telnet::Item;
+use telnet_chat::telnet_client::TelnetClient;
+
+fn plain(line: &str) -> String {
+    String::from_utf8(render(line.as_bytes(), false)).unwrap()
+}
+
+fn ansi(line: &str) -> String {
+    String::from_utf8(render(line.as_bytes(), true)).unwrap()
+}
+
+#[test]
+fn renders_bold_and_colours() {
+    assert_eq!(ansi("a *b c* d"), "a \x1b[1mb c\x1b[22m d");
+    assert_eq!(ansi("{red}hot{/} dog"), "\x1b[31mhot\x1b[0m dog");
+    // An open colour is closed at the end of the line.
+    assert_eq!(ansi("{blue}sky"), "\x1b[34msky\x1b[0m");
+}
+
+#[test]
+fn plain_text_keeps_what_reads_well() {
+    assert_eq!(plain("a *b c* d"), "a *b c* d");
+    assert_eq!(plain("{red}hot{/} dog"), "hot dog");
+    assert_eq!(plain("<{@alice}> hi"), "<alice> hi");
+}
+
+#[test]
+fn leaves_other_text_alone() {
+    assert_eq!(ansi("* bob has joined"), "* bob has joined");
+    assert_eq!(ansi("2 * 3 = 6"), "2 * 3 = 6");
+    assert_eq!(ansi("{nope} {{red}"), "{nope} {red}");
+}
+
+#[test]
+fn escaped_text_is_shown_as_it_is() {
+    for text in ["if (*p) { *q = 1; }", "{red}x{/} {{ {@bob}", "*", "{", "{*}"] {
+        assert_eq!(ansi(&escape(text)), text);
+        assert_eq!(plain(&escape(text)), text);
+    }
+}
+
+#[test]
+fn nick_colour_is_stable() {
+    assert_eq!(ansi("{@Alice}").replace("Alice", "alice"), ansi("{@alice}"));
+    assert!(ansi("{@alice}").starts_with("\x1b[3"));
+}
+
+#[test]
+fn strips_control_characters() {
+    assert_eq!(ansi("evil\x1b[2J\x07 \u{9b}31m"), "evil[2J 31m");
+    assert_eq!(plain("tab\there"), "tabhere");
+}
+
+#[test]
+fn knows_colour_terminals() {
+    assert!(supports_colour("xterm-256color"));
+    assert!(supports_colour("SCREEN"));
+    assert!(!supports_colour("dumb"));
+    assert!(!supports_colour("vt100"));
+    assert!(!supports_colour("UNKNOWN"));
+}
+
+/// Log in with the given terminal type, and return the line alice sends.
+async fn chat_line_for(terminal_type: Option<&str>) -> String {
+    let server = TestServer::start().await;
+
+    let mut client = TelnetClient::connect(server.addr).await.unwrap();
+    client.set_terminal_type(terminal_type.map(str::to_string));
+    client.start().await.unwrap();
+    client.send_line(b"viewer").await.unwrap();
+    read_line_with(&mut client, "You are now in").await;
+
+    let mut alice = server.login("alice").await;
+    alice.send("*hi* {green}there").await;
+
+    read_line_with(&mut client, "there").await
+}
+
+async fn read_line_with(client: &mut TelnetClient<TcpStream>, text: &str) -> String {
+    let mut seen = String::new();
+    let found = timeout(Duration::from_secs(1), async {
+        while let Some(item) = client.next().await {
+            if let Item::Data(data) = item.unwrap() {
+                seen.push_str(&String::from_utf8_lossy(&data));
+            }
+            let line = seen.split("\r\n").find(|line| line.contains(text));
+            if let Some(line) = line {
+                return line.to_string();
+            }
+        }
+        panic!("Disconnected");
+    }).await;
+
+    found.unwrap_or_else(|_| panic!("No line with {:?} in {:?}", text, seen))
+}
+
+#[tokio::test]
+async fn colour_terminals_get_ansi() {
+    let line = chat_line_for(Some("xterm-256color")).await;
+    assert!(line.contains("\x1b[1mhi\x1b[22m \x1b[32mthere\x1b[0m"), "{:?}", line);
+}
+
+#[tokio::test]
+async fn dumb_terminals_get_plain_text() {
+    let line = chat_line_for(Some("dumb")).await;
+    assert!(line.ends_with("] <alice> *hi* there"), "{:?}", line);
+}
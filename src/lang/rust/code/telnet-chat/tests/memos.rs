@@ -0,0 +1,66 @@
+mod common;
+
+use common::TestServer;
+
+#[tokio::test]
+async fn memos_are_delivered_at_login() {
+    let server = TestServer::start().await;
+    server.accounts.set_password("bob", "hunter2").unwrap();
+    let mut alice = server.login("alice").await;
+
+    alice.send("/memo Bob call me").await;
+    alice.expect_line("* Memo saved. Bob will get it when they log in.").await;
+    alice.send("/msg bob are you there?").await;
+    alice.expect_line("* bob is not online. Use /memo to leave a message.").await;
+
+    let mut bob = server.login_registered("bob", "hunter2").await;
+    bob.expect_line("* You have 1 memo:").await;
+    // Checking the password takes a while, so the memo may be a second old.
+    bob.expect_line("ago: call me").await;
+    drop(bob);
+
+    // They were marked read, and survive a restart of the store.
+    let memos = std::fs::read_to_string(server.path("memos.txt")).unwrap();
+    assert!(memos.contains(" 1 bob alice call me"), "{:?}", memos);
+    let mut bob = server.login_registered("bob", "hunter2").await;
+    bob.send("/whois bob").await;
+    bob.expect_line("* bob: in #lobby, registered, idle 0s").await;
+}
+
+#[tokio::test]
+async fn memos_need_a_registered_offline_nick() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let _carol = server.login("carol").await;
+
+    alice.send("/memo bob hi").await;
+    alice.expect_line("* bob is not a registered nick, so memos can't be kept for them.").await;
+
+    server.accounts.set_password("carol", "secret").unwrap();
+    alice.send("/memo carol hi").await;
+    alice.expect_line("* carol is online. Use /msg instead.").await;
+}
+
+#[tokio::test]
+async fn quotas_limit_unread_memos() {
+    let server = TestServer::start_with(|config| config.memo_quota = 2).await;
+    server.accounts.set_password("bob", "hunter2").unwrap();
+    server.accounts.set_password("dave", "hunter2").unwrap();
+    let mut alice = server.login("alice").await;
+    let mut carol = server.login("carol").await;
+
+    alice.send("/memo bob one").await;
+    alice.send("/memo bob two").await;
+    alice.expect_line("* Memo saved. bob will get it when they log in.").await;
+    alice.expect_line("* Memo saved. bob will get it when they log in.").await;
+    carol.send("/memo bob three").await;
+    carol.expect_line("* bob has too many unread memos.").await;
+
+    alice.send("/memo dave three").await;
+    alice.expect_line("* You have too many memos that were not read yet.").await;
+
+    // Reading them frees the quota.
+    let _bob = server.login_registered("bob", "hunter2").await;
+    alice.send("/memo dave three").await;
+    alice.expect_line("* Memo saved. dave will get it when they log in.").await;
+}
@@ -0,0 +1,217 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use telnet_chat::moderation::{parse_duration, DurationError, MAX_DURATION};
+
+use common::TestServer;
+
+/// Start a server where `root` is a server operator, and log in as root.
+async fn start_with_root() -> (TestServer, common::TestClient) {
+    let server = TestServer::start_with(|config| config.operators = vec!["root".to_string()]).await;
+    server.accounts.set_password("root", "hunter2").unwrap();
+    let root = server.login_registered("root", "hunter2").await;
+    (server, root)
+}
+
+fn audit_log(server: &TestServer) -> String {
+    std::fs::read_to_string(&server.config.audit_log).unwrap()
+}
+
+#[test]
+fn durations_have_units_and_a_limit() {
+    assert_eq!(parse_duration("30s"), Ok(Some(Duration::from_secs(30))));
+    assert_eq!(parse_duration("2h"), Ok(Some(Duration::from_secs(2 * 60 * 60))));
+    assert_eq!(parse_duration("365d"), Ok(Some(MAX_DURATION)));
+    assert_eq!(parse_duration("perm"), Ok(None));
+
+    assert_eq!(parse_duration("366d"), Err(DurationError::TooLong));
+    assert_eq!(parse_duration("200000000000000d"), Err(DurationError::TooLong));
+    assert_eq!(parse_duration("99999999999999999999999s"), Err(DurationError::TooLong));
+    for bad in ["", "d", "10", "10w", "-1h", "+1h", "1.5h", "é"] {
+        assert_eq!(parse_duration(bad), Err(DurationError::Invalid), "{:?}", bad);
+    }
+}
+
+#[tokio::test]
+async fn kicks_move_to_the_lobby_or_disconnect() {
+    let (server, mut root) = start_with_root().await;
+    let mut bob = server.login("bob").await;
+
+    root.send("/join dev").await;
+    root.expect_line("* You are now in #dev.").await;
+    bob.send("/join dev").await;
+    root.expect_line("* bob has joined").await;
+
+    root.send("/kick bob spamming").await;
+    root.expect_line("* bob was kicked by root (spamming)").await;
+    bob.expect_line("* You were kicked from #dev by root (spamming).").await;
+    bob.expect_line("* You are now in #lobby.").await;
+
+    // There is nowhere to go from the lobby.
+    root.send("/join lobby").await;
+    root.expect_line("* You are now in #lobby.").await;
+    root.send("/kick bob").await;
+    bob.expect_line("* You were kicked from #lobby by root (no reason).").await;
+    bob.expect_closed().await;
+    root.expect_line("* bob has left (kicked)").await;
+
+    root.send("/kick nobody").await;
+    root.expect_line("* nobody is not in this room.").await;
+
+    let audit = audit_log(&server);
+    assert!(audit.contains(" root kick bob from #dev: spamming\n"), "{}", audit);
+    assert!(audit.contains(" root kick bob from #lobby: no reason\n"), "{}", audit);
+}
+
+#[tokio::test]
+async fn mutes_end_on_time_or_when_lifted() {
+    let (server, mut root) = start_with_root().await;
+    let mut bob = server.login("bob").await;
+
+    root.send("/mute bob 1s").await;
+    bob.expect_line("* bob was muted by root for 1s").await;
+    bob.send("can you hear me").await;
+    bob.expect_line("* You are muted in this room.").await;
+    root.expect_no_line("<bob> can you hear me", Duration::from_millis(100)).await;
+
+    sleep(Duration::from_millis(1100)).await;
+    bob.send("and now").await;
+    root.expect_line("<bob> and now").await;
+
+    root.send("/mute bob").await;
+    root.expect_line("* bob was muted by root until unmuted").await;
+    root.send("/unmute bob").await;
+    root.expect_line("* bob was unmuted by root").await;
+    root.send("/unmute bob").await;
+    root.expect_line("* bob is not muted.").await;
+
+    // Durations too long to add to the clock are refused, and the server
+    // keeps going.
+    root.send("/mute bob 200000000000000d").await;
+    root.expect_line("Durations can be at most 365d. Use perm for longer.").await;
+    root.send("/ban bob 99999999999999999999999s").await;
+    root.expect_line("Durations can be at most 365d. Use perm for longer.").await;
+    bob.send("still here").await;
+    root.expect_line("<bob> still here").await;
+
+    let audit = audit_log(&server);
+    assert!(audit.contains(" root mute bob in #lobby for 1s\n"), "{}", audit);
+    assert!(audit.contains(" root unmute bob in #lobby\n"), "{}", audit);
+}
+
+#[tokio::test]
+async fn room_operators_set_the_topic_and_make_others_operators() {
+    let server = TestServer::start().await;
+    server.accounts.set_password("alice", "hunter2").unwrap();
+    server.accounts.set_password("dave", "hunter2").unwrap();
+    let mut alice = server.login_registered("alice", "hunter2").await;
+    let mut dave = server.login_registered("dave", "hunter2").await;
+    let mut carol = server.login("carol").await;
+
+    // Whoever creates a room is its operator.
+    alice.send("/join dev").await;
+    alice.expect_line("* You are now in #dev.").await;
+    alice.send("/topic Release on Friday").await;
+    alice.expect_line("* alice set the topic: Release on Friday").await;
+
+    carol.send("/join dev").await;
+    carol.expect_line("* Topic: Release on Friday").await;
+    carol.send("/topic Release never").await;
+    carol.expect_line("* Only operators of this room can do that.").await;
+    carol.send("/topic").await;
+    carol.expect_line("* Topic: Release on Friday").await;
+
+    // Only registered nicks can be operators.
+    alice.send("/op carol").await;
+    alice.expect_line("* carol is not a registered nick.").await;
+    alice.send("/op dave").await;
+    alice.expect_line("* alice made dave an operator").await;
+    dave.send("/join dev").await;
+    dave.expect_line("* You are now in #dev.").await;
+    dave.send("/topic Release on Monday").await;
+    carol.expect_line("* dave set the topic: Release on Monday").await;
+
+    alice.send("/deop dave").await;
+    carol.expect_line("* alice removed dave as operator").await;
+    dave.send("/topic Release whenever").await;
+    dave.expect_line("* Only operators of this room can do that.").await;
+    alice.send("/deop dave").await;
+    alice.expect_line("* dave is not an operator here.").await;
+
+    let audit = audit_log(&server);
+    assert!(audit.contains(" alice topic #dev: Release on Friday\n"), "{}", audit);
+    assert!(audit.contains(" alice op dave in #dev\n"), "{}", audit);
+    assert!(audit.contains(" dave topic #dev: Release on Monday\n"), "{}", audit);
+    assert!(audit.contains(" alice deop dave in #dev\n"), "{}", audit);
+}
+
+#[tokio::test]
+async fn room_operators_are_forgotten_with_the_room() {
+    let server = TestServer::start().await;
+    server.accounts.set_password("alice", "hunter2").unwrap();
+    server.accounts.set_password("dave", "hunter2").unwrap();
+    let mut alice = server.login_registered("alice", "hunter2").await;
+    let mut dave = server.login_registered("dave", "hunter2").await;
+
+    alice.send("/join dev").await;
+    alice.expect_line("* You are now in #dev.").await;
+    alice.send("/topic Release on Friday").await;
+    alice.expect_line("* alice set the topic: Release on Friday").await;
+    alice.send("/join lobby").await;
+    alice.expect_line("* You are now in #lobby.").await;
+
+    // The room was removed when it emptied, so it starts over with a new
+    // operator and no topic.
+    dave.send("/join dev").await;
+    dave.expect_line("* You are now in #dev.").await;
+    dave.send("/topic").await;
+    dave.expect_line("* This room has no topic.").await;
+    alice.send("/join dev").await;
+    alice.expect_line("* You are now in #dev.").await;
+    alice.send("/topic Release on Friday").await;
+    alice.expect_line("* Only operators of this room can do that.").await;
+    dave.send("/topic Release on Monday").await;
+    alice.expect_line("* dave set the topic: Release on Monday").await;
+}
+
+#[tokio::test]
+async fn bans_are_saved_and_loaded() {
+    let server = TestServer::start_with(|config| {
+        config.operators = vec!["root".to_string()];
+        // Saved by an earlier run: one permanent ban and one that is over.
+        let bans = "nick mallory 0 mallory spam\nnick eve 1 eve old news\n";
+        std::fs::write(&config.bans_file, bans).unwrap();
+    }).await;
+    server.accounts.set_password("root", "hunter2").unwrap();
+
+    let mut mallory = server.connect().await;
+    mallory.send("mallory").await;
+    mallory.expect_line("You are banned (spam).").await;
+    mallory.expect_closed().await;
+    let _eve = server.login("eve").await;
+
+    let mut root = server.login_registered("root", "hunter2").await;
+    root.send("/ban trudy 2h flooding").await;
+    root.expect_line("* trudy is banned for 7200s.").await;
+    let bans = std::fs::read_to_string(&server.config.bans_file).unwrap();
+    assert!(bans.contains("nick mallory 0 mallory spam\n"), "{}", bans);
+    assert!(bans.contains(" trudy flooding\n"), "{}", bans);
+    assert!(!bans.contains("eve"), "{}", bans);
+
+    let mut trudy = server.connect().await;
+    trudy.send("trudy").await;
+    trudy.expect_line("You are banned for 120 more minutes (flooding).").await;
+
+    root.send("/unban trudy").await;
+    root.expect_line("* trudy is no longer banned.").await;
+    root.send("/unban trudy").await;
+    root.expect_line("* trudy is not banned.").await;
+    let _trudy = server.login("trudy").await;
+
+    let audit = audit_log(&server);
+    assert!(audit.contains(" root ban trudy for 7200s: flooding\n"), "{}", audit);
+    assert!(audit.contains(" root unban trudy\n"), "{}", audit);
+}
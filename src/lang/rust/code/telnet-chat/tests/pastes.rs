@@ -0,0 +1,86 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestServer;
+
+#[tokio::test]
+async fn pastes_are_announced_and_shown_whole() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    alice.send("/paste hello world").await;
+    alice.expect_line("Paste mode. End with a line with only a '.', or cancel with /cancel.").await;
+    alice.send("fn main() {").await;
+    alice.send("\tprintln!(\"hi\");").await;
+    alice.send("}").await;
+    alice.send(".").await;
+    alice.expect_line("* alice pasted #1 (3 lines): hello world. Use /show 1 to see it.").await;
+    bob.expect_line("* alice pasted #1 (3 lines): hello world. Use /show 1 to see it.").await;
+
+    bob.send("/show #1").await;
+    bob.expect_line("* Paste #1 by alice: hello world").await;
+    bob.expect_line("| fn main() {").await;
+    bob.expect_line("|     println!(\"hi\");").await;
+    bob.expect_line("| }").await;
+    bob.expect_line("* End of paste #1.").await;
+
+    bob.send("/show 2").await;
+    bob.expect_line("* There is no paste #2.").await;
+}
+
+#[tokio::test]
+async fn pasted_lines_are_not_said_in_the_room() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    alice.send("/paste").await;
+    alice.send("secret line").await;
+    alice.send("/cancel").await;
+    alice.expect_line("Cancelled.").await;
+    alice.send("/paste").await;
+    alice.send(".").await;
+    alice.expect_line("Nothing was pasted.").await;
+    bob.expect_no_line("<alice> secret line", Duration::from_millis(200)).await;
+
+    // A paste that is too long is read to the end, then dropped.
+    alice.send("/paste").await;
+    for i in 0..501 {
+        alice.send(&format!("line {}", i)).await;
+    }
+    alice.send(".").await;
+    alice.expect_line("The paste was discarded, as it is over 500 lines or 64 KiB.").await;
+    alice.send("back to chat").await;
+    bob.expect_line("<alice> back to chat").await;
+}
+
+#[tokio::test]
+async fn old_pastes_are_forgotten() {
+    let server = TestServer::start_with(|config| config.max_pastes = 1).await;
+    let mut alice = server.login("alice").await;
+
+    for text in ["first", "second"] {
+        alice.send("/paste").await;
+        alice.send(text).await;
+        alice.send(".").await;
+    }
+    alice.expect_line("* alice pasted #2 (1 line). Use /show 2 to see it.").await;
+    alice.send("/show 1").await;
+    alice.expect_line("* There is no paste #1.").await;
+    alice.send("/show 2").await;
+    alice.expect_line("| second").await;
+}
+
+#[tokio::test]
+async fn pasted_code_is_not_formatted() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+
+    alice.send("/paste").await;
+    alice.send("*p = *q; // {red} {{").await;
+    alice.send(".").await;
+    alice.send("/show 1").await;
+    alice.expect_line("| *p = *q; // {red} {{").await;
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestServer;
+use telnet_chat::config::{BotConfig, FilterAction, FilterConfig, FilterRule};
+use telnet_chat::plugins::{Plugin, Replies};
+
+/// Answers `!roll` with a roll of a die that always lands on 4, and greets
+/// and sees off users.
+struct Dice;
+
+impl Plugin for Dice {
+    fn name(&self) -> &str {
+        "dice"
+    }
+
+    fn on_message(&mut self, replies: &Replies, room: &str, nick: &str, text: &str) {
+        if text == "!roll" {
+            replies.say(room, format!("{} rolled a 4", nick));
+        }
+    }
+
+    fn on_join(&mut self, replies: &Replies, room: &str, nick: &str) {
+        replies.say(room, format!("Welcome to #{}, {}!", room, nick));
+    }
+
+    fn on_part(&mut self, replies: &Replies, room: &str, nick: &str) {
+        replies.say(room, format!("Bye, {}.", nick));
+    }
+}
+
+/// Repeats every message.
+struct Parrot;
+
+impl Plugin for Parrot {
+    fn name(&self) -> &str {
+        "parrot"
+    }
+
+    fn on_message(&mut self, replies: &Replies, room: &str, _nick: &str, text: &str) {
+        replies.say(room, text);
+    }
+}
+
+#[tokio::test]
+async fn plugins_reply_to_messages_joins_and_parts() {
+    let server = TestServer::start_with_plugins(|_| {}, |plugins| plugins.add(Dice)).await;
+    let mut alice = server.login("alice").await;
+    alice.expect_line("<dice> Welcome to #lobby, alice!").await;
+
+    alice.send("!roll").await;
+    alice.expect_line("<dice> alice rolled a 4").await;
+
+    let mut bob = server.login("bob").await;
+    alice.expect_line("<dice> Welcome to #lobby, bob!").await;
+    bob.send("/join den").await;
+    alice.expect_line("<dice> Bye, bob.").await;
+    bob.expect_line("<dice> Welcome to #den, bob!").await;
+}
+
+#[tokio::test]
+async fn replies_are_filtered_and_not_told_to_plugins() {
+    let server = TestServer::start_with_plugins(
+        |config| config.filters = vec![FilterConfig {
+            rule: FilterRule::Blocklist { words: vec!["rolled".to_string()] },
+            action: FilterAction::Mute,
+            rooms: Default::default(),
+        }],
+        |plugins| {
+            plugins.add(Dice);
+            plugins.add(Parrot);
+        },
+    ).await;
+    let mut alice = server.login("alice").await;
+
+    // The parrot hears alice, but not the dice or itself, and the dice is
+    // muted for what it said.
+    alice.send("!roll").await;
+    let lines = alice.lines_until_idle().await;
+    assert_eq!(lines.iter().filter(|line| line.contains("<parrot>")).count(), 1, "{:?}", lines);
+    assert!(lines.iter().any(|line| line.starts_with("* dice was muted for")), "{:?}", lines);
+    assert!(!lines.iter().any(|line| line.contains("rolled")), "{:?}", lines);
+
+    alice.send("!roll").await;
+    alice.expect_line("<parrot> !roll").await;
+    alice.expect_no_line("<dice> alice rolled a 4", Duration::from_millis(200)).await;
+    let audit = std::fs::read_to_string(&server.config.audit_log).unwrap();
+    assert!(audit.contains("filter mute message by dice in #lobby: blocked words"), "{}", audit);
+}
+
+#[tokio::test]
+async fn matching_messages_are_appended_to_a_file() {
+    let mut log = None;
+    let server = TestServer::start_with(|config| {
+        let file = config.accounts_file.with_file_name("deploys.log");
+        log = Some(file.clone());
+        config.bots.push(BotConfig::AppendToFile {
+            name: "logger".to_string(),
+            file,
+            rooms: vec!["#Ops".to_string()],
+            contains: Some("deploy".to_string()),
+        });
+    }).await;
+    let log = log.unwrap();
+    let mut alice = server.login("alice").await;
+
+    alice.send("Deploy in the lobby").await;
+    alice.send("/join ops").await;
+    alice.send("lunch?").await;
+    alice.send("DEPLOY started").await;
+    alice.send("deploy done").await;
+
+    // The lines are written by a task of their own, in order.
+    let mut text = String::new();
+    for _ in 0..100 {
+        text = std::fs::read_to_string(&log).unwrap_or_default();
+        if text.contains("deploy done") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 2, "{:?}", text);
+    assert!(lines[0].ends_with(" #ops alice DEPLOY started"), "{:?}", text);
+    assert!(lines[1].ends_with(" #ops alice deploy done"), "{:?}", text);
+}
+
+#[tokio::test]
+async fn commands_get_the_message_and_reply_with_their_output() {
+    let server = TestServer::start_with(|config| {
+        config.bots.push(BotConfig::RunCommand {
+            name: "ci".to_string(),
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "read line; echo \"$CHAT_NICK in #$CHAT_ROOM: $line\"".to_string(),
+            ],
+            rooms: Vec::new(),
+            contains: Some("!build".to_string()),
+        });
+    }).await;
+    let mut alice = server.login("alice").await;
+
+    alice.send("!build main").await;
+    alice.expect_line("<ci> alice in #lobby: !build main").await;
+    alice.send("no build here").await;
+    alice.expect_no_line("<ci> alice in #lobby: no build here", Duration::from_millis(300)).await;
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use telnet_chat::commands::{self, Command, NewPoll};
+
+use common::TestServer;
+
+#[test]
+fn poll_questions_and_options_may_be_quoted() {
+    let cmd = commands::parse(br#"/poll 10m "Where to?" park "the beach""#);
+    assert_eq!(cmd, Some(Ok(Command::Poll(Some(NewPoll {
+        question: "Where to?".to_string(),
+        options: vec!["park".to_string(), "the beach".to_string()],
+        duration: Some(Duration::from_secs(600)),
+    })))));
+
+    // Without a duration, a question that looks like one is a question.
+    let cmd = commands::parse(b"/poll 10m yes no");
+    assert!(matches!(cmd, Some(Ok(Command::Poll(Some(NewPoll { duration: None, .. }))))));
+
+    assert!(matches!(commands::parse(br#"/poll "Where to? park beach"#), Some(Err(_))));
+    assert!(matches!(commands::parse(b"/poll question only-one"), Some(Err(_))));
+    assert_eq!(commands::parse(b"/poll"), Some(Ok(Command::Poll(None))));
+}
+
+#[tokio::test]
+async fn polls_are_open_for_at_most_a_week() {
+    let cmd = commands::parse(b"/poll 7d lunch? pizza sushi");
+    assert!(matches!(cmd, Some(Ok(Command::Poll(Some(NewPoll { duration: Some(_), .. }))))));
+
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    for duration in ["8d", "200000000000000d", "99999999999999999999999s"] {
+        alice.send(&format!("/poll {} lunch? pizza sushi", duration)).await;
+        alice.expect_line("A poll can be open for at most 7d.").await;
+    }
+    alice.send("/poll").await;
+    alice.expect_line("* There is no poll in #lobby.").await;
+}
+
+#[tokio::test]
+async fn polls_take_votes_until_they_close() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    alice.send(r#"/poll 2s "Lunch?" pizza "sushi rolls""#).await;
+    bob.expect_line(r#"* alice started a poll: "Lunch?", closing in 2s."#).await;
+    bob.expect_line("*   1. pizza").await;
+    bob.expect_line("*   2. sushi rolls").await;
+
+    alice.send(r#"/poll "Dinner?" soup salad"#).await;
+    alice.expect_line_containing("* There is already a poll in #lobby.").await;
+
+    // Votes are counted once per user, and can be changed.
+    bob.send("/vote 1").await;
+    alice.expect_line_containing(r#"* Poll "Lunch?": 1. pizza (1), 2. sushi rolls (0)."#).await;
+    bob.send("/vote 2").await;
+    alice.expect_line_containing(r#"* Poll "Lunch?": 1. pizza (0), 2. sushi rolls (1)."#).await;
+    bob.send("/vote 2").await;
+    bob.expect_line("* You already voted for sushi rolls.").await;
+    alice.send("/vote Sushi Rolls").await;
+    bob.expect_line_containing(r#"* Poll "Lunch?": 1. pizza (0), 2. sushi rolls (2)."#).await;
+    alice.send("/vote 3").await;
+    alice.expect_line("* There is no option 3. Choose 1 to 2.").await;
+
+    sleep(Duration::from_secs(2)).await;
+    let outcome = r#"* The poll "Lunch?" has closed: 1. pizza (0), 2. sushi rolls (2). sushi rolls wins."#;
+    alice.expect_line(outcome).await;
+    bob.expect_line(outcome).await;
+
+    bob.send("/vote 1").await;
+    bob.expect_line("* There is no poll in #lobby.").await;
+}
+
+#[tokio::test]
+async fn reactions_are_counted_per_message() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+    let mut carol = server.login("carol").await;
+
+    alice.send("hello").await;
+    let line = bob.expect_line_containing("<alice> hello").await;
+    carol.expect_line_containing("<alice> hello").await;
+    let id = line.trim_start_matches('[').split(']').next().unwrap().to_string();
+
+    bob.send(&format!("/react {} +1", id)).await;
+    alice.expect_line(&format!("* bob reacted with +1 to [{}] by alice. Reactions: +1 (1)", id)).await;
+    bob.send(&format!("/react {} +1", id)).await;
+    bob.expect_line(&format!("* You already reacted with +1 to [{}].", id)).await;
+
+    carol.send(&format!("/react [{}] :)", id)).await;
+    alice.expect_line(&format!("* carol reacted with :) to [{}] by alice. Reactions: +1 (1), :) (1)", id)).await;
+
+    carol.send("/react 999 +1").await;
+    carol.expect_line("* There is no recent message [999] in #lobby.").await;
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{TestServer, expect_output};
+use telnet_chat::presence::{format_idle, typing_notice};
+use telnet_chat::telnet::Item;
+
+#[test]
+fn formats_idle_time() {
+    assert_eq!(format_idle(Duration::from_secs(45)), "45s");
+    assert_eq!(format_idle(Duration::from_secs(192)), "3m 12s");
+    assert_eq!(format_idle(Duration::from_secs(7500)), "2h 5m");
+    assert_eq!(format_idle(Duration::from_secs(3 * 86400 + 4 * 3600)), "3d 4h");
+}
+
+#[test]
+fn describes_who_is_typing() {
+    assert_eq!(typing_notice(&[]), "");
+    assert_eq!(typing_notice(&["bob"]), "bob is typing");
+    assert_eq!(typing_notice(&["bob", "eve"]), "bob and eve are typing");
+    assert_eq!(typing_notice(&["a", "b", "c", "d"]), "a, b and 2 others are typing");
+}
+
+#[tokio::test]
+async fn messages_to_away_users_get_the_away_reply() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    bob.send("/away lunch").await;
+    bob.expect_line("* You are marked as away: lunch").await;
+
+    alice.send("/msg Bob are you there?").await;
+    bob.expect_line("[alice -> you] are you there?").await;
+    alice.expect_line("[you -> bob] are you there?").await;
+    alice.expect_line("* bob is away: lunch.").await;
+
+    // Talking doesn't end an away set with /away, but /back does.
+    bob.send("hi").await;
+    bob.send("/back").await;
+    bob.expect_line("* You are no longer marked as away.").await;
+    alice.send("/msg bob welcome back").await;
+    alice.expect_line("[you -> bob] welcome back").await;
+    alice.expect_no_line("* bob is away: lunch.", Duration::from_millis(200)).await;
+
+    alice.send("/msg carol hello").await;
+    alice.expect_line("* carol is not online.").await;
+}
+
+#[tokio::test]
+async fn whois_shows_room_idle_time_and_status() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    bob.send("/join den").await;
+    bob.send("/away").await;
+    bob.expect_line("* You are marked as away.").await;
+
+    alice.send("/whois BOB").await;
+    alice.expect_line("* bob: in #den, idle 0s").await;
+    alice.expect_line("* bob is away.").await;
+}
+
+#[tokio::test]
+async fn idle_users_are_marked_away() {
+    let server = TestServer::start_with(|config| config.auto_away_secs = 1).await;
+    let mut alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    // Idle users are checked once a second.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    bob.expect_line("* You are marked as away after 1s of inactivity.").await;
+    alice.send("/whois bob").await;
+    alice.expect_line("* bob is away (idle).").await;
+
+    // Any activity ends it.
+    bob.send("I'm back").await;
+    bob.expect_line("* You are no longer marked as away.").await;
+    alice.send("/msg bob good").await;
+    alice.expect_no_line("* bob is away (idle).", Duration::from_millis(200)).await;
+}
+
+#[tokio::test]
+async fn typing_notices_are_opt_in() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect_full_screen().await;
+    alice.send(Item::Data(b"alice\r".to_vec())).await.unwrap();
+    expect_output(&mut alice, "alice  1 user").await;
+    let mut bob = server.connect_full_screen().await;
+    bob.send(Item::Data(b"bob\r".to_vec())).await.unwrap();
+    expect_output(&mut bob, "bob  2 users").await;
+
+    // Only bob opted in, so his typing isn't shown to alice.
+    bob.send(Item::Data(b"/typing on\r".to_vec())).await.unwrap();
+    expect_output(&mut bob, "* Typing notices are on.").await;
+    bob.send(Item::Data(b"hel".to_vec())).await.unwrap();
+    alice.send(Item::Data(b"/typing on\r".to_vec())).await.unwrap();
+    expect_output(&mut alice, "alice  2 users  bob is typing").await;
+
+    // Sending the line ends the notice.
+    bob.send(Item::Data(b"lo\r".to_vec())).await.unwrap();
+    expect_output(&mut alice, "> hello").await;
+    expect_output(&mut alice, "alice  2 users    ").await;
+
+    // Commands are not announced, and neither is alice to bob, who sees
+    // nobody typing.
+    bob.send(Item::Data(b"/whois alice".to_vec())).await.unwrap();
+    alice.send(Item::Data(b"hm".to_vec())).await.unwrap();
+    expect_output(&mut bob, "bob  2 users  alice is typing").await;
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use common::TestServer;
+use telnet_chat::proxy::{Cidr, read_header};
+
+#[test]
+fn cidrs_contain_their_addresses() {
+    let net: Cidr = "10.1.0.0/16".parse().unwrap();
+    assert!(net.contains("10.1.2.3".parse().unwrap()));
+    assert!(!net.contains("10.2.0.1".parse().unwrap()));
+    // IPv4 addresses mapped into IPv6, as seen on dual-stack sockets.
+    assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+
+    let one: Cidr = "::1".parse().unwrap();
+    assert!(one.contains("::1".parse().unwrap()));
+    assert!(!one.contains("127.0.0.1".parse().unwrap()));
+
+    let all: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(all.contains("192.0.2.1".parse().unwrap()));
+
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("example.com/8".parse::<Cidr>().is_err());
+}
+
+#[tokio::test]
+async fn reads_headers_and_nothing_more() {
+    let mut v1: &[u8] = b"PROXY TCP4 203.0.113.7 192.0.2.1 5555 3456\r\nalice\r\n";
+    let addr = read_header(&mut v1).await.unwrap();
+    assert_eq!(addr, Some("203.0.113.7:5555".parse().unwrap()));
+    assert_eq!(v1, b"alice\r\n");
+
+    let mut unknown: &[u8] = b"PROXY UNKNOWN\r\n";
+    assert_eq!(read_header(&mut unknown).await.unwrap(), None);
+
+    let mut v2 = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+    v2.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1, 0x15, 0xb3, 0x0d, 0x80]);
+    v2.extend_from_slice(b"alice\r\n");
+    let mut read = &v2[..];
+    let addr = read_header(&mut read).await.unwrap();
+    assert_eq!(addr, Some("203.0.113.7:5555".parse().unwrap()));
+    assert_eq!(read, b"alice\r\n");
+
+    // LOCAL, as used for health checks.
+    let mut local = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+    assert_eq!(read_header(&mut &local[..]).await.unwrap(), None);
+
+    let mut bad: &[u8] = b"PROXY TCP6 203.0.113.7 192.0.2.1 5555 3456\r\n";
+    assert!(read_header(&mut bad).await.is_err());
+    let mut telnet: &[u8] = b"alice\r\nhello\r\n";
+    assert!(read_header(&mut telnet).await.is_err());
+}
+
+#[tokio::test]
+async fn bans_use_the_address_from_the_proxy() {
+    let server = TestServer::start_with(|config| {
+        config.trusted_proxies = vec!["127.0.0.0/8".parse().unwrap()];
+        config.operators = vec!["root".to_string()];
+    }).await;
+    server.accounts.set_password("root", "hunter2").unwrap();
+
+    let mut root = server.connect().await;
+    root.send_raw(b"PROXY TCP4 198.51.100.1 127.0.0.1 4000 3456\r\n").await;
+    root.send("root").await;
+    root.send("hunter2").await;
+    root.expect_line("* You are now in #lobby.").await;
+
+    let mut bob = server.connect().await;
+    bob.send_raw(b"PROXY TCP4 203.0.113.7 127.0.0.1 5555 3456\r\n").await;
+    bob.send("bob").await;
+    bob.expect_line("* You are now in #lobby.").await;
+    root.send("/ban bob 1h spam").await;
+    root.expect_line("* bob is banned for 3600s.").await;
+
+    // The ban is on bob's address, not on the proxy's.
+    let mut eve = server.connect().await;
+    eve.send_raw(b"PROXY TCP4 203.0.113.7 127.0.0.1 5556 3456\r\n").await;
+    eve.send("eve").await;
+    eve.expect_line("You are banned for 60 more minutes (spam).").await;
+
+    let mut carol = server.connect().await;
+    carol.send_raw(b"PROXY TCP4 203.0.113.8 127.0.0.1 5557 3456\r\n").await;
+    carol.send("carol").await;
+    carol.expect_line("* You are now in #lobby.").await;
+}
+
+#[tokio::test]
+async fn headers_are_only_read_from_trusted_proxies() {
+    let server = TestServer::start_with(|config| {
+        config.trusted_proxies = vec!["192.0.2.0/24".parse().unwrap()];
+    }).await;
+
+    // Without trust, the header is just a line of text.
+    let mut client = server.connect().await;
+    client.send("PROXY TCP4 203.0.113.7 127.0.0.1 5555 3456").await;
+    client.expect_line("Nicks are 1 to 16 letters, digits, '-' or '_'.").await;
+
+    let trusting = TestServer::start_with(|config| {
+        config.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    }).await;
+    // And with trust, anything else is refused.
+    let mut client = trusting.connect().await;
+    client.send("alice").await;
+    client.send("hello everyone").await;
+    client.expect_closed().await;
+}
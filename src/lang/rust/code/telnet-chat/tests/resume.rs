@@ -0,0 +1,111 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use common::{TestClient, TestServer};
+
+/// The token from the line that tells the user how to resume.
+async fn token(client: &mut TestClient) -> String {
+    let line = client.expect_line_containing("/resume ").await;
+    let rest = line.split("/resume ").nth(1).unwrap();
+    rest.split(' ').next().unwrap().to_string()
+}
+
+/// Wait until the server has noticed that the connection of `nick` is lost.
+async fn until_lost(watcher: &mut TestClient, nick: &str) {
+    for _ in 0..50 {
+        watcher.send(&format!("/whois {}", nick)).await;
+        let line = watcher.expect_line_containing(&format!("* {}: in #", nick)).await;
+        if line.ends_with("connection lost") {
+            return;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    panic!("The server did not notice that {} is gone.", nick);
+}
+
+#[tokio::test]
+async fn lost_sessions_resume_with_what_was_missed() {
+    let server = TestServer::start().await;
+    let mut alice = server.login("alice").await;
+    let token = token(&mut alice).await;
+    let mut bob = server.login("bob").await;
+    alice.expect_line("* bob has joined").await;
+
+    alice.reset();
+    until_lost(&mut bob, "alice").await;
+    bob.send("are you there?").await;
+
+    // The nick is still taken.
+    let mut other = server.connect().await;
+    other.send("alice").await;
+    other.expect_line("That nick is already in use.").await;
+    other.send("/resume 0123").await;
+    other.expect_line("There is no session to resume with that token.").await;
+
+    other.send(&format!("/resume {}", token)).await;
+    other.expect_line("* Welcome back. You are still in #lobby.").await;
+    other.expect_line("* While you were gone (1 line):").await;
+    other.expect_line("<bob> are you there?").await;
+    other.expect_line("* End of missed lines.").await;
+
+    other.send("yes").await;
+    bob.expect_line("<alice> yes").await;
+    bob.expect_no_line("* alice has left", Duration::from_millis(100)).await;
+    bob.expect_no_line("* alice has joined", Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn registered_users_take_over_their_session() {
+    let server = TestServer::start().await;
+    server.accounts.set_password("alice", "hunter2").unwrap();
+    let mut alice = server.login_registered("alice", "hunter2").await;
+    let mut bob = server.login("bob").await;
+    alice.send("/join kitchen").await;
+    alice.expect_line("* You are now in #kitchen.").await;
+    bob.send("/join kitchen").await;
+    alice.expect_line("* bob has joined").await;
+
+    // The old connection still looks alive to the server.
+    let mut again = server.connect().await;
+    again.send("alice").await;
+    again.send("hunter2").await;
+    again.expect_line("* Welcome back. You are still in #kitchen.").await;
+    alice.expect_line("* Your session was resumed from another connection.").await;
+    alice.expect_closed().await;
+
+    again.send("hello").await;
+    bob.expect_line("<alice> hello").await;
+    bob.expect_no_line("* alice has left", Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn lost_sessions_expire() {
+    let server = TestServer::start_with(|config| config.resume_secs = 1).await;
+    let mut alice = server.login("alice").await;
+    let token = token(&mut alice).await;
+    let mut bob = server.login("bob").await;
+
+    alice.reset();
+    until_lost(&mut bob, "alice").await;
+    sleep(Duration::from_secs(2)).await;
+    bob.expect_line_containing("* alice has left (error").await;
+
+    let mut other = server.connect().await;
+    other.send(&format!("/resume {}", token)).await;
+    other.expect_line("There is no session to resume with that token.").await;
+    other.send("alice").await;
+    other.expect_line("* You are now in #lobby.").await;
+}
+
+#[tokio::test]
+async fn quitting_is_final() {
+    let server = TestServer::start().await;
+    let alice = server.login("alice").await;
+    let mut bob = server.login("bob").await;
+
+    drop(alice);
+    bob.expect_line("* alice has left").await;
+}
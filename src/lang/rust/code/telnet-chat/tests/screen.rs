@@ -0,0 +1,158 @@
+mod common;
+
+use common::{TestServer, expect_output};
+use telnet_chat::screen::{Screen, supports_screen};
+use telnet_chat::telnet::Item;
+use telnet_chat::telnet_client::TelnetClient;
+
+fn text(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap()
+}
+
+#[test]
+fn draws_pane_status_and_input() {
+    let mut screen = Screen::default();
+    screen.line(b"old news".to_vec());
+    let drawn = text(screen.start(40, 10));
+
+    // The pane is rows 1 to 8, and the old line is at its bottom.
+    assert!(drawn.contains("\x1b[1;8r"), "{:?}", drawn);
+    assert!(drawn.contains("\x1b[8;1Hold news"), "{:?}", drawn);
+    // Status bar on row 9, input on row 10.
+    assert!(drawn.contains("\x1b[9;1H\x1b[7m"), "{:?}", drawn);
+    assert!(drawn.contains("\x1b[10;1H\x1b[K"), "{:?}", drawn);
+
+    let status = text(screen.status("lobby", 3));
+    assert!(status.contains(" #lobby  3 users"), "{:?}", status);
+    let status = text(screen.nick("alice"));
+    assert!(status.contains(" #lobby  alice  3 users"), "{:?}", status);
+}
+
+#[test]
+fn new_lines_scroll_the_pane_and_keep_the_input() {
+    let mut screen = Screen::default();
+    screen.start(40, 10);
+    screen.input("half a thou", 4, false);
+
+    let drawn = text(screen.line(b"<bob> hi".to_vec()));
+    assert!(drawn.starts_with("\x1b[8;1H\r\n<bob> hi"), "{:?}", drawn);
+    // The input line is drawn again with the cursor after "half".
+    assert!(drawn.ends_with("\x1b[10;1H\x1b[Khalf a thou\x1b[10;5H"), "{:?}", drawn);
+}
+
+#[test]
+fn hidden_input_is_not_shown() {
+    let mut screen = Screen::default();
+    screen.start(40, 10);
+    screen.prompt("Password: ");
+    let drawn = text(screen.input("secret", 6, true));
+    assert!(!drawn.contains("secret"), "{:?}", drawn);
+
+    let drawn = text(screen.submit("secret", true));
+    assert!(drawn.contains("Password: "), "{:?}", drawn);
+    assert!(!drawn.contains("secret"), "{:?}", drawn);
+}
+
+#[test]
+fn long_input_scrolls_sideways() {
+    let mut screen = Screen::default();
+    screen.start(20, 5);
+    let input = "abcdefghijklmnopqrstuvwxyz";
+    let drawn = text(screen.input(input, input.len(), false));
+    assert!(drawn.ends_with("\x1b[5;1H\x1b[Khijklmnopqrstuvwxyz\x1b[5;20H"), "{:?}", drawn);
+}
+
+#[test]
+fn scrolling_back_holds_the_view() {
+    let mut screen = Screen::default();
+    screen.start(40, 10);
+    for i in 0..20 {
+        screen.line(format!("line {}", i).into_bytes());
+    }
+
+    let drawn = text(screen.scroll(true));
+    assert!(drawn.contains("-- more --"), "{:?}", drawn);
+    assert!(!drawn.contains("line 19"), "{:?}", drawn);
+
+    // New lines don't move the view while scrolled back.
+    let drawn = text(screen.line(b"line 20".to_vec()));
+    assert!(!drawn.contains("line 20"), "{:?}", drawn);
+
+    // Paging down twice gets back to the end, as a line was added.
+    screen.scroll(false);
+    let drawn = text(screen.scroll(false));
+    assert!(drawn.contains("line 20"), "{:?}", drawn);
+    assert!(!drawn.contains("-- more --"), "{:?}", drawn);
+}
+
+#[test]
+fn knows_screen_terminals() {
+    assert!(supports_screen("xterm"));
+    assert!(supports_screen("VT100"));
+    assert!(!supports_screen("dumb"));
+    assert!(!supports_screen("UNKNOWN"));
+}
+
+#[tokio::test]
+async fn capable_clients_get_the_layout() {
+    let server = TestServer::start().await;
+    // The scrolling region leaves room for the status bar and input line.
+    let mut alice = server.connect_full_screen().await;
+    expect_output(&mut alice, "Nick: ").await;
+    assert!(alice.char_mode());
+
+    // Keys are sent one at a time, and backspace works.
+    for key in [&b"alicx"[..], b"\x7f", b"e", b"\r\n"] {
+        alice.send(Item::Data(key.to_vec())).await.unwrap();
+    }
+    expect_output(&mut alice, " #lobby  alice  1 user").await;
+
+    let mut bob = server.login("bob").await;
+    expect_output(&mut alice, " #lobby  alice  2 users").await;
+
+    // Half a line is typed when bob speaks. The line from bob scrolls the
+    // pane, and the input line is drawn again below it.
+    alice.send(Item::Data(b"hel".to_vec())).await.unwrap();
+    expect_output(&mut alice, "\x1b[12;1H\x1b[Khel").await;
+    bob.send("hi").await;
+    expect_output(&mut alice, "> hi\x1b[12;1H\x1b[Khel\x1b[12;4H").await;
+
+    alice.send(Item::Data(b"lo\r".to_vec())).await.unwrap();
+    bob.expect_line("<alice> hello").await;
+
+    // A resize draws everything again.
+    alice.set_window_size(70, 20).await.unwrap();
+    let rest = expect_output(&mut alice, "\x1b[1;18r").await;
+    assert!(rest.contains("> hi"), "{:?}", rest);
+}
+
+#[tokio::test]
+async fn the_esc_key_does_not_eat_the_next_key() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect_full_screen().await;
+    expect_output(&mut alice, "Nick: ").await;
+    alice.send(Item::Data(b"alice\r".to_vec())).await.unwrap();
+    expect_output(&mut alice, " #lobby  alice  1 user").await;
+    let mut bob = server.login("bob").await;
+
+    // Esc on its own, then Esc and a key in one read, then an arrow key.
+    for keys in [&b"a\x1b"[..], b"b", b"\x1bc", b"\x1b[Dd\r"] {
+        alice.send(Item::Data(keys.to_vec())).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    bob.expect_line("<alice> abdc").await;
+}
+
+#[tokio::test]
+async fn dumb_clients_keep_line_mode() {
+    let server = TestServer::start().await;
+    let mut client = TelnetClient::connect(server.addr).await.unwrap();
+    client.set_terminal_type(Some("dumb".to_string()));
+    client.set_window_size(80, 24).await.unwrap();
+    client.start().await.unwrap();
+
+    expect_output(&mut client, "Nick: ").await;
+    client.send_line(b"alice").await.unwrap();
+    expect_output(&mut client, "* You are now in #lobby.\r\n").await;
+    assert!(!client.char_mode());
+}
@@ -0,0 +1,248 @@
+//! Simulations of many clients on the paused clock, with faults injected, to
+//! check the invariants of the accept loop, client actors and main loop. See
+//! `common::sim`.
+
+mod common;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use proptest::prelude::*;
+use tokio::time::sleep;
+
+use common::sim::{said, Sim, SimClient};
+
+const IAC: u8 = 255;
+const NOP: u8 = 241;
+const WILL: u8 = 251;
+const ECHO: u8 = 1;
+
+/// Fail if a user was sent one of their own messages.
+fn assert_not_echoed(nick: &str, lines: &[String]) {
+    for line in lines {
+        if let Some((from, _)) = said(line) {
+            assert_ne!(from, nick, "{} was sent their own message: {}", nick, line);
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_readers_are_removed_and_aborted() {
+    let sim = Sim::start();
+    let mut alice = sim.login("alice").await;
+    let mut bob = sim.login("bob").await;
+    // Reads nothing after logging in, and has room for only a few lines.
+    let mut slow = sim.connect_with(256);
+    slow.send("slow").await;
+    slow.expect_line("* You are now in #lobby.").await;
+    assert_eq!(sim.open_connections(), 3);
+
+    for i in 0..200 {
+        alice.send(&format!("flood {}", i)).await;
+    }
+
+    let lines = bob.lines_until_idle().await;
+    assert!(lines.iter().any(|line| line == "* slow has left (can't keep up)"), "{:#?}", lines);
+    let flood: Vec<&str> = lines.iter().filter_map(|line| said(line)).map(|(_, text)| text).collect();
+    assert_eq!(flood.len(), 200);
+    assert_eq!(flood.last(), Some(&"flood 199"));
+
+    let lines = alice.lines_until_idle().await;
+    assert_not_echoed("alice", &lines);
+
+    // The actor of the slow client was aborted, and dropped its connection.
+    assert_eq!(sim.open_connections(), 2);
+    slow.expect_closed().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn abrupt_closes_free_the_nick() {
+    let sim = Sim::start();
+    let mut alice = sim.login("alice").await;
+    let mut bob = sim.login("bob").await;
+
+    // Half a line, then the connection is gone.
+    bob.send_raw(b"never fini").await;
+    drop(bob);
+    alice.expect_line("* bob has left").await;
+    alice.expect_no_line("never fini", Duration::from_secs(5)).await;
+    assert_eq!(sim.open_connections(), 1);
+
+    let mut bob = sim.login("bob").await;
+    bob.send("back").await;
+    alice.expect_line("<bob> back").await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn telnet_commands_split_across_reads_are_put_together() {
+    let sim = Sim::start();
+    let mut alice = sim.login("alice").await;
+    let mut bob = sim.login("bob").await;
+
+    // Each piece is read on its own, as the server has nothing else to do
+    // while the clock moves.
+    for piece in [&b"hel"[..], &[IAC], &[NOP], b"lo", &[IAC, WILL], &[ECHO], b" there\r", b"\n"] {
+        alice.send_raw(piece).await;
+        sleep(Duration::from_millis(10)).await;
+    }
+    bob.expect_line("<alice> hello there").await;
+    assert_eq!(sim.open_connections(), 2);
+}
+
+const USERS: usize = 4;
+
+#[derive(Debug, Clone)]
+enum Step {
+    Say(usize),
+    /// Say something with a telnet command in the line, split between two
+    /// reads at this point.
+    SayInPieces(usize, usize),
+    /// Close the connection without a goodbye.
+    Hangup(usize),
+    /// Log in again after a hangup.
+    Login(usize),
+    /// Let this many milliseconds pass.
+    Wait(u64),
+}
+
+fn step() -> impl Strategy<Value = Step> {
+    let user = 0..USERS;
+    prop_oneof![
+        6 => user.clone().prop_map(Step::Say),
+        2 => (user.clone(), any::<usize>()).prop_map(|(user, at)| Step::SayInPieces(user, at)),
+        1 => user.clone().prop_map(Step::Hangup),
+        1 => user.prop_map(Step::Login),
+        1 => (1..5000u64).prop_map(Step::Wait),
+    ]
+}
+
+/// What a user must have been sent: chat messages without their id, and
+/// join and leave notices.
+#[derive(Default)]
+struct Seen {
+    by_nick: HashMap<String, Vec<String>>,
+}
+
+impl Seen {
+    fn push(&mut self, nick: &str, line: String) {
+        self.by_nick.entry(nick.to_string()).or_default().push(line);
+    }
+
+    /// Messages from different users may arrive in any order, but those of
+    /// each user must arrive in the order they were sent.
+    fn from_lines(lines: &[String]) -> Seen {
+        let mut seen = Seen::default();
+        for line in lines {
+            if let Some((nick, text)) = said(line) {
+                seen.push(nick, format!("<{}> {}", nick, text));
+            } else if let Some(nick) = line.strip_prefix("* ").and_then(|l| l.split(" has ").next()) {
+                if line.ends_with(" has joined") || line.ends_with(" has left") {
+                    seen.push(nick, line.clone());
+                }
+            }
+        }
+        seen
+    }
+}
+
+async fn simulate(steps: Vec<Step>) {
+    let sim = Sim::start();
+    let nick = |user: usize| format!("user{}", user);
+
+    let mut users: Vec<Option<SimClient>> = Vec::new();
+    let mut expected: Vec<Seen> = Vec::new();
+    for user in 0..USERS {
+        users.push(Some(sim.login(&nick(user)).await));
+        expected.push(Seen::default());
+        for seen in &mut expected[..user] {
+            seen.push(&nick(user), format!("* {} has joined", nick(user)));
+        }
+    }
+
+    // Tell everyone else who is connected.
+    let tell = |users: &[Option<SimClient>], expected: &mut [Seen], from: usize, line: String| {
+        for other in (0..USERS).filter(|&other| other != from && users[other].is_some()) {
+            expected[other].push(&nick(from), line.clone());
+        }
+    };
+
+    for (n, step) in steps.into_iter().enumerate() {
+        match step {
+            Step::Say(user) => {
+                if let Some(client) = &mut users[user] {
+                    let text = format!("message {}", n);
+                    client.send(&text).await;
+                    tell(&users, &mut expected, user, format!("<{}> {}", nick(user), text));
+                }
+            },
+            Step::SayInPieces(user, at) => {
+                if let Some(client) = &mut users[user] {
+                    let text = format!("message {}", n);
+                    let at = at % (text.len() + 1);
+                    let mut first = text.as_bytes()[..at].to_vec();
+                    first.push(IAC);
+                    let mut second = vec![NOP];
+                    second.extend_from_slice(&text.as_bytes()[at..]);
+                    second.extend_from_slice(b"\r\n");
+
+                    client.send_raw(&first).await;
+                    sleep(Duration::from_millis(1)).await;
+                    client.send_raw(&second).await;
+                    tell(&users, &mut expected, user, format!("<{}> {}", nick(user), text));
+                }
+            },
+            Step::Hangup(user) => {
+                if users[user].take().is_some() {
+                    tell(&users, &mut expected, user, format!("* {} has left", nick(user)));
+                }
+            },
+            Step::Login(user) => {
+                if users[user].is_none() {
+                    // Let what was sent before arrive before the user is back.
+                    sim.settle().await;
+                    users[user] = Some(sim.login(&nick(user)).await);
+                    expected[user] = Seen::default();
+                    tell(&users, &mut expected, user, format!("* {} has joined", nick(user)));
+                }
+            },
+            Step::Wait(ms) => sleep(Duration::from_millis(ms)).await,
+        }
+    }
+
+    let mut connected = 0;
+    for (user, client) in users.iter_mut().enumerate() {
+        let client = match client {
+            Some(client) => client,
+            None => continue,
+        };
+        connected += 1;
+        let lines = client.lines_until_idle().await;
+        assert_not_echoed(&nick(user), &lines);
+
+        let seen = Seen::from_lines(&lines);
+        for other in (0..USERS).filter(|&other| other != user) {
+            let other = nick(other);
+            assert_eq!(
+                seen.by_nick.get(&other),
+                expected[user].by_nick.get(&other),
+                "What {} was sent by {}", nick(user), other,
+            );
+        }
+    }
+
+    // Everyone who hung up is gone, actor and all.
+    sim.settle().await;
+    assert_eq!(sim.open_connections(), connected);
+}
+
+proptest! {
+    #[test]
+    fn invariants_hold_under_faults(steps in proptest::collection::vec(step(), 1..40)) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        runtime.block_on(simulate(steps));
+    }
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use std::sync::Arc;
+
+use argon2::password_hash::rand_core::OsRng;
+use russh::client::{self, Msg};
+use russh::keys::{Algorithm, PrivateKey, PrivateKeyWithHashAlg, PublicKey};
+use russh::{Channel, ChannelMsg};
+use tokio::time::timeout;
+
+use common::{TestServer, EXPECT_TIMEOUT};
+
+struct TestSsh;
+
+impl client::Handler for TestSsh {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _key: &PublicKey) -> Result<bool, russh::Error> {
+        Ok(true)
+    }
+}
+
+async fn start() -> TestServer {
+    TestServer::start_with(|config| {
+        config.ssh_bind = Some(([127, 0, 0, 1], 0).into());
+    }).await
+}
+
+fn new_key() -> PrivateKey {
+    PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap()
+}
+
+/// Add the key to the server's authorized keys file.
+fn authorize(server: &TestServer, key: &PrivateKey) {
+    let line = key.public_key().to_openssh().unwrap();
+    let path = &server.config.ssh_authorized_keys;
+    let mut text = std::fs::read_to_string(path).unwrap_or_default();
+    text.push_str(&line);
+    text.push('\n');
+    std::fs::write(path, text).unwrap();
+}
+
+/// Connect, and return the session if the server accepts the key.
+async fn connect(server: &TestServer, key: PrivateKey) -> Option<client::Handle<TestSsh>> {
+    let config = Arc::new(client::Config::default());
+    let mut session = client::connect(config, server.ssh_addr.unwrap(), TestSsh).await.unwrap();
+    let auth = session
+        .authenticate_publickey("chat", PrivateKeyWithHashAlg::new(Arc::new(key), None))
+        .await
+        .unwrap();
+    if auth.success() {
+        Some(session)
+    } else {
+        None
+    }
+}
+
+/// Open a shell on a terminal of this type and size.
+async fn shell(session: &client::Handle<TestSsh>, term: &str, cols: u32, rows: u32) -> Channel<Msg> {
+    let channel = session.channel_open_session().await.unwrap();
+    channel.request_pty(true, term, cols, rows, 0, 0, &[]).await.unwrap();
+    channel.request_shell(true).await.unwrap();
+    channel
+}
+
+async fn type_keys(channel: &Channel<Msg>, keys: &str) {
+    channel.data(keys.as_bytes()).await.unwrap();
+}
+
+/// Read until the output so far contains `expected`. Returns the output
+/// after it.
+async fn expect_output(channel: &mut Channel<Msg>, expected: &str) -> String {
+    let mut seen = String::new();
+    let res = timeout(EXPECT_TIMEOUT, async {
+        while let Some(msg) = channel.wait().await {
+            if let ChannelMsg::Data { data } = msg {
+                seen.push_str(&String::from_utf8_lossy(&data));
+                if let Some(i) = seen.find(expected) {
+                    return seen[i + expected.len()..].to_string();
+                }
+            }
+        }
+        panic!("Disconnected");
+    }).await;
+
+    res.unwrap_or_else(|_| panic!("Expected {:?}, got {:?}", expected, seen))
+}
+
+#[tokio::test]
+async fn only_authorized_keys_log_in() {
+    let server = start().await;
+    let known = new_key();
+    authorize(&server, &known);
+
+    assert!(connect(&server, new_key()).await.is_none());
+    assert!(connect(&server, known).await.is_some());
+}
+
+#[tokio::test]
+async fn line_mode_is_edited_and_echoed_here() {
+    let server = start().await;
+    let key = new_key();
+    authorize(&server, &key);
+
+    let session = connect(&server, key).await.unwrap();
+    let mut alice = shell(&session, "dumb", 80, 24).await;
+    expect_output(&mut alice, "Nick: ").await;
+    type_keys(&alice, "alicx\x7fe\r").await;
+    expect_output(&mut alice, "alicx\x08 \x08e\r\n").await;
+    expect_output(&mut alice, "* You are now in #lobby.\r\n").await;
+
+    let mut bob = server.login("bob").await;
+    type_keys(&alice, "héllo\r").await;
+    bob.expect_line("<alice> héllo").await;
+
+    bob.send("hi").await;
+    expect_output(&mut alice, "<bob> hi\r\n").await;
+
+    // Closing the channel logs out.
+    alice.close().await.unwrap();
+    bob.expect_line("* alice has left").await;
+}
+
+#[tokio::test]
+async fn window_changes_are_reported_like_naws() {
+    let server = start().await;
+    let key = new_key();
+    authorize(&server, &key);
+
+    let session = connect(&server, key).await.unwrap();
+    let mut alice = shell(&session, "xterm", 60, 12).await;
+    // The scrolling region leaves room for the status bar and input line.
+    expect_output(&mut alice, "\x1b[1;10r").await;
+    type_keys(&alice, "alice\r").await;
+    expect_output(&mut alice, " #lobby  alice  1 user").await;
+
+    alice.window_change(70, 20, 0, 0).await.unwrap();
+    expect_output(&mut alice, "\x1b[1;18r").await;
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use std::time::Duration;
+
+use futures::stream::StreamExt;
+use tokio::io::{duplex, AsyncWriteExt};
+use tokio::time::timeout;
+use tokio_util::codec::FramedRead;
+
+use common::TestServer;
+use telnet_chat::telnet::{Item, TelnetCodec, option};
+use telnet_chat::telnet_client::TelnetClient;
+
+/// Read from the client until the text seen so far contains `expected`.
+async fn expect_text<T>(client: &mut TelnetClient<T>, expected: &str)
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut seen = String::new();
+    let res = timeout(Duration::from_secs(1), async {
+        while let Some(item) = client.next().await {
+            if let Item::Data(text) = item.unwrap() {
+                seen.push_str(&String::from_utf8_lossy(&text));
+                if seen.contains(expected) {
+                    return;
+                }
+            }
+        }
+    }).await;
+
+    if res.is_err() || !seen.contains(expected) {
+        panic!("Expected {:?}, got {:?}", expected, seen);
+    }
+}
+
+#[tokio::test]
+async fn chats_with_the_server() {
+    let server = TestServer::start().await;
+    let mut bob = server.login("bob").await;
+
+    let mut alice = TelnetClient::connect(server.addr).await.unwrap();
+    alice.start().await.unwrap();
+    // The prompt arrives without ending the line.
+    expect_text(&mut alice, "Nick: ").await;
+    alice.send_line(b"alice").await.unwrap();
+    expect_text(&mut alice, "* You are now in #lobby.\r\n").await;
+
+    alice.send_line(b"hello").await.unwrap();
+    bob.expect_line("<alice> hello").await;
+}
+
+#[tokio::test]
+async fn hides_input_while_the_server_echoes() {
+    let server = TestServer::start().await;
+    let mut alice = TelnetClient::connect(server.addr).await.unwrap();
+    alice.start().await.unwrap();
+    alice.send_line(b"alice").await.unwrap();
+    expect_text(&mut alice, "* You are now in #lobby.").await;
+    assert!(!alice.server_echoes());
+
+    alice.send_line(b"/register").await.unwrap();
+    expect_text(&mut alice, "New password: ").await;
+    assert!(alice.server_echoes());
+}
+
+#[tokio::test]
+async fn reports_window_size_and_terminal_type() {
+    let (client_io, server_io) = duplex(1024);
+    let (read, mut write) = tokio::io::split(server_io);
+    let mut server = FramedRead::new(read, TelnetCodec::new());
+
+    let mut client = TelnetClient::new(client_io);
+    client.set_terminal_type(Some("xterm".to_string()));
+    client.set_window_size(80, 24).await.unwrap();
+    client.start().await.unwrap();
+
+    assert_eq!(server.next().await.unwrap().unwrap(), Item::Do(option::SUPPRESS_GO_AHEAD));
+    assert_eq!(server.next().await.unwrap().unwrap(), Item::Will(option::TERMINAL_TYPE));
+    assert_eq!(server.next().await.unwrap().unwrap(), Item::Will(option::NAWS));
+
+    // Agree to both, and ask for the terminal type.
+    write.write_all(&[255, 253, option::NAWS, 255, 253, option::TERMINAL_TYPE]).await.unwrap();
+    write.write_all(&[255, 250, option::TERMINAL_TYPE, 1, 255, 240]).await.unwrap();
+    write.write_all(b"hi").await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), Item::Data(b"hi".to_vec()));
+
+    assert_eq!(
+        server.next().await.unwrap().unwrap(),
+        Item::Subnegotiation(option::NAWS, vec![0, 80, 0, 24]),
+    );
+    assert_eq!(
+        server.next().await.unwrap().unwrap(),
+        Item::Subnegotiation(option::TERMINAL_TYPE, b"\0xterm".to_vec()),
+    );
+
+    // Resizing reports the new size.
+    client.set_window_size(100, 40).await.unwrap();
+    assert_eq!(
+        server.next().await.unwrap().unwrap(),
+        Item::Subnegotiation(option::NAWS, vec![0, 100, 0, 40]),
+    );
+}
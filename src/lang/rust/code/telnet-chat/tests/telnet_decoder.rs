@@ -0,0 +1,214 @@
+//! Property tests for `TelnetCodec`: however the input is split into reads,
+//! the decoder yields the same items, and bad input is an `InvalidData` error
+//! rather than a panic. Both the line mode of the server and the data mode of
+//! `TelnetClient` are checked, as they parse commands the same way.
+
+use std::io;
+
+use bytes::BytesMut;
+use proptest::prelude::*;
+use tokio_util::codec::{Decoder, Encoder};
+
+use telnet_chat::telnet::{Item, TelnetCodec};
+
+/// What decoding a stream gives: the items, and the kind of error that ended
+/// the stream, if any.
+type Decoded = (Vec<Item>, Option<io::ErrorKind>);
+
+/// Feed the chunks to a decoder one at a time, the way `FramedRead` does.
+fn decode_with<'a>(mut codec: TelnetCodec, chunks: impl IntoIterator<Item = &'a [u8]>) -> Decoded {
+    let mut buf = BytesMut::new();
+    let mut items = Vec::new();
+
+    for chunk in chunks {
+        buf.extend_from_slice(chunk);
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(item)) => items.push(item),
+                Ok(None) => break,
+                Err(err) => return (items, Some(err.kind())),
+            }
+        }
+    }
+
+    (items, None)
+}
+
+fn decode_chunks<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Decoded {
+    decode_with(TelnetCodec::new(), chunks)
+}
+
+/// Like `decode_chunks`, in data mode. Text is handed out as soon as it
+/// arrives, so how it is split depends on the reads, and runs of `Data` are
+/// joined to compare them.
+fn decode_data_chunks<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Decoded {
+    let (items, err) = decode_with(TelnetCodec::data(), chunks);
+    let mut joined: Vec<Item> = Vec::new();
+    for item in items {
+        match (joined.last_mut(), item) {
+            (Some(Item::Data(data)), Item::Data(more)) => data.extend(more),
+            (_, item) => joined.push(item),
+        }
+    }
+    (joined, err)
+}
+
+/// Split `input` at the given points, which are taken modulo its length.
+fn split_at_points(input: &[u8], points: &[usize]) -> Vec<Vec<u8>> {
+    if input.is_empty() {
+        return vec![];
+    }
+
+    let mut points: Vec<usize> = points.iter().map(|p| p % input.len()).collect();
+    points.sort_unstable();
+    points.dedup();
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for point in points {
+        chunks.push(input[start..point].to_vec());
+        start = point;
+    }
+    chunks.push(input[start..].to_vec());
+    chunks
+}
+
+/// Input that looks like a telnet session: text, line endings and commands,
+/// some of them invalid.
+fn telnet_bytes() -> impl Strategy<Value = Vec<u8>> {
+    let piece = prop_oneof![
+        4 => proptest::collection::vec(32u8..127, 0..20),
+        2 => Just(b"\r\n".to_vec()),
+        1 => Just(b"\n".to_vec()),
+        2 => (240u8..=255).prop_map(|cmd| vec![0xff, cmd]),
+        2 => (251u8..=254, any::<u8>()).prop_map(|(cmd, opt)| vec![0xff, cmd, opt]),
+        1 => any::<u8>().prop_map(|cmd| vec![0xff, cmd]),
+        1 => proptest::collection::vec(any::<u8>(), 0..8),
+    ];
+    proptest::collection::vec(piece, 0..40).prop_map(|pieces| pieces.concat())
+}
+
+proptest! {
+    #[test]
+    fn split_does_not_matter(
+        input in telnet_bytes(),
+        points in proptest::collection::vec(any::<usize>(), 0..16),
+    ) {
+        let whole = decode_chunks([input.as_slice()]);
+        let chunks = split_at_points(&input, &points);
+        let split = decode_chunks(chunks.iter().map(|c| c.as_slice()));
+        prop_assert_eq!(whole, split);
+    }
+
+    #[test]
+    fn byte_at_a_time(input in telnet_bytes()) {
+        let whole = decode_chunks([input.as_slice()]);
+        let bytes = decode_chunks(input.chunks(1));
+        prop_assert_eq!(whole, bytes);
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(input in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let (_, err) = decode_chunks([input.as_slice()]);
+        if let Some(kind) = err {
+            prop_assert_eq!(kind, io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn split_does_not_matter_for_data(
+        input in telnet_bytes(),
+        points in proptest::collection::vec(any::<usize>(), 0..16),
+    ) {
+        let whole = decode_data_chunks([input.as_slice()]);
+        let chunks = split_at_points(&input, &points);
+        let split = decode_data_chunks(chunks.iter().map(|c| c.as_slice()));
+        prop_assert_eq!(whole, split);
+    }
+
+    #[test]
+    fn data_byte_at_a_time(input in telnet_bytes()) {
+        let whole = decode_data_chunks([input.as_slice()]);
+        let bytes = decode_data_chunks(input.chunks(1));
+        prop_assert_eq!(whole, bytes);
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic_in_data_mode(input in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let (_, err) = decode_data_chunks([input.as_slice()]);
+        if let Some(kind) = err {
+            prop_assert_eq!(kind, io::ErrorKind::InvalidData);
+        }
+    }
+
+    /// What the server writes with `Item::Data` reads back the same, except
+    /// for the NULs that follow a bare carriage return.
+    #[test]
+    fn encoded_data_decodes_the_same(data in proptest::collection::vec(any::<u8>(), 1..256)) {
+        let mut buf = BytesMut::new();
+        TelnetCodec::new().encode(Item::Data(data.clone()), &mut buf).unwrap();
+        let (items, err) = decode_data_chunks([&buf[..]]);
+
+        let expected: Vec<u8> = data.into_iter().filter(|&byte| byte != 0).collect();
+        let expected = if expected.is_empty() { vec![] } else { vec![Item::Data(expected)] };
+        prop_assert_eq!(items, expected);
+        prop_assert_eq!(err, None);
+    }
+}
+
+#[test]
+fn partial_command_waits_for_more() {
+    let (items, err) = decode_chunks([&[0xff][..], &[251][..], &[3][..]]);
+    assert_eq!(items, vec![Item::Will(3)]);
+    assert_eq!(err, None);
+}
+
+#[test]
+fn escaped_iac_is_data() {
+    let (items, _) = decode_chunks([&b"a\xff\xffb\n"[..]]);
+    assert_eq!(items, vec![Item::Line(b"a\xffb".to_vec())]);
+}
+
+#[test]
+fn subnegotiation_is_one_item() {
+    // NAWS: 80 columns, 255 rows, with the 255 escaped.
+    let input = [255, 250, 31, 0, 80, 0, 255, 255, 255, 240, b'h', b'i', b'\n'];
+    let (items, err) = decode_chunks(input.chunks(1));
+    assert_eq!(items, vec![
+        Item::Subnegotiation(31, vec![0, 80, 0, 255]),
+        Item::Line(b"hi".to_vec()),
+    ]);
+    assert_eq!(err, None);
+}
+
+#[test]
+fn endless_subnegotiation_is_invalid() {
+    let mut input = vec![255, 250, 24];
+    input.resize(4096, b'x');
+    let (_, err) = decode_chunks([input.as_slice()]);
+    assert_eq!(err, Some(io::ErrorKind::InvalidData));
+}
+
+#[test]
+fn unknown_commands_are_skipped() {
+    let (items, err) = decode_chunks([&b"a\xff\x07b\xff\xf0c\n"[..]]);
+    assert_eq!(items, vec![Item::Line(b"abc".to_vec())]);
+    assert_eq!(err, None);
+}
+
+#[test]
+fn data_mark_discards_the_line_so_far() {
+    let (items, _) = decode_chunks([&b"typo\xff\xf2fixed\n"[..]]);
+    assert_eq!(items, vec![Item::DataMark, Item::Line(b"fixed".to_vec())]);
+}
+
+#[test]
+fn data_is_handed_out_before_commands() {
+    let (items, err) = decode_data_chunks([&b"Nick: \xff\xfb\x01pass\r\0\xff\xffx"[..]]);
+    assert_eq!(items, vec![
+        Item::Data(b"Nick: ".to_vec()),
+        Item::Will(1),
+        Item::Data(b"pass\r\xffx".to_vec()),
+    ]);
+    assert_eq!(err, None);
+}